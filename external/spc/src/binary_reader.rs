@@ -7,6 +7,7 @@ pub trait ReadAll : Read {
 pub trait BinaryRead : ReadAll {
     fn read_u8(&mut self) -> Result<u8>;
     fn read_le_u16(&mut self) -> Result<u16>;
+    fn read_le_u32(&mut self) -> Result<u32>;
     fn read_le_i32(&mut self) -> Result<i32>;
 }
 
@@ -55,11 +56,15 @@ impl<R: Read> BinaryRead for BinaryReader<R> {
         Ok(((buf[1] as u16) << 8) | (buf[0] as u16))
     }
 
-    fn read_le_i32(&mut self) -> Result<i32> {
+    fn read_le_u32(&mut self) -> Result<u32> {
         let mut buf = [0; 4];
         try!(self.read_all(&mut buf));
-        Ok((
+        Ok(
             ((buf[3] as u32) << 24) | ((buf[2] as u32) << 16) |
-            ((buf[1] as u32) << 8) | (buf[0] as u32)) as i32)
+            ((buf[1] as u32) << 8) | (buf[0] as u32))
+    }
+
+    fn read_le_i32(&mut self) -> Result<i32> {
+        Ok(self.read_le_u32()? as i32)
     }
 }
@@ -1,2 +1,4 @@
+extern crate encoding_rs;
+
 mod binary_reader;
 pub mod spc;
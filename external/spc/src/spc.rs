@@ -2,6 +2,7 @@ use std::char;
 use std::io::{Read, Result, Error, ErrorKind, Seek, SeekFrom, BufReader};
 use std::path::Path;
 use std::fs::File;
+use encoding_rs::SHIFT_JIS;
 use super::binary_reader::{ReadAll, BinaryRead, BinaryReader};
 
 macro_rules! fail {
@@ -16,6 +17,12 @@ const HEADER_LEN: usize = 33;
 const HEADER_BYTES: &'static [u8; HEADER_LEN] =
     b"SNES-SPC700 Sound File Data v0.30";
 
+const XID6_OFFSET: u64 = 0x10200;
+const XID6_HEADER_BYTES: &[u8; 4] = b"xid6";
+
+/// Extended ID666 lengths are stored in 1/64000ths of a second.
+pub const XID6_TICKS_PER_SECOND: u32 = 64000;
+
 pub struct Spc {
     pub version_minor: u8,
     pub pc: u16,
@@ -25,6 +32,7 @@ pub struct Spc {
     pub psw: u8,
     pub sp: u8,
     pub id666_tag: Option<Id666Tag>,
+    pub xid6_tag: Option<Xid6Tag>,
     pub ram: [u8; RAM_LEN],
     pub regs: [u8; REG_LEN],
     pub ipl_rom: [u8; IPL_ROM_LEN]
@@ -84,6 +92,13 @@ impl Spc {
         let mut ipl_rom = [0; IPL_ROM_LEN];
         try!(r.read_all(&mut ipl_rom));
 
+        // Extended tags are optional and not always well-formed, so a tag that can't be
+        // read shouldn't prevent the rest of the file from loading.
+        let xid6_tag = match r.seek(SeekFrom::Start(XID6_OFFSET)) {
+            Ok(_) => Xid6Tag::load(&mut r).unwrap_or(None),
+            Err(_) => None
+        };
+
         Ok(Spc {
             version_minor: version_minor,
             pc: pc,
@@ -93,6 +108,7 @@ impl Spc {
             psw: psw,
             sp: sp,
             id666_tag: id666_tag,
+            xid6_tag,
             ram: ram,
             regs: regs,
             ipl_rom: ipl_rom
@@ -197,19 +213,24 @@ impl Id666Tag {
     }
 
     fn read_string<R: BinaryRead>(r: &mut R, max_len: i32) -> Result<String> {
-        // TODO: Reimplement as iterator or something similar
-        let mut ret = "".to_string();
-        let mut has_ended = false;
-        for _ in 0..max_len {
-            let b = try!(r.read_u8());
-            if !has_ended {
-                match char::from_u32(b as u32) {
-                    Some(c) if b != 0 => ret.push(c),
-                    _ => has_ended = true
-                }
-            }
+        let mut buf = vec![0; max_len as usize];
+        r.read_all(&mut buf)?;
+        Ok(Id666Tag::decode_string(&buf))
+    }
+
+    /// Decodes a NUL terminated tag string. Japanese dumps are usually Shift-JIS, so text
+    /// that isn't valid UTF-8 is read as Shift-JIS, and as Latin-1 if it isn't that either.
+    fn decode_string(buf: &[u8]) -> String {
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        let buf = &buf[..len];
+
+        if let Ok(s) = std::str::from_utf8(buf) {
+            return s.to_string();
         }
-        Ok(ret)
+        if let Some(s) = SHIFT_JIS.decode_without_bom_handling_and_without_replacement(buf) {
+            return s.into_owned();
+        }
+        buf.iter().map(|&b| b as char).collect()
     }
 
     fn is_text_region<R: BinaryRead>(r: &mut R, len: i32) -> Result<bool> {
@@ -252,3 +273,271 @@ impl Id666Tag {
         Ok(ret)
     }
 }
+
+/// Extended ID666 tag, stored in a chunk after the end of the SPC snapshot.
+///
+/// Every field is optional, since dumpers only write the sub-chunks they have data for.
+#[derive(Default)]
+pub struct Xid6Tag {
+    pub song_title: Option<String>,
+    pub game_title: Option<String>,
+    pub artist_name: Option<String>,
+    pub dumper_name: Option<String>,
+    pub date_dumped: Option<u32>,
+    pub dumping_emulator: Option<Emulator>,
+    pub comments: Option<String>,
+    pub ost_title: Option<String>,
+    pub ost_disc: Option<u8>,
+    pub ost_track: Option<Xid6Track>,
+    pub publisher_name: Option<String>,
+    pub copyright_year: Option<u16>,
+    pub intro_length: Option<u32>,
+    pub loop_length: Option<u32>,
+    pub end_length: Option<u32>,
+    pub fade_length: Option<u32>,
+    pub muted_voices: Option<u8>,
+    pub loop_count: Option<u8>,
+    pub amplification: Option<u32>
+}
+
+/// OST track number, with an optional suffix character (e.g. track "12a").
+#[derive(Clone, Copy)]
+pub struct Xid6Track {
+    pub number: u8,
+    pub suffix: Option<char>
+}
+
+impl std::fmt::Display for Xid6Track {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.suffix {
+            Some(suffix) => write!(f, "{}{}", self.number, suffix),
+            None => write!(f, "{}", self.number)
+        }
+    }
+}
+
+impl Xid6Tag {
+    fn load<R: BinaryRead + Seek>(r: &mut R) -> Result<Option<Xid6Tag>> {
+        // Running out of file here just means there is no tag
+        let mut header = [0; 4];
+        if r.read_all(&mut header).is_err() || header != *XID6_HEADER_BYTES {
+            return Ok(None);
+        }
+        let chunk_len = r.read_le_u32()?;
+
+        let mut tag = Xid6Tag::default();
+        let mut pos = 0;
+        while pos + 4 <= chunk_len {
+            let id = r.read_u8()?;
+            let data_type = r.read_u8()?;
+            let header_data = r.read_le_u16()?;
+            pos += 4;
+
+            // Type 0 stores its value in the sub-chunk header, every other type is
+            // followed by `header_data` bytes of data padded to a multiple of 4.
+            let data = match data_type {
+                0 => Vec::new(),
+                _ => {
+                    let padded_len = ((header_data as u32) + 3) & !3;
+                    if pos + padded_len > chunk_len {
+                        fail!("Sub-chunk extends past the end of the chunk");
+                    }
+                    let mut data = vec![0; padded_len as usize];
+                    r.read_all(&mut data)?;
+                    pos += padded_len;
+                    data.truncate(header_data as usize);
+                    data
+                }
+            };
+
+            match id {
+                0x01 => tag.song_title = Some(Xid6Tag::string(data_type, &data)?),
+                0x02 => tag.game_title = Some(Xid6Tag::string(data_type, &data)?),
+                0x03 => tag.artist_name = Some(Xid6Tag::string(data_type, &data)?),
+                0x04 => tag.dumper_name = Some(Xid6Tag::string(data_type, &data)?),
+                0x05 => tag.date_dumped = Some(Xid6Tag::integer(data_type, &data)?),
+                0x06 => tag.dumping_emulator = Some(match header_data & 0xff {
+                    1 => Emulator::ZSnes,
+                    2 => Emulator::Snes9x,
+                    _ => Emulator::Unknown
+                }),
+                0x07 => tag.comments = Some(Xid6Tag::string(data_type, &data)?),
+                0x10 => tag.ost_title = Some(Xid6Tag::string(data_type, &data)?),
+                0x11 => tag.ost_disc = Some(header_data as u8),
+                0x12 => tag.ost_track = Some(Xid6Track {
+                    number: (header_data >> 8) as u8,
+                    suffix: match header_data as u8 {
+                        0 => None,
+                        c => char::from_u32(c as u32)
+                    }
+                }),
+                0x13 => tag.publisher_name = Some(Xid6Tag::string(data_type, &data)?),
+                0x14 => tag.copyright_year = Some(header_data),
+                0x30 => tag.intro_length = Some(Xid6Tag::integer(data_type, &data)?),
+                0x31 => tag.loop_length = Some(Xid6Tag::integer(data_type, &data)?),
+                0x32 => tag.end_length = Some(Xid6Tag::integer(data_type, &data)?),
+                0x33 => tag.fade_length = Some(Xid6Tag::integer(data_type, &data)?),
+                0x34 => tag.muted_voices = Some(header_data as u8),
+                0x35 => tag.loop_count = Some(header_data as u8),
+                0x36 => tag.amplification = Some(Xid6Tag::integer(data_type, &data)?),
+                _ => () // Unknown sub-chunk, skip it
+            }
+        }
+
+        Ok(Some(tag))
+    }
+
+    fn string(data_type: u8, data: &[u8]) -> Result<String> {
+        if data_type != 1 {
+            fail!("Expected string sub-chunk");
+        }
+        Ok(Id666Tag::decode_string(data))
+    }
+
+    fn integer(data_type: u8, data: &[u8]) -> Result<u32> {
+        if data_type != 4 || data.len() != 4 {
+            fail!("Expected integer sub-chunk");
+        }
+        Ok(
+            ((data[3] as u32) << 24) | ((data[2] as u32) << 16) |
+            ((data[1] as u32) << 8) | (data[0] as u32))
+    }
+
+    /// Total play length before the fadeout (in ticks), if the tag specifies an intro length.
+    pub fn play_length(&self) -> Option<u64> {
+        let intro_length = match self.intro_length {
+            Some(l) => l as u64,
+            None => return None
+        };
+        let loop_length = self.loop_length.unwrap_or(0) as u64;
+        let loop_count = self.loop_count.unwrap_or(1) as u64;
+        let end_length = self.end_length.unwrap_or(0) as u64;
+
+        Some(intro_length + loop_length * loop_count + end_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    /// A sub-chunk with its data padded to a multiple of 4 bytes
+    fn sub_chunk(id: u8, data_type: u8, header_data: u16, data: &[u8]) -> Vec<u8> {
+        let mut chunk = vec![id, data_type, header_data as u8, (header_data >> 8) as u8];
+        chunk.extend_from_slice(data);
+        chunk.resize(4 + ((data.len() + 3) & !3), 0);
+        chunk
+    }
+
+    fn integer_sub_chunk(id: u8, value: u32) -> Vec<u8> {
+        sub_chunk(id, 4, 4, &[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+    }
+
+    /// An xid6 chunk whose header claims `chunk_len` bytes of sub-chunks
+    fn xid6_with_len(sub_chunks: &[Vec<u8>], chunk_len: u32) -> Vec<u8> {
+        let mut blob = XID6_HEADER_BYTES.to_vec();
+        blob.extend_from_slice(&[chunk_len as u8, (chunk_len >> 8) as u8, (chunk_len >> 16) as u8, (chunk_len >> 24) as u8]);
+        for chunk in sub_chunks {
+            blob.extend_from_slice(chunk);
+        }
+        blob
+    }
+
+    fn xid6(sub_chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunk_len = sub_chunks.iter().map(|chunk| chunk.len()).sum::<usize>();
+        xid6_with_len(sub_chunks, chunk_len as u32)
+    }
+
+    fn load(blob: Vec<u8>) -> Result<Option<Xid6Tag>> {
+        Xid6Tag::load(&mut BinaryReader::new(Cursor::new(blob)))
+    }
+
+    #[test]
+    fn decode_string_test() {
+        assert_eq!(Id666Tag::decode_string(b"Opening\0\0garbage"), "Opening");
+        assert_eq!(Id666Tag::decode_string(b"No terminator"), "No terminator");
+        // "\u{30bb}\u{30ec}\u{30af}\u{30c8}" (Select) in Shift-JIS and in UTF-8
+        assert_eq!(Id666Tag::decode_string(&[0x83, 0x5A, 0x83, 0x8C, 0x83, 0x4E, 0x83, 0x67, 0x00]), "\u{30bb}\u{30ec}\u{30af}\u{30c8}");
+        assert_eq!(Id666Tag::decode_string("\u{30bb}\u{30ec}\u{30af}\u{30c8}".as_bytes()), "\u{30bb}\u{30ec}\u{30af}\u{30c8}");
+        // Neither UTF-8 nor Shift-JIS
+        assert_eq!(Id666Tag::decode_string(&[b'C', b'a', b'f', 0xE9, 0xFF]), "Caf\u{e9}\u{ff}");
+    }
+
+    #[test]
+    fn play_length_test() {
+        let tag = load(xid6(&[
+            integer_sub_chunk(0x30, 10 * XID6_TICKS_PER_SECOND),
+            integer_sub_chunk(0x31, 20 * XID6_TICKS_PER_SECOND),
+            integer_sub_chunk(0x32, 3 * XID6_TICKS_PER_SECOND),
+            integer_sub_chunk(0x33, 5 * XID6_TICKS_PER_SECOND),
+            sub_chunk(0x35, 0, 2, &[])
+        ])).unwrap().unwrap();
+
+        assert_eq!(tag.loop_count, Some(2));
+        assert_eq!(tag.fade_length, Some(5 * XID6_TICKS_PER_SECOND));
+        assert_eq!(tag.play_length(), Some((10 + 2 * 20 + 3) * XID6_TICKS_PER_SECOND as u64));
+    }
+
+    #[test]
+    fn play_length_defaults_test() {
+        // Without a loop count the loop plays once, and without an intro there is no length
+        let tag = load(xid6(&[
+            integer_sub_chunk(0x30, 64000),
+            integer_sub_chunk(0x31, 128000)
+        ])).unwrap().unwrap();
+        assert_eq!(tag.play_length(), Some(192000));
+
+        let tag = load(xid6(&[integer_sub_chunk(0x31, 128000)])).unwrap().unwrap();
+        assert_eq!(tag.play_length(), None);
+    }
+
+    #[test]
+    fn inline_and_extended_data_test() {
+        let tag = load(xid6(&[
+            // 5 bytes of text padded to 8, so the next sub-chunk has to be found after it
+            sub_chunk(0x01, 1, 5, b"Title"),
+            sub_chunk(0x12, 0, (12 << 8) | b'a' as u16, &[]),
+            sub_chunk(0x14, 0, 1995, &[]),
+            sub_chunk(0x03, 1, 6, b"Artist")
+        ])).unwrap().unwrap();
+
+        assert_eq!(tag.song_title.as_deref(), Some("Title"));
+        assert_eq!(tag.artist_name.as_deref(), Some("Artist"));
+        assert_eq!(tag.ost_track.map(|track| track.to_string()), Some("12a".to_string()));
+        assert_eq!(tag.copyright_year, Some(1995));
+    }
+
+    #[test]
+    fn unknown_sub_chunk_test() {
+        let tag = load(xid6(&[
+            sub_chunk(0x7f, 1, 3, b"???"),
+            sub_chunk(0x7e, 0, 0x1234, &[]),
+            integer_sub_chunk(0x30, 64000)
+        ])).unwrap().unwrap();
+
+        assert_eq!(tag.intro_length, Some(64000));
+    }
+
+    #[test]
+    fn missing_tag_test() {
+        assert!(load(Vec::new()).unwrap().is_none());
+        assert!(load(b"id66\0\0\0\0".to_vec()).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_tag_test() {
+        // The chunk claims more sub-chunks than the file holds
+        let chunks = [integer_sub_chunk(0x30, 64000), integer_sub_chunk(0x31, 64000)];
+        let mut blob = xid6(&chunks);
+        blob.truncate(blob.len() - 2);
+        assert!(load(blob).is_err());
+
+        // A sub-chunk's data runs past the end of the chunk
+        let chunks = [sub_chunk(0x01, 1, 16, b"Title")];
+        assert!(load(xid6_with_len(&chunks, 8)).is_err());
+
+        // An integer sub-chunk with the wrong size
+        assert!(load(xid6(&[sub_chunk(0x30, 4, 2, &[1, 2])])).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::emulator::SpcMetadata;
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{track} - {title}";
+pub const DEFAULT_OUTPUT_FORMAT: &str = "mp4";
+
+const ARCHIVE_EXTENSIONS: [&str; 2] = ["rsn", "rar"];
+const TRACK_EXTENSIONS: [&str; 3] = ["spc", "snsf", "minisnsf"];
+
+// Numbers the extraction directories, so every set in the process gets its own
+static EXTRACTED_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| extensions.iter().any(|x| e.eq_ignore_ascii_case(x)))
+        .unwrap_or(false)
+}
+
+/// Whether the input path should be rendered as a set of tracks (a directory or
+/// an RSN/RAR archive of SPCs) instead of as a single SPC.
+pub fn is_batch_input<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.is_dir() || has_extension(path, &ARCHIVE_EXTENSIONS)
+}
+
+/// A set of SPC files to render, read from a directory or extracted from an archive.
+/// Extracted files are removed again when the set is dropped.
+pub struct SpcSet {
+    tracks: Vec<PathBuf>,
+    extracted_dir: Option<PathBuf>
+}
+
+impl SpcSet {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+
+        let mut spc_set = Self {
+            tracks: Vec::new(),
+            extracted_dir: None
+        };
+
+        let dir = if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            let dir_index = EXTRACTED_DIR_COUNT.fetch_add(1, Ordering::Relaxed);
+            let dir = env::temp_dir().join(format!("spc-presenter-{}-{}", process::id(), dir_index));
+            spc_set.extracted_dir = Some(dir.clone());
+            extract_archive(path, &dir)?;
+            dir
+        };
+
+        // Archives keep their folders, so tracks with the same name in different folders
+        // don't overwrite each other
+        find_tracks(&dir, spc_set.extracted_dir.is_some(), &mut spc_set.tracks)?;
+        spc_set.tracks.sort();
+
+        if spc_set.tracks.is_empty() {
+            return Err(format!("No SPC or SNSF files found in {}", path.display()));
+        }
+
+        Ok(spc_set)
+    }
+
+    pub fn tracks(&self) -> &[PathBuf] {
+        &self.tracks
+    }
+}
+
+impl Drop for SpcSet {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.extracted_dir {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Add the tracks in `dir` to `tracks`, and those in its subdirectories if `recursive`
+fn find_tracks(dir: &Path, recursive: bool, tracks: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if path.is_dir() && recursive {
+            find_tracks(&path, recursive, tracks)?;
+        } else if path.is_file() && has_extension(&path, &TRACK_EXTENSIONS) {
+            tracks.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Extract an archive with whichever of `unrar` or `7z` is installed. RAR decompression
+/// is left to an external tool, since the UnRAR source can't be bundled under this
+/// project's license.
+fn extract_archive(archive_path: &Path, dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir)
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    let mut dir_arg = dir.as_os_str().to_os_string();
+    dir_arg.push(std::path::MAIN_SEPARATOR_STR);
+    let mut seven_zip_out = OsString::from("-o");
+    seven_zip_out.push(dir);
+
+    let extractors: [(&str, Vec<&OsStr>); 2] = [
+        ("unrar", vec![OsStr::new("x"), OsStr::new("-y"), OsStr::new("-o+"), OsStr::new("-idq"), archive_path.as_os_str(), &dir_arg]),
+        ("7z", vec![OsStr::new("x"), OsStr::new("-y"), &seven_zip_out, archive_path.as_os_str()])
+    ];
+    for (program, args) in extractors.iter() {
+        let output = match Command::new(program).args(args).output() {
+            Ok(output) => output,
+            // Not installed, try the next one
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to run {}: {}", program, e))
+        };
+
+        return match output.status.success() {
+            true => Ok(()),
+            false => Err(format!("Failed to extract {} with {}: {}", archive_path.display(), program,
+                                 String::from_utf8_lossy(&output.stderr).trim()))
+        };
+    }
+
+    Err("Extracting RSN/RAR archives needs unrar or 7z to be installed".to_string())
+}
+
+fn sanitize_file_name(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Build an output file name from a template and the extension of the output format.
+/// Supported placeholders are `{title}`, `{game}`, `{artist}`, `{track}` and
+/// `{filename}`. The track number comes from the extended ID666 tag if present, or the
+/// position of the file in the set otherwise.
+pub fn output_file_name(template: &str, format: &str, metadata: Option<&SpcMetadata>, spc_path: &Path, index: usize) -> String {
+    let file_stem = spc_path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let or_file_stem = |s: String| if s.trim().is_empty() { file_stem.clone() } else { s };
+
+    let title = or_file_stem(metadata.map(|m| m.title.clone()).unwrap_or_default());
+    let game = metadata.map(|m| m.game.clone()).unwrap_or_default();
+    let artist = metadata.map(|m| m.artist.clone()).unwrap_or_default();
+    let track = match metadata.and_then(|m| m.track.clone()) {
+        Some(track) => pad_track_number(&track),
+        None => format!("{:02}", index + 1)
+    };
+
+    let name = template
+        .replace("{title}", &sanitize_file_name(&title))
+        .replace("{game}", &sanitize_file_name(&game))
+        .replace("{artist}", &sanitize_file_name(&artist))
+        .replace("{track}", &sanitize_file_name(&track))
+        .replace("{filename}", &sanitize_file_name(&file_stem));
+
+    format!("{}.{}", name, format.trim_start_matches('.'))
+}
+
+/// Zero-pad the number a track starts with (keeping a suffix like the "a" in "1a") to two
+/// digits, so tagged tracks sort the same way as those numbered by their position.
+fn pad_track_number(track: &str) -> String {
+    let digits = track.len() - track.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match digits {
+        0 => track.to_string(),
+        _ => format!("{:0>2}{}", &track[..digits], &track[digits..])
+    }
+}
+
+/// `file_name`, or if an earlier track in the batch already uses it, the same name with
+/// " (2)", " (3)" and so on before the extension. Names are compared ignoring case, since
+/// they would still collide on Windows and macOS.
+pub fn unique_file_name(file_name: &str, used_names: &mut HashSet<String>) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+
+    let mut unique_name = file_name.to_string();
+    let mut count = 1;
+    while !used_names.insert(unique_name.to_lowercase()) {
+        count += 1;
+        unique_name = format!("{} ({}){}", stem, count, extension);
+    }
+
+    unique_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(title: &str, game: &str, track: Option<&str>) -> SpcMetadata {
+        SpcMetadata {
+            title: title.to_string(),
+            artist: "Composer".to_string(),
+            game: game.to_string(),
+            dumper: "".to_string(),
+            comments: "".to_string(),
+            ost_title: None,
+            publisher: None,
+            copyright_year: None,
+            disc: None,
+            track: track.map(|t| t.to_string()),
+            duration_frames: 0,
+            fadeout_frames: 0
+        }
+    }
+
+    #[test]
+    fn output_file_name_test() {
+        let spc_path = Path::new("music/101 Opening.spc");
+        let metadata = metadata("Opening", "Game", Some("1a"));
+
+        assert_eq!(output_file_name(DEFAULT_NAME_TEMPLATE, "mp4", Some(&metadata), spc_path, 0), "01a - Opening.mp4");
+        assert_eq!(output_file_name("{game} - {artist} - {filename}", ".flac", Some(&metadata), spc_path, 0), "Game - Composer - 101 Opening.flac");
+    }
+
+    #[test]
+    fn pad_track_number_test() {
+        assert_eq!(pad_track_number("7"), "07");
+        assert_eq!(pad_track_number("12b"), "12b");
+        assert_eq!(pad_track_number("101"), "101");
+        assert_eq!(pad_track_number("Bonus"), "Bonus");
+    }
+
+    #[test]
+    fn output_file_name_fallback_test() {
+        // The track number falls back to the position in the set, and the title to the file name
+        let spc_path = Path::new("music/ending.spc");
+        let metadata = metadata("  ", "", None);
+
+        assert_eq!(output_file_name(DEFAULT_NAME_TEMPLATE, "wav", Some(&metadata), spc_path, 8), "09 - ending.wav");
+        assert_eq!(output_file_name("{track} - {title} ({game})", "mkv", None, spc_path, 11), "12 - ending ().mkv");
+    }
+
+    #[test]
+    fn output_file_name_sanitize_test() {
+        let metadata = metadata("What? <Boss>: \"Final\" | Phase 1/2", "", Some("3"));
+
+        assert_eq!(
+            output_file_name("{title}", "mp4", Some(&metadata), Path::new("boss.spc"), 0),
+            "What_ _Boss__ _Final_ _ Phase 1_2.mp4"
+        );
+    }
+
+    #[test]
+    fn unique_file_name_test() {
+        let mut used_names = HashSet::new();
+
+        assert_eq!(unique_file_name("01 - Title.mp4", &mut used_names), "01 - Title.mp4");
+        assert_eq!(unique_file_name("01 - Title.mp4", &mut used_names), "01 - Title (2).mp4");
+        assert_eq!(unique_file_name("01 - TITLE.mp4", &mut used_names), "01 - TITLE (3).mp4");
+        assert_eq!(unique_file_name("01 - Title.flac", &mut used_names), "01 - Title.flac");
+    }
+}
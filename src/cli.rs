@@ -1,13 +1,18 @@
-use std::collections::HashMap;
-use clap::{arg, Arg, ArgAction, value_parser, Command};
-use std::path::PathBuf;
-use indicatif::{FormattedDuration, HumanBytes, ProgressBar, ProgressStyle};
+use std::collections::{HashMap, HashSet};
+use clap::{arg, Arg, ArgAction, ArgMatches, value_parser, Command};
+use clap::parser::ValueSource;
+use std::path::{Path, PathBuf};
+use indicatif::{FormattedDuration, HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
 use std::fmt::Write;
 use std::fs;
 use std::str::FromStr;
-use csscolorparser::Color as CssColor;
+use crate::batch::{self, SpcSet};
+use crate::emulator::Emulator;
+use crate::frame_rate::FrameRate;
 use crate::renderer::{Renderer, render_options::{RendererOptions, StopCondition}};
 use crate::tuning;
+use crate::visualizer::layout::LAYOUT_PRESETS;
+use crate::visualizer::theme::{parse_css_color, THEME_PRESETS};
 
 fn codec_option_value_parser(s: &str) -> Result<(String, String), String> {
     let (key, value) = s.split_once('=')
@@ -67,19 +72,13 @@ fn sample_color_value_parser(s: &str) -> Result<(u8, raqote::Color), String> {
         .ok_or("Invalid color specification (must be of the form 'source_index=color').".to_string())?;
 
     let sample_index = sample_tuning_numeric_parser(sample_index_str)?;
-    let parsed_color = color_str.parse::<CssColor>()
-        .map_err(|e| e.to_string())?;
-
-    Ok((sample_index, raqote::Color::new(
-        (parsed_color.a * 255.0) as u8,
-        (parsed_color.r * 255.0) as u8,
-        (parsed_color.g * 255.0) as u8,
-        (parsed_color.b * 255.0) as u8
-    )))
+    let parsed_color = parse_css_color(color_str)?;
+
+    Ok((sample_index, parsed_color))
 }
 
-fn get_renderer_options() -> RendererOptions {
-    let matches = Command::new("SPCPresenter")
+fn build_command() -> Command {
+    Command::new("SPCPresenter")
         .arg(arg!(-c --"video-codec" <CODEC> "Set the output video codec")
             .required(false)
             .default_value("libx264"))
@@ -96,6 +95,10 @@ fn get_renderer_options() -> RendererOptions {
             .required(false)
             .value_parser(value_parser!(i32))
             .default_value("44100"))
+        .arg(arg!(-r --"frame-rate" <FPS> "Set the output video frame rate (e.g. 60, 59.94, 50, 30 or 60000/1001)")
+            .required(false)
+            .value_parser(value_parser!(FrameRate))
+            .default_value("60"))
         .arg(arg!(-s --"stop-at" <CONDITION> "Set the stop condition")
             .required(false)
             .value_parser(value_parser!(StopCondition))
@@ -112,6 +115,28 @@ fn get_renderer_options() -> RendererOptions {
             .required(false)
             .value_parser(value_parser!(u32))
             .default_value("1080"))
+        .arg(arg!(--"cw" <WIDTH> "Set the visualizer canvas width")
+            .required(false)
+            .value_parser(value_parser!(u32))
+            .default_value("960"))
+        .arg(arg!(--"ch" <HEIGHT> "Set the visualizer canvas height")
+            .required(false)
+            .value_parser(value_parser!(u32))
+            .default_value("540"))
+        .arg(arg!(-L --"layout" <LAYOUT> "Set the built-in visualizer layout (default, piano-roll, scope-grid, scope-stack, side-by-side, horizontal, falling-notes)")
+            .required(false)
+            .value_parser(LAYOUT_PRESETS)
+            .default_value("default"))
+        .arg(arg!(--"layout-file" <FILE> "Use a JSON or TOML visualizer layout file")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(-T --"theme" <THEME> "Set the built-in visualizer theme (default, neon, flat)")
+            .required(false)
+            .value_parser(THEME_PRESETS)
+            .default_value("default"))
+        .arg(arg!(--"theme-file" <FILE> "Use a JSON or TOML visualizer theme file")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)))
         .arg(arg!(-o --"video-option" <OPTION> "Pass an option to the video codec (option=value)")
             .required(false)
             .value_parser(codec_option_value_parser)
@@ -134,14 +159,26 @@ fn get_renderer_options() -> RendererOptions {
         .arg(arg!(-B --"background" <BACKGROUND> "Set the output background")
             .required(false)
             .value_parser(value_parser!(PathBuf)))
-        .arg(arg!(<spc> "SPC to render")
+        .arg(arg!(--"stems" <DIRECTORY> "Also export per-voice, main mix and echo return stems as WAV files")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"stem-tracks" "Add the per-voice stems to the output video as extra audio streams")
+            .required(false))
+        .arg(arg!(-n --"name-template" <TEMPLATE> "Set the output file name template for batch rendering")
+            .required(false)
+            .default_value(batch::DEFAULT_NAME_TEMPLATE))
+        .arg(arg!(-e --"format" <FORMAT> "Set the output file format for batch rendering (e.g. mp4, mkv, mov, wav or flac)")
+            .required(false)
+            .default_value(batch::DEFAULT_OUTPUT_FORMAT))
+        .arg(arg!(<spc> "SPC to render (or a directory or RSN/RAR archive of SPCs to batch render)")
             .value_parser(value_parser!(PathBuf))
             .required(true))
-        .arg(arg!(<output> "Output video file")
+        .arg(arg!(<output> "Output video file, WAV/FLAC file for audio only (or output directory when batch rendering)")
             .value_parser(value_parser!(PathBuf))
             .required(true))
-        .get_matches();
+}
 
+fn get_renderer_options(matches: &ArgMatches) -> RendererOptions {
     let mut options = RendererOptions::default();
 
     options.input_path = matches.get_one::<PathBuf>("spc").cloned().unwrap().to_str().unwrap().to_string();
@@ -155,6 +192,7 @@ fn get_renderer_options() -> RendererOptions {
     options.video_options.sample_rate = sample_rate;
     options.video_options.audio_time_base = (1, sample_rate).into();
 
+    options.frame_rate = matches.get_one::<FrameRate>("frame-rate").cloned().unwrap();
     options.stop_condition = matches.get_one::<StopCondition>("stop-at").cloned().unwrap();
     options.fadeout_length = matches.get_one::<u64>("stop-fadeout").cloned().unwrap();
 
@@ -162,6 +200,20 @@ fn get_renderer_options() -> RendererOptions {
     let oh = matches.get_one::<u32>("oh").cloned().unwrap();
     options.video_options.resolution_out = (ow, oh);
 
+    let cw = matches.get_one::<u32>("cw").cloned().unwrap();
+    let ch = matches.get_one::<u32>("ch").cloned().unwrap();
+    options.canvas_resolution = (cw, ch);
+
+    options.layout_preset = matches.get_one::<String>("layout").cloned().unwrap();
+    if let Some(layout_path) = matches.get_one::<PathBuf>("layout-file").cloned() {
+        options.layout_path = Some(layout_path.to_str().unwrap().to_string());
+    }
+
+    options.theme_preset = matches.get_one::<String>("theme").cloned().unwrap();
+    if let Some(theme_path) = matches.get_one::<PathBuf>("theme-file").cloned() {
+        options.theme_path = Some(theme_path.to_str().unwrap().to_string());
+    }
+
     if let Some(video_options) = matches.get_many::<(String, String)>("video-option") {
         for (k, v) in video_options.cloned() {
             options.video_options.video_codec_params.insert(k, v);
@@ -196,24 +248,114 @@ fn get_renderer_options() -> RendererOptions {
     if let Some(background_path) = matches.get_one::<PathBuf>("background").cloned() {
         options.video_options.background_path = Some(background_path.to_str().unwrap().to_string());
     }
+    if let Some(stems_path) = matches.get_one::<PathBuf>("stems").cloned() {
+        options.stems_path = Some(stems_path.to_str().unwrap().to_string());
+    }
+    options.stem_tracks = matches.get_flag("stem-tracks");
 
     options
 }
 
 pub fn run() {
-    let options = get_renderer_options();
-    let mut renderer = Renderer::new(options).unwrap();
+    let matches = build_command().get_matches();
+    let options = get_renderer_options(&matches);
+
+    if batch::is_batch_input(&options.input_path) {
+        let name_template = matches.get_one::<String>("name-template").cloned().unwrap();
+        let format = matches.get_one::<String>("format").cloned().unwrap();
+        let use_spc_duration = matches.value_source("stop-at") == Some(ValueSource::DefaultValue);
+        let input_path = options.input_path.clone();
+        if let Err(e) = run_batch(options, &name_template, &format, use_spc_duration) {
+            println!("[FAIL] {}: {}", input_path, e);
+            std::process::exit(1);
+        }
+    } else {
+        render(options, &ProgressBar::new(0)).unwrap();
+    }
+}
+
+/// `path` as a string for `RendererOptions`, or an error if it isn't valid UTF-8
+fn path_string(path: &Path) -> Result<String, String> {
+    path.to_str()
+        .map(|s| s.to_string())
+        .ok_or(format!("Path is not valid UTF-8: {}", path.display()))
+}
+
+/// Fails without rendering anything if the tracks can't be found or the output directory
+/// can't be created. Tracks that fail on their own are reported along with the rest.
+fn run_batch(options: RendererOptions, name_template: &str, format: &str, use_spc_duration: bool) -> Result<(), String> {
+    let spc_set = SpcSet::open(&options.input_path)?;
+    let output_dir = PathBuf::from(&options.video_options.output_path);
+    fs::create_dir_all(&output_dir)
+        .map_err(|e| format!("Failed to create {}: {}", output_dir.display(), e))?;
+
+    let mp = MultiProgress::new();
+    let batch_pb = mp.add(ProgressBar::new(spc_set.tracks().len() as u64));
+    batch_pb.set_style(ProgressStyle::with_template("{msg}\n{wide_bar} {pos}/{len} tracks")
+        .unwrap());
+
+    let mut results: Vec<(String, Result<PathBuf, String>)> = Vec::new();
+    let mut used_names = HashSet::new();
+    for (i, spc_path) in spc_set.tracks().iter().enumerate() {
+        let track_name = spc_path.file_name().unwrap().to_string_lossy().to_string();
+        batch_pb.set_message(format!("BAT] track={}", track_name));
+
+        let pb = mp.add(ProgressBar::new(0));
+        let result = Emulator::new(spc_path).and_then(|emulator| {
+            let metadata = emulator.get_spc_metadata();
+            let file_name = batch::output_file_name(name_template, format, metadata.as_ref(), spc_path, i);
+            let file_name = batch::unique_file_name(&file_name, &mut used_names);
+
+            let mut track_options = options.clone();
+            track_options.input_path = path_string(spc_path)?;
+            track_options.video_options.output_path = path_string(&output_dir.join(&file_name))?;
+            // Each track gets its own stems directory, named after its output file
+            if let Some(stems_path) = &options.stems_path {
+                let stems_dir = PathBuf::from(stems_path).join(PathBuf::from(&file_name).file_stem().unwrap());
+                track_options.stems_path = Some(path_string(&stems_dir)?);
+            }
+
+            // Tracks without a length tag fall back to the default stop condition.
+            if use_spc_duration && metadata.map(|m| m.duration_frames > 0).unwrap_or(false) {
+                track_options.stop_condition = StopCondition::SpcDuration;
+            }
+
+            render(track_options, &pb)?;
+            Ok(output_dir.join(file_name))
+        });
+        pb.finish_and_clear();
+        mp.remove(&pb);
+
+        results.push((track_name, result));
+        batch_pb.inc(1);
+    }
+    batch_pb.finish_with_message("BAT] done");
+
+    let succeeded = results.iter().filter(|(_, result)| result.is_ok()).count();
+    println!();
+    for (track_name, result) in results.iter() {
+        match result {
+            Ok(output_path) => println!("[ OK ] {} -> {}", track_name, output_path.display()),
+            Err(e) => println!("[FAIL] {}: {}", track_name, e)
+        }
+    }
+    println!("{}/{} tracks rendered successfully", succeeded, results.len());
+
+    Ok(())
+}
+
+fn render(options: RendererOptions, pb: &ProgressBar) -> Result<(), String> {
+    let mut renderer = Renderer::new(options)?;
 
-    let pb = ProgressBar::new(0);
     let pb_style_initial = ProgressStyle::with_template("{msg}\n{spinner} Waiting for loop detection...")
         .unwrap();
     let pb_style = ProgressStyle::with_template("{msg}\n{wide_bar} {percent}%")
         .unwrap();
     pb.set_style(pb_style_initial);
 
-    renderer.start_encoding().unwrap();
+    renderer.start_encoding()?;
     loop {
-        if !(renderer.step().unwrap()) {
+        if !(renderer.step()?) {
             break;
         }
 
@@ -254,5 +396,5 @@ pub fn run() {
     }
 
     pb.finish_with_message("Finalizing encode...");
-    renderer.finish_encoding().unwrap();
+    renderer.finish_encoding()
 }
\ No newline at end of file
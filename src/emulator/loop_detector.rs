@@ -0,0 +1,219 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Minimum length of a repeat (in APU samples) that has to be observed before it is
+/// accepted as the song loop, so that a repeated bar or phrase isn't taken for it.
+/// Loops longer than this have to repeat in full instead.
+const MATCH_WINDOW: u64 = 10 * 32000;
+/// Minimum number of matching key events before a repeat is accepted.
+const MIN_MATCH_EVENTS: usize = 16;
+/// Maximum drift (in APU samples) between the timing of two matching key events.
+/// Drivers write KON/KOF at slightly different points within a 64-cycle DSP step.
+const TIMING_TOLERANCE: u64 = 32;
+/// Longest loop (in APU samples) that is looked for.
+const MAX_LOOP_LENGTH: u64 = 5 * 60 * 32000;
+/// How far back key events are kept. The loop start of a candidate that hasn't been
+/// accepted yet is at most three periods plus the match window back.
+const HISTORY_LENGTH: u64 = 3 * MAX_LOOP_LENGTH + MATCH_WINDOW;
+
+/// A KON or KOF write, timestamped in APU samples since the start of playback.
+#[derive(Copy, Clone)]
+pub struct KeyEvent {
+    pub sample: u64,
+    pub fingerprint: u64
+}
+
+/// The looped section of a song, in APU samples.
+#[derive(Copy, Clone)]
+pub struct SongLoop {
+    pub start: u64,
+    pub length: u64
+}
+
+impl SongLoop {
+    /// Position (in APU samples) at which the loop has played `loops` times.
+    pub fn end(&self, loops: u64) -> u64 {
+        self.start + self.length * loops
+    }
+}
+
+/// Detects song loops by looking for a repeat in the stream of key on/off events.
+///
+/// Every event that shares its fingerprint with an earlier one starts (or extends) a
+/// candidate period. A candidate is accepted once every event of a whole period has
+/// matched with consistent timing, for at least `MATCH_WINDOW`, at which point the start
+/// of the first matching event is taken as the loop point. If several periods qualify
+/// at once, the longest one wins, since a shorter one is a repeat within the loop.
+pub struct LoopDetector {
+    /// Key events within `HISTORY_LENGTH` of the newest one
+    events: VecDeque<KeyEvent>,
+    /// Index of the oldest event still in `events`
+    first_index: usize,
+    /// Indices of the events within `MAX_LOOP_LENGTH` of each fingerprint
+    positions: HashMap<u64, Vec<usize>>,
+    /// Number of events each period has matched in a row, up to the newest event
+    runs: HashMap<usize, usize>,
+    song_loop: Option<SongLoop>
+}
+
+impl LoopDetector {
+    pub fn new() -> Self {
+        Self {
+            events: VecDeque::new(),
+            first_index: 0,
+            positions: HashMap::new(),
+            runs: HashMap::new(),
+            song_loop: None
+        }
+    }
+
+    pub fn song_loop(&self) -> Option<SongLoop> {
+        self.song_loop
+    }
+
+    fn event(&self, index: usize) -> &KeyEvent {
+        &self.events[index - self.first_index]
+    }
+
+    pub fn push(&mut self, event: KeyEvent) {
+        if self.song_loop.is_some() {
+            return;
+        }
+
+        while self.events.front().is_some_and(|oldest| oldest.sample + HISTORY_LENGTH < event.sample) {
+            self.events.pop_front();
+            self.first_index += 1;
+        }
+        let index = self.first_index + self.events.len();
+        self.events.push_back(event);
+
+        // Earlier occurrences of this fingerprint too far back to be a loop are dropped
+        let mut previous = self.positions.remove(&event.fingerprint).unwrap_or_default();
+        previous.retain(|&previous_index| {
+            previous_index >= self.first_index && self.event(previous_index).sample + MAX_LOOP_LENGTH >= event.sample
+        });
+
+        // Extend every period that matched at the previous event, and start a new one
+        // for every earlier occurrence of this fingerprint.
+        let mut runs = HashMap::new();
+        for &previous_index in previous.iter() {
+            let period = index - previous_index;
+            let shift = event.sample - self.event(previous_index).sample;
+            let run = match self.runs.get(&period) {
+                Some(run) if self.is_consistent(index - 1, period, shift) => run + 1,
+                _ => 1
+            };
+            runs.insert(period, run);
+        }
+        previous.push(index);
+        self.positions.insert(event.fingerprint, previous);
+        self.runs = runs;
+
+        let accepted_period = self.runs.iter()
+            .filter(|(period, run)| self.is_loop(index, **period, **run))
+            .map(|(period, _)| *period)
+            .max();
+
+        if let Some(period) = accepted_period {
+            let run = self.runs[&period];
+            let first_repeat = index + 1 - run;
+            let start = self.event(first_repeat - period).sample;
+            let length = self.event(first_repeat).sample - start;
+
+            self.song_loop = Some(SongLoop { start, length });
+        }
+    }
+
+    fn is_consistent(&self, index: usize, period: usize, shift: u64) -> bool {
+        if index < self.first_index + period {
+            return false;
+        }
+        let event_shift = self.event(index).sample - self.event(index - period).sample;
+        event_shift.abs_diff(shift) <= TIMING_TOLERANCE
+    }
+
+    /// Whether a period has repeated for long enough to be the song loop: every event of
+    /// the loop has matched, and the match has held for a whole loop length past the
+    /// first repeated event.
+    fn is_loop(&self, index: usize, period: usize, run: usize) -> bool {
+        if run < MIN_MATCH_EVENTS.max(period + 1) {
+            return false;
+        }
+
+        let first_repeat = index + 1 - run;
+        let shift = self.event(index).sample - self.event(index - period).sample;
+        let span = self.event(index).sample - self.event(first_repeat).sample;
+
+        shift > 0 && span >= MATCH_WINDOW.max(shift)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 32000;
+
+    /// Key events for `fingerprints`, each one played for `spacing` samples
+    fn phrase(fingerprints: &[u64], spacing: u64) -> Vec<(u64, u64)> {
+        fingerprints.iter().map(|&fingerprint| (fingerprint, spacing)).collect()
+    }
+
+    /// Feed phrases one after the other, moving each event by `jitter(i)` samples
+    fn detect(phrases: &[&[(u64, u64)]], jitter: impl Fn(usize) -> i64) -> Option<SongLoop> {
+        let mut detector = LoopDetector::new();
+        let mut sample = 0;
+        for (i, &(fingerprint, spacing)) in phrases.iter().flat_map(|phrase| phrase.iter()).enumerate() {
+            detector.push(KeyEvent { sample: (sample as i64 + jitter(i)).max(0) as u64, fingerprint });
+            sample += spacing;
+        }
+        detector.song_loop()
+    }
+
+    #[test]
+    fn intro_and_loop_test() {
+        let intro = phrase(&(1000..1020).collect::<Vec<_>>(), SECOND / 4);
+        // 40 events over 20 seconds, with uneven timing
+        let song_loop: Vec<(u64, u64)> = (0..40).map(|i| (i, SECOND / 4 + (i % 3) * SECOND / 4)).collect();
+        let loop_length: u64 = song_loop.iter().map(|&(_, spacing)| spacing).sum();
+
+        let result = detect(&[&intro, &song_loop, &song_loop, &song_loop, &song_loop], |_| 0).unwrap();
+
+        assert_eq!(result.start, 5 * SECOND);
+        assert_eq!(result.length, loop_length);
+        assert_eq!(result.end(2), 5 * SECOND + 2 * loop_length);
+    }
+
+    #[test]
+    fn repeated_phrase_test() {
+        // A-A-B, where the A-A repeat is both shorter than the loop and long on its own
+        let a = phrase(&(0..24).collect::<Vec<_>>(), SECOND / 2);
+        let b = phrase(&(100..124).collect::<Vec<_>>(), SECOND / 2);
+
+        let result = detect(&[&a, &a, &b, &a, &a, &b, &a, &a, &b, &a, &a, &b], |_| 0).unwrap();
+
+        assert_eq!(result.start, 0);
+        assert_eq!(result.length, 36 * SECOND);
+    }
+
+    #[test]
+    fn timing_jitter_test() {
+        let intro = phrase(&(1000..1010).collect::<Vec<_>>(), SECOND / 2);
+        let song_loop = phrase(&(0..48).collect::<Vec<_>>(), SECOND / 4);
+        // The drift between two events is up to twice this, and between two periods up to
+        // four times
+        let max_jitter = (TIMING_TOLERANCE / 4) as i64;
+        let jitter = |i: usize| (i as i64 * 7919) % (2 * max_jitter + 1) - max_jitter;
+
+        let result = detect(&[&intro, &song_loop, &song_loop, &song_loop, &song_loop], jitter).unwrap();
+
+        assert!(result.start.abs_diff(5 * SECOND) <= TIMING_TOLERANCE);
+        assert!(result.length.abs_diff(12 * SECOND) <= TIMING_TOLERANCE);
+    }
+
+    #[test]
+    fn no_repeat_test() {
+        let song = phrase(&(0..200).collect::<Vec<_>>(), SECOND / 4);
+
+        assert!(detect(&[&song], |_| 0).is_none());
+    }
+}
@@ -0,0 +1,59 @@
+/// How each byte of APU RAM was accessed since the last frame, as a set of flags per byte.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MemoryAccess(pub Box<[u8]>);
+
+impl MemoryAccess {
+    /// Read by the SMP or the S-DSP
+    pub const READ: u8 = 0x01;
+    /// Written by the SMP or the S-DSP, including the echo buffer
+    pub const WRITE: u8 = 0x02;
+    /// Fetched by the SMP as part of an instruction
+    pub const EXECUTE: u8 = 0x04;
+    /// Read by the S-DSP as a sample directory entry
+    pub const DIRECTORY: u8 = 0x08;
+    /// Read by the S-DSP as a BRR block
+    pub const SAMPLE: u8 = 0x10;
+
+    pub fn get(&self, address: u16) -> u8 {
+        self.0[address as usize]
+    }
+
+    /// Flag `length` bytes starting at `address`, wrapping around the end of RAM
+    pub fn mark(&mut self, address: u32, length: u32, flags: u8) {
+        for offset in 0..length {
+            self.0[(address.wrapping_add(offset) & 0xffff) as usize] |= flags;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0.fill(0);
+    }
+}
+
+impl Default for MemoryAccess {
+    fn default() -> Self {
+        Self(vec![0; 0x10000].into_boxed_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_access_test() {
+        let mut access = MemoryAccess::default();
+        access.mark(0x1234, 1, MemoryAccess::READ);
+        access.mark(0x1234, 1, MemoryAccess::EXECUTE);
+        access.mark(0xfffe, 4, MemoryAccess::WRITE);
+
+        assert_eq!(access.get(0x1234), MemoryAccess::READ | MemoryAccess::EXECUTE);
+        assert_eq!(access.get(0x1235), 0);
+        assert_eq!(access.get(0xffff), MemoryAccess::WRITE);
+        assert_eq!(access.get(0x0001), MemoryAccess::WRITE);
+        assert_eq!(access.get(0x0002), 0);
+
+        access.clear();
+        assert_eq!(access, MemoryAccess::default());
+    }
+}
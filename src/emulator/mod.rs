@@ -1,101 +1,336 @@
 mod snes_apu;
 mod resampler;
 mod filter;
+mod loop_detector;
+mod snsf;
+mod registers;
+mod memory;
 
 use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
 use std::path::Path;
 use std::rc::Rc;
-use spc::spc::{Id666Tag, Spc};
+use spc::spc::{Id666Tag, Spc, Xid6Tag, XID6_TICKS_PER_SECOND};
 use snes_apu::apu::Apu;
+use crate::frame_rate::{FrameRate, DEFAULT_FRAME_RATE};
+use snes_apu::dsp::stems::StemRecorder;
 pub use snes_apu::dsp::voice::ResamplingMode;
+pub use snes_apu::dsp::envelope::{EnvelopePhase, EnvelopeState};
+pub use snes_apu::dsp::brr_block_decoder::{BrrSample, VoiceSample};
+pub use snes_apu::dsp::dsp::SAMPLE_RATE;
+pub use snes_apu::dsp::stems::{Stem, NUM_STEMS};
+pub use loop_detector::SongLoop;
+pub use snsf::is_snsf_path;
+pub use registers::{DspRegisters, VoiceRegisters};
+pub use memory::MemoryAccess;
 
 pub trait ApuStateReceiver {
     fn receive(&mut self, channel: usize, volume: u8, amplitude: i16, frequency: f64, timbre: usize, balance: f64, edge: bool, kon_frames: usize);
+
+    /// Called with a copy of every S-DSP register once per frame
+    fn receive_registers(&mut self, _registers: &DspRegisters) {}
+
+    /// Called with every sample returned by the echo unit after its FIR filter, as the
+    /// average of both sides and before the echo volume is applied
+    fn receive_echo(&mut self, _sample: i16) {}
+
+    /// Called with every output sample of the S-DSP: the final stereo mix, and what each
+    /// voice adds to either side of it before the main volume and echo
+    fn receive_mix(&mut self, _left: i16, _right: i16, _voices: &[(i16, i16); 8]) {}
+
+    /// Called with the envelope of every voice once per output sample
+    fn receive_envelopes(&mut self, _envelopes: &[EnvelopeState; 8]) {}
+
+    /// Called with the sample each voice is playing once per frame, or `None` for noise
+    fn receive_samples(&mut self, _samples: &[Option<VoiceSample>; 8]) {}
+
+    /// Called once per frame with how APU RAM was accessed during that frame
+    fn receive_memory(&mut self, _access: &MemoryAccess) {}
 }
 
 pub struct SpcMetadata {
     pub title: String,
     pub artist: String,
     pub game: String,
+    pub dumper: String,
+    pub comments: String,
+    pub ost_title: Option<String>,
+    pub publisher: Option<String>,
+    pub copyright_year: Option<u16>,
+    pub disc: Option<u8>,
+    pub track: Option<String>,
     pub duration_frames: u64,
     pub fadeout_frames: u64
 }
 
+fn xid6_ticks_to_frames(ticks: u64, frame_rate: FrameRate) -> u64 {
+    ticks * frame_rate.numerator as u64 / (XID6_TICKS_PER_SECOND as u64 * frame_rate.denominator as u64)
+}
+
+enum SongSource {
+    Spc(Box<Spc>),
+    Snsf(Box<snsf::Snsf>)
+}
+
+/// Output processing for one exported stem, mirroring the processing of the main mix.
+struct StemChannel {
+    resampler: resampler::Resampler,
+    filter: filter::BlarggSpcFilter,
+    sample_buffer: VecDeque<i16>,
+    held_samples: usize
+}
+
 pub struct Emulator {
-    spc_file: Spc,
+    source: SongSource,
     apu: Box<Apu>,
-    frame_count: usize,
+    frame_count: u64,
+    frame_rate: FrameRate,
+    sample_rate: u32,
     sample_buffer: VecDeque<i16>,
+    /// Samples at the end of `sample_buffer` that are kept back from the output
+    held_samples: usize,
     resampler: resampler::Resampler,
     filter: filter::BlarggSpcFilter,
-    filter_enabled: bool
+    filter_enabled: bool,
+    amplification: i32,
+    loop_detector: loop_detector::LoopDetector,
+    stem_channels: Vec<StemChannel>
 }
 
+/// Extended ID666 amplification value for unity gain
+const AMPLIFICATION_UNIT: i32 = 0x10000;
+
 impl Emulator {
     pub fn new<P: AsRef<Path>>(spc_path: P) -> Result<Self, String> {
-        let spc_file = Spc::load(spc_path)
-            .map_err(|e| format!("Failed to load SPC! {}", e))?;
-        let apu = Apu::from_spc(&spc_file);
+        let (source, apu, amplification) = if is_snsf_path(&spc_path) {
+            let snsf = snsf::Snsf::load(spc_path)
+                .map_err(|e| format!("Failed to load SNSF! {}", e))?;
+            (SongSource::Snsf(Box::new(snsf)), Apu::new(), AMPLIFICATION_UNIT)
+        } else {
+            let spc_file = Spc::load(spc_path)
+                .map_err(|e| format!("Failed to load SPC! {}", e))?;
+            let apu = Apu::from_spc(&spc_file);
+            let amplification = spc_file.xid6_tag.as_ref()
+                .and_then(|t| t.amplification)
+                .map(|a| a as i32)
+                .unwrap_or(AMPLIFICATION_UNIT);
+            (SongSource::Spc(Box::new(spc_file)), apu, amplification)
+        };
 
+        Self::from_source(source, apu, amplification)
+    }
+
+    fn from_source(source: SongSource, apu: Box<Apu>, amplification: i32) -> Result<Self, String> {
         Ok(Self {
-            spc_file,
+            source,
             apu,
             frame_count: 0,
+            frame_rate: DEFAULT_FRAME_RATE,
+            sample_rate: 44_100,
             sample_buffer: VecDeque::new(),
+            held_samples: 0,
             resampler: resampler::Resampler::new(44_100)?,
             filter: filter::BlarggSpcFilter::default(),
-            filter_enabled: false
+            filter_enabled: false,
+            amplification,
+            loop_detector: loop_detector::LoopDetector::new(),
+            stem_channels: Vec::new()
         })
     }
 
+    /// Sets how many frames `step()` splits each second into. This also sets the unit of
+    /// the frame counts in `SpcMetadata`.
+    pub fn set_frame_rate(&mut self, frame_rate: FrameRate) {
+        self.frame_rate = frame_rate;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), String> {
+        self.sample_rate = sample_rate;
+        self.resampler = resampler::Resampler::new(sample_rate)?;
+        self.sample_buffer.clear();
+        self.held_samples = 0;
+
+        for stem_channel in self.stem_channels.iter_mut() {
+            stem_channel.resampler = resampler::Resampler::new(sample_rate)?;
+            stem_channel.sample_buffer.clear();
+            stem_channel.held_samples = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Start or stop recording the per-voice, main mix and echo return stems (see `Stem`).
+    /// Must be set before the first `step()` so the stems stay in sync with the main output.
+    pub fn set_stems_enabled(&mut self, stems_enabled: bool) -> Result<(), String> {
+        self.stem_channels.clear();
+        if !stems_enabled {
+            self.apu.dsp.as_mut().unwrap().stems = None;
+            return Ok(());
+        }
+
+        for _ in 0..NUM_STEMS {
+            self.stem_channels.push(StemChannel {
+                resampler: resampler::Resampler::new(self.sample_rate)?,
+                filter: filter::BlarggSpcFilter::default(),
+                sample_buffer: VecDeque::new(),
+                held_samples: 0
+            });
+        }
+        self.apu.dsp.as_mut().unwrap().stems = Some(Box::new(StemRecorder::new()));
+
+        Ok(())
+    }
+
+    /// Keep track of how APU RAM is accessed for `ApuStateReceiver::receive_memory`. This
+    /// slows down emulation, so it's off unless a visualizer needs it.
+    pub fn set_memory_tracking(&mut self, enabled: bool) {
+        self.apu.memory_access = enabled.then(MemoryAccess::default);
+    }
+
     pub fn set_filter_enabled(&mut self, filter_enabled: bool) {
         self.filter_enabled = filter_enabled;
     }
 
     pub fn init(&mut self) {
-        self.apu.clear_echo_buffer();
+        // SNSF songs boot the APU from reset, so there's no snapshot echo buffer to clear
+        if let SongSource::Spc(_) = self.source {
+            self.apu.clear_echo_buffer();
+        }
         self.filter.clear();
+        for stem_channel in self.stem_channels.iter_mut() {
+            stem_channel.filter.clear();
+        }
+    }
+
+    fn post_process(resampler: &mut resampler::Resampler, filter: &mut filter::BlarggSpcFilter, filter_enabled: bool, amplification: i32,
+                    l_sample_buffer: &[i16], r_sample_buffer: &[i16]) -> Result<Vec<i16>, String> {
+        let mut combined_sample_buffer: Vec<i16> = Vec::new();
+        for sample in resampler.run(l_sample_buffer, r_sample_buffer)? {
+            combined_sample_buffer.push(sample);
+        }
+        if filter_enabled {
+            filter.run(&mut combined_sample_buffer)?;
+        }
+        if amplification != AMPLIFICATION_UNIT {
+            for sample in combined_sample_buffer.iter_mut() {
+                let amplified = (*sample as i64 * amplification as i64) / AMPLIFICATION_UNIT as i64;
+                *sample = amplified.clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+            }
+        }
+
+        Ok(combined_sample_buffer)
     }
 
     pub fn step(&mut self) -> Result<(), String> {
-        let sample_count = if self.frame_count % 3 == 0 { 534 } else { 533 };
+        // Spread the APU samples over the frames so that no fraction of a sample is ever lost
+        let sample_count = match self.frame_count {
+            0 => self.frame_rate.samples_at_frame_end(0, SAMPLE_RATE as u64),
+            n => self.frame_rate.samples_at_frame_end(n, SAMPLE_RATE as u64) - self.frame_rate.samples_at_frame_end(n - 1, SAMPLE_RATE as u64)
+        } as usize;
 
         let mut l_sample_buffer = vec![0i16; sample_count];
         let mut r_sample_buffer = vec![0i16; sample_count];
-        self.apu.render(&mut l_sample_buffer, &mut r_sample_buffer, sample_count as i32);
+        match &mut self.source {
+            SongSource::Spc(_) => self.apu.render(&mut l_sample_buffer, &mut r_sample_buffer, sample_count as i32),
+            SongSource::Snsf(snsf) => snsf.render(&mut self.apu, &mut l_sample_buffer, &mut r_sample_buffer, sample_count as i32)
+        }
 
-        let mut combined_sample_buffer: Vec<i16> = Vec::new();
-        for sample in self.resampler.run(&l_sample_buffer, &r_sample_buffer)? {
-            combined_sample_buffer.push(sample);
+        let combined_sample_buffer = Self::post_process(&mut self.resampler, &mut self.filter, self.filter_enabled, self.amplification,
+                                                        &l_sample_buffer, &r_sample_buffer)?;
+        self.sample_buffer.extend(combined_sample_buffer.iter());
+
+        if let Some(stems) = self.apu.dsp.as_mut().unwrap().stems.as_mut() {
+            for (stem, stem_channel) in Stem::all().into_iter().zip(self.stem_channels.iter_mut()) {
+                stems.read(stem, &mut l_sample_buffer, &mut r_sample_buffer, sample_count as i32);
+                let stem_samples = Self::post_process(&mut stem_channel.resampler, &mut stem_channel.filter, self.filter_enabled, self.amplification,
+                                                      &l_sample_buffer, &r_sample_buffer)?;
+                stem_channel.sample_buffer.extend(stem_samples.iter());
+            }
         }
-        if self.filter_enabled {
-            self.filter.run(&mut combined_sample_buffer)?;
+
+        for event in self.apu.dsp.as_mut().unwrap().key_events.drain(..) {
+            self.loop_detector.push(event);
+        }
+
+        let dsp = self.apu.dsp.as_mut().unwrap();
+        if let Some(state_receiver) = dsp.state_receiver.clone() {
+            let registers = dsp.registers();
+            let samples = dsp.voice_samples();
+            let mut state_receiver = state_receiver.borrow_mut();
+            state_receiver.receive_registers(&registers);
+            state_receiver.receive_samples(&samples);
+            if let Some(memory_access) = &self.apu.memory_access {
+                state_receiver.receive_memory(memory_access);
+            }
+        }
+        if let Some(memory_access) = self.apu.memory_access.as_mut() {
+            memory_access.clear();
         }
-        self.sample_buffer.extend(combined_sample_buffer.iter());
 
         self.frame_count += 1;
 
         Ok(())
     }
 
+    /// Keep all audio rendered so far out of `get_audio_samples()` and `get_stem_samples()`
+    /// for good, so the output lags the emulation by that much. This lets the emulator run
+    /// ahead of the video, e.g. for a piano roll look-ahead, without the audio running ahead too.
+    pub fn hold_back_buffered_audio(&mut self) {
+        self.held_samples = self.sample_buffer.len();
+        for stem_channel in self.stem_channels.iter_mut() {
+            stem_channel.held_samples = stem_channel.sample_buffer.len();
+        }
+    }
+
+    /// With no frame size, returns everything left apart from the held back samples.
     pub fn get_audio_samples(&mut self, frame_size: Option<usize>) -> Option<Vec<i16>> {
+        let available = self.sample_buffer.len().saturating_sub(self.held_samples);
         match frame_size {
             Some(frame_size) => {
-                if self.sample_buffer.len() < frame_size * 2 {
+                if available < frame_size * 2 {
                     return None;
                 }
                 let result: Vec<_> = self.sample_buffer.drain(0..(frame_size * 2)).collect();
                 Some(result)
             },
             None => {
-                let result: Vec<_> = self.sample_buffer.clone().into_iter().collect();
+                let result: Vec<_> = self.sample_buffer.drain(0..available).collect();
                 self.sample_buffer.clear();
                 Some(result)
             }
         }
     }
 
+    /// Returns one frame of samples for every stem, in `Stem::all()` order, once all of
+    /// them have a full frame available. With no frame size, returns everything left apart
+    /// from the held back samples, like `get_audio_samples()`.
+    pub fn get_stem_samples(&mut self, frame_size: Option<usize>) -> Option<Vec<Vec<i16>>> {
+        if self.stem_channels.is_empty() {
+            return None;
+        }
+
+        match frame_size {
+            Some(frame_size) => {
+                if self.stem_channels.iter().any(|c| c.sample_buffer.len().saturating_sub(c.held_samples) < frame_size * 2) {
+                    return None;
+                }
+                Some(self.stem_channels.iter_mut()
+                    .map(|c| c.sample_buffer.drain(0..(frame_size * 2)).collect())
+                    .collect())
+            },
+            None => {
+                Some(self.stem_channels.iter_mut()
+                    .map(|c| {
+                        let available = c.sample_buffer.len().saturating_sub(c.held_samples);
+                        let result: Vec<_> = c.sample_buffer.drain(0..available).collect();
+                        c.sample_buffer.clear();
+                        result
+                    })
+                    .collect())
+            }
+        }
+    }
+
     pub fn set_state_receiver(&mut self, state_receiver: Option<Rc<RefCell<dyn ApuStateReceiver>>>) {
         self.apu.dsp.as_mut().unwrap().state_receiver = state_receiver;
     }
@@ -104,27 +339,141 @@ impl Emulator {
         self.apu.dsp.as_mut().unwrap().set_resampling_mode(resampling_mode);
     }
 
+    pub fn set_voice_muted(&mut self, channel: usize, muted: bool) {
+        self.apu.dsp.as_mut().unwrap().voices[channel].is_muted = muted;
+    }
+
+    /// Voices the SPC dump asks to be muted by default (bit n = voice n), preferring the
+    /// extended ID666 tag.
+    pub fn default_muted_voices(&self) -> u8 {
+        let spc_file = match &self.source {
+            SongSource::Spc(spc_file) => spc_file,
+            SongSource::Snsf(_) => return 0
+        };
+        let xid6_muted_voices = spc_file.xid6_tag.as_ref().and_then(|t| t.muted_voices);
+        let id666_muted_voices = spc_file.id666_tag.as_ref().map(|t| t.default_channel_disables);
+
+        xid6_muted_voices.or(id666_muted_voices).unwrap_or(0)
+    }
+
     pub fn get_spc_metadata(&self) -> Option<SpcMetadata> {
-        if self.spc_file.id666_tag.is_none() {
+        let spc_file = match &self.source {
+            SongSource::Spc(spc_file) => spc_file,
+            SongSource::Snsf(snsf) => return Some(snsf.metadata(self.frame_rate))
+        };
+        if spc_file.id666_tag.is_none() && spc_file.xid6_tag.is_none() {
             return None;
         }
 
-        let title = self.spc_file.id666_tag.as_ref().unwrap().song_title.clone();
-        let artist = self.spc_file.id666_tag.as_ref().unwrap().artist_name.clone();
-        let game = self.spc_file.id666_tag.as_ref().unwrap().game_title.clone();
-        let duration_frames = 60 * (self.spc_file.id666_tag.as_ref().unwrap().seconds_to_play_before_fading_out as u64);
-        let fadeout_frames = 60 * (self.spc_file.id666_tag.as_ref().unwrap().fade_out_length as u64) / 1000;
+        let id666_tag = spc_file.id666_tag.as_ref();
+        let xid6_tag = spc_file.xid6_tag.as_ref();
+
+        // Prefer the extended tag where present, since its strings aren't limited to 32 characters
+        let tag_string = |xid6_field: fn(&Xid6Tag) -> &Option<String>, id666_field: fn(&Id666Tag) -> &String| {
+            xid6_tag.and_then(|t| xid6_field(t).clone())
+                .or(id666_tag.map(|t| id666_field(t).clone()))
+                .unwrap_or_default()
+        };
+
+        let title = tag_string(|t| &t.song_title, |t| &t.song_title);
+        let artist = tag_string(|t| &t.artist_name, |t| &t.artist_name);
+        let game = tag_string(|t| &t.game_title, |t| &t.game_title);
+        let dumper = tag_string(|t| &t.dumper_name, |t| &t.dumper_name);
+        let comments = tag_string(|t| &t.comments, |t| &t.comments);
+
+        let duration_frames = match xid6_tag.and_then(|t| t.play_length()) {
+            Some(ticks) => xid6_ticks_to_frames(ticks, self.frame_rate),
+            None => self.frame_rate.frames(id666_tag.map(|t| t.seconds_to_play_before_fading_out).unwrap_or(0) as f64)
+        };
+        let fadeout_frames = match xid6_tag.and_then(|t| t.fade_length) {
+            Some(ticks) => xid6_ticks_to_frames(ticks as u64, self.frame_rate),
+            None => self.frame_rate.frames(id666_tag.map(|t| t.fade_out_length).unwrap_or(0) as f64 / 1000.0)
+        };
 
         Some(SpcMetadata {
             title,
             artist,
             game,
+            dumper,
+            comments,
+            ost_title: xid6_tag.and_then(|t| t.ost_title.clone()),
+            publisher: xid6_tag.and_then(|t| t.publisher_name.clone()),
+            copyright_year: xid6_tag.and_then(|t| t.copyright_year),
+            disc: xid6_tag.and_then(|t| t.ost_disc),
+            track: xid6_tag.and_then(|t| t.ost_track).map(|t| t.to_string()),
             duration_frames,
             fadeout_frames
         })
     }
 
+    /// Returns the song loop, taken from the extended ID666 tag if it specifies one, or
+    /// detected from playback otherwise.
+    pub fn song_loop(&self) -> Option<SongLoop> {
+        let xid6_tag = match &self.source {
+            SongSource::Spc(spc_file) => spc_file.xid6_tag.as_ref(),
+            SongSource::Snsf(_) => None
+        };
+        if let Some(xid6_tag) = xid6_tag {
+            if let (Some(intro_length), Some(loop_length)) = (xid6_tag.intro_length, xid6_tag.loop_length) {
+                if loop_length > 0 {
+                    let ticks_to_samples = |ticks: u32| ticks as u64 * SAMPLE_RATE as u64 / XID6_TICKS_PER_SECOND as u64;
+                    return Some(SongLoop {
+                        start: ticks_to_samples(intro_length),
+                        length: ticks_to_samples(loop_length)
+                    });
+                }
+            }
+        }
+
+        self.loop_detector.song_loop()
+    }
+
     pub fn set_manual_sample_tuning(&mut self, source: u8, pitch: f64) {
         self.apu.dsp.as_mut().unwrap().source_pitches.insert(source, pitch);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use spc::spc::{IPL_ROM_LEN, RAM_LEN, REG_LEN};
+    use super::*;
+
+    #[test]
+    fn stem_samples_drain_test() {
+        let spc_file = Spc {
+            version_minor: 30, pc: 0, a: 0, x: 0, y: 0, psw: 0, sp: 0xef,
+            id666_tag: None, xid6_tag: None,
+            ram: [0; RAM_LEN], regs: [0; REG_LEN], ipl_rom: [0; IPL_ROM_LEN]
+        };
+        let apu = Apu::from_spc(&spc_file);
+        let mut emulator = Emulator::from_source(SongSource::Spc(Box::new(spc_file)), apu, AMPLIFICATION_UNIT).unwrap();
+        emulator.set_sample_rate(48_000).unwrap();
+        emulator.set_stems_enabled(true).unwrap();
+        emulator.init();
+
+        // Taken in frames of different sizes, with some held back, then drained at the end
+        let mut main_total = 0;
+        let mut stem_totals = vec![0; NUM_STEMS];
+        for frame in 0..37 {
+            emulator.step().unwrap();
+            if frame == 5 {
+                emulator.hold_back_buffered_audio();
+            }
+            while let Some(audio) = emulator.get_audio_samples(Some(1024)) {
+                main_total += audio.len();
+            }
+            while let Some(stems) = emulator.get_stem_samples(Some(1152)) {
+                for (total, audio) in stem_totals.iter_mut().zip(stems) {
+                    *total += audio.len();
+                }
+            }
+        }
+        main_total += emulator.get_audio_samples(None).unwrap().len();
+        for (total, audio) in stem_totals.iter_mut().zip(emulator.get_stem_samples(None).unwrap()) {
+            *total += audio.len();
+        }
+
+        assert!(main_total > 0);
+        assert_eq!(stem_totals, vec![main_total; NUM_STEMS]);
+    }
+}
@@ -0,0 +1,138 @@
+/// A copy of the 128 S-DSP registers, taken at the end of a frame.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DspRegisters(pub [u8; 128]);
+
+/// The registers of a single voice.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VoiceRegisters {
+    pub vol_left: u8,
+    pub vol_right: u8,
+    /// 14-bit pitch
+    pub pitch: u16,
+    pub source: u8,
+    pub adsr1: u8,
+    pub adsr2: u8,
+    pub gain: u8,
+    pub envx: u8,
+    pub outx: u8
+}
+
+impl Default for DspRegisters {
+    fn default() -> Self {
+        Self([0; 128])
+    }
+}
+
+impl DspRegisters {
+    pub fn voice(&self, channel: usize) -> VoiceRegisters {
+        let regs = &self.0[(channel << 4)..((channel << 4) + 0x10)];
+        VoiceRegisters {
+            vol_left: regs[0x00],
+            vol_right: regs[0x01],
+            pitch: (((regs[0x03] as u16) << 8) | regs[0x02] as u16) & 0x3FFF,
+            source: regs[0x04],
+            adsr1: regs[0x05],
+            adsr2: regs[0x06],
+            gain: regs[0x07],
+            envx: regs[0x08],
+            outx: regs[0x09]
+        }
+    }
+
+    pub fn main_volume(&self) -> (u8, u8) {
+        (self.0[0x0c], self.0[0x1c])
+    }
+
+    pub fn echo_volume(&self) -> (u8, u8) {
+        (self.0[0x2c], self.0[0x3c])
+    }
+
+    /// The last value written to KON, not just the voices keyed on this frame
+    pub fn key_on(&self) -> u8 {
+        self.0[0x4c]
+    }
+
+    /// The last value written to KOF
+    pub fn key_off(&self) -> u8 {
+        self.0[0x5c]
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.0[0x6c]
+    }
+
+    pub fn end_of_sample(&self) -> u8 {
+        self.0[0x7c]
+    }
+
+    pub fn echo_feedback(&self) -> u8 {
+        self.0[0x0d]
+    }
+
+    pub fn pitch_modulation(&self) -> u8 {
+        self.0[0x2d]
+    }
+
+    pub fn noise_on(&self) -> u8 {
+        self.0[0x3d]
+    }
+
+    pub fn echo_on(&self) -> u8 {
+        self.0[0x4d]
+    }
+
+    pub fn source_directory(&self) -> u8 {
+        self.0[0x5d]
+    }
+
+    /// Page of the echo buffer, so the address is this times 0x100
+    pub fn echo_start(&self) -> u8 {
+        self.0[0x6d]
+    }
+
+    pub fn echo_delay(&self) -> u8 {
+        self.0[0x7d]
+    }
+
+    /// Length of the echo buffer in bytes. Without any delay, the one sample in it still
+    /// takes up 4 bytes.
+    pub fn echo_length(&self) -> u32 {
+        ((self.echo_delay() & 0x0f) as u32 * 0x800).max(4)
+    }
+
+    /// FIR filter coefficients C0-C7
+    pub fn fir_coefficients(&self) -> [u8; 8] {
+        let mut coefficients = [0u8; 8];
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            *coefficient = self.0[(i << 4) | 0x0f];
+        }
+        coefficients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_test() {
+        let mut registers = DspRegisters::default();
+        registers.0[0x30] = 0x7f;
+        registers.0[0x32] = 0x34;
+        registers.0[0x33] = 0xd2; // The top two bits aren't part of the pitch
+        registers.0[0x39] = 0x80;
+        registers.0[0x0f] = 0x7f;
+        registers.0[0x7f] = 0xff;
+        registers.0[0x4c] = 0x09;
+
+        let voice = registers.voice(3);
+        assert_eq!(voice.vol_left, 0x7f);
+        assert_eq!(voice.pitch, 0x1234);
+        assert_eq!(voice.outx, 0x80);
+        assert_eq!(registers.fir_coefficients(), [0x7f, 0, 0, 0, 0, 0, 0, 0xff]);
+        assert_eq!(registers.key_on(), 0x09);
+        assert_eq!(registers.echo_length(), 4);
+        registers.0[0x7d] = 0x03;
+        assert_eq!(registers.echo_length(), 0x1800);
+    }
+}
@@ -2,6 +2,7 @@ use super::smp::Smp;
 use super::dsp::dsp::Dsp;
 use super::timer::Timer;
 use super::spc::spc::{Spc, RAM_LEN, IPL_ROM_LEN};
+use crate::emulator::MemoryAccess;
 
 static DEFAULT_IPL_ROM: [u8; IPL_ROM_LEN] = [
     0xcd, 0xef, 0xbd, 0xe8, 0x00, 0xc6, 0x1d, 0xd0,
@@ -24,7 +25,14 @@ pub struct Apu {
     timers: [Timer; 3],
 
     is_ipl_rom_enabled: bool,
-    dsp_reg_address: u8
+    dsp_reg_address: u8,
+
+    // Values written by the SMP to $f4-$f7, read by the main CPU through $2140-$2143
+    cpu_ports: [u8; 4],
+
+    /// RAM accesses by the SMP and S-DSP since this was last cleared, only tracked for
+    /// visualizers that show them
+    pub memory_access: Option<MemoryAccess>
 }
 
 impl Apu {
@@ -39,7 +47,11 @@ impl Apu {
             timers: [Timer::new(256), Timer::new(256), Timer::new(32)],
 
             is_ipl_rom_enabled: true,
-            dsp_reg_address: 0
+            dsp_reg_address: 0,
+
+            cpu_ports: [0; 4],
+
+            memory_access: None
         });
         let ret_ptr = &mut *ret as *mut _;
         ret.smp = Some(Box::new(Smp::new(ret_ptr)));
@@ -97,6 +109,34 @@ impl Apu {
         dsp.output_buffer.read(left_buffer, right_buffer, num_samples);
     }
 
+    /// Runs the SMP for roughly `num_cycles` cycles and returns the number actually run.
+    /// Used when the APU is driven by the main CPU instead of running from a snapshot.
+    pub fn run_cycles(&mut self, num_cycles: i32) -> i32 {
+        let cycles = self.smp.as_mut().unwrap().run(num_cycles);
+        self.dsp.as_mut().unwrap().flush();
+        cycles
+    }
+
+    pub fn read_samples(&mut self, left_buffer: &mut [i16], right_buffer: &mut [i16], num_samples: i32) -> bool {
+        let dsp = self.dsp.as_mut().unwrap();
+        if dsp.output_buffer.get_sample_count() < num_samples {
+            return false;
+        }
+
+        dsp.output_buffer.read(left_buffer, right_buffer, num_samples);
+        true
+    }
+
+    /// Main CPU write to one of the APUIO ports ($2140-$2143)
+    pub fn write_port(&mut self, port: usize, value: u8) {
+        self.ram[0xf4 + port] = value;
+    }
+
+    /// Main CPU read from one of the APUIO ports ($2140-$2143)
+    pub fn read_port(&self, port: usize) -> u8 {
+        self.cpu_ports[port]
+    }
+
     pub fn cpu_cycles_callback(&mut self, num_cycles: i32) {
         self.dsp.as_mut().unwrap().cycles_callback(num_cycles);
         for timer in self.timers.iter_mut() {
@@ -106,6 +146,7 @@ impl Apu {
 
     pub fn read_u8(&mut self, address: u32) -> u8 {
         let address = address & 0xffff;
+        self.mark_memory(address, 1, MemoryAccess::READ);
         if address >= 0xf0 && address < 0x0100 {
             match address {
                 0xf0 | 0xf1 => 0,
@@ -128,8 +169,20 @@ impl Apu {
         }
     }
 
+    /// Read RAM without the side effects of reading I/O registers, like timer counters
+    /// being reset
+    pub fn peek_u8(&self, address: u32) -> u8 {
+        let address = address & 0xffff;
+        if address >= 0xffc0 && self.is_ipl_rom_enabled {
+            self.ipl_rom[(address - 0xffc0) as usize]
+        } else {
+            self.ram[address as usize]
+        }
+    }
+
     pub fn write_u8(&mut self, address: u32, value: u8) {
         let address = address & 0xffff;
+        self.mark_memory(address, 1, MemoryAccess::WRITE);
         if address >= 0x00f0 && address < 0x0100 {
             match address {
                 0xf0 => { self.set_test_reg(value); },
@@ -137,7 +190,7 @@ impl Apu {
                 0xf2 => { self.dsp_reg_address = value; },
                 0xf3 => { self.dsp.as_mut().unwrap().set_register(self.dsp_reg_address, value); },
 
-                // 0xf4 ..= 0xf9 => { self.ram[address as usize] = value; },
+                0xf4 ..= 0xf7 => { self.cpu_ports[(address - 0xf4) as usize] = value; },
                 0xf8 ..= 0xf9 => { self.ram[address as usize] = value; },
 
                 0xfa => { self.timers[0].set_target(value); },
@@ -151,6 +204,13 @@ impl Apu {
         }
     }
 
+    /// Flag `length` bytes of RAM as accessed, if memory accesses are being tracked
+    pub fn mark_memory(&mut self, address: u32, length: u32, flags: u8) {
+        if let Some(memory_access) = self.memory_access.as_mut() {
+            memory_access.mark(address, length, flags);
+        }
+    }
+
     pub fn clear_echo_buffer(&mut self) {
         let dsp = self.dsp.as_mut().unwrap();
         let length = dsp.calculate_echo_length();
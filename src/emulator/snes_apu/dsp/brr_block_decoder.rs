@@ -1,5 +1,56 @@
+use std::rc::Rc;
 use super::dsp_helpers;
 
+/// A whole BRR sample decoded from APU RAM, for display.
+#[derive(Debug, PartialEq)]
+pub struct BrrSample {
+    pub samples: Vec<i16>,
+    /// Index in `samples` the sample loops back to, if it loops to a block of its own
+    pub loop_start: Option<usize>
+}
+
+impl BrrSample {
+    /// Decode the blocks from `start_address` up to the one with the end flag, reading
+    /// APU RAM through `read_u8`.
+    pub fn decode<F: FnMut(u32) -> u8>(mut read_u8: F, start_address: u32, loop_address: u32) -> Self {
+        let mut decoder = BrrBlockDecoder::new();
+        let mut samples = Vec::new();
+        let mut address = start_address;
+        loop {
+            let mut buf = [0u8; 9];
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = read_u8(address + i as u32);
+            }
+            decoder.read(&buf);
+            while !decoder.is_finished() {
+                samples.push(decoder.read_next_sample());
+            }
+
+            address += 9;
+            if decoder.is_end || address + 9 > 0x10000 {
+                break;
+            }
+        }
+
+        let loops_within = loop_address >= start_address && loop_address < address && (loop_address - start_address) % 9 == 0;
+        let loop_start = match decoder.is_looping && loops_within {
+            true => Some((loop_address - start_address) as usize / 9 * 16),
+            false => None
+        };
+
+        Self { samples, loop_start }
+    }
+}
+
+/// The sample a voice is playing, and how far into it the voice is.
+#[derive(Clone, Debug)]
+pub struct VoiceSample {
+    pub source: u8,
+    pub sample: Rc<BrrSample>,
+    /// In decoded samples from the start
+    pub position: f64
+}
+
 pub struct BrrBlockDecoder {
     pub is_end: bool,
     pub is_looping: bool,
@@ -106,7 +157,46 @@ impl BrrBlockDecoder {
         ret
     }
 
+    /// Number of samples read from the current block
+    pub fn sample_index(&self) -> usize {
+        self.sample_index as usize
+    }
+
     pub fn is_finished(&self) -> bool {
         self.sample_index >= 16
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block of 16 samples of `value` with filter 0, so each decodes on its own
+    fn block(value: u8, end: bool, looping: bool) -> [u8; 9] {
+        let header = 0xb0 | ((looping as u8) << 1) | (end as u8);
+        let nybbles = (value << 4) | value;
+        [header, nybbles, nybbles, nybbles, nybbles, nybbles, nybbles, nybbles, nybbles]
+    }
+
+    #[test]
+    fn decode_test() {
+        let mut ram = vec![0u8; 0x10000];
+        for (i, block) in [block(1, false, false), block(2, false, false), block(3, true, true)].iter().enumerate() {
+            ram[0x1000 + i * 9..0x1000 + (i + 1) * 9].copy_from_slice(block);
+        }
+
+        let sample = BrrSample::decode(|address| ram[address as usize], 0x1000, 0x1009);
+        assert_eq!(sample.samples.len(), 48);
+        assert_eq!(sample.loop_start, Some(16));
+        assert!(sample.samples[..16].iter().all(|&s| s == sample.samples[0]));
+        assert!(sample.samples[16] > sample.samples[0] && sample.samples[32] > sample.samples[16]);
+
+        // Loop points outside of the sample or between blocks don't count
+        assert_eq!(BrrSample::decode(|address| ram[address as usize], 0x1000, 0x0800).loop_start, None);
+        assert_eq!(BrrSample::decode(|address| ram[address as usize], 0x1000, 0x1004).loop_start, None);
+
+        // Without the loop flag, the sample just ends
+        ram[0x1012] = 0xb1;
+        assert_eq!(BrrSample::decode(|address| ram[address as usize], 0x1000, 0x1009).loop_start, None);
+    }
+}
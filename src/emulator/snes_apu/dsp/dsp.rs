@@ -1,14 +1,19 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 use pitch_detection::detector::PitchDetector;
 use pitch_detection::detector::yin::YINDetector;
-use crate::emulator::ApuStateReceiver;
-use crate::emulator::snes_apu::dsp::brr_block_decoder::BrrBlockDecoder;
+use crate::emulator::{ApuStateReceiver, DspRegisters, MemoryAccess};
+use crate::emulator::loop_detector::KeyEvent;
+use crate::emulator::snes_apu::dsp::brr_block_decoder::{BrrBlockDecoder, BrrSample, VoiceSample};
 use super::super::apu::Apu;
-use super::voice::{Voice, ResamplingMode};
+use super::voice::{Voice, VoiceOutput, ResamplingMode};
+use super::envelope::EnvelopeState;
 use super::filter::Filter;
 use super::ring_buffer::RingBuffer;
+use super::stems::{StemMixState, StemRecorder};
 use super::super::spc::spc::{Spc, REG_LEN};
 use super::dsp_helpers;
 
@@ -35,6 +40,7 @@ pub struct Dsp {
     left_filter: Filter,
     right_filter: Filter,
     pub output_buffer: RingBuffer,
+    pub stems: Option<Box<StemRecorder>>,
 
     vol_left: u8,
     vol_right: u8,
@@ -56,11 +62,16 @@ pub struct Dsp {
     noise: i32,
     echo_pos: i32,
     echo_length: i32,
+    sample_count: u64,
 
     resampling_mode: ResamplingMode,
 
     pub state_receiver: Option<Rc<RefCell<dyn ApuStateReceiver>>>,
-    pub source_pitches: HashMap<u8, f64>
+    pub source_pitches: HashMap<u8, f64>,
+    /// Decoded samples by start and loop address, along with a hash of their blocks, which
+    /// tells when another sample was uploaded to the same place and the entry is stale
+    brr_samples: HashMap<(u32, u32), (u64, Rc<BrrSample>)>,
+    pub key_events: Vec<KeyEvent>
 }
 
 impl Dsp {
@@ -74,6 +85,7 @@ impl Dsp {
             left_filter: Filter::new(),
             right_filter: Filter::new(),
             output_buffer: RingBuffer::new(),
+            stems: None,
 
             vol_left: 0x89,
             vol_right: 0x9c,
@@ -95,11 +107,14 @@ impl Dsp {
             noise: 0x4000,
             echo_pos: 0,
             echo_length: 0,
+            sample_count: 0,
 
             resampling_mode: resampling_mode,
 
             state_receiver: None,
-            source_pitches: HashMap::new()
+            source_pitches: HashMap::new(),
+            brr_samples: HashMap::new(),
+            key_events: Vec::new()
         });
         let ret_ptr = &mut *ret as *mut _;
         for _ in 0..NUM_VOICES {
@@ -193,8 +208,10 @@ impl Dsp {
             let mut left_echo_out = 0;
             let mut right_echo_out = 0;
             let mut last_voice_out = 0;
-            for voice in self.voices.iter_mut() {
+            let mut voice_outputs = [(VoiceOutput::default(), false); NUM_VOICES];
+            for (channel, voice) in self.voices.iter_mut().enumerate() {
                 let output = voice.render_sample(last_voice_out, self.noise, are_any_voices_solod);
+                voice_outputs[channel] = (output, voice.echo_on);
 
                 left_out = dsp_helpers::clamp(dsp_helpers::cast_arb_int(left_out + output.left_out, 17));
                 right_out = dsp_helpers::clamp(dsp_helpers::cast_arb_int(right_out + output.right_out, 17));
@@ -218,9 +235,35 @@ impl Dsp {
             left_echo_in = dsp_helpers::clamp(self.left_filter.next(left_echo_in, false)) & !1;
             right_echo_in = dsp_helpers::clamp(self.right_filter.next(right_echo_in, true)) & !1;
 
+            if self.state_receiver.is_some() {
+                self.state_receiver.clone().unwrap().borrow_mut().receive_echo(((left_echo_in + right_echo_in) / 2) as i16);
+            }
+
             let left_out = dsp_helpers::clamp(dsp_helpers::cast_arb_int(left_out + dsp_helpers::multiply_volume(left_echo_in, self.echo_vol_left), 17)) as i16;
             let right_out = dsp_helpers::clamp(dsp_helpers::cast_arb_int(right_out + dsp_helpers::multiply_volume(right_echo_in, self.echo_vol_right), 17)) as i16;
+            if self.state_receiver.is_some() {
+                let mut voices = [(0i16, 0i16); NUM_VOICES];
+                for (voice, (output, _)) in voices.iter_mut().zip(voice_outputs.iter()) {
+                    *voice = (dsp_helpers::clamp(output.left_out) as i16, dsp_helpers::clamp(output.right_out) as i16);
+                }
+                self.state_receiver.clone().unwrap().borrow_mut().receive_mix(left_out, right_out, &voices);
+            }
             self.output_buffer.write_sample(left_out, right_out);
+            self.sample_count += 1;
+
+            if let Some(stems) = self.stems.as_mut() {
+                let state = StemMixState {
+                    vol_left: self.vol_left,
+                    vol_right: self.vol_right,
+                    echo_vol_left: self.echo_vol_left,
+                    echo_vol_right: self.echo_vol_right,
+                    echo_feedback: self.echo_feedback,
+                    echo_write_enabled: self.echo_write_enabled,
+                    echo_pos: self.echo_pos,
+                    filter_coefficients: self.left_filter.coefficients
+                };
+                stems.record(&state, &voice_outputs, (left_out, right_out), (left_echo_in, right_echo_in));
+            }
 
             if self.echo_write_enabled {
                 left_echo_out = dsp_helpers::clamp(dsp_helpers::cast_arb_int(left_echo_out + ((((left_echo_in * ((self.echo_feedback as i8) as i32)) >> 7) as i16) as i32), 17)) & !1;
@@ -282,6 +325,12 @@ impl Dsp {
 
                     self.state_receiver.clone().unwrap().borrow_mut().receive(channel, volume, amplitude, frequency, timbre, balance, edge, kon_frames);
                 }
+
+                let mut envelopes = [EnvelopeState::default(); NUM_VOICES];
+                for (envelope, voice) in envelopes.iter_mut().zip(self.voices.iter_mut()) {
+                    *envelope = voice.envelope_state();
+                }
+                self.state_receiver.clone().unwrap().borrow_mut().receive_envelopes(&envelopes);
             }
         }
 
@@ -388,6 +437,14 @@ impl Dsp {
         }
     }
 
+    pub fn registers(&mut self) -> DspRegisters {
+        let mut registers = DspRegisters::default();
+        for (address, value) in registers.0.iter_mut().enumerate() {
+            *value = self.get_register(address as u8);
+        }
+        registers
+    }
+
     pub fn read_counter(&self, rate: i32) -> bool {
         ((self.counter + COUNTER_OFFSETS[rate as usize]) % COUNTER_RATES[rate as usize]) != 0
     }
@@ -405,11 +462,38 @@ impl Dsp {
         let entry_address = dir_address + index * 4;
         let mut ret = self.emulator().read_u8((entry_address as u32) + (offset as u32)) as u32;
         ret |= (self.emulator().read_u8((entry_address as u32) + (offset as u32) + 1) as u32) << 8;
+        self.emulator().mark_memory((entry_address as u32) + (offset as u32), 2, MemoryAccess::DIRECTORY);
         ret
     }
 
+    fn push_key_event(&mut self, register: u8, voice_mask: u8) {
+        if voice_mask == 0 {
+            return;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (register, voice_mask).hash(&mut hasher);
+        if register == 0x4c {
+            // Fingerprint the voice setup at key on, so that different notes played on
+            // the same channel can be told apart
+            for i in 0..NUM_VOICES {
+                if ((voice_mask as usize) & (1 << i)) != 0 {
+                    let voice = &self.voices[i];
+                    (voice.source, voice.pitch(), voice.vol_left, voice.vol_right).hash(&mut hasher);
+                    (voice.envelope.adsr0, voice.envelope.adsr1, voice.envelope.gain).hash(&mut hasher);
+                }
+            }
+        }
+
+        self.key_events.push(KeyEvent {
+            sample: self.sample_count,
+            fingerprint: hasher.finish()
+        });
+    }
+
     fn set_kon(&mut self, voice_mask: u8) {
         self.kon_cache = voice_mask;
+        self.push_key_event(0x4c, voice_mask);
         for i in 0..NUM_VOICES {
             if ((voice_mask as usize) & (1 << i)) != 0 {
                 self.voices[i].key_on();
@@ -419,6 +503,7 @@ impl Dsp {
 
     fn set_kof(&mut self, voice_mask: u8) {
         self.koff_cache = voice_mask;
+        self.push_key_event(0x5c, voice_mask);
         for i in 0..NUM_VOICES {
             if ((voice_mask as usize) & (1 << i)) != 0 {
                 self.voices[i].key_off();
@@ -503,6 +588,56 @@ impl Dsp {
         result
     }
 
+    /// The sample each voice is playing, apart from voices playing noise
+    pub fn voice_samples(&mut self) -> [Option<VoiceSample>; NUM_VOICES] {
+        let mut result: [Option<VoiceSample>; NUM_VOICES] = Default::default();
+        for (channel, voice_sample) in result.iter_mut().enumerate() {
+            if self.voices[channel].noise_on {
+                continue;
+            }
+
+            let (start_address, loop_address) = self.voices[channel].sample_addresses();
+            // A sample uploaded over an older one at the same address replaces it
+            let key = (start_address, loop_address);
+            let brr_hash = self.brr_hash(start_address);
+            let sample = match self.brr_samples.get(&key) {
+                Some((hash, sample)) if *hash == brr_hash => sample.clone(),
+                _ => {
+                    let sample = Rc::new(BrrSample::decode(|address| self.emulator().peek_u8(address), start_address, loop_address));
+                    self.brr_samples.insert(key, (brr_hash, sample.clone()));
+                    sample
+                }
+            };
+
+            *voice_sample = Some(VoiceSample {
+                source: self.voices[channel].source,
+                sample,
+                position: self.voices[channel].sample_position()
+            });
+        }
+        result
+    }
+
+    /// Hash of the BRR blocks from `start_address` up to the one with the end flag, the
+    /// same ones `BrrSample::decode` reads
+    fn brr_hash(&self, start_address: u32) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut address = start_address;
+        loop {
+            let mut block = [0u8; 9];
+            for (i, byte) in block.iter_mut().enumerate() {
+                *byte = self.emulator().peek_u8(address + i as u32);
+            }
+            block.hash(&mut hasher);
+
+            address += 9;
+            if block[0] & 0x01 != 0 || address + 9 > 0x10000 {
+                break;
+            }
+        }
+        hasher.finish()
+    }
+
     fn detect_voice_pitch(&mut self, channel: usize) -> f64 {
         if self.voices[channel].noise_on {
             const C_0: f64 = 16.351597831287;
@@ -530,7 +665,7 @@ impl Dsp {
         loop {
             let mut buf = [0; 9];
             for i in 0..9 {
-                buf[i] = self.emulator().read_u8(sample_address + i as u32);
+                buf[i] = self.emulator().peek_u8(sample_address + i as u32);
             }
             brr_block_decoder.read(&buf);
             sample_address += 9;
@@ -1,5 +1,32 @@
 use super::dsp::Dsp;
 
+/// What an envelope is doing: one of the ADSR phases, or the GAIN mode that is set
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum EnvelopePhase {
+    Attack,
+    Decay,
+    Sustain,
+    #[default]
+    Release,
+    /// GAIN sets the level directly
+    Direct,
+    LinearDecrease,
+    ExponentialDecrease,
+    LinearIncrease,
+    /// Linear increase that slows down at 3/4 of the maximum level
+    BentIncrease
+}
+
+/// A voice's envelope at one output sample, and whether it was just keyed on or off.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct EnvelopeState {
+    /// 11-bit level
+    pub level: u16,
+    pub phase: EnvelopePhase,
+    pub key_on: bool,
+    pub key_off: bool
+}
+
 enum Mode {
     Attack,
     Decay,
@@ -139,9 +166,56 @@ impl Envelope {
         }
     }
 
+    pub fn phase(&self) -> EnvelopePhase {
+        match self.mode {
+            Mode::Release => EnvelopePhase::Release,
+            _ if (self.adsr0 & 0x80) != 0 => match self.mode {
+                Mode::Attack => EnvelopePhase::Attack,
+                Mode::Decay => EnvelopePhase::Decay,
+                _ => EnvelopePhase::Sustain
+            },
+            _ => match self.gain >> 5 {
+                0..=3 => EnvelopePhase::Direct,
+                4 => EnvelopePhase::LinearDecrease,
+                5 => EnvelopePhase::ExponentialDecrease,
+                6 => EnvelopePhase::LinearIncrease,
+                _ => EnvelopePhase::BentIncrease
+            }
+        }
+    }
+
     pub fn skip_brr(&mut self) -> bool {
         let result = self.hidden_skip_brr;
         self.hidden_skip_brr = false;
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use super::*;
+
+    #[test]
+    fn phase_test() {
+        let mut envelope = Envelope::new(ptr::null_mut());
+        assert_eq!(envelope.phase(), EnvelopePhase::Release);
+
+        envelope.adsr0 = 0x8f;
+        envelope.key_on();
+        assert_eq!(envelope.phase(), EnvelopePhase::Attack);
+        envelope.mode = Mode::Sustain;
+        assert_eq!(envelope.phase(), EnvelopePhase::Sustain);
+
+        // GAIN modes only apply while ADSR is off
+        envelope.adsr0 = 0x0f;
+        for (gain, phase) in [(0x7f, EnvelopePhase::Direct), (0x9f, EnvelopePhase::LinearDecrease), (0xbf, EnvelopePhase::ExponentialDecrease),
+                              (0xdf, EnvelopePhase::LinearIncrease), (0xff, EnvelopePhase::BentIncrease)] {
+            envelope.gain = gain;
+            assert_eq!(envelope.phase(), phase);
+        }
+
+        envelope.key_off();
+        assert_eq!(envelope.phase(), EnvelopePhase::Release);
+    }
+}
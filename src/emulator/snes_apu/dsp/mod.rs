@@ -1,8 +1,9 @@
 mod dsp_helpers;
-mod envelope;
+pub mod envelope;
 pub(crate) mod brr_block_decoder;
 mod gaussian;
 pub mod voice;
 mod filter;
 mod ring_buffer;
+pub mod stems;
 pub mod dsp;
@@ -0,0 +1,138 @@
+use super::dsp_helpers;
+use super::filter::Filter;
+use super::ring_buffer::RingBuffer;
+use super::voice::VoiceOutput;
+
+const NUM_VOICES: usize = 8;
+
+pub const NUM_STEMS: usize = NUM_VOICES * 2 + 2;
+
+// Longest possible echo buffer (EDL = $0f), in stereo samples
+const MAX_ECHO_SAMPLES: usize = 0x7800 / 4;
+
+/// One isolated part of the DSP output.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Stem {
+    /// A voice after the main volume, without any echo
+    Dry(usize),
+    /// A voice after the main volume, plus its share of the echo return
+    Wet(usize),
+    /// The regular DSP output
+    Main,
+    /// The output of the echo FIR filter after the echo volume
+    EchoReturn
+}
+
+impl Stem {
+    pub fn all() -> Vec<Stem> {
+        (0..NUM_VOICES).map(Stem::Dry)
+            .chain((0..NUM_VOICES).map(Stem::Wet))
+            .chain([Stem::Main, Stem::EchoReturn])
+            .collect()
+    }
+
+    pub fn index(&self) -> usize {
+        match self {
+            Stem::Dry(voice) => *voice,
+            Stem::Wet(voice) => NUM_VOICES + *voice,
+            Stem::Main => NUM_VOICES * 2,
+            Stem::EchoReturn => NUM_VOICES * 2 + 1
+        }
+    }
+}
+
+/// The DSP registers the stems are mixed with, copied out of the DSP for every sample.
+pub struct StemMixState {
+    pub vol_left: u8,
+    pub vol_right: u8,
+    pub echo_vol_left: u8,
+    pub echo_vol_right: u8,
+    pub echo_feedback: u8,
+    pub echo_write_enabled: bool,
+    pub echo_pos: i32,
+    pub filter_coefficients: [u8; 8]
+}
+
+/// A private echo buffer and FIR filter for a single voice. It only ever receives that
+/// voice's echo output, which splits the shared echo return up per voice.
+struct VoiceEcho {
+    buffer: Box<[(i32, i32)]>,
+    left_filter: Filter,
+    right_filter: Filter
+}
+
+impl VoiceEcho {
+    fn new() -> VoiceEcho {
+        VoiceEcho {
+            buffer: vec![(0, 0); MAX_ECHO_SAMPLES].into_boxed_slice(),
+            left_filter: Filter::new(),
+            right_filter: Filter::new()
+        }
+    }
+
+    fn next(&mut self, state: &StemMixState, left_echo_out: i32, right_echo_out: i32) -> (i32, i32) {
+        self.left_filter.coefficients = state.filter_coefficients;
+        self.right_filter.coefficients = state.filter_coefficients;
+
+        let index = (state.echo_pos / 4) as usize % MAX_ECHO_SAMPLES;
+        let (left_echo_in, right_echo_in) = self.buffer[index];
+        let left_echo_in = dsp_helpers::clamp(self.left_filter.next(left_echo_in, false)) & !1;
+        let right_echo_in = dsp_helpers::clamp(self.right_filter.next(right_echo_in, true)) & !1;
+
+        if state.echo_write_enabled {
+            let left_feedback = (((left_echo_in * ((state.echo_feedback as i8) as i32)) >> 7) as i16) as i32;
+            let right_feedback = (((right_echo_in * ((state.echo_feedback as i8) as i32)) >> 7) as i16) as i32;
+            self.buffer[index] = (
+                dsp_helpers::clamp(dsp_helpers::cast_arb_int(left_echo_out + left_feedback, 17)) & !1,
+                dsp_helpers::clamp(dsp_helpers::cast_arb_int(right_echo_out + right_feedback, 17)) & !1
+            );
+        }
+
+        (left_echo_in, right_echo_in)
+    }
+}
+
+/// Records every stem alongside the regular DSP output, so they can all be exported from
+/// a single emulation pass. The per-voice echo is tracked separately from the real echo
+/// buffer in ARAM, so the wet stems only add up to the main mix up to clamping.
+pub struct StemRecorder {
+    buffers: Vec<RingBuffer>,
+    voice_echoes: Vec<VoiceEcho>
+}
+
+impl StemRecorder {
+    pub fn new() -> StemRecorder {
+        StemRecorder {
+            buffers: (0..NUM_STEMS).map(|_| RingBuffer::new()).collect(),
+            voice_echoes: (0..NUM_VOICES).map(|_| VoiceEcho::new()).collect()
+        }
+    }
+
+    pub fn record(&mut self, state: &StemMixState, voice_outputs: &[(VoiceOutput, bool)], main_out: (i16, i16), echo_in: (i32, i32)) {
+        for (voice, (output, echo_on)) in voice_outputs.iter().enumerate() {
+            let left_out = dsp_helpers::multiply_volume(dsp_helpers::clamp(output.left_out), state.vol_left);
+            let right_out = dsp_helpers::multiply_volume(dsp_helpers::clamp(output.right_out), state.vol_right);
+
+            let (left_echo_out, right_echo_out) = match echo_on {
+                true => (output.left_out, output.right_out),
+                false => (0, 0)
+            };
+            let (left_echo_in, right_echo_in) = self.voice_echoes[voice].next(state, left_echo_out, right_echo_out);
+            let left_wet = left_out + dsp_helpers::multiply_volume(left_echo_in, state.echo_vol_left);
+            let right_wet = right_out + dsp_helpers::multiply_volume(right_echo_in, state.echo_vol_right);
+
+            self.buffers[Stem::Dry(voice).index()].write_sample(dsp_helpers::clamp(left_out) as i16, dsp_helpers::clamp(right_out) as i16);
+            self.buffers[Stem::Wet(voice).index()].write_sample(dsp_helpers::clamp(left_wet) as i16, dsp_helpers::clamp(right_wet) as i16);
+        }
+
+        self.buffers[Stem::Main.index()].write_sample(main_out.0, main_out.1);
+        self.buffers[Stem::EchoReturn.index()].write_sample(
+            dsp_helpers::clamp(dsp_helpers::multiply_volume(echo_in.0, state.echo_vol_left)) as i16,
+            dsp_helpers::clamp(dsp_helpers::multiply_volume(echo_in.1, state.echo_vol_right)) as i16
+        );
+    }
+
+    pub fn read(&mut self, stem: Stem, left: &mut [i16], right: &mut [i16], num_samples: i32) {
+        self.buffers[stem.index()].read(left, right, num_samples);
+    }
+}
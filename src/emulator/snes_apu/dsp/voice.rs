@@ -1,7 +1,8 @@
 use crate::emulator::snes_apu::dsp::gaussian::construct_accurate_gaussian_table;
 use super::dsp::Dsp;
 use super::super::apu::Apu;
-use super::envelope::Envelope;
+use crate::emulator::MemoryAccess;
+use super::envelope::{Envelope, EnvelopeState};
 use super::brr_block_decoder::BrrBlockDecoder;
 use super::dsp_helpers;
 use super::gaussian::{HALF_KERNEL_SIZE, HALF_KERNEL};
@@ -79,6 +80,9 @@ pub struct Voice {
     sample_pos: i32,
 
     pub(crate) edge_hit: bool,
+    /// Keyed on or off by KON/KOF since the last `envelope_state()`
+    key_on_hit: bool,
+    key_off_hit: bool,
     pub(crate) sample_frame: usize,
     endx_bit: bool,
     endx_latch: bool,
@@ -119,6 +123,8 @@ impl Voice {
             sample_pos: 0,
 
             edge_hit: false,
+            key_on_hit: false,
+            key_off_hit: false,
             sample_frame: 0,
             endx_bit: false,
             endx_latch: false,
@@ -285,6 +291,7 @@ impl Voice {
         self.read_next_sample();
         self.envelope.key_on();
         self.edge_hit = true;
+        self.key_on_hit = true;
         self.sample_frame = 0;
         self.endx_bit = false;
         self.endx_latch = false;
@@ -294,6 +301,7 @@ impl Voice {
     pub fn key_off(&mut self) {
         self.envelope.key_off();
         self.edge_hit = true;
+        self.key_off_hit = true;
     }
 
     fn read_entry(&mut self) {
@@ -311,6 +319,7 @@ impl Voice {
         for i in 0..9 {
             buf[i] = self.emulator().read_u8(self.sample_address + (i as u32));
         }
+        self.emulator().mark_memory(self.sample_address, 9, MemoryAccess::SAMPLE);
         self.brr_block_decoder.read(&buf);
         self.sample_address += 9;
     }
@@ -327,12 +336,38 @@ impl Voice {
         (((self.pitch_high as u16) << 8) | (self.pitch_low as u16)) & 0x3FFF
     }
 
+    /// Start and loop address of the sample being played
+    pub fn sample_addresses(&self) -> (u32, u32) {
+        (self.sample_start_address, self.loop_start_address)
+    }
+
+    /// How far into its sample the voice is, in decoded samples from the start
+    pub fn sample_position(&self) -> f64 {
+        // The current block has already been read, and its samples are read one at a time
+        let blocks_read = self.sample_address.wrapping_sub(self.sample_start_address) / 9;
+        (blocks_read.saturating_sub(1) * 16) as f64
+            + self.brr_block_decoder.sample_index() as f64
+            + self.sample_pos as f64 / 0x1000 as f64
+    }
+
     pub fn edge_detected(&mut self) -> bool {
         let result = self.edge_hit;
         self.edge_hit = false;
         result
     }
 
+    pub fn envelope_state(&mut self) -> EnvelopeState {
+        let result = EnvelopeState {
+            level: self.envelope.level as u16,
+            phase: self.envelope.phase(),
+            key_on: self.key_on_hit,
+            key_off: self.key_off_hit
+        };
+        self.key_on_hit = false;
+        self.key_off_hit = false;
+        result
+    }
+
     pub fn get_sample_frame(&mut self) -> usize {
         let result = self.sample_frame;
         self.sample_frame += 1;
@@ -1,4 +1,5 @@
 use super::apu::Apu;
+use crate::emulator::MemoryAccess;
 
 pub struct Smp {
     emulator: *mut Apu,
@@ -104,6 +105,7 @@ impl Smp {
 
     fn read_pc(&mut self) -> u8 {
         let addr = self.reg_pc;
+        self.emulator().mark_memory(addr as u32, 1, MemoryAccess::EXECUTE);
         let ret = self.read(addr);
         self.reg_pc = self.reg_pc.wrapping_add(1);
         ret
@@ -0,0 +1,535 @@
+/// Master cycles per scanline and scanlines per frame (NTSC)
+const SCANLINE_CYCLES: u32 = 1364;
+const SCANLINES: u32 = 262;
+const VBLANK_START: u32 = 225;
+
+const WRAM_LEN: usize = 0x20000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Mapping {
+    LoRom,
+    HiRom,
+    ExHiRom
+}
+
+#[derive(PartialEq, Debug)]
+enum Region {
+    Wram(usize),
+    Rom(usize),
+    Sram(usize),
+    Io(u16),
+    Open
+}
+
+/// The main CPU's view of the SNES: cartridge, work RAM, and just enough of the
+/// CPU-side I/O registers (timing, interrupts, math, DMA, APUIO) to run sound code.
+/// The PPU isn't emulated; writes to it are dropped.
+pub struct Bus {
+    rom: Vec<u8>,
+    sram: Vec<u8>,
+    wram: Box<[u8]>,
+    mapping: Mapping,
+
+    // Last values written by the SMP to $f4-$f7, and pending main CPU writes to $2140-$2143
+    pub apu_ports: [u8; 4],
+    apu_port_writes: [Option<u8>; 4],
+
+    open_bus: u8,
+    cycles: u64,
+    h_cycle: u32,
+    scanline: u32,
+
+    nmitimen: u8,
+    nmi_flag: bool,
+    nmi_pending: bool,
+    irq_flag: bool,
+    htime: u16,
+    vtime: u16,
+    fast_rom: bool,
+
+    wram_address: u32,
+    multiplicand: u8,
+    dividend: u16,
+    quotient: u16,
+    product_remainder: u16,
+
+    dma_regs: [[u8; 16]; 8]
+}
+
+impl Bus {
+    pub fn new(rom: Vec<u8>, sram: Vec<u8>) -> Self {
+        let mapping = detect_mapping(&rom);
+
+        Self {
+            rom,
+            sram,
+            wram: vec![0x55; WRAM_LEN].into_boxed_slice(),
+            mapping,
+
+            apu_ports: [0; 4],
+            apu_port_writes: [None; 4],
+
+            open_bus: 0,
+            cycles: 0,
+            h_cycle: 0,
+            scanline: 0,
+
+            nmitimen: 0,
+            nmi_flag: false,
+            nmi_pending: false,
+            irq_flag: false,
+            htime: 0x1ff,
+            vtime: 0x1ff,
+            fast_rom: false,
+
+            wram_address: 0,
+            multiplicand: 0xff,
+            dividend: 0xffff,
+            quotient: 0,
+            product_remainder: 0,
+
+            dma_regs: [[0xff; 16]; 8]
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn take_apu_port_writes(&mut self) -> [Option<u8>; 4] {
+        std::mem::take(&mut self.apu_port_writes)
+    }
+
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi_pending, false)
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    pub fn idle(&mut self) {
+        self.tick(6);
+    }
+
+    pub fn read(&mut self, address: u32) -> u8 {
+        let (bank, offset) = ((address >> 16) as u8, address as u16);
+        self.tick(self.access_cycles(bank, offset));
+
+        let value = match self.decode(bank, offset) {
+            Region::Io(offset) => self.read_io(offset),
+            region => self.read_region(region)
+        };
+        self.open_bus = value;
+        value
+    }
+
+    pub fn write(&mut self, address: u32, value: u8) {
+        let (bank, offset) = ((address >> 16) as u8, address as u16);
+        self.tick(self.access_cycles(bank, offset));
+
+        self.open_bus = value;
+        match self.decode(bank, offset) {
+            Region::Io(offset) => self.write_io(offset, value),
+            region => self.write_region(region, value)
+        }
+    }
+
+    fn decode(&self, bank: u8, offset: u16) -> Region {
+        if bank == 0x7e || bank == 0x7f {
+            return Region::Wram((((bank - 0x7e) as usize) << 16) | offset as usize);
+        }
+
+        let is_system_bank = (bank & 0x40) == 0;
+        if is_system_bank && offset < 0x2000 {
+            return Region::Wram(offset as usize);
+        }
+        if is_system_bank && offset < 0x6000 {
+            return Region::Io(offset);
+        }
+
+        match self.mapping {
+            Mapping::LoRom => {
+                let bank = (bank & 0x7f) as usize;
+                if offset >= 0x8000 {
+                    Region::Rom(bank * 0x8000 + (offset as usize - 0x8000))
+                } else if bank >= 0x70 {
+                    Region::Sram((bank - 0x70) * 0x8000 + offset as usize)
+                } else if !is_system_bank {
+                    Region::Rom(bank * 0x8000 + offset as usize)
+                } else {
+                    Region::Open
+                }
+            },
+            Mapping::HiRom | Mapping::ExHiRom => {
+                if is_system_bank && offset < 0x8000 {
+                    if (bank & 0x3f) >= 0x20 {
+                        Region::Sram(((bank & 0x1f) as usize) * 0x2000 + (offset as usize - 0x6000))
+                    } else {
+                        Region::Open
+                    }
+                } else {
+                    let base = if self.mapping == Mapping::ExHiRom && bank < 0x80 { 0x400000 } else { 0 };
+                    Region::Rom(base + (((bank & 0x3f) as usize) << 16) + offset as usize)
+                }
+            }
+        }
+    }
+
+    fn access_cycles(&self, bank: u8, offset: u16) -> u32 {
+        let rom_cycles = if bank >= 0x80 && self.fast_rom { 6 } else { 8 };
+        if (bank & 0x40) != 0 {
+            return if bank == 0x7e || bank == 0x7f { 8 } else { rom_cycles };
+        }
+
+        match offset {
+            0x0000 ..= 0x1fff => 8,
+            0x2000 ..= 0x3fff => 6,
+            0x4000 ..= 0x41ff => 12,
+            0x4200 ..= 0x5fff => 6,
+            0x6000 ..= 0x7fff => 8,
+            _ => rom_cycles
+        }
+    }
+
+    fn read_region(&self, region: Region) -> u8 {
+        match region {
+            Region::Wram(address) => self.wram[address],
+            Region::Rom(address) if !self.rom.is_empty() => self.rom[address % self.rom.len()],
+            Region::Sram(address) if !self.sram.is_empty() => self.sram[address % self.sram.len()],
+            _ => self.open_bus
+        }
+    }
+
+    fn write_region(&mut self, region: Region, value: u8) {
+        match region {
+            Region::Wram(address) => { self.wram[address] = value; },
+            Region::Sram(address) if !self.sram.is_empty() => {
+                let len = self.sram.len();
+                self.sram[address % len] = value;
+            },
+            _ => () // ROM and open bus
+        }
+    }
+
+    fn read_io(&mut self, offset: u16) -> u8 {
+        match offset {
+            0x2140 ..= 0x217f => self.apu_ports[(offset & 0x03) as usize],
+            0x2180 => {
+                let value = self.wram[self.wram_address as usize];
+                self.wram_address = (self.wram_address + 1) & 0x1ffff;
+                value
+            },
+
+            0x4016 | 0x4017 => self.open_bus & 0xfc,
+
+            0x4210 => {
+                let value = ((self.nmi_flag as u8) << 7) | (self.open_bus & 0x70) | 0x02;
+                self.nmi_flag = false;
+                value
+            },
+            0x4211 => {
+                let value = ((self.irq_flag as u8) << 7) | (self.open_bus & 0x7f);
+                self.irq_flag = false;
+                value
+            },
+            0x4212 => {
+                let vblank = self.scanline >= VBLANK_START;
+                let hblank = self.h_cycle < 4 || self.h_cycle >= 274 * 4;
+                let auto_joypad = (self.nmitimen & 0x01) != 0 && self.scanline >= VBLANK_START && self.scanline < VBLANK_START + 3;
+                ((vblank as u8) << 7) | ((hblank as u8) << 6) | (self.open_bus & 0x3e) | (auto_joypad as u8)
+            },
+
+            0x4214 => self.quotient as u8,
+            0x4215 => (self.quotient >> 8) as u8,
+            0x4216 => self.product_remainder as u8,
+            0x4217 => (self.product_remainder >> 8) as u8,
+            0x4218 ..= 0x421f => 0,
+
+            0x4300 ..= 0x437f => self.dma_regs[((offset >> 4) & 0x07) as usize][(offset & 0x0f) as usize],
+
+            _ => self.open_bus
+        }
+    }
+
+    fn write_io(&mut self, offset: u16, value: u8) {
+        match offset {
+            0x2140 ..= 0x217f => { self.apu_port_writes[(offset & 0x03) as usize] = Some(value); },
+            0x2180 => {
+                self.wram[self.wram_address as usize] = value;
+                self.wram_address = (self.wram_address + 1) & 0x1ffff;
+            },
+            0x2181 => { self.wram_address = (self.wram_address & 0x1ff00) | value as u32; },
+            0x2182 => { self.wram_address = (self.wram_address & 0x100ff) | ((value as u32) << 8); },
+            0x2183 => { self.wram_address = (self.wram_address & 0x0ffff) | (((value & 0x01) as u32) << 16); },
+
+            0x4200 => {
+                if (value & 0x80) != 0 && (self.nmitimen & 0x80) == 0 && self.nmi_flag {
+                    self.nmi_pending = true;
+                }
+                if (value & 0x30) == 0 {
+                    self.irq_flag = false;
+                }
+                self.nmitimen = value;
+            },
+            0x4202 => { self.multiplicand = value; },
+            0x4203 => { self.product_remainder = self.multiplicand as u16 * value as u16; },
+            0x4204 => { self.dividend = (self.dividend & 0xff00) | value as u16; },
+            0x4205 => { self.dividend = (self.dividend & 0x00ff) | ((value as u16) << 8); },
+            0x4206 => {
+                if value == 0 {
+                    self.quotient = 0xffff;
+                    self.product_remainder = self.dividend;
+                } else {
+                    self.quotient = self.dividend / value as u16;
+                    self.product_remainder = self.dividend % value as u16;
+                }
+            },
+            0x4207 => { self.htime = (self.htime & 0x100) | value as u16; },
+            0x4208 => { self.htime = (self.htime & 0x0ff) | (((value & 0x01) as u16) << 8); },
+            0x4209 => { self.vtime = (self.vtime & 0x100) | value as u16; },
+            0x420a => { self.vtime = (self.vtime & 0x0ff) | (((value & 0x01) as u16) << 8); },
+            0x420b => self.run_dma(value),
+            0x420d => { self.fast_rom = (value & 0x01) != 0; },
+
+            0x4300 ..= 0x437f => { self.dma_regs[((offset >> 4) & 0x07) as usize][(offset & 0x0f) as usize] = value; },
+
+            _ => () // PPU and other unemulated registers
+        }
+    }
+
+    fn run_dma(&mut self, channels: u8) {
+        self.tick(8);
+
+        for channel in 0..8 {
+            if (channels & (1 << channel)) == 0 {
+                continue;
+            }
+
+            let regs = self.dma_regs[channel];
+            let params = regs[0];
+            let b_address = regs[1];
+            let mut a_address = u16::from_le_bytes([regs[2], regs[3]]);
+            let a_bank = regs[4];
+            let mut count = u16::from_le_bytes([regs[5], regs[6]]);
+
+            let pattern: &[u8] = match params & 0x07 {
+                0 => &[0],
+                1 => &[0, 1],
+                2 | 6 => &[0, 0],
+                3 | 7 => &[0, 0, 1, 1],
+                4 => &[0, 1, 2, 3],
+                _ => &[0, 1, 0, 1]
+            };
+
+            self.tick(8);
+            let mut i = 0;
+            loop {
+                let a = ((a_bank as u32) << 16) | a_address as u32;
+                let b = 0x2100 | b_address.wrapping_add(pattern[i % pattern.len()]) as u16;
+                if (params & 0x80) == 0 {
+                    let value = self.dma_read_a(a);
+                    self.write_io(b, value);
+                } else {
+                    let value = self.read_io(b);
+                    self.dma_write_a(a, value);
+                }
+                self.tick(8);
+
+                match params & 0x18 {
+                    0x00 => { a_address = a_address.wrapping_add(1); },
+                    0x10 => { a_address = a_address.wrapping_sub(1); },
+                    _ => () // Fixed address
+                }
+                i += 1;
+                count = count.wrapping_sub(1);
+                if count == 0 {
+                    break;
+                }
+            }
+
+            self.dma_regs[channel][2..4].copy_from_slice(&a_address.to_le_bytes());
+            self.dma_regs[channel][5] = 0;
+            self.dma_regs[channel][6] = 0;
+        }
+    }
+
+    fn dma_read_a(&mut self, address: u32) -> u8 {
+        match self.decode((address >> 16) as u8, address as u16) {
+            Region::Io(_) => self.open_bus,
+            region => self.read_region(region)
+        }
+    }
+
+    fn dma_write_a(&mut self, address: u32, value: u8) {
+        match self.decode((address >> 16) as u8, address as u16) {
+            Region::Io(_) => (),
+            region => self.write_region(region, value)
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let step = remaining.min(SCANLINE_CYCLES - self.h_cycle);
+            self.check_irq(self.h_cycle, self.h_cycle + step);
+            self.h_cycle += step;
+            remaining -= step;
+
+            if self.h_cycle == SCANLINE_CYCLES {
+                self.h_cycle = 0;
+                self.scanline = (self.scanline + 1) % SCANLINES;
+                if self.scanline == VBLANK_START {
+                    self.nmi_flag = true;
+                    if (self.nmitimen & 0x80) != 0 {
+                        self.nmi_pending = true;
+                    }
+                } else if self.scanline == 0 {
+                    self.nmi_flag = false;
+                }
+            }
+        }
+    }
+
+    fn check_irq(&mut self, from: u32, to: u32) {
+        let irq_cycle = match self.nmitimen & 0x30 {
+            0x10 => self.htime as u32 * 4,
+            0x20 if self.scanline == self.vtime as u32 => 0,
+            0x30 if self.scanline == self.vtime as u32 => self.htime as u32 * 4,
+            _ => return
+        };
+
+        if from <= irq_cycle && irq_cycle < to {
+            self.irq_flag = true;
+        }
+    }
+}
+
+/// Picks the cartridge mapping by scoring the internal header at each possible location.
+fn detect_mapping(rom: &[u8]) -> Mapping {
+    let header_score = |offset: usize, map_mode: u8| -> i32 {
+        if rom.len() < offset + 0x40 {
+            return -1;
+        }
+        let header = &rom[offset..(offset + 0x40)];
+
+        let mut score = 0;
+        if (header[0x15] & !0x10) == map_mode {
+            score += 2;
+        }
+        let complement = u16::from_le_bytes([header[0x1c], header[0x1d]]);
+        let checksum = u16::from_le_bytes([header[0x1e], header[0x1f]]);
+        if (checksum ^ complement) == 0xffff {
+            score += 2;
+        }
+        if u16::from_le_bytes([header[0x3c], header[0x3d]]) >= 0x8000 {
+            score += 1;
+        }
+        score
+    };
+
+    let lo_rom_score = header_score(0x7fc0, 0x20);
+    let hi_rom_score = header_score(0xffc0, 0x21);
+    let ex_hi_rom_score = header_score(0x40ffc0, 0x25);
+
+    if ex_hi_rom_score > hi_rom_score && ex_hi_rom_score > lo_rom_score {
+        Mapping::ExHiRom
+    } else if hi_rom_score > lo_rom_score {
+        Mapping::HiRom
+    } else {
+        Mapping::LoRom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM of `len` bytes with a valid internal header for `map_mode` at `header_offset`
+    fn rom_with_header(len: usize, header_offset: usize, map_mode: u8) -> Vec<u8> {
+        let mut rom = vec![0; len];
+        let header = &mut rom[header_offset..(header_offset + 0x40)];
+        header[0x15] = map_mode;
+        header[0x1c..0x1e].copy_from_slice(&0x1234u16.to_le_bytes());
+        header[0x1e..0x20].copy_from_slice(&(!0x1234u16).to_le_bytes());
+        header[0x3c..0x3e].copy_from_slice(&0x8000u16.to_le_bytes());
+        rom
+    }
+
+    #[test]
+    fn detect_mapping_test() {
+        assert_eq!(detect_mapping(&rom_with_header(0x80000, 0x7fc0, 0x20)), Mapping::LoRom);
+        assert_eq!(detect_mapping(&rom_with_header(0x80000, 0xffc0, 0x21)), Mapping::HiRom);
+        // FastROM sets bit 4 of the map mode
+        assert_eq!(detect_mapping(&rom_with_header(0x80000, 0xffc0, 0x31)), Mapping::HiRom);
+        assert_eq!(detect_mapping(&rom_with_header(0x600000, 0x40ffc0, 0x25)), Mapping::ExHiRom);
+        // Without any valid header, LoROM is assumed
+        assert_eq!(detect_mapping(&vec![0; 0x80000]), Mapping::LoRom);
+    }
+
+    #[test]
+    fn lo_rom_decode_test() {
+        let bus = Bus::new(rom_with_header(0x80000, 0x7fc0, 0x20), Vec::new());
+        assert_eq!(bus.mapping, Mapping::LoRom);
+
+        assert_eq!(bus.decode(0x00, 0x8000), Region::Rom(0));
+        assert_eq!(bus.decode(0x01, 0x8000), Region::Rom(0x8000));
+        assert_eq!(bus.decode(0x80, 0xffff), Region::Rom(0x7fff));
+        assert_eq!(bus.decode(0x81, 0x9234), Region::Rom(0x9234));
+        assert_eq!(bus.decode(0x40, 0x0000), Region::Rom(0x200000));
+        assert_eq!(bus.decode(0x70, 0x0000), Region::Sram(0));
+        assert_eq!(bus.decode(0xf0, 0x0010), Region::Sram(0x10));
+        assert_eq!(bus.decode(0x00, 0x6000), Region::Open);
+
+        assert_eq!(bus.decode(0x00, 0x0123), Region::Wram(0x123));
+        assert_eq!(bus.decode(0x80, 0x1fff), Region::Wram(0x1fff));
+        assert_eq!(bus.decode(0x7e, 0x1234), Region::Wram(0x1234));
+        assert_eq!(bus.decode(0x7f, 0x0000), Region::Wram(0x10000));
+        assert_eq!(bus.decode(0x00, 0x2140), Region::Io(0x2140));
+        assert_eq!(bus.decode(0xbf, 0x4210), Region::Io(0x4210));
+    }
+
+    #[test]
+    fn hi_rom_decode_test() {
+        let bus = Bus::new(rom_with_header(0x80000, 0xffc0, 0x21), Vec::new());
+        assert_eq!(bus.mapping, Mapping::HiRom);
+
+        assert_eq!(bus.decode(0xc0, 0x0000), Region::Rom(0));
+        assert_eq!(bus.decode(0xc1, 0x2345), Region::Rom(0x12345));
+        assert_eq!(bus.decode(0x40, 0x0000), Region::Rom(0));
+        assert_eq!(bus.decode(0x00, 0x8000), Region::Rom(0x8000));
+        assert_eq!(bus.decode(0x85, 0xffff), Region::Rom(0x5ffff));
+        assert_eq!(bus.decode(0x20, 0x6000), Region::Sram(0));
+        assert_eq!(bus.decode(0xa1, 0x7fff), Region::Sram(0x3fff));
+        assert_eq!(bus.decode(0x00, 0x6000), Region::Open);
+        assert_eq!(bus.decode(0x00, 0x1000), Region::Wram(0x1000));
+    }
+
+    #[test]
+    fn ex_hi_rom_decode_test() {
+        let bus = Bus::new(rom_with_header(0x600000, 0x40ffc0, 0x25), Vec::new());
+        assert_eq!(bus.mapping, Mapping::ExHiRom);
+
+        // Banks $c0-$ff hold the first 4 MiB, and banks $40-$7d the rest
+        assert_eq!(bus.decode(0xc0, 0x0000), Region::Rom(0));
+        assert_eq!(bus.decode(0xff, 0xffff), Region::Rom(0x3fffff));
+        assert_eq!(bus.decode(0x40, 0x0000), Region::Rom(0x400000));
+        assert_eq!(bus.decode(0x41, 0x8000), Region::Rom(0x418000));
+        assert_eq!(bus.decode(0x80, 0x8000), Region::Rom(0x8000));
+        assert_eq!(bus.decode(0x00, 0xffc0), Region::Rom(0x40ffc0));
+        assert_eq!(bus.decode(0x7e, 0x0000), Region::Wram(0));
+    }
+
+    #[test]
+    fn rom_mirror_test() {
+        let mut rom = rom_with_header(0x80000, 0x7fc0, 0x20);
+        rom[0x1234] = 0xab;
+        let mut bus = Bus::new(rom, Vec::new());
+
+        assert_eq!(bus.read(0x00_9234), 0xab);
+        assert_eq!(bus.read(0x80_9234), 0xab);
+    }
+}
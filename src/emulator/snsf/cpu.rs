@@ -0,0 +1,1013 @@
+use super::bus::Bus;
+
+const FLAG_C: u8 = 0x01;
+const FLAG_Z: u8 = 0x02;
+const FLAG_I: u8 = 0x04;
+const FLAG_D: u8 = 0x08;
+const FLAG_X: u8 = 0x10;
+const FLAG_M: u8 = 0x20;
+const FLAG_V: u8 = 0x40;
+const FLAG_N: u8 = 0x80;
+
+/// 65816 core of the 5A22. Timing is approximate: memory accesses are charged at the
+/// speed of the region they hit, plus an I/O cycle for most internal operations.
+pub struct Cpu {
+    a: u16,
+    x: u16,
+    y: u16,
+    s: u16,
+    d: u16,
+    db: u8,
+    pb: u8,
+    pc: u16,
+    p: u8,
+    e: bool,
+
+    is_waiting: bool,
+    is_stopped: bool
+}
+
+impl Cpu {
+    pub fn new(bus: &mut Bus) -> Self {
+        let mut cpu = Self {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0x01ff,
+            d: 0,
+            db: 0,
+            pb: 0,
+            pc: 0,
+            p: FLAG_M | FLAG_X | FLAG_I,
+            e: true,
+
+            is_waiting: false,
+            is_stopped: false
+        };
+        cpu.pc = cpu.read16(bus, 0xfffc);
+        cpu
+    }
+
+    /// Runs one instruction (or services one interrupt) and returns the number of
+    /// master cycles it took.
+    pub fn step(&mut self, bus: &mut Bus) -> u64 {
+        let start = bus.cycles();
+
+        if self.is_stopped {
+            bus.idle();
+        } else if bus.take_nmi() {
+            self.is_waiting = false;
+            self.interrupt(bus, 0xffea, 0xfffa, false);
+        } else if bus.irq() && (self.is_waiting || (self.p & FLAG_I) == 0) {
+            self.is_waiting = false;
+            if (self.p & FLAG_I) == 0 {
+                self.interrupt(bus, 0xffee, 0xfffe, false);
+            }
+        } else if self.is_waiting {
+            bus.idle();
+        } else {
+            let opcode = self.fetch8(bus);
+            self.execute(bus, opcode);
+        }
+
+        bus.cycles() - start
+    }
+
+    fn m8(&self) -> bool {
+        (self.p & FLAG_M) != 0
+    }
+
+    fn x8(&self) -> bool {
+        (self.p & FLAG_X) != 0
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        (self.p & flag) != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn set_p(&mut self, value: u8) {
+        self.p = value;
+        if self.e {
+            self.p |= FLAG_M | FLAG_X;
+        }
+        if self.x8() {
+            self.x &= 0xff;
+            self.y &= 0xff;
+        }
+    }
+
+    fn set_nz(&mut self, value: u16, is_8bit: bool) {
+        if is_8bit {
+            self.set_flag(FLAG_Z, (value & 0xff) == 0);
+            self.set_flag(FLAG_N, (value & 0x80) != 0);
+        } else {
+            self.set_flag(FLAG_Z, value == 0);
+            self.set_flag(FLAG_N, (value & 0x8000) != 0);
+        }
+    }
+
+    fn set_a(&mut self, value: u16) {
+        if self.m8() {
+            self.a = (self.a & 0xff00) | (value & 0xff);
+        } else {
+            self.a = value;
+        }
+        let m8 = self.m8();
+        self.set_nz(value, m8);
+    }
+
+    fn index_value(&self, value: u16) -> u16 {
+        if self.x8() { value & 0xff } else { value }
+    }
+
+    fn read16(&mut self, bus: &mut Bus, address: u32) -> u16 {
+        let lo = bus.read(address) as u16;
+        let hi = bus.read((address + 1) & 0xffffff) as u16;
+        (hi << 8) | lo
+    }
+
+    fn write16(&mut self, bus: &mut Bus, address: u32, value: u16) {
+        bus.write(address, value as u8);
+        bus.write((address + 1) & 0xffffff, (value >> 8) as u8);
+    }
+
+    fn read_bank0_16(&mut self, bus: &mut Bus, address: u16) -> u16 {
+        let lo = bus.read(address as u32) as u16;
+        let hi = bus.read(address.wrapping_add(1) as u32) as u16;
+        (hi << 8) | lo
+    }
+
+    fn read_sized(&mut self, bus: &mut Bus, address: u32, is_8bit: bool) -> u16 {
+        if is_8bit {
+            bus.read(address) as u16
+        } else {
+            self.read16(bus, address)
+        }
+    }
+
+    fn write_sized(&mut self, bus: &mut Bus, address: u32, value: u16, is_8bit: bool) {
+        if is_8bit {
+            bus.write(address, value as u8);
+        } else {
+            self.write16(bus, address, value);
+        }
+    }
+
+    fn fetch8(&mut self, bus: &mut Bus) -> u8 {
+        let value = bus.read(((self.pb as u32) << 16) | self.pc as u32);
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    fn fetch16(&mut self, bus: &mut Bus) -> u16 {
+        let lo = self.fetch8(bus) as u16;
+        let hi = self.fetch8(bus) as u16;
+        (hi << 8) | lo
+    }
+
+    fn fetch24(&mut self, bus: &mut Bus) -> u32 {
+        let lo = self.fetch16(bus) as u32;
+        let bank = self.fetch8(bus) as u32;
+        (bank << 16) | lo
+    }
+
+    fn push8(&mut self, bus: &mut Bus, value: u8) {
+        bus.write(self.s as u32, value);
+        self.s = if self.e { 0x0100 | (self.s.wrapping_sub(1) & 0xff) } else { self.s.wrapping_sub(1) };
+    }
+
+    fn pull8(&mut self, bus: &mut Bus) -> u8 {
+        self.s = if self.e { 0x0100 | (self.s.wrapping_add(1) & 0xff) } else { self.s.wrapping_add(1) };
+        bus.read(self.s as u32)
+    }
+
+    fn push16(&mut self, bus: &mut Bus, value: u16) {
+        self.push8(bus, (value >> 8) as u8);
+        self.push8(bus, value as u8);
+    }
+
+    fn pull16(&mut self, bus: &mut Bus) -> u16 {
+        let lo = self.pull8(bus) as u16;
+        let hi = self.pull8(bus) as u16;
+        (hi << 8) | lo
+    }
+
+    fn push_sized(&mut self, bus: &mut Bus, value: u16, is_8bit: bool) {
+        if is_8bit {
+            self.push8(bus, value as u8);
+        } else {
+            self.push16(bus, value);
+        }
+    }
+
+    fn pull_sized(&mut self, bus: &mut Bus, is_8bit: bool) -> u16 {
+        if is_8bit {
+            self.pull8(bus) as u16
+        } else {
+            self.pull16(bus)
+        }
+    }
+
+    fn interrupt(&mut self, bus: &mut Bus, native_vector: u16, emulation_vector: u16, is_brk: bool) {
+        bus.idle();
+        if !self.e {
+            let pb = self.pb;
+            self.push8(bus, pb);
+        }
+        let pc = self.pc;
+        self.push16(bus, pc);
+        let p = if !self.e {
+            self.p
+        } else if is_brk {
+            self.p | FLAG_X
+        } else {
+            self.p & !FLAG_X
+        };
+        self.push8(bus, p);
+
+        self.p = (self.p | FLAG_I) & !FLAG_D;
+        self.pb = 0;
+        self.pc = self.read_bank0_16(bus, if self.e { emulation_vector } else { native_vector });
+    }
+
+    // Addressing modes, each returning a 24-bit effective address
+
+    fn immediate(&mut self, is_8bit: bool) -> u32 {
+        let address = ((self.pb as u32) << 16) | self.pc as u32;
+        self.pc = self.pc.wrapping_add(if is_8bit { 1 } else { 2 });
+        address
+    }
+
+    fn direct_pointer(&mut self, bus: &mut Bus, index: u16) -> u16 {
+        let offset = self.fetch8(bus) as u16;
+        if (self.d & 0xff) != 0 {
+            bus.idle();
+        }
+        if index != 0 {
+            bus.idle();
+        }
+        self.d.wrapping_add(offset).wrapping_add(index)
+    }
+
+    fn direct(&mut self, bus: &mut Bus, index: u16) -> u32 {
+        self.direct_pointer(bus, index) as u32
+    }
+
+    fn absolute(&mut self, bus: &mut Bus, index: u16) -> u32 {
+        let address = ((self.db as u32) << 16) | self.fetch16(bus) as u32;
+        (address + index as u32) & 0xffffff
+    }
+
+    fn long(&mut self, bus: &mut Bus, index: u16) -> u32 {
+        let address = self.fetch24(bus);
+        (address + index as u32) & 0xffffff
+    }
+
+    fn direct_indirect(&mut self, bus: &mut Bus, pre_index: u16, post_index: u16) -> u32 {
+        let pointer = self.direct_pointer(bus, pre_index);
+        let address = ((self.db as u32) << 16) | self.read_bank0_16(bus, pointer) as u32;
+        (address + post_index as u32) & 0xffffff
+    }
+
+    fn direct_indirect_long(&mut self, bus: &mut Bus, index: u16) -> u32 {
+        let pointer = self.direct_pointer(bus, 0);
+        let lo = self.read_bank0_16(bus, pointer) as u32;
+        let bank = bus.read(pointer.wrapping_add(2) as u32) as u32;
+        (((bank << 16) | lo) + index as u32) & 0xffffff
+    }
+
+    fn stack_relative(&mut self, bus: &mut Bus) -> u32 {
+        let offset = self.fetch8(bus) as u16;
+        bus.idle();
+        self.s.wrapping_add(offset) as u32
+    }
+
+    fn stack_relative_indirect(&mut self, bus: &mut Bus) -> u32 {
+        let pointer = self.stack_relative(bus) as u16;
+        let address = ((self.db as u32) << 16) | self.read_bank0_16(bus, pointer) as u32;
+        bus.idle();
+        (address + self.y as u32) & 0xffffff
+    }
+
+    /// Addressing mode shared by the ORA/AND/EOR/ADC/STA/LDA/CMP/SBC opcode group
+    fn alu_address(&mut self, bus: &mut Bus, opcode: u8) -> u32 {
+        let (x, y) = (self.x, self.y);
+        match opcode & 0x1f {
+            0x01 => self.direct_indirect(bus, x, 0),
+            0x03 => self.stack_relative(bus),
+            0x05 => self.direct(bus, 0),
+            0x07 => self.direct_indirect_long(bus, 0),
+            0x09 => { let m8 = self.m8(); self.immediate(m8) },
+            0x0d => self.absolute(bus, 0),
+            0x0f => self.long(bus, 0),
+            0x11 => self.direct_indirect(bus, 0, y),
+            0x12 => self.direct_indirect(bus, 0, 0),
+            0x13 => self.stack_relative_indirect(bus),
+            0x15 => self.direct(bus, x),
+            0x17 => self.direct_indirect_long(bus, y),
+            0x19 => self.absolute(bus, y),
+            0x1d => self.absolute(bus, x),
+            0x1f => self.long(bus, x),
+            _ => unreachable!()
+        }
+    }
+
+    /// Addressing mode shared by the read-modify-write opcodes
+    fn modify_address(&mut self, bus: &mut Bus, opcode: u8) -> u32 {
+        let x = self.x;
+        match opcode & 0x1f {
+            0x04 | 0x06 => self.direct(bus, 0),
+            0x0c | 0x0e => self.absolute(bus, 0),
+            0x14 => self.direct(bus, 0),
+            0x16 => self.direct(bus, x),
+            0x1c => self.absolute(bus, 0),
+            0x1e => self.absolute(bus, x),
+            _ => unreachable!()
+        }
+    }
+
+    fn adc(&mut self, value: u16) {
+        let carry = self.flag(FLAG_C) as i32;
+        let decimal = self.flag(FLAG_D);
+
+        if self.m8() {
+            let a = (self.a & 0xff) as i32;
+            let value = (value & 0xff) as i32;
+            let mut result;
+            if !decimal {
+                result = a + value + carry;
+            } else {
+                result = (a & 0x0f) + (value & 0x0f) + carry;
+                if result > 0x09 { result += 0x06; }
+                let c = (result > 0x0f) as i32;
+                result = (a & 0xf0) + (value & 0xf0) + (c << 4) + (result & 0x0f);
+            }
+            self.set_flag(FLAG_V, (!(a ^ value) & (a ^ result) & 0x80) != 0);
+            if decimal && result > 0x9f { result += 0x60; }
+            self.set_flag(FLAG_C, result > 0xff);
+            self.set_a(result as u16 & 0xff);
+        } else {
+            let a = self.a as i32;
+            let value = value as i32;
+            let mut result;
+            if !decimal {
+                result = a + value + carry;
+            } else {
+                result = (a & 0x000f) + (value & 0x000f) + carry;
+                if result > 0x0009 { result += 0x0006; }
+                let c = (result > 0x000f) as i32;
+                result = (a & 0x00f0) + (value & 0x00f0) + (c << 4) + (result & 0x000f);
+                if result > 0x009f { result += 0x0060; }
+                let c = (result > 0x00ff) as i32;
+                result = (a & 0x0f00) + (value & 0x0f00) + (c << 8) + (result & 0x00ff);
+                if result > 0x09ff { result += 0x0600; }
+                let c = (result > 0x0fff) as i32;
+                result = (a & 0xf000) + (value & 0xf000) + (c << 12) + (result & 0x0fff);
+            }
+            self.set_flag(FLAG_V, (!(a ^ value) & (a ^ result) & 0x8000) != 0);
+            if decimal && result > 0x9fff { result += 0x6000; }
+            self.set_flag(FLAG_C, result > 0xffff);
+            self.set_a(result as u16);
+        }
+    }
+
+    fn sbc(&mut self, value: u16) {
+        let carry = self.flag(FLAG_C) as i32;
+        let decimal = self.flag(FLAG_D);
+
+        if self.m8() {
+            let a = (self.a & 0xff) as i32;
+            let value = (!value & 0xff) as i32;
+            let mut result;
+            if !decimal {
+                result = a + value + carry;
+            } else {
+                result = (a & 0x0f) + (value & 0x0f) + carry;
+                if result <= 0x0f { result -= 0x06; }
+                let c = (result > 0x0f) as i32;
+                result = (a & 0xf0) + (value & 0xf0) + (c << 4) + (result & 0x0f);
+            }
+            self.set_flag(FLAG_V, (!(a ^ value) & (a ^ result) & 0x80) != 0);
+            if decimal && result <= 0xff { result -= 0x60; }
+            self.set_flag(FLAG_C, result > 0xff);
+            self.set_a(result as u16 & 0xff);
+        } else {
+            let a = self.a as i32;
+            let value = (!value) as i32;
+            let mut result;
+            if !decimal {
+                result = a + value + carry;
+            } else {
+                result = (a & 0x000f) + (value & 0x000f) + carry;
+                if result <= 0x000f { result -= 0x0006; }
+                let c = (result > 0x000f) as i32;
+                result = (a & 0x00f0) + (value & 0x00f0) + (c << 4) + (result & 0x000f);
+                if result <= 0x00ff { result -= 0x0060; }
+                let c = (result > 0x00ff) as i32;
+                result = (a & 0x0f00) + (value & 0x0f00) + (c << 8) + (result & 0x00ff);
+                if result <= 0x0fff { result -= 0x0600; }
+                let c = (result > 0x0fff) as i32;
+                result = (a & 0xf000) + (value & 0xf000) + (c << 12) + (result & 0x0fff);
+            }
+            self.set_flag(FLAG_V, (!(a ^ value) & (a ^ result) & 0x8000) != 0);
+            if decimal && result <= 0xffff { result -= 0x6000; }
+            self.set_flag(FLAG_C, result > 0xffff);
+            self.set_a(result as u16);
+        }
+    }
+
+    fn compare(&mut self, register: u16, value: u16, is_8bit: bool) {
+        let (register, value) = if is_8bit {
+            (register & 0xff, value & 0xff)
+        } else {
+            (register, value)
+        };
+        let result = register.wrapping_sub(value);
+        self.set_flag(FLAG_C, register >= value);
+        self.set_nz(result, is_8bit);
+    }
+
+    fn bit(&mut self, value: u16, is_immediate: bool) {
+        let is_8bit = self.m8();
+        let (a, msb) = if is_8bit { (self.a & 0xff, 0x80) } else { (self.a, 0x8000) };
+        self.set_flag(FLAG_Z, (a & value) == 0);
+        if !is_immediate {
+            self.set_flag(FLAG_N, (value & msb) != 0);
+            self.set_flag(FLAG_V, (value & (msb >> 1)) != 0);
+        }
+    }
+
+    /// ASL/ROL/LSR/ROR (selected by bits 5-6 of the opcode) and INC/DEC
+    fn modify(&mut self, opcode: u8, value: u16) -> u16 {
+        let is_8bit = self.m8();
+        let msb = if is_8bit { 0x80 } else { 0x8000 };
+        let mask = if is_8bit { 0xff } else { 0xffff };
+        let carry = self.flag(FLAG_C) as u16;
+
+        let result = match opcode {
+            0x1a | 0xe6 | 0xee | 0xf6 | 0xfe => value.wrapping_add(1),
+            0x3a | 0xc6 | 0xce | 0xd6 | 0xde => value.wrapping_sub(1),
+            _ => match opcode >> 5 {
+                0 => {
+                    self.set_flag(FLAG_C, (value & msb) != 0);
+                    value << 1
+                },
+                1 => {
+                    self.set_flag(FLAG_C, (value & msb) != 0);
+                    (value << 1) | carry
+                },
+                2 => {
+                    self.set_flag(FLAG_C, (value & 0x01) != 0);
+                    (value & mask) >> 1
+                },
+                _ => {
+                    self.set_flag(FLAG_C, (value & 0x01) != 0);
+                    ((value & mask) >> 1) | if carry != 0 { msb } else { 0 }
+                }
+            }
+        } & mask;
+
+        self.set_nz(result, is_8bit);
+        result
+    }
+
+    fn branch(&mut self, bus: &mut Bus, condition: bool) {
+        let offset = self.fetch8(bus) as i8;
+        if condition {
+            bus.idle();
+            self.pc = self.pc.wrapping_add(offset as i16 as u16);
+        }
+    }
+
+    fn execute(&mut self, bus: &mut Bus, opcode: u8) {
+        let m8 = self.m8();
+        let x8 = self.x8();
+        let (x, y) = (self.x, self.y);
+
+        match opcode {
+            // ORA, AND, EOR, ADC, STA, LDA, CMP, SBC
+            _ if opcode != 0x89 && matches!(opcode & 0x1f, 0x01 | 0x03 | 0x05 | 0x07 | 0x09 | 0x0d | 0x0f |
+                0x11 | 0x12 | 0x13 | 0x15 | 0x17 | 0x19 | 0x1d | 0x1f) => {
+                let address = self.alu_address(bus, opcode);
+                if opcode >> 5 == 4 {
+                    let a = self.a;
+                    self.write_sized(bus, address, a, m8);
+                    return;
+                }
+
+                let value = self.read_sized(bus, address, m8);
+                match opcode >> 5 {
+                    0 => { let a = self.a | value; self.set_a(a); },
+                    1 => { let a = self.a & value; self.set_a(a); },
+                    2 => { let a = self.a ^ value; self.set_a(a); },
+                    3 => self.adc(value),
+                    5 => self.set_a(value),
+                    6 => { let a = self.a; self.compare(a, value, m8); },
+                    _ => self.sbc(value)
+                }
+            },
+
+            // ASL, ROL, LSR, ROR, INC, DEC on the accumulator
+            0x0a | 0x2a | 0x4a | 0x6a | 0x1a | 0x3a => {
+                bus.idle();
+                let a = self.a;
+                let result = self.modify(opcode, a);
+                self.set_a(result);
+            },
+            // ASL, ROL, LSR, ROR, INC, DEC on memory
+            0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e |
+            0x46 | 0x4e | 0x56 | 0x5e | 0x66 | 0x6e | 0x76 | 0x7e |
+            0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => {
+                let address = self.modify_address(bus, opcode);
+                let value = self.read_sized(bus, address, m8);
+                bus.idle();
+                let result = self.modify(opcode, value);
+                self.write_sized(bus, address, result, m8);
+            },
+            // TSB, TRB
+            0x04 | 0x0c | 0x14 | 0x1c => {
+                let address = self.modify_address(bus, opcode);
+                let value = self.read_sized(bus, address, m8);
+                bus.idle();
+                let a = if m8 { self.a & 0xff } else { self.a };
+                self.set_flag(FLAG_Z, (value & a) == 0);
+                let result = if (opcode & 0x10) == 0 { value | a } else { value & !a };
+                self.write_sized(bus, address, result, m8);
+            },
+
+            // BIT
+            0x89 => {
+                let address = self.immediate(m8);
+                let value = self.read_sized(bus, address, m8);
+                self.bit(value, true);
+            },
+            0x24 | 0x2c | 0x34 | 0x3c => {
+                let address = match opcode {
+                    0x24 => self.direct(bus, 0),
+                    0x2c => self.absolute(bus, 0),
+                    0x34 => self.direct(bus, x),
+                    _ => self.absolute(bus, x)
+                };
+                let value = self.read_sized(bus, address, m8);
+                self.bit(value, false);
+            },
+
+            // LDX, LDY, CPX, CPY
+            0xa2 | 0xa6 | 0xae | 0xb6 | 0xbe | 0xa0 | 0xa4 | 0xac | 0xb4 | 0xbc |
+            0xe0 | 0xe4 | 0xec | 0xc0 | 0xc4 | 0xcc => {
+                let address = match opcode {
+                    0xa0 | 0xa2 | 0xc0 | 0xe0 => self.immediate(x8),
+                    0xa4 | 0xa6 | 0xc4 | 0xe4 => self.direct(bus, 0),
+                    0xac | 0xae | 0xcc | 0xec => self.absolute(bus, 0),
+                    0xb4 => self.direct(bus, x),
+                    0xb6 => self.direct(bus, y),
+                    0xbc => self.absolute(bus, x),
+                    _ => self.absolute(bus, y)
+                };
+                let value = self.read_sized(bus, address, x8);
+                match opcode {
+                    0xa2 | 0xa6 | 0xae | 0xb6 | 0xbe => { self.x = value; self.set_nz(value, x8); },
+                    0xa0 | 0xa4 | 0xac | 0xb4 | 0xbc => { self.y = value; self.set_nz(value, x8); },
+                    0xe0 | 0xe4 | 0xec => self.compare(x, value, x8),
+                    _ => self.compare(y, value, x8)
+                }
+            },
+            // STX, STY
+            0x86 | 0x8e | 0x96 | 0x84 | 0x8c | 0x94 => {
+                let address = match opcode {
+                    0x84 | 0x86 => self.direct(bus, 0),
+                    0x8c | 0x8e => self.absolute(bus, 0),
+                    0x94 => self.direct(bus, x),
+                    _ => self.direct(bus, y)
+                };
+                let value = if (opcode & 0x02) != 0 { x } else { y };
+                self.write_sized(bus, address, value, x8);
+            },
+            // STZ
+            0x64 | 0x74 | 0x9c | 0x9e => {
+                let address = match opcode {
+                    0x64 => self.direct(bus, 0),
+                    0x74 => self.direct(bus, x),
+                    0x9c => self.absolute(bus, 0),
+                    _ => self.absolute(bus, x)
+                };
+                self.write_sized(bus, address, 0, m8);
+            },
+
+            // INX, INY, DEX, DEY
+            0xe8 | 0xc8 | 0xca | 0x88 => {
+                bus.idle();
+                let value = match opcode {
+                    0xe8 => x.wrapping_add(1),
+                    0xc8 => y.wrapping_add(1),
+                    0xca => x.wrapping_sub(1),
+                    _ => y.wrapping_sub(1)
+                };
+                let value = self.index_value(value);
+                if opcode == 0xe8 || opcode == 0xca {
+                    self.x = value;
+                } else {
+                    self.y = value;
+                }
+                self.set_nz(value, x8);
+            },
+
+            // Branches
+            0x10 => { let c = !self.flag(FLAG_N); self.branch(bus, c); },
+            0x30 => { let c = self.flag(FLAG_N); self.branch(bus, c); },
+            0x50 => { let c = !self.flag(FLAG_V); self.branch(bus, c); },
+            0x70 => { let c = self.flag(FLAG_V); self.branch(bus, c); },
+            0x90 => { let c = !self.flag(FLAG_C); self.branch(bus, c); },
+            0xb0 => { let c = self.flag(FLAG_C); self.branch(bus, c); },
+            0xd0 => { let c = !self.flag(FLAG_Z); self.branch(bus, c); },
+            0xf0 => { let c = self.flag(FLAG_Z); self.branch(bus, c); },
+            0x80 => self.branch(bus, true),
+            0x82 => {
+                let offset = self.fetch16(bus);
+                bus.idle();
+                self.pc = self.pc.wrapping_add(offset);
+            },
+
+            // Jumps and calls
+            0x4c => { self.pc = self.fetch16(bus); },
+            0x5c => {
+                let address = self.fetch24(bus);
+                self.pb = (address >> 16) as u8;
+                self.pc = address as u16;
+            },
+            0x6c => {
+                let pointer = self.fetch16(bus);
+                self.pc = self.read_bank0_16(bus, pointer);
+            },
+            0x7c => {
+                let pointer = self.fetch16(bus).wrapping_add(x);
+                bus.idle();
+                self.pc = self.read16(bus, ((self.pb as u32) << 16) | pointer as u32);
+            },
+            0xdc => {
+                let pointer = self.fetch16(bus);
+                let lo = self.read_bank0_16(bus, pointer);
+                self.pb = bus.read(pointer.wrapping_add(2) as u32);
+                self.pc = lo;
+            },
+            0x20 => {
+                let target = self.fetch16(bus);
+                bus.idle();
+                let return_address = self.pc.wrapping_sub(1);
+                self.push16(bus, return_address);
+                self.pc = target;
+            },
+            0xfc => {
+                let pointer = self.fetch16(bus).wrapping_add(x);
+                let return_address = self.pc.wrapping_sub(1);
+                self.push16(bus, return_address);
+                bus.idle();
+                self.pc = self.read16(bus, ((self.pb as u32) << 16) | pointer as u32);
+            },
+            0x22 => {
+                let address = self.fetch24(bus);
+                let pb = self.pb;
+                self.push8(bus, pb);
+                bus.idle();
+                let return_address = self.pc.wrapping_sub(1);
+                self.push16(bus, return_address);
+                self.pb = (address >> 16) as u8;
+                self.pc = address as u16;
+            },
+            0x60 => {
+                bus.idle();
+                self.pc = self.pull16(bus).wrapping_add(1);
+                bus.idle();
+            },
+            0x6b => {
+                bus.idle();
+                self.pc = self.pull16(bus).wrapping_add(1);
+                self.pb = self.pull8(bus);
+            },
+            0x40 => {
+                bus.idle();
+                let p = self.pull8(bus);
+                self.set_p(p);
+                self.pc = self.pull16(bus);
+                if !self.e {
+                    self.pb = self.pull8(bus);
+                }
+            },
+            0x00 => {
+                self.fetch8(bus);
+                self.interrupt(bus, 0xffe6, 0xfffe, true);
+            },
+            0x02 => {
+                self.fetch8(bus);
+                self.interrupt(bus, 0xffe4, 0xfff4, true);
+            },
+
+            // Stack
+            0x48 => { let a = self.a; self.push_sized(bus, a, m8); },
+            0xda => self.push_sized(bus, x, x8),
+            0x5a => self.push_sized(bus, y, x8),
+            0x68 => {
+                bus.idle();
+                let value = self.pull_sized(bus, m8);
+                self.set_a(value);
+            },
+            0xfa | 0x7a => {
+                bus.idle();
+                let value = self.pull_sized(bus, x8);
+                if opcode == 0xfa {
+                    self.x = value;
+                } else {
+                    self.y = value;
+                }
+                self.set_nz(value, x8);
+            },
+            0x8b => { let db = self.db; self.push8(bus, db); },
+            0xab => {
+                bus.idle();
+                self.db = self.pull8(bus);
+                let db = self.db as u16;
+                self.set_nz(db, true);
+            },
+            0x0b => { let d = self.d; self.push16(bus, d); },
+            0x2b => {
+                bus.idle();
+                self.d = self.pull16(bus);
+                let d = self.d;
+                self.set_nz(d, false);
+            },
+            0x4b => { let pb = self.pb; self.push8(bus, pb); },
+            0x08 => { let p = self.p; self.push8(bus, p); },
+            0x28 => {
+                bus.idle();
+                let p = self.pull8(bus);
+                self.set_p(p);
+            },
+            0xf4 => {
+                let value = self.fetch16(bus);
+                self.push16(bus, value);
+            },
+            0xd4 => {
+                let pointer = self.direct_pointer(bus, 0);
+                let value = self.read_bank0_16(bus, pointer);
+                self.push16(bus, value);
+            },
+            0x62 => {
+                let offset = self.fetch16(bus);
+                bus.idle();
+                let value = self.pc.wrapping_add(offset);
+                self.push16(bus, value);
+            },
+
+            // Transfers
+            0xaa | 0xa8 => {
+                bus.idle();
+                let value = self.index_value(self.a);
+                if opcode == 0xaa {
+                    self.x = value;
+                } else {
+                    self.y = value;
+                }
+                self.set_nz(value, x8);
+            },
+            0x8a => { bus.idle(); self.set_a(x); },
+            0x98 => { bus.idle(); self.set_a(y); },
+            0x9b => { bus.idle(); self.y = x; self.set_nz(x, x8); },
+            0xbb => { bus.idle(); self.x = y; self.set_nz(y, x8); },
+            0xba => {
+                bus.idle();
+                let value = self.index_value(self.s);
+                self.x = value;
+                self.set_nz(value, x8);
+            },
+            0x9a => {
+                bus.idle();
+                self.s = if self.e { 0x0100 | (x & 0xff) } else { x };
+            },
+            0x1b => {
+                bus.idle();
+                self.s = if self.e { 0x0100 | (self.a & 0xff) } else { self.a };
+            },
+            0x3b => { bus.idle(); self.a = self.s; self.set_nz(self.s, false); },
+            0x5b => { bus.idle(); self.d = self.a; self.set_nz(self.a, false); },
+            0x7b => { bus.idle(); self.a = self.d; self.set_nz(self.d, false); },
+            0xeb => {
+                bus.idle();
+                bus.idle();
+                self.a = self.a.swap_bytes();
+                let a = self.a;
+                self.set_nz(a, true);
+            },
+            0xfb => {
+                bus.idle();
+                let carry = self.flag(FLAG_C);
+                let e = self.e;
+                self.set_flag(FLAG_C, e);
+                self.e = carry;
+                if self.e {
+                    self.s = 0x0100 | (self.s & 0xff);
+                }
+                let p = self.p;
+                self.set_p(p);
+            },
+
+            // Flags
+            0x18 => { bus.idle(); self.set_flag(FLAG_C, false); },
+            0x38 => { bus.idle(); self.set_flag(FLAG_C, true); },
+            0x58 => { bus.idle(); self.set_flag(FLAG_I, false); },
+            0x78 => { bus.idle(); self.set_flag(FLAG_I, true); },
+            0xb8 => { bus.idle(); self.set_flag(FLAG_V, false); },
+            0xd8 => { bus.idle(); self.set_flag(FLAG_D, false); },
+            0xf8 => { bus.idle(); self.set_flag(FLAG_D, true); },
+            0xc2 => {
+                let value = self.fetch8(bus);
+                bus.idle();
+                let p = self.p & !value;
+                self.set_p(p);
+            },
+            0xe2 => {
+                let value = self.fetch8(bus);
+                bus.idle();
+                let p = self.p | value;
+                self.set_p(p);
+            },
+
+            // Block moves (one byte per execution; the instruction repeats until A wraps)
+            0x44 | 0x54 => {
+                let destination_bank = self.fetch8(bus);
+                let source_bank = self.fetch8(bus);
+                self.db = destination_bank;
+                let value = bus.read(((source_bank as u32) << 16) | x as u32);
+                bus.write(((destination_bank as u32) << 16) | y as u32, value);
+                bus.idle();
+                bus.idle();
+
+                let (x, y) = if opcode == 0x54 {
+                    (x.wrapping_add(1), y.wrapping_add(1))
+                } else {
+                    (x.wrapping_sub(1), y.wrapping_sub(1))
+                };
+                self.x = self.index_value(x);
+                self.y = self.index_value(y);
+                self.a = self.a.wrapping_sub(1);
+                if self.a != 0xffff {
+                    self.pc = self.pc.wrapping_sub(3);
+                }
+            },
+
+            0xcb => { bus.idle(); self.is_waiting = true; },
+            0xdb => { bus.idle(); self.is_stopped = true; },
+            0x42 => { self.fetch8(bus); },
+            0xea => { bus.idle(); },
+
+            _ => unreachable!("Unhandled 65816 opcode ${:02x}", opcode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `program` from $00:8000 of a LoROM cartridge, in emulation mode, until it
+    /// reaches the STP appended to it.
+    fn run(program: &[u8]) -> (Cpu, Bus) {
+        let mut rom = vec![0; 0x8000];
+        rom[..program.len()].copy_from_slice(program);
+        rom[program.len()] = 0xdb;
+        rom[0x7fd5] = 0x20;
+        rom[0x7ffc..0x7ffe].copy_from_slice(&0x8000u16.to_le_bytes());
+
+        let mut bus = Bus::new(rom, Vec::new());
+        let mut cpu = Cpu::new(&mut bus);
+        for _ in 0..1000 {
+            if cpu.is_stopped {
+                break;
+            }
+            cpu.step(&mut bus);
+        }
+        assert!(cpu.is_stopped);
+        (cpu, bus)
+    }
+
+    /// CLC; XCE; REP #$30, switching to native mode with a 16-bit accumulator and index registers
+    const NATIVE_16: [u8; 4] = [0x18, 0xfb, 0xc2, 0x30];
+
+    #[test]
+    fn adc_binary_test() {
+        // CLC; LDA #$7f; ADC #$01
+        let (cpu, _) = run(&[0x18, 0xa9, 0x7f, 0x69, 0x01]);
+        assert_eq!(cpu.a & 0xff, 0x80);
+        assert!(cpu.flag(FLAG_V) && cpu.flag(FLAG_N) && !cpu.flag(FLAG_C));
+
+        // SEC; LDA #$ff; ADC #$00
+        let (cpu, _) = run(&[0x38, 0xa9, 0xff, 0x69, 0x00]);
+        assert_eq!(cpu.a & 0xff, 0x00);
+        assert!(cpu.flag(FLAG_C) && cpu.flag(FLAG_Z) && !cpu.flag(FLAG_V));
+    }
+
+    #[test]
+    fn adc_decimal_test() {
+        // SED; CLC; LDA #$58; ADC #$46
+        let (cpu, _) = run(&[0xf8, 0x18, 0xa9, 0x58, 0x69, 0x46]);
+        assert_eq!(cpu.a & 0xff, 0x04);
+        assert!(cpu.flag(FLAG_C));
+
+        // SED; SEC; LDA #$09; ADC #$00
+        let (cpu, _) = run(&[0xf8, 0x38, 0xa9, 0x09, 0x69, 0x00]);
+        assert_eq!(cpu.a & 0xff, 0x10);
+        assert!(!cpu.flag(FLAG_C));
+
+        // SED; CLC; LDA #$1999; ADC #$0001
+        let (cpu, _) = run(&[&NATIVE_16[..], &[0xf8, 0x18, 0xa9, 0x99, 0x19, 0x69, 0x01, 0x00]].concat());
+        assert_eq!(cpu.a, 0x2000);
+        assert!(!cpu.flag(FLAG_C));
+
+        // SED; CLC; LDA #$9999; ADC #$0001
+        let (cpu, _) = run(&[&NATIVE_16[..], &[0xf8, 0x18, 0xa9, 0x99, 0x99, 0x69, 0x01, 0x00]].concat());
+        assert_eq!(cpu.a, 0x0000);
+        assert!(cpu.flag(FLAG_C) && cpu.flag(FLAG_Z));
+    }
+
+    #[test]
+    fn sbc_decimal_test() {
+        // SED; SEC; LDA #$40; SBC #$13
+        let (cpu, _) = run(&[0xf8, 0x38, 0xa9, 0x40, 0xe9, 0x13]);
+        assert_eq!(cpu.a & 0xff, 0x27);
+        assert!(cpu.flag(FLAG_C));
+
+        // SED; SEC; LDA #$12; SBC #$21, which borrows
+        let (cpu, _) = run(&[0xf8, 0x38, 0xa9, 0x12, 0xe9, 0x21]);
+        assert_eq!(cpu.a & 0xff, 0x91);
+        assert!(!cpu.flag(FLAG_C));
+
+        // SED; SEC; LDA #$1000; SBC #$0001
+        let (cpu, _) = run(&[&NATIVE_16[..], &[0xf8, 0x38, 0xa9, 0x00, 0x10, 0xe9, 0x01, 0x00]].concat());
+        assert_eq!(cpu.a, 0x0999);
+        assert!(cpu.flag(FLAG_C));
+    }
+
+    #[test]
+    fn addressing_modes_test() {
+        // Direct page, indexed: LDA #$42; STA $10; LDA #$00; LDX #$02; LDA $0e,X
+        let (cpu, _) = run(&[0xa9, 0x42, 0x85, 0x10, 0xa9, 0x00, 0xa2, 0x02, 0xb5, 0x0e]);
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // Absolute, indexed: LDA #$42; STA $1234; LDA #$00; LDY #$04; LDA $1230,Y
+        let (cpu, _) = run(&[0xa9, 0x42, 0x8d, 0x34, 0x12, 0xa9, 0x00, 0xa0, 0x04, 0xb9, 0x30, 0x12]);
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // Long, indexed across a bank: LDA #$42; STA $7f0000; LDA #$00; LDX #$01; LDA $7effff,X
+        let (cpu, mut bus) = run(&[0xa9, 0x42, 0x8f, 0x00, 0x00, 0x7f, 0xa9, 0x00, 0xa2, 0x01, 0xbf, 0xff, 0xff, 0x7e]);
+        assert_eq!(cpu.a & 0xff, 0x42);
+        assert_eq!(bus.read(0x7f0000), 0x42);
+
+        // Pointer to $1200 at $20
+        let pointer = [0xa9, 0x00, 0x85, 0x20, 0xa9, 0x12, 0x85, 0x21];
+
+        // (dp),Y: LDA #$42; STA $1205; LDA #$00; LDY #$05; LDA ($20),Y
+        let (cpu, _) = run(&[&pointer[..], &[0xa9, 0x42, 0x8d, 0x05, 0x12, 0xa9, 0x00, 0xa0, 0x05, 0xb1, 0x20]].concat());
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // (dp,X): LDA #$42; STA $1200; LDA #$00; LDX #$04; LDA ($1c,X)
+        let (cpu, _) = run(&[&pointer[..], &[0xa9, 0x42, 0x8d, 0x00, 0x12, 0xa9, 0x00, 0xa2, 0x04, 0xa1, 0x1c]].concat());
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // [dp],Y with the pointer's bank set to $7f: LDA #$7f; STA $22; LDA #$42; STA $7f1203;
+        // LDA #$00; LDY #$03; LDA [$20],Y
+        let (cpu, _) = run(&[&pointer[..], &[0xa9, 0x7f, 0x85, 0x22, 0xa9, 0x42, 0x8f, 0x03, 0x12, 0x7f,
+            0xa9, 0x00, 0xa0, 0x03, 0xb7, 0x20]].concat());
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // Direct page register: LDA #$42; STA $1205; LDA #$12; XBA; LDA #$00; TCD; LDA $05
+        let (cpu, _) = run(&[0xa9, 0x42, 0x8d, 0x05, 0x12, 0xa9, 0x12, 0xeb, 0xa9, 0x00, 0x5b, 0xa5, 0x05]);
+        assert_eq!(cpu.d, 0x1200);
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // Stack relative: LDA #$42; PHA; LDA #$00; LDA $01,S
+        let (cpu, _) = run(&[0xa9, 0x42, 0x48, 0xa9, 0x00, 0xa3, 0x01]);
+        assert_eq!(cpu.a & 0xff, 0x42);
+
+        // Absolute addresses use the data bank: LDA #$7f; PHA; PLB; LDA #$42; STA $0010
+        let (cpu, mut bus) = run(&[0xa9, 0x7f, 0x48, 0xab, 0xa9, 0x42, 0x8d, 0x10, 0x00]);
+        assert_eq!(cpu.db, 0x7f);
+        assert_eq!(bus.read(0x7f0010), 0x42);
+    }
+
+    #[test]
+    fn immediate_size_test() {
+        // With only M cleared, LDA takes a 16-bit operand and LDX an 8-bit one:
+        // CLC; XCE; REP #$20; LDA #$1234; LDX #$56
+        let (cpu, _) = run(&[0x18, 0xfb, 0xc2, 0x20, 0xa9, 0x34, 0x12, 0xa2, 0x56]);
+        assert!(!cpu.e);
+        assert_eq!(cpu.a, 0x1234);
+        assert_eq!(cpu.x, 0x56);
+    }
+}
@@ -0,0 +1,473 @@
+mod bus;
+mod cpu;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use flate2::Crc;
+use flate2::read::ZlibDecoder;
+use super::snes_apu::apu::Apu;
+use super::snes_apu::dsp::dsp::SAMPLE_RATE;
+use super::SpcMetadata;
+use crate::frame_rate::FrameRate;
+use bus::Bus;
+use cpu::Cpu;
+
+const PSF_SIGNATURE: &[u8] = b"PSF";
+const PSF_TAG_SIGNATURE: &[u8] = b"[TAG]";
+const SNSF_VERSION: u8 = 0x23;
+const PSF_HEADER_LEN: usize = 16;
+const MAX_LIB_DEPTH: usize = 10;
+/// Largest ROM the SNES can address, which is 8 MiB for ExHiROM
+const MAX_ROM_SIZE: usize = 0x800000;
+
+const SNSF_EXTENSIONS: [&str; 2] = ["snsf", "minisnsf"];
+
+/// NTSC master clock rate, and the rate of the SMP cycle counter (64 cycles per sample)
+const MASTER_CLOCK_RATE: i64 = 21_477_272;
+const SMP_CLOCK_RATE: i64 = SAMPLE_RATE as i64 * 64;
+/// Number of SMP cycles the two CPUs run for between exchanges of the APUIO ports
+const SYNC_CYCLES: i32 = 16;
+
+pub fn is_snsf_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension()
+        .and_then(|e| e.to_str())
+        .map(|e| SNSF_EXTENSIONS.iter().any(|x| e.eq_ignore_ascii_case(x)))
+        .unwrap_or(false)
+}
+
+struct PsfFile {
+    reserved: Vec<u8>,
+    program: Vec<u8>,
+    tags: HashMap<String, String>
+}
+
+fn read_le_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..(offset + 4))
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+impl PsfFile {
+    fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&data, &path.display().to_string())
+    }
+
+    fn parse(data: &[u8], name: &str) -> Result<Self, String> {
+        if data.len() < PSF_HEADER_LEN || &data[0..3] != PSF_SIGNATURE {
+            return Err(format!("{} is not a PSF file", name));
+        }
+        if data[3] != SNSF_VERSION {
+            return Err(format!("{} is not an SNSF file (PSF version ${:02x})", name, data[3]));
+        }
+
+        let reserved_len = read_le_u32(data, 4).unwrap() as usize;
+        let program_len = read_le_u32(data, 8).unwrap() as usize;
+        let program_crc = read_le_u32(data, 12).unwrap();
+        let reserved_end = PSF_HEADER_LEN.saturating_add(reserved_len);
+        let program_end = reserved_end.saturating_add(program_len);
+        if data.len() < program_end {
+            return Err(format!("{} is truncated", name));
+        }
+
+        let reserved = data[PSF_HEADER_LEN..reserved_end].to_vec();
+        let compressed_program = &data[reserved_end..program_end];
+        let mut crc = Crc::new();
+        crc.update(compressed_program);
+        if crc.sum() != program_crc {
+            return Err(format!("{} is corrupt (program CRC mismatch)", name));
+        }
+
+        let mut program = Vec::new();
+        if program_len > 0 {
+            ZlibDecoder::new(compressed_program)
+                .read_to_end(&mut program)
+                .map_err(|e| format!("Failed to decompress {}: {}", name, e))?;
+        }
+
+        let mut tags: HashMap<String, String> = HashMap::new();
+        let tag_data = &data[program_end..];
+        if tag_data.starts_with(PSF_TAG_SIGNATURE) {
+            let tag_text = String::from_utf8_lossy(&tag_data[PSF_TAG_SIGNATURE.len()..]);
+            for line in tag_text.lines() {
+                if let Some((key, value)) = line.split_once('=') {
+                    // Repeated keys continue a multi-line value
+                    tags.entry(key.trim().to_ascii_lowercase())
+                        .and_modify(|v| { v.push('\n'); v.push_str(value.trim()); })
+                        .or_insert(value.trim().to_string());
+                }
+            }
+        }
+
+        Ok(Self { reserved, program, tags })
+    }
+}
+
+/// ROM and SRAM contents built up from an SNSF and its libraries
+struct SnsfImage {
+    rom: Vec<u8>,
+    sram: Vec<u8>,
+    base_offset: Option<usize>
+}
+
+impl SnsfImage {
+    fn load(&mut self, path: &Path, depth: usize) -> Result<HashMap<String, String>, String> {
+        if depth > MAX_LIB_DEPTH {
+            return Err("SNSF library nesting is too deep".to_string());
+        }
+
+        let psf = PsfFile::load(path)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+
+        if let Some(lib) = psf.tags.get("_lib") {
+            self.load(&directory.join(lib), depth + 1)?;
+        }
+
+        self.load_program(&psf.program)?;
+        self.load_reserved(&psf.reserved);
+
+        for n in 2.. {
+            match psf.tags.get(&format!("_lib{}", n)) {
+                Some(lib) => { self.load(&directory.join(lib), depth + 1)?; },
+                None => break
+            }
+        }
+
+        Ok(psf.tags)
+    }
+
+    fn load_program(&mut self, program: &[u8]) -> Result<(), String> {
+        let (offset, size) = match (read_le_u32(program, 0), read_le_u32(program, 4)) {
+            (Some(offset), Some(size)) => (offset as usize, size as usize),
+            _ => return Ok(())
+        };
+        let data = &program[8..];
+        let size = size.min(data.len());
+
+        // The first file loaded sets the base that later offsets are relative to
+        let offset = match self.base_offset {
+            Some(base_offset) => base_offset.saturating_add(offset),
+            None => {
+                self.base_offset = Some(offset);
+                offset
+            }
+        };
+
+        let end = offset.checked_add(size)
+            .filter(|&end| end <= MAX_ROM_SIZE)
+            .ok_or(format!("SNSF ROM offset out of range: {:#x}", offset))?;
+
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[offset..end].copy_from_slice(&data[..size]);
+
+        Ok(())
+    }
+
+    fn load_reserved(&mut self, reserved: &[u8]) {
+        let mut position = 0;
+        while let (Some(block_type), Some(block_len)) = (read_le_u32(reserved, position), read_le_u32(reserved, position + 4)) {
+            let block_start = position + 8;
+            let block_end = (block_start + block_len as usize).min(reserved.len());
+            let block = &reserved[block_start..block_end];
+
+            // Type 0 is SRAM: a 32-bit offset followed by the data
+            if block_type == 0 {
+                if let Some(offset) = read_le_u32(block, 0) {
+                    let offset = offset as usize;
+                    let data = &block[4..];
+                    if self.sram.len() < offset + data.len() {
+                        self.sram.resize(offset + data.len(), 0);
+                    }
+                    self.sram[offset..(offset + data.len())].copy_from_slice(data);
+                }
+            }
+
+            position = block_end;
+        }
+    }
+}
+
+/// Parses a PSF length tag ("seconds", "m:ss" or "h:mm:ss", with optional decimals)
+fn parse_psf_duration(s: &str) -> Option<f64> {
+    s.trim()
+        .replace(',', ".")
+        .split(':')
+        .try_fold(0.0, |total, part| part.parse::<f64>().ok().map(|v| total * 60.0 + v))
+}
+
+/// An SNSF song: the main CPU side of the SNES, which drives an `Apu` through the APUIO
+/// ports the same way the game does.
+pub struct Snsf {
+    cpu: Cpu,
+    bus: Bus,
+    tags: HashMap<String, String>,
+    // Master cycles owed to the main CPU, in units of 1/SMP_CLOCK_RATE
+    cycle_debt: i64
+}
+
+impl Snsf {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let mut image = SnsfImage {
+            rom: Vec::new(),
+            sram: Vec::new(),
+            base_offset: None
+        };
+        let tags = image.load(path.as_ref(), 0)?;
+        if image.rom.is_empty() {
+            return Err("SNSF does not contain any ROM data".to_string());
+        }
+
+        let mut bus = Bus::new(image.rom, image.sram);
+        let cpu = Cpu::new(&mut bus);
+
+        Ok(Self {
+            cpu,
+            bus,
+            tags,
+            cycle_debt: 0
+        })
+    }
+
+    /// Runs both CPUs until the APU has produced `num_samples` samples.
+    pub fn render(&mut self, apu: &mut Apu, left_buffer: &mut [i16], right_buffer: &mut [i16], num_samples: i32) {
+        while !apu.read_samples(left_buffer, right_buffer, num_samples) {
+            for (port, value) in self.bus.take_apu_port_writes().iter().enumerate() {
+                if let Some(value) = value {
+                    apu.write_port(port, *value);
+                }
+            }
+
+            let smp_cycles = apu.run_cycles(SYNC_CYCLES);
+            for port in 0..4 {
+                self.bus.apu_ports[port] = apu.read_port(port);
+            }
+
+            self.cycle_debt += smp_cycles as i64 * MASTER_CLOCK_RATE;
+            while self.cycle_debt > 0 {
+                let master_cycles = self.cpu.step(&mut self.bus);
+                self.cycle_debt -= master_cycles as i64 * SMP_CLOCK_RATE;
+            }
+        }
+    }
+
+    pub fn metadata(&self, frame_rate: FrameRate) -> SpcMetadata {
+        let tag = |key: &str| self.tags.get(key).cloned();
+        let duration_frames = |key: &str| tag(key)
+            .and_then(|s| parse_psf_duration(&s))
+            .map(|seconds| frame_rate.frames(seconds))
+            .unwrap_or(0);
+
+        SpcMetadata {
+            title: tag("title").unwrap_or_default(),
+            artist: tag("artist").unwrap_or_default(),
+            game: tag("game").unwrap_or_default(),
+            dumper: tag("snsfby").unwrap_or_default(),
+            comments: tag("comment").unwrap_or_default(),
+            ost_title: None,
+            publisher: tag("copyright"),
+            copyright_year: tag("year").and_then(|s| s.get(0..4).and_then(|y| y.parse().ok())),
+            disc: None,
+            track: tag("track"),
+            duration_frames: duration_frames("length"),
+            fadeout_frames: duration_frames("fade")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    /// A PSF file with the given sections, with the program compressed and its CRC filled in
+    fn psf(reserved: &[u8], program: &[u8], tags: &str) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(program).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut crc = Crc::new();
+        crc.update(&compressed);
+
+        let mut data = b"PSF\x23".to_vec();
+        data.extend_from_slice(&(reserved.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        data.extend_from_slice(&crc.sum().to_le_bytes());
+        data.extend_from_slice(reserved);
+        data.extend_from_slice(&compressed);
+        if !tags.is_empty() {
+            data.extend_from_slice(PSF_TAG_SIGNATURE);
+            data.extend_from_slice(tags.as_bytes());
+        }
+        data
+    }
+
+    /// An SNSF program section loading `data` at `offset`
+    fn program(offset: u32, data: &[u8]) -> Vec<u8> {
+        [&offset.to_le_bytes()[..], &(data.len() as u32).to_le_bytes(), data].concat()
+    }
+
+    /// A reserved section block of `block_type`
+    fn block(block_type: u32, data: &[u8]) -> Vec<u8> {
+        [&block_type.to_le_bytes()[..], &(data.len() as u32).to_le_bytes(), data].concat()
+    }
+
+    fn sram_block(offset: u32, data: &[u8]) -> Vec<u8> {
+        block(0, &[&offset.to_le_bytes()[..], data].concat())
+    }
+
+    fn parse_error(data: &[u8]) -> String {
+        match PsfFile::parse(data, "test") {
+            Ok(_) => panic!("parsed an invalid PSF"),
+            Err(e) => e
+        }
+    }
+
+    fn empty_image() -> SnsfImage {
+        SnsfImage { rom: Vec::new(), sram: Vec::new(), base_offset: None }
+    }
+
+    /// A scratch directory for library files, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("snsf_test_{}_{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, name: &str, data: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, data).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        let data = psf(&[1, 2, 3], &program(0, &[0xaa; 4]), "\ntitle=Song\ncomment=one\ncomment=two\nLENGTH= 1:30 \n");
+        let psf = PsfFile::parse(&data, "test").unwrap();
+
+        assert_eq!(psf.reserved, vec![1, 2, 3]);
+        assert_eq!(psf.program, program(0, &[0xaa; 4]));
+        assert_eq!(psf.tags["title"], "Song");
+        assert_eq!(psf.tags["comment"], "one\ntwo");
+        assert_eq!(psf.tags["length"], "1:30");
+    }
+
+    #[test]
+    fn invalid_file_test() {
+        assert!(PsfFile::parse(b"PSF", "test").is_err());
+        assert!(PsfFile::parse(b"NOTAPSFFILE_____", "test").is_err());
+
+        let mut data = psf(&[], &program(0, &[0xaa; 4]), "");
+        data[3] = 0x01;
+        assert!(parse_error(&data).contains("not an SNSF"));
+
+        let data = psf(&[0; 8], &program(0, &[0xaa; 4]), "");
+        assert!(parse_error(&data[..data.len() - 1]).contains("truncated"));
+
+        let mut data = psf(&[], &program(0, &[0xaa; 4]), "");
+        data[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_error(&data).contains("truncated"));
+    }
+
+    #[test]
+    fn crc_test() {
+        let data = psf(&[], &program(0, &[0xaa; 64]), "");
+        assert!(PsfFile::parse(&data, "test").is_ok());
+
+        let mut corrupt = data.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        assert!(parse_error(&corrupt).contains("CRC"));
+
+        let mut wrong_crc = data.clone();
+        wrong_crc[12] ^= 0x01;
+        assert!(parse_error(&wrong_crc).contains("CRC"));
+    }
+
+    #[test]
+    fn reserved_test() {
+        let reserved = [
+            sram_block(0x10, &[1, 2]),
+            block(5, &[0xff; 6]),
+            sram_block(0, &[3])
+        ].concat();
+
+        let mut image = empty_image();
+        image.load_reserved(&reserved);
+        let mut expected = vec![0; 0x12];
+        expected[0] = 3;
+        expected[0x10..].copy_from_slice(&[1, 2]);
+        assert_eq!(image.sram, expected);
+
+        // A block running past the end of the section is cut short
+        let mut truncated = sram_block(4, &[7, 8, 9]);
+        truncated.truncate(truncated.len() - 1);
+        let mut image = empty_image();
+        image.load_reserved(&truncated);
+        assert_eq!(image.sram, vec![0, 0, 0, 0, 7, 8]);
+    }
+
+    #[test]
+    fn lib_test() {
+        let dir = TempDir::new("lib");
+        dir.write("base.snsflib", &psf(&sram_block(0, &[0x11; 4]), &program(0x100, &[1; 8]), "title=Base"));
+        dir.write("patch.snsflib", &psf(&[], &program(5, &[3]), ""));
+        let song = dir.write("song.minisnsf", &psf(
+            &sram_block(2, &[0x22]),
+            &program(4, &[2, 2]),
+            "title=Song\n_lib=base.snsflib\n_lib2=patch.snsflib"
+        ));
+
+        // The libraries' offsets are relative to the first one loaded, and _lib is loaded
+        // before the song while _lib2 is loaded after it
+        let mut image = empty_image();
+        let tags = image.load(&song, 0).unwrap();
+        assert_eq!(tags["title"], "Song");
+        assert_eq!(image.base_offset, Some(0x100));
+        assert_eq!(image.rom.len(), 0x108);
+        assert_eq!(&image.rom[0x100..], &[1, 1, 1, 1, 2, 3, 1, 1]);
+        assert_eq!(image.sram, vec![0x11, 0x11, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn lib_errors_test() {
+        let dir = TempDir::new("lib_errors");
+        let missing = dir.write("missing.minisnsf", &psf(&[], &program(0, &[1]), "_lib=nothing.snsflib"));
+        assert!(empty_image().load(&missing, 0).unwrap_err().contains("nothing.snsflib"));
+
+        let looped = dir.write("loop.minisnsf", &psf(&[], &program(0, &[1]), "_lib=loop.minisnsf"));
+        assert!(empty_image().load(&looped, 0).unwrap_err().contains("too deep"));
+    }
+
+    #[test]
+    fn rom_offset_test() {
+        let mut image = empty_image();
+        assert!(image.load_program(&program(MAX_ROM_SIZE as u32 - 2, &[1, 2])).is_ok());
+        assert_eq!(image.rom.len(), MAX_ROM_SIZE);
+
+        // Offsets past what the SNES can address fail instead of allocating all of them
+        let mut image = empty_image();
+        assert!(image.load_program(&program(0xfffffff0, &[1])).unwrap_err().contains("out of range"));
+        assert!(image.rom.is_empty());
+
+        // Including ones that only get there relative to the base offset
+        let mut image = empty_image();
+        image.load_program(&program(0x400000, &[1])).unwrap();
+        assert!(image.load_program(&program(0x400000, &[1])).unwrap_err().contains("out of range"));
+    }
+}
@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+/// A video frame rate, kept as a fraction so NTSC rates like 59.94 (60000/1001) are exact.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameRate {
+    pub numerator: u32,
+    pub denominator: u32
+}
+
+pub const DEFAULT_FRAME_RATE: FrameRate = FrameRate { numerator: 60, denominator: 1 };
+
+impl FrameRate {
+    pub fn as_f64(&self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    /// Number of whole frames in `seconds`
+    pub fn frames(&self, seconds: f64) -> u64 {
+        (seconds * self.numerator as f64 / self.denominator as f64).round() as u64
+    }
+
+    /// Number of whole frames in `samples` samples at `sample_rate`
+    pub fn frames_from_samples(&self, samples: u64, sample_rate: u64) -> u64 {
+        samples * self.numerator as u64 / (sample_rate * self.denominator as u64)
+    }
+
+    /// Total number of samples at `sample_rate` by the end of frame `frame` (rounded up)
+    pub fn samples_at_frame_end(&self, frame: u64, sample_rate: u64) -> u64 {
+        let samples = (frame + 1) * sample_rate * self.denominator as u64;
+        samples.div_ceil(self.numerator as u64)
+    }
+}
+
+impl FromStr for FrameRate {
+    type Err = String;
+
+    /// Parses "60", "50", "59.94", "29.97" or an explicit fraction like "60000/1001".
+    /// Decimal rates just below a whole number are treated as the matching NTSC rate.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid frame rate {}, try one of '60', '59.94', '50' or '60000/1001'", s);
+
+        let (numerator, denominator) = match s.split_once('/') {
+            Some((n, d)) => (
+                u32::from_str(n.trim()).map_err(|_| invalid())?,
+                u32::from_str(d.trim()).map_err(|_| invalid())?
+            ),
+            None => {
+                let fps = f64::from_str(s.trim()).map_err(|_| invalid())?;
+                let ntsc_fps = (fps * 1.001).round();
+                if fps.fract() == 0.0 {
+                    (fps as u32, 1)
+                } else if (ntsc_fps / 1.001 - fps).abs() < 0.01 {
+                    (ntsc_fps as u32 * 1000, 1001)
+                } else {
+                    ((fps * 1000.0).round() as u32, 1000)
+                }
+            }
+        };
+
+        if numerator == 0 || denominator == 0 {
+            return Err(invalid());
+        }
+
+        Ok(FrameRate { numerator, denominator })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_rate(numerator: u32, denominator: u32) -> FrameRate {
+        FrameRate { numerator, denominator }
+    }
+
+    #[test]
+    fn from_str_test() {
+        assert_eq!(FrameRate::from_str("60"), Ok(frame_rate(60, 1)));
+        assert_eq!(FrameRate::from_str(" 50 "), Ok(frame_rate(50, 1)));
+        assert_eq!(FrameRate::from_str("59.94"), Ok(frame_rate(60000, 1001)));
+        assert_eq!(FrameRate::from_str("29.97"), Ok(frame_rate(30000, 1001)));
+        assert_eq!(FrameRate::from_str("23.976"), Ok(frame_rate(24000, 1001)));
+        assert_eq!(FrameRate::from_str("60000/1001"), Ok(frame_rate(60000, 1001)));
+        assert_eq!(FrameRate::from_str("25 / 1"), Ok(frame_rate(25, 1)));
+        assert_eq!(FrameRate::from_str("12.5"), Ok(frame_rate(12500, 1000)));
+    }
+
+    #[test]
+    fn from_str_invalid_test() {
+        assert!(FrameRate::from_str("").is_err());
+        assert!(FrameRate::from_str("fast").is_err());
+        assert!(FrameRate::from_str("0").is_err());
+        assert!(FrameRate::from_str("60/0").is_err());
+        assert!(FrameRate::from_str("60/").is_err());
+        assert!(FrameRate::from_str("-30").is_err());
+    }
+
+    #[test]
+    fn frames_test() {
+        assert_eq!(DEFAULT_FRAME_RATE.frames(1.5), 90);
+        assert_eq!(frame_rate(60000, 1001).frames(1001.0), 60000);
+        assert_eq!(frame_rate(50, 1).frames(0.0), 0);
+    }
+
+    #[test]
+    fn frames_from_samples_test() {
+        assert_eq!(DEFAULT_FRAME_RATE.frames_from_samples(32000, 32000), 60);
+        // Partial frames are dropped
+        assert_eq!(DEFAULT_FRAME_RATE.frames_from_samples(533, 32000), 0);
+        assert_eq!(DEFAULT_FRAME_RATE.frames_from_samples(534, 32000), 1);
+        assert_eq!(frame_rate(60000, 1001).frames_from_samples(32000 * 1001, 32000), 60000);
+        // Ten hours of samples don't overflow
+        assert_eq!(frame_rate(60000, 1001).frames_from_samples(10 * 3600 * 32000, 32000), 2_157_842);
+    }
+
+    #[test]
+    fn samples_at_frame_end_test() {
+        assert_eq!(DEFAULT_FRAME_RATE.samples_at_frame_end(0, 32000), 534);
+        assert_eq!(DEFAULT_FRAME_RATE.samples_at_frame_end(59, 32000), 32000);
+        assert_eq!(frame_rate(60000, 1001).samples_at_frame_end(59999, 32000), 32000 * 1001);
+    }
+}
@@ -9,7 +9,8 @@ use indicatif::{FormattedDuration, HumanBytes, HumanDuration};
 use native_dialog::{FileDialog, MessageDialog, MessageType};
 use slint;
 use slint::Model as _;
-use crate::emulator::ResamplingMode;
+use crate::emulator::{self, Emulator, ResamplingMode};
+use crate::frame_rate::DEFAULT_FRAME_RATE;
 use crate::gui::render_thread::{RenderThreadMessage, RenderThreadRequest};
 use crate::renderer::render_options::{RendererOptions, StopCondition};
 use crate::tuning;
@@ -47,8 +48,9 @@ fn slint_color_component_arr<I: IntoIterator<Item = raqote::Color>>(a: I) -> sli
 
 fn browse_for_module_dialog() -> Option<String> {
     let file = FileDialog::new()
-        .add_filter("All supported formats", &["spc"])
+        .add_filter("All supported formats", &["spc", "snsf", "minisnsf"])
         .add_filter("SPC files", &["spc"])
+        .add_filter("SNSF files", &["snsf", "minisnsf"])
         .show_open_single_file();
 
     match file {
@@ -70,6 +72,32 @@ fn browse_for_background_dialog() -> Option<String> {
     }
 }
 
+fn browse_for_layout_dialog() -> Option<String> {
+    let file = FileDialog::new()
+        .add_filter("All supported formats", &["json", "toml"])
+        .add_filter("JSON layout files", &["json"])
+        .add_filter("TOML layout files", &["toml"])
+        .show_open_single_file();
+
+    match file {
+        Ok(Some(path)) => Some(path.to_str().unwrap().to_string()),
+        _ => None
+    }
+}
+
+fn browse_for_theme_dialog() -> Option<String> {
+    let file = FileDialog::new()
+        .add_filter("All supported formats", &["json", "toml"])
+        .add_filter("JSON theme files", &["json"])
+        .add_filter("TOML theme files", &["toml"])
+        .show_open_single_file();
+
+    match file {
+        Ok(Some(path)) => Some(path.to_str().unwrap().to_string()),
+        _ => None
+    }
+}
+
 fn browse_for_tuning_data() -> Option<String> {
     let file = FileDialog::new()
         .add_filter("All supported formats", &["json"])
@@ -84,10 +112,12 @@ fn browse_for_tuning_data() -> Option<String> {
 
 fn browse_for_video_dialog() -> Option<String> {
     let file = FileDialog::new()
-        .add_filter("All supported formats", &["mp4", "mkv", "mov"])
+        .add_filter("All supported formats", &["mp4", "mkv", "mov", "wav", "flac"])
         .add_filter("MPEG-4 Video", &["mp4"])
         .add_filter("Matroska Video", &["mkv"])
         .add_filter("QuickTime Video", &["mov"])
+        .add_filter("WAV Audio (audio only)", &["wav"])
+        .add_filter("FLAC Audio (audio only)", &["flac"])
         .show_save_single_file();
 
     match file {
@@ -133,20 +163,39 @@ fn parse_hex(s: slint::SharedString) -> Option<u8> {
 }
 
 fn get_spc_metadata<P: AsRef<Path>>(spc_path: P) -> (bool, Option<Duration>, slint::ModelRc<slint::SharedString>) {
+    if emulator::is_snsf_path(&spc_path) {
+        let (snsf_valid, duration, lines) = match Emulator::new(spc_path).map(|e| e.get_spc_metadata()) {
+            Ok(Some(metadata)) => (
+                true,
+                Some(Duration::from_secs_f64(metadata.duration_frames as f64 / DEFAULT_FRAME_RATE.as_f64())).filter(|d| !d.is_zero()),
+                vec![metadata.title, metadata.artist, metadata.game]
+            ),
+            _ => (false, None, vec!["<no metadata>".to_string()])
+        };
+        return (snsf_valid, duration, slint_string_arr(lines));
+    }
+
     let (spc_valid, duration, lines) = match spc::spc::Spc::load(spc_path) {
         Ok(spc_file) => {
-            match spc_file.id666_tag {
+            let xid6_duration = spc_file.xid6_tag.as_ref()
+                .and_then(|t| t.play_length())
+                .map(|ticks| Duration::from_secs_f64(ticks as f64 / spc::spc::XID6_TICKS_PER_SECOND as f64));
+            let xid6_string = |field: fn(&spc::spc::Xid6Tag) -> &Option<String>| {
+                spc_file.xid6_tag.as_ref().and_then(|t| field(t).clone())
+            };
+
+            match &spc_file.id666_tag {
                 Some(metadata) => (
                     true,
-                    Some(Duration::from_secs(metadata.seconds_to_play_before_fading_out as _)),
+                    xid6_duration.or(Some(Duration::from_secs(metadata.seconds_to_play_before_fading_out as _))),
                     vec![
-                        metadata.song_title,
-                        metadata.artist_name,
-                        metadata.game_title,
-                        metadata.dumper_name
+                        xid6_string(|t| &t.song_title).unwrap_or(metadata.song_title.clone()),
+                        xid6_string(|t| &t.artist_name).unwrap_or(metadata.artist_name.clone()),
+                        xid6_string(|t| &t.game_title).unwrap_or(metadata.game_title.clone()),
+                        xid6_string(|t| &t.dumper_name).unwrap_or(metadata.dumper_name.clone())
                     ]
                 ),
-                None => (true, None, vec!["<no metadata>".to_string()])
+                None => (true, xid6_duration, vec!["<no metadata>".to_string()])
             }
         },
         _ => (false, None, vec!["<no metadata>".to_string()])
@@ -338,12 +387,54 @@ pub fn run() {
         });
     }
 
+    {
+        let mut options = options.clone();
+        main_window.on_browse_for_layout(move || {
+            match browse_for_layout_dialog() {
+                Some(path) => {
+                    options.borrow_mut().layout_path = Some(path.clone());
+
+                    path.into()
+                },
+                None => options.borrow().layout_path.clone().unwrap_or("".to_string()).into()
+            }
+        });
+    }
+
+    {
+        let mut options = options.clone();
+        main_window.on_layout_cleared(move || {
+            options.borrow_mut().layout_path = None;
+        });
+    }
+
+    {
+        let mut options = options.clone();
+        main_window.on_browse_for_theme(move || {
+            match browse_for_theme_dialog() {
+                Some(path) => {
+                    options.borrow_mut().theme_path = Some(path.clone());
+
+                    path.into()
+                },
+                None => options.borrow().theme_path.clone().unwrap_or("".to_string()).into()
+            }
+        });
+    }
+
+    {
+        let mut options = options.clone();
+        main_window.on_theme_cleared(move || {
+            options.borrow_mut().theme_path = None;
+        });
+    }
+
     {
         let mut options = options.clone();
         main_window.on_format_duration(move |stop_condition_type, stop_condition_num| {
             let duration = match stop_condition_type {
                 StopConditionType::Frames => {
-                    let seconds = (stop_condition_num as f64) / 60.0;
+                    let seconds = (stop_condition_num as f64) / options.borrow().frame_rate.as_f64();
                     Duration::from_secs_f64(seconds)
                 },
                 StopConditionType::Time => {
@@ -500,11 +591,15 @@ pub fn run() {
             options.borrow_mut().fadeout_length = main_window_weak.unwrap().get_fadeout_duration() as u64;
             options.borrow_mut().video_options.resolution_out.0 = main_window_weak.unwrap().get_output_width() as u32;
             options.borrow_mut().video_options.resolution_out.1 = main_window_weak.unwrap().get_output_height() as u32;
+            options.borrow_mut().canvas_resolution.0 = main_window_weak.unwrap().get_canvas_width() as u32;
+            options.borrow_mut().canvas_resolution.1 = main_window_weak.unwrap().get_canvas_height() as u32;
+            options.borrow_mut().layout_preset = main_window_weak.unwrap().get_layout_preset().to_string();
+            options.borrow_mut().theme_preset = main_window_weak.unwrap().get_theme_preset().to_string();
 
             let stop_condition_num = main_window_weak.unwrap().get_stop_condition_num() as u64;
             options.borrow_mut().stop_condition = match main_window_weak.unwrap().get_stop_condition_type() {
                 StopConditionType::Frames => StopCondition::Frames(stop_condition_num),
-                StopConditionType::Time => StopCondition::Frames(stop_condition_num * 60),
+                StopConditionType::Time => StopCondition::Time(stop_condition_num),
                 StopConditionType::SpcDuration => StopCondition::SpcDuration
             };
 
@@ -2,7 +2,9 @@ mod emulator;
 mod visualizer;
 mod video_builder;
 mod renderer;
+mod frame_rate;
 mod cli;
+mod batch;
 mod tuning;
 mod gui;
 
@@ -3,70 +3,167 @@ pub mod render_options;
 use std::cell::RefCell;
 use std::fmt::{Display, Formatter};
 use std::fs;
+use std::path::Path;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 use ringbuf::{HeapRb, Rb};
 use ringbuf::ring_buffer::RbBase;
 use spc::spc::Spc;
 use render_options::RendererOptions;
-use crate::emulator::{Emulator, ResamplingMode};
+use crate::emulator::{Emulator, ResamplingMode, SongLoop, Stem, SAMPLE_RATE};
 use crate::renderer::render_options::StopCondition;
 use crate::video_builder;
-use crate::video_builder::VideoBuilder;
+use crate::video_builder::{AudioBuilder, VideoBuilder};
+use crate::video_builder::video_options::{AudioStreamOptions, VideoOptions};
 use crate::visualizer::Visualizer;
+use crate::visualizer::layout::Layout;
+use crate::visualizer::theme::Theme;
+
+/// Where rendered frames end up: a video, or just the audio when the output is a WAV or FLAC file.
+enum RendererOutput {
+    Video(VideoBuilder),
+    Audio(AudioBuilder)
+}
 
 pub struct Renderer {
     options: RendererOptions,
     emulator: Emulator,
     viz: Rc<RefCell<Visualizer>>,
-    vb: VideoBuilder,
+    output: RendererOutput,
+    stem_builders: Vec<AudioBuilder>,
+    stem_tracks: bool,
 
     cur_frame: u64,
     encode_start: Instant,
     frame_timestamp: f64,
     frame_times: HeapRb<f64>,
     loop_count: u64,
-    loop_duration: Option<u64>,
     fadeout_timer: Option<u64>,
     expected_duration: Option<usize>
 }
 
 impl Renderer {
     pub fn new(options: RendererOptions) -> Result<Self, String> {
-        let emulator = Emulator::new(options.input_path.clone())?;
-        let viz = Rc::new(RefCell::new(Visualizer::new()));
+        let mut options = options;
+        let frame_rate = options.frame_rate;
+        if let StopCondition::Time(seconds) = options.stop_condition {
+            options.stop_condition = StopCondition::Frames(frame_rate.frames(seconds as f64));
+        }
+
+        let mut emulator = Emulator::new(options.input_path.clone())?;
+        emulator.set_frame_rate(frame_rate);
+        let (canvas_w, canvas_h) = options.canvas_resolution;
+        if canvas_w == 0 || canvas_h == 0 {
+            return Err(format!("Invalid canvas size {}x{}", canvas_w, canvas_h));
+        }
+        let layout = match &options.layout_path {
+            Some(layout_path) => Layout::load(layout_path, canvas_w, canvas_h)?,
+            None => Layout::preset(&options.layout_preset, canvas_w, canvas_h)?
+        };
+        let theme = match &options.theme_path {
+            Some(theme_path) => Theme::load(theme_path)?,
+            None => Theme::preset(&options.theme_preset)?
+        };
+        let viz = Rc::new(RefCell::new(Visualizer::new(layout)));
+        viz.borrow_mut().set_theme(theme)?;
+        viz.borrow_mut().set_frame_rate(frame_rate.as_f64());
 
         let mut video_options = options.video_options.clone();
+        video_options.resolution_in = viz.borrow().layout().resolution();
+        video_options.video_time_base = (frame_rate.denominator as i32, frame_rate.numerator as i32).into();
 
         if let Some(metadata) = emulator.get_spc_metadata() {
+            viz.borrow_mut().set_song_metadata(&metadata);
             video_options.metadata.insert("title".to_string(), metadata.title);
             video_options.metadata.insert("artist".to_string(), metadata.artist);
-            video_options.metadata.insert("album".to_string(), metadata.game);
-            // video_options.metadata.insert("track".to_string(), format!("{}/{}", options.track_index, emulator.track_count()));
+            video_options.metadata.insert("album".to_string(), metadata.ost_title.unwrap_or(metadata.game));
+            if let Some(track) = metadata.track {
+                video_options.metadata.insert("track".to_string(), track);
+            }
+            if let Some(disc) = metadata.disc {
+                video_options.metadata.insert("disc".to_string(), disc.to_string());
+            }
+            if let Some(publisher) = metadata.publisher {
+                video_options.metadata.insert("publisher".to_string(), publisher);
+            }
+            if let Some(copyright_year) = metadata.copyright_year {
+                video_options.metadata.insert("date".to_string(), copyright_year.to_string());
+            }
             video_options.metadata.insert("comment".to_string(), "Encoded with SPCPresenter".to_string());
         }
 
-        let vb = VideoBuilder::new(video_options)?;
+        let stem_builders = match &options.stems_path {
+            Some(stems_path) => Self::create_stem_builders(stems_path, &video_options, &viz.borrow())?,
+            None => Vec::new()
+        };
+
+        let audio_only = video_builder::audio_only_codec(&video_options.output_path).is_some();
+        let stem_tracks = options.stem_tracks && !audio_only;
+        if stem_tracks {
+            let settings_manager = viz.borrow().settings_manager();
+            video_options.extra_audio_streams = (0..8)
+                .map(|channel| AudioStreamOptions { title: settings_manager.settings(channel).name() })
+                .collect();
+        }
+
+        let output = match audio_only {
+            true => RendererOutput::Audio(AudioBuilder::new(video_options)?),
+            false => RendererOutput::Video(VideoBuilder::new(video_options)?)
+        };
 
         Ok(Self {
             options: options.clone(),
             emulator,
             viz,
-            vb,
+            output,
+            stem_builders,
+            stem_tracks,
             cur_frame: 0,
             encode_start: Instant::now(),
             frame_timestamp: 0.0,
             frame_times: HeapRb::new(600),
             loop_count: 0,
-            loop_duration: None,
             fadeout_timer: None,
             expected_duration: None
         })
     }
 
+    fn create_stem_builders(stems_path: &str, video_options: &VideoOptions, viz: &Visualizer) -> Result<Vec<AudioBuilder>, String> {
+        fs::create_dir_all(stems_path)
+            .map_err(|e| format!("Failed to create {}: {}", stems_path, e))?;
+
+        let settings_manager = viz.settings_manager();
+        Stem::all().into_iter()
+            .map(|stem| {
+                let stem_name = match stem {
+                    Stem::Dry(channel) => format!("{} (dry)", settings_manager.settings(channel).name()),
+                    Stem::Wet(channel) => format!("{} (wet)", settings_manager.settings(channel).name()),
+                    Stem::Main => "Main mix".to_string(),
+                    Stem::EchoReturn => "Echo return".to_string()
+                };
+
+                let mut stem_options = video_options.clone();
+                stem_options.output_path = Path::new(stems_path)
+                    .join(format!("{:02} {}.wav", stem.index() + 1, stem_name))
+                    .to_str().unwrap().to_string();
+                if let Some(title) = video_options.metadata.get("title") {
+                    stem_options.metadata.insert("title".to_string(), format!("{} ({})", title, stem_name));
+                }
+
+                AudioBuilder::new(stem_options)
+            })
+            .collect()
+    }
+
     pub fn start_encoding(&mut self) -> Result<(), String> {
         self.emulator.init();
-        self.emulator.set_state_receiver(Some(self.viz.clone()));
+        self.emulator.set_sample_rate(self.options.video_options.sample_rate as u32)?;
+        self.emulator.set_stems_enabled(self.stem_tracks || !self.stem_builders.is_empty())?;
+        // Audio-only renders never draw anything, so don't bother collecting state for the visualizer
+        if let RendererOutput::Video(_) = self.output {
+            self.emulator.set_state_receiver(Some(self.viz.clone()));
+            self.emulator.set_memory_tracking(self.viz.borrow().layout().has_memory_map());
+        }
         self.emulator.set_resampling_mode(self.options.resampling_mode.clone());
         self.emulator.set_filter_enabled(self.options.filter_enabled);
 
@@ -74,6 +171,13 @@ impl Renderer {
             self.viz.borrow_mut().settings_manager_mut().settings_mut(i).set_colors(&vec![color.clone()]);
         }
 
+        let muted_voices = self.emulator.default_muted_voices();
+        for channel in 0..8 {
+            let muted = (muted_voices & (1 << channel)) != 0;
+            self.emulator.set_voice_muted(channel, muted);
+            self.viz.borrow_mut().settings_manager_mut().settings_mut(channel).set_muted(muted);
+        }
+
         for (source, pitch) in &self.options.manual_sample_tunings {
             self.emulator.set_manual_sample_tuning(*source, *pitch);
         }
@@ -82,7 +186,24 @@ impl Renderer {
             self.viz.borrow_mut().settings_manager_mut().put_per_sample_colors(self.options.per_sample_colors.clone());
         }
 
-        self.vb.start_encoding()?;
+        // Run ahead of the video so the piano rolls can show upcoming notes. The audio of
+        // these frames is held back, so it stays in sync with the video.
+        if let RendererOutput::Video(_) = self.output {
+            let look_ahead_frames = self.viz.borrow().look_ahead_frames();
+            for _ in 0..look_ahead_frames {
+                self.emulator.step()?;
+                self.viz.borrow_mut().end_frame();
+            }
+            self.emulator.hold_back_buffered_audio();
+        }
+
+        match &mut self.output {
+            RendererOutput::Video(vb) => vb.start_encoding()?,
+            RendererOutput::Audio(ab) => ab.start_encoding()?
+        }
+        for stem_builder in self.stem_builders.iter_mut() {
+            stem_builder.start_encoding()?;
+        }
         self.encode_start = Instant::now();
 
         Ok(())
@@ -91,23 +212,56 @@ impl Renderer {
     pub fn step(&mut self) -> Result<bool, String> {
         self.emulator.step()?;
 
-        self.viz.borrow_mut().clear();
-        self.viz.borrow_mut().draw_oscilloscopes();
-        self.viz.borrow_mut().draw_piano_roll();
+        let elapsed = Duration::from_secs_f64(self.cur_frame as f64 / self.options.frame_rate.as_f64());
+        self.viz.borrow_mut().set_playback_position(elapsed, self.expected_duration(), self.loop_count);
 
-        self.vb.push_video_data(&self.viz.borrow().get_canvas_buffer())?;
-        if let Some(audio) = self.emulator.get_audio_samples(Some(self.vb.audio_frame_size())) {
-            let adjusted_audio = match self.fadeout_timer {
-                Some(t) => {
-                    let volume_divisor = (self.options.fadeout_length as f64 / t as f64) as i16;
-                    audio.iter().map(|s| s / volume_divisor).collect()
-                },
-                None => audio
-            };
-            self.vb.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?;
+        match &mut self.output {
+            RendererOutput::Video(vb) => {
+                self.viz.borrow_mut().clear();
+                self.viz.borrow_mut().draw();
+
+                vb.push_video_data(&self.viz.borrow().get_canvas_buffer())?;
+                while let Some(audio) = self.emulator.get_audio_samples(Some(vb.audio_frame_size())) {
+                    let adjusted_audio = Self::apply_fadeout(audio, self.fadeout_timer, self.options.fadeout_length);
+                    vb.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?;
+                }
+            },
+            RendererOutput::Audio(ab) => {
+                while let Some(audio) = self.emulator.get_audio_samples(Some(ab.audio_frame_size())) {
+                    let adjusted_audio = Self::apply_fadeout(audio, self.fadeout_timer, self.options.fadeout_length);
+                    ab.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?;
+                }
+            }
+        }
+
+        // Stem tracks have to match the video's audio frame size, WAV stems take any frame size
+        let stem_frame_size = match &self.output {
+            RendererOutput::Video(vb) if self.stem_tracks => Some(vb.audio_frame_size()),
+            _ => self.stem_builders.first().map(|sb| sb.audio_frame_size())
+        };
+        if let Some(frame_size) = stem_frame_size {
+            while let Some(stems) = self.emulator.get_stem_samples(Some(frame_size)) {
+                for (stem, audio) in Stem::all().into_iter().zip(stems) {
+                    let adjusted_audio = Self::apply_fadeout(audio, self.fadeout_timer, self.options.fadeout_length);
+                    if let Some(stem_builder) = self.stem_builders.get_mut(stem.index()) {
+                        stem_builder.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?;
+                    }
+                    if let (RendererOutput::Video(vb), Stem::Wet(channel)) = (&mut self.output, stem) {
+                        if self.stem_tracks {
+                            vb.push_stream_audio_data(channel + 1, video_builder::as_u8_slice(&adjusted_audio))?;
+                        }
+                    }
+                }
+            }
         }
 
-        self.vb.step_encoding()?;
+        match &mut self.output {
+            RendererOutput::Video(vb) => vb.step_encoding()?,
+            RendererOutput::Audio(ab) => ab.step_encoding()?
+        }
+        for stem_builder in self.stem_builders.iter_mut() {
+            stem_builder.step_encoding()?;
+        }
 
         let elapsed_secs = self.elapsed().as_secs_f64();
         let frame_time = elapsed_secs - self.frame_timestamp;
@@ -126,25 +280,67 @@ impl Renderer {
 
         self.cur_frame += 1;
 
-        // if let Some(current_position) = self.song_position() {
-        //     if current_position.row < self.last_position.row {
-        //         self.loop_count += 1;
-        //         if self.loop_duration.is_none() {
-        //             self.loop_duration = Some(self.cur_frame);
-        //         }
-        //     }
-        //     self.last_position = current_position;
-        // }
+        if let Some(song_loop) = self.emulator.song_loop() {
+            while self.cur_frame >= self.loop_end_frame(&song_loop, self.loop_count + 1) {
+                self.loop_count += 1;
+            }
+        }
 
         Ok(true)
     }
 
     pub fn finish_encoding(&mut self) -> Result<(), String> {
-        self.vb.finish_encoding()?;
+        // The samples left over after the last whole audio frame
+        if let Some(audio) = self.emulator.get_audio_samples(None).filter(|audio| !audio.is_empty()) {
+            let adjusted_audio = Self::apply_fadeout(audio, self.fadeout_timer, self.options.fadeout_length);
+            match &mut self.output {
+                RendererOutput::Video(vb) => vb.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?,
+                RendererOutput::Audio(ab) => ab.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?
+            }
+        }
+
+        // The stems have leftovers of their own, which they and the stem tracks need to end along
+        // with the main mix
+        if let Some(stems) = self.emulator.get_stem_samples(None) {
+            for (stem, audio) in Stem::all().into_iter().zip(stems) {
+                if audio.is_empty() {
+                    continue;
+                }
+                let adjusted_audio = Self::apply_fadeout(audio, self.fadeout_timer, self.options.fadeout_length);
+                if let Some(stem_builder) = self.stem_builders.get_mut(stem.index()) {
+                    stem_builder.push_audio_data(video_builder::as_u8_slice(&adjusted_audio))?;
+                }
+                if let (RendererOutput::Video(vb), Stem::Wet(channel)) = (&mut self.output, stem) {
+                    if self.stem_tracks {
+                        vb.push_stream_audio_data(channel + 1, video_builder::as_u8_slice(&adjusted_audio))?;
+                    }
+                }
+            }
+        }
+
+        match &mut self.output {
+            RendererOutput::Video(vb) => vb.finish_encoding()?,
+            RendererOutput::Audio(ab) => ab.finish_encoding()?
+        }
+        for stem_builder in self.stem_builders.iter_mut() {
+            stem_builder.finish_encoding()?;
+        }
 
         Ok(())
     }
 
+    fn apply_fadeout(audio: Vec<i16>, fadeout_timer: Option<u64>, fadeout_length: u64) -> Vec<i16> {
+        match fadeout_timer {
+            // The fadeout is over (or has no length), so there's nothing left to hear
+            Some(0) => vec![0; audio.len()],
+            Some(t) => {
+                let volume_divisor = (fadeout_length as f64 / t as f64) as i16;
+                audio.iter().map(|s| s / volume_divisor).collect()
+            },
+            None => audio
+        }
+    }
+
     pub fn current_frame(&self) -> u64 {
         self.cur_frame
     }
@@ -161,14 +357,16 @@ impl Renderer {
         match self.options.stop_condition {
             StopCondition::Frames(stop_frames) => Some((stop_frames + self.options.fadeout_length) as usize),
             StopCondition::Loops(stop_loop_count) => {
-                match self.loop_duration {
-                    Some(d) => Some(self.options.fadeout_length as usize + d as usize * stop_loop_count),
-                    None => None
-                }
+                let song_loop = self.emulator.song_loop()?;
+                // The loop is only found once it has started repeating, so with a low loop
+                // count the fadeout may have to start right away.
+                let loop_end = self.loop_end_frame(&song_loop, stop_loop_count as u64).max(self.current_frame());
+                Some((loop_end + self.options.fadeout_length) as usize)
             },
             StopCondition::SpcDuration => {
                 Some((self.emulator.get_spc_metadata()?.duration_frames + self.options.fadeout_length) as usize)
-            }
+            },
+            StopCondition::Time(_) => unreachable!("time stop conditions are converted to frames in Renderer::new")
         }
     }
 
@@ -207,12 +405,17 @@ impl Renderer {
                         } else {
                             None
                         }
-                    }
+                    },
+                    StopCondition::Time(_) => unreachable!("time stop conditions are converted to frames in Renderer::new")
                 }
             }
         }
     }
 
+    fn loop_end_frame(&self, song_loop: &SongLoop, loops: u64) -> u64 {
+        self.options.frame_rate.frames_from_samples(song_loop.end(loops), SAMPLE_RATE as u64)
+    }
+
     pub fn loop_count(&self) -> u64 {
         self.loop_count
     }
@@ -232,15 +435,21 @@ impl Renderer {
     }
 
     pub fn encode_rate(&self) -> f64 {
-        self.average_fps() as f64 / 60.0
+        self.average_fps() as f64 / self.options.frame_rate.as_f64()
     }
 
     pub fn encoded_duration(&self) -> Duration {
-        self.vb.encoded_video_duration()
+        match &self.output {
+            RendererOutput::Video(vb) => vb.encoded_video_duration(),
+            RendererOutput::Audio(ab) => ab.encoded_audio_duration()
+        }
     }
 
     pub fn encoded_size(&self) -> usize {
-        self.vb.encoded_video_size()
+        match &self.output {
+            RendererOutput::Video(vb) => vb.encoded_video_size(),
+            RendererOutput::Audio(ab) => ab.encoded_audio_size()
+        }
     }
 
     pub fn expected_duration_frames(&self) -> Option<usize> {
@@ -250,7 +459,7 @@ impl Renderer {
     pub fn expected_duration(&self) -> Option<Duration> {
         match self.expected_duration {
             Some(d) => {
-                let secs = d as f64 / 60.0;
+                let secs = d as f64 / self.options.frame_rate.as_f64();
                 Some(Duration::from_secs_f64(secs))
             },
             None => None
@@ -269,3 +478,20 @@ impl Renderer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_fadeout_test() {
+        let audio = vec![1000, -1000, 500, -500];
+
+        assert_eq!(Renderer::apply_fadeout(audio.clone(), None, 180), audio);
+        assert_eq!(Renderer::apply_fadeout(audio.clone(), Some(90), 180), vec![500, -500, 250, -250]);
+
+        // A finished or zero length fadeout is silent instead of dividing by zero
+        assert_eq!(Renderer::apply_fadeout(audio.clone(), Some(0), 180), vec![0; 4]);
+        assert_eq!(Renderer::apply_fadeout(audio, Some(0), 0), vec![0; 4]);
+    }
+}
@@ -2,10 +2,10 @@ use std::collections::HashMap;
 use std::str::FromStr;
 use std::ffi::OsStr;
 use crate::emulator::ResamplingMode;
+use crate::frame_rate::{FrameRate, DEFAULT_FRAME_RATE};
 use crate::video_builder::video_options::VideoOptions;
 use crate::visualizer::channel_settings::ChannelSettings;
-
-pub const FRAME_RATE: i32 = 60;
+use crate::visualizer::layout::REFERENCE_RESOLUTION;
 
 macro_rules! extra_str_traits {
     ($t: ty) => {
@@ -26,6 +26,8 @@ macro_rules! extra_str_traits {
 #[derive(Copy, Clone)]
 pub enum StopCondition {
     Frames(u64),
+    /// Converted to `Frames` once the frame rate is known
+    Time(u64),
     Loops(usize),
     SpcDuration
 }
@@ -36,7 +38,7 @@ impl FromStr for StopCondition {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = s.split(':').collect();
         if parts.len() != 2 {
-            return Err("Stop condition format invalid, try one of 'time:30', 'time:spc', or 'frames:1800'.".to_string());
+            return Err("Stop condition format invalid, try one of 'time:30', 'time:spc', 'frames:1800', or 'loops:2'.".to_string());
         }
 
         match parts[0] {
@@ -45,7 +47,7 @@ impl FromStr for StopCondition {
                     Ok(StopCondition::SpcDuration)
                 } else {
                     let time = u64::from_str(parts[1]).map_err( | e | e.to_string()) ?;
-                    Ok(StopCondition::Frames(time * FRAME_RATE as u64))
+                    Ok(StopCondition::Time(time))
                 }
             },
             "frames" => {
@@ -62,12 +64,24 @@ impl FromStr for StopCondition {
 }
 
 extra_str_traits!(StopCondition);
+extra_str_traits!(FrameRate);
 
 #[derive(Clone)]
 pub struct RendererOptions {
     pub input_path: String,
     pub video_options: VideoOptions,
 
+    pub frame_rate: FrameRate,
+    /// Size the visualizer is laid out and drawn at, before scaling to the output resolution
+    pub canvas_resolution: (u32, u32),
+    /// Built-in layout to use, see `LAYOUT_PRESETS`
+    pub layout_preset: String,
+    /// Layout file to use instead of the built-in layout
+    pub layout_path: Option<String>,
+    /// Built-in theme to use, see `THEME_PRESETS`
+    pub theme_preset: String,
+    /// Theme file to use instead of the built-in theme
+    pub theme_path: Option<String>,
     pub stop_condition: StopCondition,
     pub fadeout_length: u64,
 
@@ -75,7 +89,9 @@ pub struct RendererOptions {
     pub manual_sample_tunings: HashMap<u8, f64>,
     pub per_sample_colors: HashMap<u8, raqote::Color>,
     pub filter_enabled: bool,
-    pub resampling_mode: ResamplingMode
+    pub resampling_mode: ResamplingMode,
+    pub stems_path: Option<String>,
+    pub stem_tracks: bool
 }
 
 impl Default for RendererOptions {
@@ -91,7 +107,7 @@ impl Default for RendererOptions {
                 video_codec_params: Default::default(),
                 pixel_format_in: "bgra".to_string(),
                 pixel_format_out: "yuv420p".to_string(),
-                resolution_in: (960, 540),
+                resolution_in: REFERENCE_RESOLUTION,
                 resolution_out: (1920, 1080),
                 audio_time_base: (1, 44_100).into(),
                 audio_codec: "aac".to_string(),
@@ -100,14 +116,23 @@ impl Default for RendererOptions {
                 sample_format_in: "s16".to_string(),
                 sample_format_out: "fltp".to_string(),
                 sample_rate: 44_100,
+                extra_audio_streams: Vec::new()
             },
-            stop_condition: StopCondition::Frames(300 * FRAME_RATE as u64),
+            frame_rate: DEFAULT_FRAME_RATE,
+            canvas_resolution: REFERENCE_RESOLUTION,
+            layout_preset: "default".to_string(),
+            layout_path: None,
+            theme_preset: "default".to_string(),
+            theme_path: None,
+            stop_condition: StopCondition::Time(300),
             fadeout_length: 180,
             channel_base_colors: Vec::new(),
             manual_sample_tunings: HashMap::new(),
             per_sample_colors: HashMap::new(),
             filter_enabled: true,
-            resampling_mode: ResamplingMode::AccurateGaussian
+            resampling_mode: ResamplingMode::AccurateGaussian,
+            stems_path: None,
+            stem_tracks: false
         }
     }
 }
@@ -0,0 +1,125 @@
+use std::path::Path;
+use std::time::Duration;
+use ffmpeg_next::{format, encoder, ChannelLayout, Dictionary, Packet};
+use super::audio_stream::AudioStream;
+use super::video_options::VideoOptions;
+use super::vb_unwrap::VideoBuilderUnwrap;
+use super::ffmpeg_hacks::{ffmpeg_context_bytes_written, ffmpeg_copy_codec_params, ffmpeg_copy_context_params, ffmpeg_create_context, ffmpeg_sample_format_from_string};
+
+/// Audio codec to use when the output path is an audio-only format, or `None` for video outputs.
+pub fn audio_only_codec<P: AsRef<Path>>(output_path: P) -> Option<&'static str> {
+    let extension = output_path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "wav" => Some("pcm_s16le"),
+        "flac" => Some("flac"),
+        _ => None
+    }
+}
+
+/// Encodes only the audio of a render into a WAV or FLAC file. The video related
+/// options are ignored.
+pub struct AudioBuilder {
+    options: VideoOptions,
+
+    out_ctx: format::context::Output,
+
+    a_stream: AudioStream
+}
+
+impl AudioBuilder {
+    pub fn new(options: VideoOptions) -> Result<Self, String> {
+        let codec_name = audio_only_codec(&options.output_path)
+            .ok_or_else(|| format!("{} is not an audio-only output", options.output_path))?;
+
+        let mut out_ctx = format::output(&options.output_path).vb_unwrap()?;
+
+        let mut metadata = Dictionary::new();
+        for (k, v) in options.metadata.iter() {
+            metadata.set(k.as_str(), v.as_str());
+        }
+        out_ctx.set_metadata(metadata);
+
+        // Both WAV and FLAC take the emulator's interleaved s16 samples as is,
+        // so unlike the video builder no sample format conversion is needed.
+        let sample_format = ffmpeg_sample_format_from_string(&options.sample_format_in);
+        let channel_layout = ChannelLayout::default(options.audio_channels);
+        let codec = encoder::find_by_name(codec_name)
+            .ok_or_else(|| format!("Unknown codec {}", codec_name))?;
+
+        let mut stream = out_ctx.add_stream(codec).vb_unwrap()?;
+        let mut context = ffmpeg_create_context(codec, stream.parameters())?
+            .encoder()
+            .audio()
+            .vb_unwrap()?;
+
+        context.set_rate(options.sample_rate);
+        context.set_format(sample_format);
+        context.set_channels(options.audio_channels);
+        context.set_channel_layout(channel_layout);
+        context.set_time_base(options.audio_time_base);
+
+        ffmpeg_copy_codec_params(&mut stream, &context, &codec)?;
+
+        stream.set_time_base(options.audio_time_base);
+
+        let mut context_options = Dictionary::new();
+        for (k, v) in options.audio_codec_params.iter() {
+            context_options.set(k.as_str(), v.as_str());
+        }
+
+        let a_encoder = context.open_as_with(codec, context_options).vb_unwrap()?;
+        let a_stream_idx = stream.index();
+
+        ffmpeg_copy_context_params(&mut stream, a_encoder.as_ref())?;
+
+        let a_stream = AudioStream::new(a_encoder, a_stream_idx, None, &options);
+
+        Ok(Self {
+            options,
+            out_ctx,
+            a_stream
+        })
+    }
+
+    pub fn push_audio_data(&mut self, audio: &[u8]) -> Result<(), String> {
+        self.a_stream.push(audio)
+    }
+
+    pub fn start_encoding(&mut self) -> Result<(), String> {
+        self.out_ctx.write_header().vb_unwrap()?;
+
+        Ok(())
+    }
+
+    pub fn step_encoding(&mut self) -> Result<(), String> {
+        let mut packet = Packet::empty();
+
+        while self.a_stream.send_frame()? {
+            while self.a_stream.mux_packet(&mut self.out_ctx, &mut packet)? {}
+        }
+
+        Ok(())
+    }
+
+    pub fn finish_encoding(&mut self) -> Result<(), String> {
+        let mut packet = Packet::empty();
+        self.a_stream.finish(&mut self.out_ctx, &mut packet)?;
+        while self.a_stream.mux_packet(&mut self.out_ctx, &mut packet)? {}
+
+        self.out_ctx.write_trailer().vb_unwrap()?;
+
+        Ok(())
+    }
+
+    pub fn audio_frame_size(&self) -> usize {
+        self.a_stream.frame_size()
+    }
+
+    pub fn encoded_audio_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.a_stream.pts() as f64 / self.options.sample_rate as f64)
+    }
+
+    pub fn encoded_audio_size(&self) -> usize {
+        ffmpeg_context_bytes_written(&self.out_ctx)
+    }
+}
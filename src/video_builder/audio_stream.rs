@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+use std::mem;
+use ffmpeg_next::{format, encoder, software, frame, ChannelLayout, Packet, Rational};
+use super::video_options::VideoOptions;
+use super::vb_unwrap::VideoBuilderUnwrap;
+use super::ffmpeg_hacks::ffmpeg_sample_format_from_string;
+
+/// Frame size used for encoders that accept any, such as PCM
+const DEFAULT_FRAME_SIZE: usize = 1024;
+
+/// Encoder state for one audio stream of the output. Audio can be pushed in chunks of
+/// any size; it is cut into frames of the encoder's frame size, and whatever is left
+/// over is sent as the last frame by `finish`.
+pub struct AudioStream {
+    encoder: encoder::Audio,
+    // Converts the input samples to the encoder's sample format, if they differ
+    swr_ctx: Option<software::resampling::Context>,
+    input_format: format::Sample,
+    channel_layout: ChannelLayout,
+    sample_rate: i32,
+    time_base: Rational,
+    stream_idx: usize,
+    frame_size: usize,
+    // Encoders with a fixed frame size get the last frame padded with silence
+    is_fixed_frame_size: bool,
+
+    pending: Vec<u8>,
+    frame_buf: VecDeque<frame::Audio>,
+    pts: i64,
+    pts_muxed: i64
+}
+
+impl AudioStream {
+    pub fn new(encoder: encoder::Audio, stream_idx: usize, swr_ctx: Option<software::resampling::Context>, options: &VideoOptions) -> Self {
+        let (frame_size, is_fixed_frame_size) = match encoder.frame_size() {
+            0 => (DEFAULT_FRAME_SIZE, false),
+            frame_size => (frame_size as usize, true)
+        };
+
+        Self {
+            encoder,
+            swr_ctx,
+            input_format: ffmpeg_sample_format_from_string(&options.sample_format_in),
+            channel_layout: ChannelLayout::default(options.audio_channels),
+            sample_rate: options.sample_rate,
+            time_base: options.audio_time_base,
+            stream_idx,
+            frame_size,
+            is_fixed_frame_size,
+            pending: Vec::new(),
+            frame_buf: VecDeque::new(),
+            pts: 0,
+            pts_muxed: 0
+        }
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Number of samples sent to the encoder so far
+    pub fn pts(&self) -> i64 {
+        self.pts
+    }
+
+    /// Number of packets written to the output so far
+    pub fn pts_muxed(&self) -> i64 {
+        self.pts_muxed
+    }
+
+    pub fn has_frames(&self) -> bool {
+        !self.frame_buf.is_empty()
+    }
+
+    fn bytes_per_frame(&self) -> usize {
+        self.frame_size * self.channel_layout.channels() as usize * self.input_format.bytes()
+    }
+
+    /// Queue interleaved samples in the input sample format
+    pub fn push(&mut self, audio: &[u8]) -> Result<(), String> {
+        self.pending.extend_from_slice(audio);
+
+        let bytes_per_frame = self.bytes_per_frame();
+        while self.pending.len() >= bytes_per_frame {
+            let frame_data: Vec<u8> = self.pending.drain(..bytes_per_frame).collect();
+            self.queue_frame(&frame_data)?;
+        }
+
+        Ok(())
+    }
+
+    fn queue_frame(&mut self, audio: &[u8]) -> Result<(), String> {
+        let bytes_per_sample = self.channel_layout.channels() as usize * self.input_format.bytes();
+        let samples = audio.len() / bytes_per_sample;
+
+        let mut input_frame = frame::Audio::new(self.input_format, samples, self.channel_layout);
+        input_frame.set_rate(self.sample_rate as _);
+        input_frame.data_mut(0)[..audio.len()].copy_from_slice(audio);
+
+        let output_frame = match &mut self.swr_ctx {
+            Some(swr_ctx) => {
+                let mut output_frame = frame::Audio::new(swr_ctx.output().format, samples, swr_ctx.output().channel_layout);
+                output_frame.set_rate(self.sample_rate as _);
+                swr_ctx.run(&input_frame, &mut output_frame).vb_unwrap()?;
+                output_frame
+            },
+            None => input_frame
+        };
+
+        self.frame_buf.push_back(output_frame);
+
+        Ok(())
+    }
+
+    /// Send the oldest queued frame to the encoder. Returns false if there was none.
+    pub fn send_frame(&mut self) -> Result<bool, String> {
+        match self.frame_buf.pop_front() {
+            Some(mut frame) => {
+                frame.set_pts(Some(self.pts));
+                self.encoder.send_frame(&frame).vb_unwrap()?;
+
+                self.pts += frame.samples() as i64;
+
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Write the next encoded packet to the output. Returns false if there was none ready.
+    pub fn mux_packet(&mut self, out_ctx: &mut format::context::Output, packet: &mut Packet) -> Result<bool, String> {
+        if self.encoder.receive_packet(packet).is_ok() {
+            let out_time_base = out_ctx.stream(self.stream_idx)
+                .unwrap()
+                .time_base();
+
+            packet.rescale_ts(self.time_base, out_time_base);
+            packet.set_stream(self.stream_idx);
+            packet.write_interleaved(out_ctx).vb_unwrap()?;
+
+            self.pts_muxed += 1;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Send the remaining audio, including a last partial frame, and flush the encoder.
+    /// The packets it still holds have to be muxed afterwards.
+    pub fn finish(&mut self, out_ctx: &mut format::context::Output, packet: &mut Packet) -> Result<(), String> {
+        if !self.pending.is_empty() {
+            let mut frame_data = mem::take(&mut self.pending);
+            if self.is_fixed_frame_size {
+                frame_data.resize(self.bytes_per_frame(), 0);
+            }
+            self.queue_frame(&frame_data)?;
+        }
+
+        while self.send_frame()? {
+            while self.mux_packet(out_ctx, packet)? {}
+        }
+
+        self.encoder.send_eof().vb_unwrap()?;
+
+        Ok(())
+    }
+}
@@ -72,20 +72,15 @@ impl VideoBuilder {
     }
 
     pub fn push_audio_data(&mut self, audio: &[u8]) -> Result<(), String> {
-        let bytes_per_sample = self.a_swr_ctx.input().channel_layout.channels() as usize * self.a_swr_ctx.input().format.bytes();
-        let samples = audio.len() / bytes_per_sample;
-
-        let mut input_frame = frame::Audio::new(self.a_swr_ctx.input().format, samples, self.a_swr_ctx.input().channel_layout);
-        input_frame.set_rate(self.options.sample_rate as _);
-        input_frame.data_mut(0).copy_from_slice(audio);
-
-        let mut output_frame = frame::Audio::new(self.a_swr_ctx.output().format, samples, self.a_swr_ctx.output().channel_layout);
-        output_frame.set_rate(self.options.sample_rate as _);
-        self.a_swr_ctx.run(&input_frame, &mut output_frame).vb_unwrap()?;
-
-        self.a_frame_buf.push_back(output_frame);
+        self.push_stream_audio_data(0, audio)
+    }
 
-        Ok(())
+    /// Push audio for one of the audio streams, where stream 0 is the main mix and
+    /// stream n is `extra_audio_streams[n - 1]`.
+    pub fn push_stream_audio_data(&mut self, stream: usize, audio: &[u8]) -> Result<(), String> {
+        self.a_streams.get_mut(stream)
+            .ok_or_else(|| format!("Audio stream {} does not exist", stream))?
+            .push(audio)
     }
 
     fn send_video_to_encoder(&mut self) -> Result<(), String> {
@@ -117,35 +112,6 @@ impl VideoBuilder {
         }
     }
 
-    fn send_audio_to_encoder(&mut self) -> Result<(), String> {
-        if let Some(mut frame) = self.a_frame_buf.pop_front() {
-            frame.set_pts(Some(self.a_pts));
-            self.a_encoder.send_frame(&frame).vb_unwrap()?;
-
-            self.a_pts += self.a_encoder.frame_size() as i64;
-        }
-
-        Ok(())
-    }
-
-    fn mux_audio_frame(&mut self, packet: &mut Packet) -> Result<bool, String> {
-        if self.a_encoder.receive_packet(packet).is_ok() {
-            let out_time_base = self.out_ctx.stream(self.a_stream_idx)
-                .unwrap()
-                .time_base();
-
-            packet.rescale_ts(self.options.audio_time_base, out_time_base);
-            packet.set_stream(self.a_stream_idx);
-            packet.write_interleaved(&mut self.out_ctx).vb_unwrap()?;
-
-            self.a_pts_muxed += 1;
-
-            Ok(true)
-        } else {
-            Ok(false)
-        }
-    }
-
     pub fn start_encoding(&mut self) -> Result<(), String> {
         let mut opts = Dictionary::new();
         println!("{}", self.out_ctx.format().name());
@@ -163,9 +129,15 @@ impl VideoBuilder {
         let mut packet = Packet::empty();
 
         loop {
-            if self.a_pts_muxed <= self.v_pts_muxed && !self.a_frame_buf.is_empty() {
-                self.send_audio_to_encoder()?;
-                if !(self.mux_audio_frame(&mut packet)?) {
+            // Feed the audio stream that is furthest behind, so all of them stay interleaved with the video
+            let next_audio_stream = (0..self.a_streams.len())
+                .filter(|&i| self.a_streams[i].has_frames())
+                .min_by_key(|&i| self.a_streams[i].pts_muxed());
+
+            if let Some(stream) = next_audio_stream.filter(|&i| self.a_streams[i].pts_muxed() <= self.v_pts_muxed) {
+                let a_stream = &mut self.a_streams[stream];
+                a_stream.send_frame()?;
+                if !(a_stream.mux_packet(&mut self.out_ctx, &mut packet)?) {
                     break;
                 }
             } else if !self.v_frame_buf.is_empty() {
@@ -182,12 +154,23 @@ impl VideoBuilder {
     }
 
     pub fn finish_encoding(&mut self) -> Result<(), String> {
+        let mut packet = Packet::empty();
+
+        // Send whatever step_encoding left queued before flushing the encoders
+        while !self.v_frame_buf.is_empty() {
+            self.send_video_to_encoder()?;
+            while self.mux_video_frame(&mut packet)? {}
+        }
         self.v_encoder.send_eof().vb_unwrap()?;
-        self.a_encoder.send_eof().vb_unwrap()?;
+        for a_stream in self.a_streams.iter_mut() {
+            a_stream.finish(&mut self.out_ctx, &mut packet)?;
+        }
 
-        let mut packet = Packet::empty();
         loop {
-            let muxed_audio = self.mux_audio_frame(&mut packet)?;
+            let mut muxed_audio = false;
+            for a_stream in self.a_streams.iter_mut() {
+                muxed_audio |= a_stream.mux_packet(&mut self.out_ctx, &mut packet)?;
+            }
             let muxed_video = self.mux_video_frame(&mut packet)?;
 
             if !muxed_audio && !muxed_video {
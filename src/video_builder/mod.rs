@@ -2,14 +2,18 @@ pub mod video_options;
 mod vb_unwrap;
 mod ffmpeg_hacks;
 mod encoding;
+mod audio_stream;
 pub mod backgrounds;
+pub mod audio_builder;
 
 use std::collections::VecDeque;
 use std::{mem, slice};
 use std::str::FromStr;
 use ffmpeg_next::{self, Error, Rational, format, encoder, codec, ChannelLayout, Dictionary, software, frame};
 use video_options::VideoOptions;
+use audio_stream::AudioStream;
 pub use vb_unwrap::VideoBuilderUnwrap;
+pub use audio_builder::{AudioBuilder, audio_only_codec};
 use crate::video_builder::backgrounds::{get_video_background, VideoBackground};
 use crate::video_builder::ffmpeg_hacks::{ffmpeg_copy_codec_params, ffmpeg_copy_context_params, ffmpeg_create_context, ffmpeg_sample_format_from_string, ffmpeg_set_audio_stream_frame_size};
 
@@ -41,13 +45,9 @@ pub struct VideoBuilder {
     v_pts: i64,
     v_pts_muxed: i64,
 
-    a_encoder: encoder::Audio,
-    a_swr_ctx: software::resampling::Context,
-    a_frame_buf: VecDeque<frame::Audio>,
-    a_stream_idx: usize,
-    a_frame_size: usize,
-    a_pts: i64,
-    a_pts_muxed: i64
+    // The main mix is always the first audio stream, followed by `extra_audio_streams`
+    a_streams: Vec<AudioStream>,
+    a_frame_size: usize
 }
 
 impl VideoBuilder {
@@ -120,10 +120,18 @@ impl VideoBuilder {
             channel_layout,
             options.sample_rate as u32
         );
-        let a_swr_ctx = software::resampler(swr_in, swr_out).vb_unwrap()?;
 
         let (v_encoder, v_stream_idx) = Self::create_video_encoder(options.clone(), &mut out_ctx)?;
-        let (a_encoder, a_stream_idx, a_frame_size) = Self::create_audio_encoder(options.clone(), &mut out_ctx)?;
+
+        let stream_titles = [None].into_iter()
+            .chain(options.extra_audio_streams.iter().map(|s| Some(s.title.as_str())));
+        let mut a_streams = Vec::new();
+        for title in stream_titles {
+            let (encoder, stream_idx) = Self::create_audio_encoder(options.clone(), &mut out_ctx, title)?;
+            let swr_ctx = software::resampler(swr_in, swr_out).vb_unwrap()?;
+            a_streams.push(AudioStream::new(encoder, stream_idx, Some(swr_ctx), &options));
+        }
+        let a_frame_size = a_streams[0].frame_size();
 
         Ok(Self {
             options,
@@ -136,13 +144,8 @@ impl VideoBuilder {
             v_stream_idx,
             v_pts: 0,
             v_pts_muxed: 0,
-            a_encoder,
-            a_swr_ctx,
-            a_frame_buf: VecDeque::new(),
-            a_stream_idx,
-            a_frame_size,
-            a_pts: 0,
-            a_pts_muxed: 0
+            a_streams,
+            a_frame_size
         })
     }
 
@@ -198,7 +201,7 @@ impl VideoBuilder {
         Ok((v_encoder, v_stream_idx))
     }
 
-    fn create_audio_encoder(options: VideoOptions, out_ctx: &mut format::context::Output) -> Result<(encoder::Audio, usize, usize), String> {
+    fn create_audio_encoder(options: VideoOptions, out_ctx: &mut format::context::Output, title: Option<&str>) -> Result<(encoder::Audio, usize), String> {
         let output_format = ffmpeg_sample_format_from_string(&options.sample_format_out);
         let channel_layout = ChannelLayout::default(options.audio_channels);
         let codec = encoder::find_by_name(&options.audio_codec)
@@ -221,7 +224,13 @@ impl VideoBuilder {
 
         stream.set_time_base(options.audio_time_base);
 
-        let a_frame_size = 1024; // ffmpeg_set_audio_stream_frame_size(&mut stream, 1024);
+        if let Some(title) = title {
+            let mut stream_metadata = Dictionary::new();
+            stream_metadata.set("title", title);
+            // QuickTime shows the handler name as the track name
+            stream_metadata.set("handler_name", title);
+            stream.set_metadata(stream_metadata);
+        }
 
         let mut context_options = Dictionary::new();
         // Add some default options for certain codecs
@@ -240,6 +249,6 @@ impl VideoBuilder {
 
         ffmpeg_copy_context_params(&mut stream, a_encoder.as_ref())?;
 
-        Ok((a_encoder, a_stream_idx, a_frame_size))
+        Ok((a_encoder, a_stream_idx))
     }
 }
@@ -1,6 +1,12 @@
 use std::collections::HashMap;
 use ffmpeg_next::Rational;
 
+/// An extra audio stream muxed after the main mix, using the same audio settings.
+#[derive(Clone)]
+pub struct AudioStreamOptions {
+    pub title: String
+}
+
 #[derive(Clone)]
 pub struct VideoOptions {
     pub output_path: String,
@@ -21,5 +27,6 @@ pub struct VideoOptions {
     pub audio_channels: i32,
     pub sample_format_in: String,
     pub sample_format_out: String,
-    pub sample_rate: i32
+    pub sample_rate: i32,
+    pub extra_audio_streams: Vec<AudioStreamOptions>
 }
@@ -1,13 +1,14 @@
 use std::collections::HashMap;
 use raqote::Color;
 use super::ChannelState;
+use super::theme::DimmingTheme;
 
 #[derive(Clone)]
-pub struct ChannelSettings(String, bool, Vec<Color>);
+pub struct ChannelSettings(String, bool, Vec<Color>, bool);
 
 impl ChannelSettings {
     pub fn new(name: &str, colors: &[Color]) -> Self {
-        Self(name.to_string(), false, colors.to_vec())
+        Self(name.to_string(), false, colors.to_vec(), false)
     }
 
     pub fn name(&self) -> String {
@@ -18,11 +19,14 @@ impl ChannelSettings {
         self.1
     }
 
-    pub fn color(&self, state: &ChannelState) -> Option<Color> {
+    pub fn color(&self, state: &ChannelState, dimming: &DimmingTheme) -> Option<Color> {
         let result = self.2.get(state.timbre).cloned();
         if let Some(color) = &result {
+            if self.3 {
+                return Some(dimming.muted.apply(*color));
+            }
             if state.volume == 0 {
-                return Some(Color::new(color.a(), color.r() / 2 + 0x10, color.g() / 2 + 0x10, color.b() / 2 + 0x10));
+                return Some(dimming.silent.apply(*color));
             }
         }
         result
@@ -43,6 +47,10 @@ impl ChannelSettings {
     pub fn set_colors(&mut self, colors: &[Color]) {
         self.2 = colors.to_vec();
     }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.3 = muted;
+    }
 }
 
 #[derive(Clone)]
@@ -0,0 +1,213 @@
+use std::f64::consts::PI;
+use ringbuf::Rb;
+use raqote::{Color, DrawOptions, LineCap, LineJoin, Path, PathBuilder, SolidSource, Source, StrokeStyle};
+use crate::emulator::DspRegisters;
+use crate::visualizer::layout::Rect;
+use super::Visualizer;
+
+/// Gain of the echo FIR filter at `points` frequencies spread evenly from 0 Hz up to half
+/// the S-DSP sample rate. A gain of 1 leaves a frequency as it is.
+pub fn fir_response(coefficients: &[u8; 8], points: usize) -> Vec<f32> {
+    (0..points)
+        .map(|i| {
+            let omega = PI * i as f64 / (points.max(2) - 1) as f64;
+            let (re, im) = coefficients.iter().enumerate()
+                .fold((0.0, 0.0), |(re, im), (tap, &coefficient)| {
+                    let coefficient = (coefficient as i8) as f64 / 128.0;
+                    (re + coefficient * (omega * tap as f64).cos(), im - coefficient * (omega * tap as f64).sin())
+                });
+            (re * re + im * im).sqrt() as f32
+        })
+        .collect()
+}
+
+/// Lines describing the echo delay, feedback and volume
+pub fn echo_summary(registers: &DspRegisters) -> Vec<String> {
+    // Each step of EDL adds 2 KB, or 16 ms, of echo buffer
+    let delay_ms = (registers.echo_delay() & 0x0f) as u32 * 16;
+    let feedback = (registers.echo_feedback() as i8) as i32 * 100 / 128;
+    let (left, right) = registers.echo_volume();
+
+    let mut volume = format!("Volume {} {}", left as i8, right as i8);
+    // FLG bit 5 keeps the echo unit from writing to its buffer, so nothing new is fed back
+    if registers.flags() & 0x20 != 0 {
+        volume.push_str("  Writes off");
+    }
+
+    vec![
+        format!("Delay {} ms  Feedback {:+}%", delay_ms, feedback),
+        volume
+    ]
+}
+
+pub(super) fn line_style(width: f32) -> StrokeStyle {
+    StrokeStyle {
+        width,
+        cap: LineCap::Round,
+        join: LineJoin::Round,
+        miter_limit: 2.0,
+        dash_array: vec![],
+        dash_offset: 0.0,
+    }
+}
+
+pub(super) fn polyline(points: impl Iterator<Item = (f32, f32)>) -> Path {
+    let mut pb = PathBuilder::new();
+    for (i, (px, py)) in points.enumerate() {
+        if i == 0 {
+            pb.move_to(px, py);
+        } else {
+            pb.line_to(px, py);
+        }
+    }
+    pb.finish()
+}
+
+impl Visualizer {
+    pub fn draw_echo(&mut self, rect: Rect) {
+        let Rect { x, y, w, h } = rect;
+        let theme = self.theme.echo.clone();
+        let font_scale = self.layout.pixel_scale();
+        let line_h = self.font.line_height(font_scale);
+        let padding = line_h / 2.0;
+
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.oscilloscope.background),
+            &DrawOptions::new()
+        );
+
+        let mut text_y = y + padding;
+        for line in echo_summary(&self.registers) {
+            self.font.draw_text_scaled(&mut self.canvas, &line, x + padding, text_y, font_scale, 1.0);
+            text_y += line_h + padding / 2.0;
+        }
+
+        // A square per voice in its channel color, dimmed unless the voice goes through the echo
+        let label = "EON ";
+        self.font.draw_text_scaled(&mut self.canvas, label, x + padding, text_y, font_scale, 1.0);
+        let label_w = self.font.text_width(label, font_scale);
+        let echo_on = self.registers.echo_on();
+        for channel in 0..8 {
+            let color = self.settings.settings(channel).colors().first().cloned().unwrap_or(theme.waveform);
+            let color = match echo_on & (1 << channel) != 0 {
+                true => color,
+                false => self.theme.dimming.silent.apply(color)
+            };
+            self.canvas.fill_rect(
+                x + padding + label_w + channel as f32 * line_h * 1.5, text_y, line_h, line_h,
+                &Source::from(color),
+                &DrawOptions::new()
+            );
+        }
+        text_y += line_h + padding;
+
+        // The return waveform on the left, the FIR frequency response on the right
+        let graph_w = (w - 3.0 * padding) / 2.0;
+        let graph_h = y + h - padding - text_y;
+        if graph_w <= 0.0 || graph_h <= 0.0 {
+            return;
+        }
+        self.draw_echo_waveform(Rect::new(x + padding, text_y, graph_w, graph_h), &theme.waveform, &theme.guide);
+        self.draw_fir_response(Rect::new(x + 2.0 * padding + graph_w, text_y, graph_w, graph_h), &theme.response, &theme.guide);
+    }
+
+    fn draw_echo_waveform(&mut self, rect: Rect, color: &Color, guide: &Color) {
+        let Rect { x, y, w, h } = rect;
+        let scale = self.layout.scale();
+        let line_width = self.layout.line_width();
+
+        self.canvas.fill_rect(
+            x, y + (h - line_width) / 2.0, w, line_width,
+            &Source::from(*guide),
+            &DrawOptions::new()
+        );
+
+        let window_size = usize::min((w * 2.0 / scale) as usize, self.echo_samples.len());
+        if window_size < 2 {
+            return;
+        }
+        let samples: Vec<i16> = self.echo_samples.iter()
+            .skip(self.echo_samples.len() - window_size)
+            .cloned()
+            .collect();
+
+        let path = polyline(samples.iter().enumerate().map(|(i, &sample)| {
+            let px = x + i as f32 * w / (window_size - 1) as f32;
+            let py = y + (h / 2.0) * (1.0 - sample as f32 / 32768.0);
+            (px, py)
+        }));
+        self.canvas.stroke(
+            &path,
+            &Source::Solid(SolidSource::from(*color)),
+            &line_style(self.theme.oscilloscope.line_width * scale),
+            &DrawOptions::default()
+        );
+    }
+
+    fn draw_fir_response(&mut self, rect: Rect, color: &Color, guide: &Color) {
+        const MAX_GAIN: f32 = 2.0;
+
+        let Rect { x, y, w, h } = rect;
+        let scale = self.layout.scale();
+        let line_width = self.layout.line_width();
+
+        // Unity gain halfway up
+        self.canvas.fill_rect(
+            x, y + (h - line_width) / 2.0, w, line_width,
+            &Source::from(*guide),
+            &DrawOptions::new()
+        );
+
+        let points = ((w / scale) as usize).max(2);
+        let response = fir_response(&self.registers.fir_coefficients(), points);
+        let path = polyline(response.iter().enumerate().map(|(i, &gain)| {
+            let px = x + i as f32 * w / (points - 1) as f32;
+            let py = y + h * (1.0 - gain.min(MAX_GAIN) / MAX_GAIN);
+            (px, py)
+        }));
+        self.canvas.stroke(
+            &path,
+            &Source::Solid(SolidSource::from(*color)),
+            &line_style(self.theme.oscilloscope.line_width * scale),
+            &DrawOptions::default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fir_response_test() {
+        // A single tap of 0x7F passes everything at just under unity gain
+        let response = fir_response(&[0x7f, 0, 0, 0, 0, 0, 0, 0], 5);
+        assert!(response.iter().all(|&gain| (gain - 127.0 / 128.0).abs() < 1e-6), "{:?}", response);
+
+        // Averaging two neighbors is a low-pass filter
+        let response = fir_response(&[0x40, 0x40, 0, 0, 0, 0, 0, 0], 3);
+        assert!((response[0] - 1.0).abs() < 1e-6);
+        assert!((response[1] - 0.5f32.sqrt()).abs() < 1e-6);
+        assert!(response[2].abs() < 1e-6);
+
+        // Coefficients are signed
+        let response = fir_response(&[0x40, 0xc0, 0, 0, 0, 0, 0, 0], 2);
+        assert!(response[0].abs() < 1e-6);
+        assert!((response[1] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn echo_summary_test() {
+        let mut registers = DspRegisters::default();
+        registers.0[0x7d] = 0x05; // EDL
+        registers.0[0x0d] = 0xc0; // EFB
+        registers.0[0x2c] = 0x20; // EVOLL
+        registers.0[0x3c] = 0xe0; // EVOLR
+
+        assert_eq!(echo_summary(&registers), vec!["Delay 80 ms  Feedback -50%", "Volume 32 -32"]);
+
+        registers.0[0x6c] = 0x20; // FLG
+        assert_eq!(echo_summary(&registers)[1], "Volume 32 -32  Writes off");
+    }
+}
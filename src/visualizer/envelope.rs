@@ -0,0 +1,142 @@
+use ringbuf::Rb;
+use raqote::{DrawOptions, Source};
+use crate::emulator::{EnvelopePhase, EnvelopeState, SAMPLE_RATE};
+use crate::visualizer::layout::{EnvelopesPanel, Layout, Rect};
+use super::Visualizer;
+
+/// Output samples that make up each point of envelope history
+const SAMPLES_PER_POINT: usize = 128;
+pub const POINTS_PER_SECOND: f64 = SAMPLE_RATE as f64 / SAMPLES_PER_POINT as f64;
+const MAX_LEVEL: f32 = 0x7ff as f32;
+
+/// Points of envelope history needed for the longest envelope panel
+pub(super) fn history_points(layout: &Layout) -> usize {
+    ((layout.max_envelope_duration() * POINTS_PER_SECOND).ceil() as usize).max(1)
+}
+
+/// Gathers the envelopes received every output sample into points of history.
+#[derive(Default)]
+pub struct EnvelopeAccumulator {
+    point: [EnvelopeState; 8],
+    samples: usize
+}
+
+impl EnvelopeAccumulator {
+    /// Add one output sample. Every `SAMPLES_PER_POINT` samples this returns a point with
+    /// the latest level and phase of each voice, keyed on or off if it was at any time
+    /// during those samples.
+    pub fn push(&mut self, envelopes: &[EnvelopeState; 8]) -> Option<[EnvelopeState; 8]> {
+        for (point, envelope) in self.point.iter_mut().zip(envelopes.iter()) {
+            *point = EnvelopeState {
+                key_on: point.key_on || envelope.key_on,
+                key_off: point.key_off || envelope.key_off,
+                ..*envelope
+            };
+        }
+
+        self.samples += 1;
+        if self.samples < SAMPLES_PER_POINT {
+            return None;
+        }
+
+        let point = self.point;
+        *self = Self::default();
+        Some(point)
+    }
+}
+
+impl Visualizer {
+    pub fn draw_envelopes(&mut self, panel: &EnvelopesPanel) {
+        let Rect { x, y, w, h } = panel.rect;
+        if panel.channels.is_empty() {
+            return;
+        }
+        let theme = self.theme.envelope.clone();
+        let font_scale = self.layout.pixel_scale();
+        let line_width = self.layout.line_width();
+        let padding = self.font.line_height(font_scale) / 2.0;
+
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.oscilloscope.background),
+            &DrawOptions::new()
+        );
+
+        // The newest point is at the right edge, with nothing yet before the song started
+        let points = ((panel.duration * POINTS_PER_SECOND).round() as usize).max(1);
+        let mut history = vec![[EnvelopeState::default(); 8]; points.saturating_sub(self.envelope_history.len())];
+        history.extend(self.envelope_history.iter().skip(self.envelope_history.len().saturating_sub(points)).cloned());
+        let point_w = w / points as f32;
+
+        let lane_h = h / panel.channels.len() as f32;
+        for (lane, &channel) in panel.channels.iter().enumerate() {
+            // Lanes are a line apart
+            let lane_y = y + lane as f32 * lane_h + line_width;
+            let lane_h = lane_h - line_width;
+
+            for (i, envelopes) in history.iter().enumerate() {
+                let envelope = envelopes[channel];
+                let point_x = x + i as f32 * point_w;
+
+                let level_h = envelope.level as f32 / MAX_LEVEL * lane_h;
+                if level_h > 0.0 {
+                    let color = match envelope.phase {
+                        EnvelopePhase::Attack => theme.attack,
+                        EnvelopePhase::Decay => theme.decay,
+                        EnvelopePhase::Sustain => theme.sustain,
+                        EnvelopePhase::Release => theme.release,
+                        EnvelopePhase::Direct => theme.direct,
+                        EnvelopePhase::LinearIncrease | EnvelopePhase::BentIncrease => theme.increase,
+                        EnvelopePhase::LinearDecrease | EnvelopePhase::ExponentialDecrease => theme.decrease
+                    };
+                    self.canvas.fill_rect(
+                        point_x, lane_y + lane_h - level_h, point_w, level_h,
+                        &Source::from(color),
+                        &DrawOptions::new()
+                    );
+                }
+
+                let markers = [(envelope.key_on, theme.key_on), (envelope.key_off, theme.key_off)];
+                for (_, color) in markers.iter().filter(|(hit, _)| *hit) {
+                    self.canvas.fill_rect(
+                        point_x, lane_y, line_width, lane_h,
+                        &Source::from(*color),
+                        &DrawOptions::new()
+                    );
+                }
+            }
+
+            let name = self.settings.settings(channel).name();
+            self.font.draw_text_scaled(&mut self.canvas, &name, x + padding, lane_y + padding, font_scale, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_accumulator_test() {
+        let mut accumulator = EnvelopeAccumulator::default();
+        let mut envelopes = [EnvelopeState::default(); 8];
+
+        envelopes[2] = EnvelopeState { level: 0x100, phase: EnvelopePhase::Attack, key_on: true, key_off: false };
+        assert_eq!(accumulator.push(&envelopes), None);
+
+        // Key on is kept until the point is done, the rest follows the latest sample
+        envelopes[2] = EnvelopeState { level: 0x7ff, phase: EnvelopePhase::Decay, key_on: false, key_off: false };
+        for _ in 2..SAMPLES_PER_POINT {
+            assert_eq!(accumulator.push(&envelopes), None);
+        }
+        let point = accumulator.push(&envelopes).unwrap();
+        assert_eq!(point[2], EnvelopeState { level: 0x7ff, phase: EnvelopePhase::Decay, key_on: true, key_off: false });
+        assert_eq!(point[0], EnvelopeState::default());
+
+        // The next point starts over
+        for _ in 1..SAMPLES_PER_POINT {
+            assert_eq!(accumulator.push(&envelopes), None);
+        }
+        assert!(!accumulator.push(&envelopes).unwrap()[2].key_on);
+    }
+}
@@ -6,12 +6,17 @@ pub struct HighPassIIR {
 }
 
 impl HighPassIIR {
+    /// First order filter that is 3 dB down at `cutoff_frequency`. A cutoff of 0 lets
+    /// everything through, DC included
     pub fn new(sample_rate: f32, cutoff_frequency: f32) -> Self {
         let period = 1.0 / sample_rate;
-        let tc = 1.0 / cutoff_frequency;
+        let tc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_frequency);
 
         Self {
-            alpha: tc / (tc + period),
+            alpha: match cutoff_frequency > 0.0 {
+                true => tc / (tc + period),
+                false => 1.0
+            },
             delta: 0.0,
             prev_in: 0.0,
             prev_out: 0.0
@@ -28,3 +33,46 @@ impl HighPassIIR {
         self.alpha * self.prev_out + self.alpha * self.delta
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_1_SQRT_2, PI};
+    use super::*;
+
+    /// Peak output of a filter once it has settled on a sine wave of `frequency`
+    fn sine_gain(filter: &mut HighPassIIR, frequency: f32) -> f32 {
+        let samples = (0..64000).map(|i| (2.0 * PI * frequency * i as f32 / 32000.0).sin());
+        samples.enumerate().fold(0.0, |peak: f32, (i, sample)| {
+            filter.consume(sample);
+            match i < 32000 {
+                true => peak,
+                false => peak.max(filter.output().abs())
+            }
+        })
+    }
+
+    #[test]
+    fn high_pass_test() {
+        // A constant input settles back to 0
+        let mut filter = HighPassIIR::new(32000.0, 300.0);
+        for _ in 0..1000 {
+            filter.consume(1000.0);
+        }
+        assert!(filter.output().abs() < 1.0, "{}", filter.output());
+
+        // About 3 dB down at the cutoff, with less and less getting through below it
+        let gain = sine_gain(&mut HighPassIIR::new(32000.0, 300.0), 300.0);
+        assert!((gain - FRAC_1_SQRT_2).abs() < 0.02, "{}", gain);
+        let gain = sine_gain(&mut HighPassIIR::new(32000.0, 300.0), 30.0);
+        assert!(gain < 0.11, "{}", gain);
+        let gain = sine_gain(&mut HighPassIIR::new(32000.0, 300.0), 3000.0);
+        assert!(gain > 0.95, "{}", gain);
+
+        // Without a cutoff the input goes through unchanged
+        let mut filter = HighPassIIR::new(32000.0, 0.0);
+        filter.consume(500.0);
+        assert_eq!(filter.output(), 500.0);
+        filter.consume(1000.0);
+        assert_eq!(filter.output(), 1000.0);
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::fs;
+use encoding_rs::EUC_JP;
+use raqote::{AntialiasMode, BlendMode, Color, DrawOptions, DrawTarget, Image};
+use crate::visualizer::theme::FontTheme;
+use crate::visualizer::tile_map::TileMap;
+
+/// A glyph rendered at a given scale, as premultiplied ARGB.
+#[derive(Clone)]
+struct Glyph {
+    width: usize,
+    height: usize,
+    /// Offset of the bitmap from the pen position
+    left: i32,
+    /// Offset of the bitmap from the top of the line
+    top: i32,
+    advance: f32,
+    data: Vec<u32>
+}
+
+/// A glyph of a BDF font, one bool per pixel.
+struct BdfGlyph {
+    width: usize,
+    height: usize,
+    x_offset: i32,
+    /// Offset of the bottom of the bitmap from the baseline
+    y_offset: i32,
+    advance: i32,
+    bits: Vec<bool>
+}
+
+enum GlyphSource {
+    /// Drawn at its own size, scaled up with nearest neighbor scaling like the tile fonts
+    Bdf {
+        glyphs: HashMap<char, BdfGlyph>,
+        ascent: i32,
+        line_height: usize
+    },
+    /// Rasterized at `size` times the scale
+    Outline {
+        font: fontdue::Font,
+        size: f32
+    }
+}
+
+/// A font with glyphs for any Unicode character it covers, rendered on demand.
+pub struct GlyphFont {
+    source: GlyphSource,
+    color: Color,
+    glyph_cache: HashMap<(char, usize), Option<Glyph>>
+}
+
+fn premultiply(color: Color, coverage: u8) -> u32 {
+    let alpha = color.a() as u32 * coverage as u32 / 255;
+    let channel = |c: u8| c as u32 * alpha / 255;
+    (alpha << 24) | (channel(color.r()) << 16) | (channel(color.g()) << 8) | channel(color.b())
+}
+
+/// Map a BDF `ENCODING` to a character. Japanese BDF fonts are often encoded as JIS X 0208
+/// rows and cells rather than Unicode, which EUC-JP stores with the high bits set.
+fn bdf_char(encoding: u32, charset: &str) -> Option<char> {
+    if charset.starts_with("JISX0208") {
+        let euc = [(encoding >> 8) as u8 | 0x80, encoding as u8 | 0x80];
+        return EUC_JP.decode_without_bom_handling_and_without_replacement(&euc)?.chars().next();
+    }
+    char::from_u32(encoding)
+}
+
+fn parse_bdf(bdf: &str) -> Result<GlyphSource, String> {
+    let mut glyphs = HashMap::new();
+    let mut charset = "ISO10646".to_string();
+    let mut bounding_box: Option<(usize, i32)> = None;
+    let mut font_ascent: Option<i32> = None;
+    let mut font_descent: Option<i32> = None;
+
+    let mut encoding: Option<u32> = None;
+    let mut advance = 0;
+    let mut bbx = (0usize, 0usize, 0i32, 0i32);
+    let mut bitmap_rows: Option<Vec<Vec<bool>>> = None;
+
+    let number = |s: Option<&str>| -> Result<i32, String> {
+        s.ok_or("Missing BDF value".to_string())?
+            .parse::<i32>()
+            .map_err(|e| format!("Invalid BDF value: {}", e))
+    };
+
+    for line in bdf.lines() {
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+
+        if let Some(rows) = bitmap_rows.as_mut() {
+            if keyword != "ENDCHAR" {
+                let (w, ..) = bbx;
+                // Rows can be wider than any integer, so they're decoded a digit at a time
+                let mut row = keyword.chars()
+                    .flat_map(|c| (0..4).rev().map(move |b| c.to_digit(16).map(|d| d >> b & 1 != 0)))
+                    .collect::<Option<Vec<bool>>>()
+                    .ok_or(format!("Invalid BDF bitmap row {}", keyword))?;
+                row.resize(w, false);
+                rows.push(row);
+                continue;
+            }
+        }
+
+        match keyword {
+            "FONTBOUNDINGBOX" => {
+                let h = number(words.nth(1))?;
+                let y_offset = number(words.nth(1))?;
+                bounding_box = Some((h as usize, h + y_offset));
+            },
+            "CHARSET_REGISTRY" => charset = line[keyword.len()..].trim().trim_matches('"').to_ascii_uppercase(),
+            "FONT_ASCENT" => font_ascent = Some(number(words.next())?),
+            "FONT_DESCENT" => font_descent = Some(number(words.next())?),
+            "STARTCHAR" => {
+                encoding = None;
+                advance = 0;
+                bbx = (0, 0, 0, 0);
+            },
+            "ENCODING" => encoding = u32::try_from(number(words.next())?).ok(),
+            "DWIDTH" => advance = number(words.next())?,
+            "BBX" => {
+                bbx = (
+                    number(words.next())?.max(0) as usize,
+                    number(words.next())?.max(0) as usize,
+                    number(words.next())?,
+                    number(words.next())?
+                );
+            },
+            "BITMAP" => bitmap_rows = Some(Vec::new()),
+            "ENDCHAR" => {
+                let rows = bitmap_rows.take().unwrap_or_default();
+                if let Some(c) = encoding.and_then(|encoding| bdf_char(encoding, &charset)) {
+                    let (width, height, x_offset, y_offset) = bbx;
+                    let mut bits: Vec<bool> = rows.into_iter().take(height).flatten().collect();
+                    bits.resize(width * height, false);
+                    glyphs.insert(c, BdfGlyph { width, height, x_offset, y_offset, advance, bits });
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let (line_height, ascent) = match (font_ascent, font_descent, bounding_box) {
+        (Some(ascent), Some(descent), _) => ((ascent + descent).max(0) as usize, ascent),
+        (_, _, Some(bounding_box)) => bounding_box,
+        _ => return Err("BDF font has no FONTBOUNDINGBOX".to_string())
+    };
+    if glyphs.is_empty() {
+        return Err("BDF font has no glyphs".to_string());
+    }
+
+    Ok(GlyphSource::Bdf { glyphs, ascent, line_height })
+}
+
+impl GlyphFont {
+    pub fn bdf(bdf: &str, color: Color) -> Result<Self, String> {
+        Ok(Self {
+            source: parse_bdf(bdf)?,
+            color,
+            glyph_cache: HashMap::new()
+        })
+    }
+
+    pub fn outline(data: Vec<u8>, size: f32, color: Color) -> Result<Self, String> {
+        if size <= 0.0 {
+            return Err(format!("Invalid font size {}", size));
+        }
+        let font = fontdue::Font::from_bytes(data, fontdue::FontSettings::default())?;
+
+        Ok(Self {
+            source: GlyphSource::Outline { font, size },
+            color,
+            glyph_cache: HashMap::new()
+        })
+    }
+
+    fn line_height(&self, scale: usize) -> f32 {
+        match &self.source {
+            GlyphSource::Bdf { line_height, .. } => (line_height * scale) as f32,
+            GlyphSource::Outline { font, size } => match font.horizontal_line_metrics(size * scale as f32) {
+                Some(metrics) => (metrics.ascent - metrics.descent).ceil(),
+                None => (size * scale as f32).ceil()
+            }
+        }
+    }
+
+    fn render_glyph(&self, c: char, scale: usize) -> Option<Glyph> {
+        match &self.source {
+            GlyphSource::Bdf { glyphs, ascent, .. } => {
+                let glyph = glyphs.get(&c)?;
+                let data = (0..glyph.width * scale * glyph.height * scale)
+                    .map(|i| {
+                        let dx = (i % (glyph.width * scale)) / scale;
+                        let dy = (i / (glyph.width * scale)) / scale;
+                        premultiply(self.color, if glyph.bits[dy * glyph.width + dx] { 0xFF } else { 0x00 })
+                    })
+                    .collect();
+
+                Some(Glyph {
+                    width: glyph.width * scale,
+                    height: glyph.height * scale,
+                    left: glyph.x_offset * scale as i32,
+                    top: (ascent - glyph.y_offset - glyph.height as i32) * scale as i32,
+                    advance: (glyph.advance * scale as i32) as f32,
+                    data
+                })
+            },
+            GlyphSource::Outline { font, size } => {
+                if font.lookup_glyph_index(c) == 0 {
+                    return None;
+                }
+
+                let px = size * scale as f32;
+                let ascent = font.horizontal_line_metrics(px).map_or(px, |metrics| metrics.ascent).round() as i32;
+                let (metrics, coverage) = font.rasterize(c, px);
+
+                Some(Glyph {
+                    width: metrics.width,
+                    height: metrics.height,
+                    left: metrics.xmin,
+                    top: ascent - metrics.height as i32 - metrics.ymin,
+                    advance: metrics.advance_width,
+                    data: coverage.into_iter().map(|a| premultiply(self.color, a)).collect()
+                })
+            }
+        }
+    }
+
+    fn glyph(&mut self, c: char, scale: usize) -> Option<Glyph> {
+        if let Some(glyph) = self.glyph_cache.get(&(c, scale)) {
+            return glyph.clone();
+        }
+        let glyph = self.render_glyph(c, scale);
+        self.glyph_cache.insert((c, scale), glyph.clone());
+        glyph
+    }
+}
+
+/// The font the visualizer draws its text with. Characters a font doesn't have are left out.
+pub enum Font {
+    /// A PNG of equally sized tiles, like the built-in 8x8 font
+    Tiles(TileMap),
+    /// A BDF bitmap font or a TrueType/OpenType font, for text beyond ASCII
+    Glyphs(GlyphFont)
+}
+
+impl Font {
+    /// Open the font file of a theme, telling the kind of font apart by its extension.
+    pub fn open(theme: &FontTheme, default_char_map: &str) -> Result<Self, String> {
+        let path = theme.file.as_path();
+        let extension = path.extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "bdf" => {
+                let bdf = fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read font {}: {}", path.display(), e))?;
+                Ok(Font::Glyphs(GlyphFont::bdf(&bdf, theme.color)?))
+            },
+            "ttf" | "otf" | "ttc" => {
+                let data = fs::read(path)
+                    .map_err(|e| format!("Could not read font {}: {}", path.display(), e))?;
+                Ok(Font::Glyphs(GlyphFont::outline(data, theme.size, theme.color)?))
+            },
+            _ => {
+                let char_map = theme.char_map.clone().unwrap_or(default_char_map.to_string());
+                Ok(Font::Tiles(TileMap::open(path, theme.tile_w, theme.tile_h, &char_map)?))
+            }
+        }
+    }
+
+    /// Height of a line of text, without any spacing between lines
+    pub fn line_height(&self, scale: usize) -> f32 {
+        match self {
+            Font::Tiles(tile_map) => (tile_map.tile_h() * scale.max(1)) as f32,
+            Font::Glyphs(glyph_font) => glyph_font.line_height(scale.max(1))
+        }
+    }
+
+    pub fn char_width(&mut self, c: char, scale: usize) -> f32 {
+        match self {
+            Font::Tiles(tile_map) => (tile_map.tile_w() * scale.max(1)) as f32,
+            Font::Glyphs(glyph_font) => glyph_font.glyph(c, scale.max(1)).map_or(0.0, |glyph| glyph.advance)
+        }
+    }
+
+    pub fn text_width(&mut self, text: &str, scale: usize) -> f32 {
+        text.chars().map(|c| self.char_width(c, scale)).sum()
+    }
+
+    pub fn draw_text_scaled(&mut self, dt: &mut DrawTarget, text: &str, x: f32, y: f32, scale: usize, alpha: f32) {
+        let glyph_font = match self {
+            Font::Tiles(tile_map) => return tile_map.draw_text_scaled(dt, text, x, y, scale, alpha),
+            Font::Glyphs(glyph_font) => glyph_font
+        };
+
+        let mut pen_x = x;
+        for c in text.chars() {
+            let glyph = match glyph_font.glyph(c, scale.max(1)) {
+                Some(glyph) => glyph,
+                None => continue
+            };
+
+            if glyph.width > 0 && glyph.height > 0 {
+                let image = Image {
+                    width: glyph.width as _,
+                    height: glyph.height as _,
+                    data: &glyph.data
+                };
+                dt.draw_image_at(pen_x.round() + glyph.left as f32, y.round() + glyph.top as f32, &image, &DrawOptions {
+                    blend_mode: BlendMode::SrcOver,
+                    alpha,
+                    antialias: AntialiasMode::None,
+                });
+            }
+            pen_x += glyph.advance;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BDF: &str = "STARTFONT 2.1
+FONT -test-fixed-medium-r-normal--4-40-75-75-c-40-iso10646-1
+SIZE 4 75 75
+FONTBOUNDINGBOX 4 4 0 -1
+STARTPROPERTIES 2
+FONT_ASCENT 3
+FONT_DESCENT 1
+ENDPROPERTIES
+CHARS 2
+STARTCHAR A
+ENCODING 65
+DWIDTH 4 0
+BBX 3 3 0 0
+BITMAP
+40
+A0
+E0
+ENDCHAR
+STARTCHAR uni3042
+ENCODING 12354
+DWIDTH 5 0
+BBX 4 4 0 -1
+BITMAP
+F0
+90
+90
+F0
+ENDCHAR
+ENDFONT
+";
+
+    #[test]
+    fn bdf_test() {
+        let white = Color::new(0xFF, 0xFF, 0xFF, 0xFF);
+        let mut font = Font::Glyphs(GlyphFont::bdf(BDF, white).unwrap());
+
+        assert_eq!(font.line_height(2), 8.0);
+        assert_eq!(font.text_width("A\u{3042}", 1), 9.0);
+        // Characters the font doesn't have take up no space
+        assert_eq!(font.text_width("AB", 2), 8.0);
+
+        let glyph = match &mut font {
+            Font::Glyphs(glyph_font) => glyph_font.glyph('A', 1).unwrap(),
+            Font::Tiles(_) => unreachable!()
+        };
+        assert_eq!((glyph.width, glyph.height, glyph.top), (3, 3, 0));
+        let bits: Vec<bool> = glyph.data.iter().map(|&p| p != 0).collect();
+        assert_eq!(bits, vec![false, true, false, true, false, true, true, true, true]);
+    }
+
+    #[test]
+    fn bdf_wide_glyph_test() {
+        // 72 pixels is more than fits in a u64, with only the last pixel of each row set
+        let bdf = BDF.replace("BBX 3 3 0 0\nBITMAP\n40\nA0\nE0\n", "BBX 72 2 0 0\nBITMAP\n000000000000000001\n000000000000000001\n");
+        let mut font = Font::Glyphs(GlyphFont::bdf(&bdf, Color::new(0xFF, 0xFF, 0xFF, 0xFF)).unwrap());
+
+        let glyph = match &mut font {
+            Font::Glyphs(glyph_font) => glyph_font.glyph('A', 1).unwrap(),
+            Font::Tiles(_) => unreachable!()
+        };
+        assert_eq!((glyph.width, glyph.height), (72, 2));
+        let set: Vec<usize> = glyph.data.iter().enumerate().filter(|(_, &p)| p != 0).map(|(i, _)| i).collect();
+        assert_eq!(set, vec![71, 143]);
+    }
+
+    #[test]
+    fn bdf_jis_test() {
+        // 0x2422 is the JIS X 0208 code of U+3042
+        let bdf = BDF.replace("-iso10646-1", "-jisx0208.1983-0")
+            .replace("STARTPROPERTIES 2", "STARTPROPERTIES 3\nCHARSET_REGISTRY \"JISX0208.1983\"")
+            .replace("ENCODING 12354", "ENCODING 9250");
+        let mut font = Font::Glyphs(GlyphFont::bdf(&bdf, Color::new(0xFF, 0, 0, 0)).unwrap());
+
+        assert_eq!(font.char_width('\u{3042}', 1), 5.0);
+    }
+
+    #[test]
+    fn bdf_invalid_test() {
+        let white = Color::new(0xFF, 0xFF, 0xFF, 0xFF);
+        assert!(GlyphFont::bdf("STARTFONT 2.1\nENDFONT\n", white).is_err());
+        assert!(GlyphFont::bdf(&BDF.replace("A0\n", "ZZ\n"), white).is_err());
+        assert!(GlyphFont::outline(vec![0; 16], 8.0, white).is_err());
+    }
+}
@@ -0,0 +1,1017 @@
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+
+/// Canvas size the visualizer was originally designed for. Every other canvas size is
+/// laid out by scaling relative to this.
+pub const REFERENCE_RESOLUTION: (u32, u32) = (960, 540);
+
+/// Names of the built-in layouts, in the order they are offered to the user.
+pub const LAYOUT_PRESETS: [&str; 7] = ["default", "piano-roll", "scope-grid", "scope-stack", "side-by-side", "horizontal", "falling-notes"];
+
+const KEY_THICKNESS: f32 = 8.5;
+const KEY_HEIGHT: f32 = 72.0;
+/// Piano roll scroll speed in pixels per second at the reference resolution
+const SCROLL_SPEED: f32 = 240.0;
+/// Seconds of upcoming notes shown by the horizontal preset
+const HORIZONTAL_LOOK_AHEAD: f64 = 1.5;
+const OSCILLOSCOPE_HEIGHT: f32 = 48.0;
+const SPECTRUM_BANDS: usize = 32;
+const SPECTRUM_MIN_FREQUENCY: f32 = 40.0;
+/// Half the S-DSP sample rate, the highest frequency it can output
+const SPECTRUM_MAX_FREQUENCY: f32 = 16000.0;
+/// Spectrum heights fall by this fraction of the panel each second
+const SPECTRUM_FALLOFF: f32 = 1.5;
+const SPECTRUM_PEAK_HOLD: f64 = 1.0;
+/// Seconds of history shown by envelope lanes
+const ENVELOPE_DURATION: f64 = 4.0;
+/// Seconds an access stays lit on a memory map
+const MEMORY_FADE: f64 = 0.5;
+const NUM_CHANNELS: usize = 8;
+
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32
+}
+
+impl Rect {
+    pub fn new(x: f32, y: f32, w: f32, h: f32) -> Self {
+        Self { x, y, w, h }
+    }
+
+    fn scaled(&self, sx: f32, sy: f32) -> Self {
+        Self::new(self.x * sx, self.y * sy, self.w * sx, self.h * sy)
+    }
+}
+
+fn default_key_height() -> f32 {
+    KEY_HEIGHT
+}
+
+fn default_scroll_speed() -> f32 {
+    SCROLL_SPEED
+}
+
+fn default_overlay_lines() -> Vec<String> {
+    vec!["{title}".to_string(), "{game}".to_string(), "{artist}".to_string(), "{elapsed} / {total}".to_string()]
+}
+
+fn default_text_scale() -> f32 {
+    1.0
+}
+
+fn default_spectrum_bands() -> usize {
+    SPECTRUM_BANDS
+}
+
+fn default_min_frequency() -> f32 {
+    SPECTRUM_MIN_FREQUENCY
+}
+
+fn default_max_frequency() -> f32 {
+    SPECTRUM_MAX_FREQUENCY
+}
+
+fn default_falloff() -> f32 {
+    SPECTRUM_FALLOFF
+}
+
+fn default_peak_hold() -> f64 {
+    SPECTRUM_PEAK_HOLD
+}
+
+fn default_envelope_duration() -> f64 {
+    ENVELOPE_DURATION
+}
+
+fn default_memory_fade() -> f64 {
+    MEMORY_FADE
+}
+
+fn default_columns() -> usize {
+    NUM_CHANNELS
+}
+
+fn default_rows() -> usize {
+    1
+}
+
+/// Which way a piano roll's keyboard runs. The notes scroll away from the edge opposite
+/// the keyboard: downwards for a vertical roll and right to left for a horizontal one.
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    /// Keyboard along the bottom edge
+    #[default]
+    Vertical,
+    /// Keyboard along the left edge, with the lowest key at the bottom
+    Horizontal
+}
+
+/// Where each line of an overlay sits horizontally.
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Alignment {
+    #[default]
+    Left,
+    Center,
+    Right
+}
+
+/// Where an oscilloscope lines up its window, so that the waveform holds still.
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// The last time the voice restarted its BRR sample
+    #[default]
+    Edge,
+    /// The last rising zero crossing
+    ZeroCrossing,
+    /// Wherever the waveform lines up best with the one shown the frame before
+    Correlation
+}
+
+/// How a spectrum analyzer draws its frequency bands.
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectrumStyle {
+    #[default]
+    Bars,
+    /// A line through the top of each band
+    Line
+}
+
+/// A panel as written in a layout file. Coordinates are in the layout's own units,
+/// which are stretched to fit the canvas.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PanelConfig {
+    /// A piano roll with the keyboard along its bottom or left edge
+    PianoRoll {
+        #[serde(flatten)]
+        rect: Rect,
+        #[serde(default)]
+        orientation: Orientation,
+        /// Size of the keyboard from the piano roll to the far end of the keys, so
+        /// the keyboard's width for a horizontal roll
+        #[serde(default = "default_key_height")]
+        key_height: f32,
+        /// In layout units per second
+        #[serde(default = "default_scroll_speed")]
+        scroll_speed: f32,
+        /// Seconds of upcoming notes to show ahead of the current position
+        #[serde(default)]
+        look_ahead: f64,
+        /// Show only upcoming notes, which land on the keyboard as they sound. This sets
+        /// the look-ahead to however long notes take to cross the piano roll.
+        #[serde(default)]
+        falling_notes: bool,
+        /// Channels to show, all of them if left out
+        channels: Option<Vec<usize>>
+    },
+    /// A single channel's oscilloscope
+    Oscilloscope {
+        #[serde(flatten)]
+        rect: Rect,
+        channel: usize,
+        #[serde(default)]
+        trigger: Trigger,
+        /// Periods of the channel's pitch to show, two samples per pixel if left out
+        periods: Option<f64>,
+        /// Scale the waveform to follow the channel's loudness instead of a fixed scale
+        #[serde(default)]
+        auto_gain: bool
+    },
+    /// A grid of oscilloscopes, filled row by row in the order of `channels`
+    Oscilloscopes {
+        #[serde(flatten)]
+        rect: Rect,
+        #[serde(default = "default_columns")]
+        columns: usize,
+        #[serde(default = "default_rows")]
+        rows: usize,
+        channels: Option<Vec<usize>>,
+        #[serde(default)]
+        trigger: Trigger,
+        periods: Option<f64>,
+        #[serde(default)]
+        auto_gain: bool
+    },
+    /// Lines of text about the song, see `overlay::fill_line` for the placeholders
+    Overlay {
+        #[serde(flatten)]
+        rect: Rect,
+        #[serde(default = "default_overlay_lines")]
+        lines: Vec<String>,
+        #[serde(default)]
+        align: Alignment,
+        /// Seconds into the video the overlay appears
+        #[serde(default)]
+        show_from: f64,
+        /// Seconds the overlay stays up for, until the end if left out
+        show_for: Option<f64>,
+        /// Multiple of the font size at the reference resolution
+        #[serde(default = "default_text_scale")]
+        text_scale: f32
+    },
+    /// A tracker-style readout of the S-DSP registers, a line per voice plus the globals
+    Registers {
+        #[serde(flatten)]
+        rect: Rect,
+        /// Voices to list, all of them if left out
+        channels: Option<Vec<usize>>,
+        #[serde(default = "default_text_scale")]
+        text_scale: f32
+    },
+    /// The echo unit: its return waveform, FIR frequency response, delay, feedback and
+    /// which voices go through it
+    Echo {
+        #[serde(flatten)]
+        rect: Rect
+    },
+    /// A spectrum analyzer of the mixed output, with frequency bands spaced logarithmically
+    Spectrum {
+        #[serde(flatten)]
+        rect: Rect,
+        #[serde(default)]
+        style: SpectrumStyle,
+        #[serde(default = "default_spectrum_bands")]
+        bands: usize,
+        /// Lowest and highest frequency shown, in Hz
+        #[serde(default = "default_min_frequency")]
+        min_frequency: f32,
+        #[serde(default = "default_max_frequency")]
+        max_frequency: f32,
+        /// Fraction of the panel height that bands fall by per second
+        #[serde(default = "default_falloff")]
+        falloff: f32,
+        /// Seconds the peak of each band is held for before falling, no peaks if 0
+        #[serde(default = "default_peak_hold")]
+        peak_hold: f64,
+        /// Split each band into the channels making it up, in their colors
+        #[serde(default)]
+        stack_channels: bool
+    },
+    /// A goniometer of the mix, or of a single channel, with a phase correlation meter
+    Vectorscope {
+        #[serde(flatten)]
+        rect: Rect,
+        /// The mix if left out
+        channel: Option<usize>
+    },
+    /// A lane per channel, stacked top to bottom, plotting the envelope level over time
+    Envelopes {
+        #[serde(flatten)]
+        rect: Rect,
+        /// Seconds of history across the width of the panel
+        #[serde(default = "default_envelope_duration")]
+        duration: f64,
+        channels: Option<Vec<usize>>
+    },
+    /// A grid of the BRR samples the channels are playing, filled like `Oscilloscopes`
+    Samples {
+        #[serde(flatten)]
+        rect: Rect,
+        #[serde(default = "default_columns")]
+        columns: usize,
+        #[serde(default = "default_rows")]
+        rows: usize,
+        channels: Option<Vec<usize>>
+    },
+    /// A map of the 64 KiB of APU RAM, a page per row, lighting up bytes as they are read
+    /// and written and marking what the sound driver uses them for
+    Memory {
+        #[serde(flatten)]
+        rect: Rect,
+        /// Seconds an access stays lit for
+        #[serde(default = "default_memory_fade")]
+        fade: f64
+    }
+}
+
+/// A layout file, in JSON or TOML. Panels are drawn in order, so later panels end up on top.
+#[derive(Deserialize, Clone, Debug)]
+pub struct LayoutConfig {
+    /// Size of the coordinate space the panels are given in
+    #[serde(default = "LayoutConfig::default_width")]
+    pub width: f32,
+    #[serde(default = "LayoutConfig::default_height")]
+    pub height: f32,
+    pub panels: Vec<PanelConfig>
+}
+
+impl LayoutConfig {
+    fn default_width() -> f32 {
+        REFERENCE_RESOLUTION.0 as f32
+    }
+
+    fn default_height() -> f32 {
+        REFERENCE_RESOLUTION.1 as f32
+    }
+
+    pub fn from_json(j: &str) -> Result<Self, String> {
+        serde_json::from_str(j).map_err(|e| e.to_string())
+    }
+
+    pub fn from_toml(t: &str) -> Result<Self, String> {
+        toml::from_str(t).map_err(|e| e.to_string())
+    }
+
+    /// Built-in layouts are generated for the exact canvas size, so like the default
+    /// layout they keep their proportions at any aspect ratio.
+    pub fn preset(name: &str, width: u32, height: u32) -> Result<Self, String> {
+        let w = width as f32;
+        let h = height as f32;
+        let scale = layout_scale(width, height);
+        let key_height = (KEY_HEIGHT * scale).round();
+        let scope_h = (OSCILLOSCOPE_HEIGHT * scale).round();
+        let piano_roll = |rect: Rect| PanelConfig::PianoRoll {
+            rect,
+            orientation: Orientation::Vertical,
+            key_height,
+            scroll_speed: SCROLL_SPEED * scale,
+            look_ahead: 0.0,
+            falling_notes: false,
+            channels: None
+        };
+        let oscilloscopes = |rect: Rect, columns: usize, rows: usize| PanelConfig::Oscilloscopes {
+            rect,
+            columns,
+            rows,
+            channels: None,
+            trigger: Trigger::default(),
+            periods: None,
+            auto_gain: false
+        };
+
+        let panels = match name {
+            "default" => vec![
+                oscilloscopes(Rect::new(0.0, h - scope_h, w, scope_h), NUM_CHANNELS, 1),
+                piano_roll(Rect::new(0.0, 0.0, w, h - scope_h))
+            ],
+            "piano-roll" => vec![
+                piano_roll(Rect::new(0.0, 0.0, w, h))
+            ],
+            "scope-grid" => {
+                let (columns, rows) = if w >= h { (4, 2) } else { (2, 4) };
+                vec![
+                    oscilloscopes(Rect::new(0.0, 0.0, w, h), columns, rows)
+                ]
+            },
+            "scope-stack" => vec![
+                PanelConfig::Oscilloscopes {
+                    rect: Rect::new(0.0, 0.0, w, h),
+                    columns: 1,
+                    rows: NUM_CHANNELS,
+                    channels: None,
+                    trigger: Trigger::Correlation,
+                    periods: None,
+                    auto_gain: true
+                }
+            ],
+            "side-by-side" => {
+                let scopes_w = (w / 3.0).round();
+                vec![
+                    piano_roll(Rect::new(0.0, 0.0, w - scopes_w, h)),
+                    oscilloscopes(Rect::new(w - scopes_w, 0.0, scopes_w, h), 1, NUM_CHANNELS)
+                ]
+            },
+            "horizontal" => vec![
+                oscilloscopes(Rect::new(0.0, h - scope_h, w, scope_h), NUM_CHANNELS, 1),
+                PanelConfig::PianoRoll {
+                    rect: Rect::new(0.0, 0.0, w, h - scope_h),
+                    orientation: Orientation::Horizontal,
+                    key_height,
+                    scroll_speed: SCROLL_SPEED * scale,
+                    look_ahead: HORIZONTAL_LOOK_AHEAD,
+                    falling_notes: false,
+                    channels: None
+                }
+            ],
+            "falling-notes" => vec![
+                oscilloscopes(Rect::new(0.0, h - scope_h, w, scope_h), NUM_CHANNELS, 1),
+                PanelConfig::PianoRoll {
+                    rect: Rect::new(0.0, 0.0, w, h - scope_h),
+                    orientation: Orientation::Vertical,
+                    key_height,
+                    scroll_speed: SCROLL_SPEED * scale,
+                    look_ahead: 0.0,
+                    falling_notes: true,
+                    channels: None
+                }
+            ],
+            _ => return Err(format!("Unknown layout {}, try one of {}", name, LAYOUT_PRESETS.join(", ")))
+        };
+
+        Ok(Self { width: w, height: h, panels })
+    }
+}
+
+/// The piano roll and keyboard of a `PanelConfig::PianoRoll`, in canvas pixels.
+#[derive(Clone, Debug)]
+pub struct PianoRollPanel {
+    pub roll: Rect,
+    pub keys: Rect,
+    pub orientation: Orientation,
+    pub key_thickness: f32,
+    /// In canvas pixels per second
+    pub scroll_speed: f32,
+    /// In seconds
+    pub look_ahead: f64,
+    pub channels: Vec<usize>
+}
+
+impl PianoRollPanel {
+    /// Length of the piano roll in the direction it scrolls
+    pub fn scroll_length(&self) -> f32 {
+        match self.orientation {
+            Orientation::Vertical => self.roll.h,
+            Orientation::Horizontal => self.roll.w
+        }
+    }
+}
+
+/// A `PanelConfig::Overlay` placed on the canvas.
+#[derive(Clone, Debug)]
+pub struct OverlayPanel {
+    pub rect: Rect,
+    pub lines: Vec<String>,
+    pub align: Alignment,
+    pub show_from: f64,
+    pub show_for: Option<f64>,
+    /// Integer scale of the bitmap font
+    pub text_scale: usize
+}
+
+impl OverlayPanel {
+    /// Whether the overlay is up `elapsed` seconds into the video
+    pub fn is_shown(&self, elapsed: f64) -> bool {
+        elapsed >= self.show_from && self.show_for.map_or(true, |show_for| elapsed < self.show_from + show_for)
+    }
+}
+
+/// A `PanelConfig::Registers` placed on the canvas.
+#[derive(Clone, Debug)]
+pub struct RegistersPanel {
+    pub rect: Rect,
+    pub channels: Vec<usize>,
+    /// Integer scale of the bitmap font
+    pub text_scale: usize
+}
+
+/// A `PanelConfig::Spectrum` placed on the canvas.
+#[derive(Clone, Debug)]
+pub struct SpectrumPanel {
+    pub rect: Rect,
+    pub style: SpectrumStyle,
+    pub bands: usize,
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+    pub falloff: f32,
+    pub peak_hold: f64,
+    pub stack_channels: bool
+}
+
+/// A `PanelConfig::Envelopes` placed on the canvas.
+#[derive(Clone, Debug)]
+pub struct EnvelopesPanel {
+    pub rect: Rect,
+    pub duration: f64,
+    pub channels: Vec<usize>
+}
+
+/// A panel placed on the canvas.
+#[derive(Clone, Debug)]
+pub enum Panel {
+    PianoRoll(PianoRollPanel),
+    Oscilloscope { rect: Rect, channel: usize, trigger: Trigger, periods: Option<f64>, auto_gain: bool },
+    Overlay(OverlayPanel),
+    Registers(RegistersPanel),
+    Echo { rect: Rect },
+    Spectrum(SpectrumPanel),
+    Vectorscope { rect: Rect, channel: Option<usize> },
+    Envelopes(EnvelopesPanel),
+    /// A single channel's BRR sample
+    Sample { rect: Rect, channel: usize },
+    Memory { rect: Rect, fade: f64 }
+}
+
+/// Where each part of the visualizer is drawn on a canvas of a given size.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    resolution: (u32, u32),
+    scale: f32,
+    panels: Vec<Panel>
+}
+
+fn layout_scale(width: u32, height: u32) -> f32 {
+    f32::min(width as f32 / REFERENCE_RESOLUTION.0 as f32, height as f32 / REFERENCE_RESOLUTION.1 as f32)
+}
+
+/// The cells of a grid, row by row
+fn grid_cells(rect: Rect, columns: usize, rows: usize) -> impl Iterator<Item = Rect> {
+    let cell_w = rect.w / columns as f32;
+    let cell_h = rect.h / rows as f32;
+    (0..(columns * rows)).map(move |i| {
+        let column = i % columns;
+        let row = i / columns;
+        Rect::new(rect.x + cell_w * column as f32, rect.y + cell_h * row as f32, cell_w, cell_h)
+    })
+}
+
+fn check_channels(channels: Option<&Vec<usize>>) -> Result<Vec<usize>, String> {
+    match channels {
+        Some(channels) => match channels.iter().find(|&&c| c >= NUM_CHANNELS) {
+            Some(c) => Err(format!("Invalid channel {} in layout, channels are numbered 0-{}", c, NUM_CHANNELS - 1)),
+            None => Ok(channels.clone())
+        },
+        None => Ok((0..NUM_CHANNELS).collect())
+    }
+}
+
+fn check_periods(periods: Option<f64>) -> Result<(), String> {
+    match periods {
+        Some(periods) if periods <= 0.0 || !periods.is_finite() => Err(format!("Invalid oscilloscope periods {}", periods)),
+        _ => Ok(())
+    }
+}
+
+impl Layout {
+    /// The default layout: a piano roll over a keyboard, with the oscilloscopes in a row
+    /// along the bottom. Horizontal sizes stretch with the canvas width, while heights,
+    /// line widths and text are scaled uniformly so the canvas can have any aspect ratio.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::preset("default", width, height).unwrap()
+    }
+
+    pub fn preset(name: &str, width: u32, height: u32) -> Result<Self, String> {
+        Self::from_config(&LayoutConfig::preset(name, width, height)?, width, height)
+    }
+
+    /// Load a layout file, as TOML if it has a `.toml` extension and as JSON otherwise.
+    pub fn load<P: AsRef<Path>>(path: P, width: u32, height: u32) -> Result<Self, String> {
+        let contents = fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("Could not read layout {}: {}", path.as_ref().display(), e))?;
+        let is_toml = path.as_ref().extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+
+        let config = match is_toml {
+            true => LayoutConfig::from_toml(&contents)?,
+            false => LayoutConfig::from_json(&contents)?
+        };
+
+        Self::from_config(&config, width, height)
+    }
+
+    pub fn from_config(config: &LayoutConfig, width: u32, height: u32) -> Result<Self, String> {
+        if config.width <= 0.0 || config.height <= 0.0 {
+            return Err(format!("Invalid layout size {}x{}", config.width, config.height));
+        }
+
+        let sx = width as f32 / config.width;
+        let sy = height as f32 / config.height;
+        let scale = layout_scale(width, height);
+
+        let mut panels = Vec::new();
+        for panel in config.panels.iter() {
+            match panel {
+                PanelConfig::PianoRoll { rect, orientation, key_height, scroll_speed, look_ahead, falling_notes, channels } => {
+                    if *scroll_speed <= 0.0 || *look_ahead < 0.0 {
+                        return Err(format!("Invalid piano roll scroll speed {} or look-ahead {}", scroll_speed, look_ahead));
+                    }
+
+                    let rect = rect.scaled(sx, sy);
+                    let (roll, keys, key_thickness, scroll_speed) = match orientation {
+                        Orientation::Vertical => {
+                            let key_height = (key_height * sy).min(rect.h);
+                            (
+                                Rect::new(rect.x, rect.y, rect.w, rect.h - key_height),
+                                Rect::new(rect.x, rect.y + rect.h - key_height, rect.w, key_height),
+                                KEY_THICKNESS * rect.w / REFERENCE_RESOLUTION.0 as f32,
+                                scroll_speed * sy
+                            )
+                        },
+                        Orientation::Horizontal => {
+                            let key_width = (key_height * sx).min(rect.w);
+                            (
+                                Rect::new(rect.x + key_width, rect.y, rect.w - key_width, rect.h),
+                                Rect::new(rect.x, rect.y, key_width, rect.h),
+                                KEY_THICKNESS * rect.h / REFERENCE_RESOLUTION.0 as f32,
+                                scroll_speed * sx
+                            )
+                        }
+                    };
+                    let mut piano_roll = PianoRollPanel {
+                        roll,
+                        keys,
+                        orientation: *orientation,
+                        key_thickness,
+                        scroll_speed,
+                        look_ahead: *look_ahead,
+                        channels: check_channels(channels.as_ref())?
+                    };
+                    if *falling_notes {
+                        piano_roll.look_ahead = (piano_roll.scroll_length() / scroll_speed) as f64;
+                    }
+                    panels.push(Panel::PianoRoll(piano_roll));
+                },
+                PanelConfig::Oscilloscope { rect, channel, trigger, periods, auto_gain } => {
+                    let channel = check_channels(Some(&vec![*channel]))?[0];
+                    check_periods(*periods)?;
+                    panels.push(Panel::Oscilloscope {
+                        rect: rect.scaled(sx, sy),
+                        channel,
+                        trigger: *trigger,
+                        periods: *periods,
+                        auto_gain: *auto_gain
+                    });
+                },
+                PanelConfig::Oscilloscopes { rect, columns, rows, channels, trigger, periods, auto_gain } => {
+                    let channels = check_channels(channels.as_ref())?;
+                    if columns * rows < channels.len() {
+                        return Err(format!("A {}x{} oscilloscope grid can't fit {} channels", columns, rows, channels.len()));
+                    }
+                    check_periods(*periods)?;
+
+                    for (rect, channel) in grid_cells(rect.scaled(sx, sy), *columns, *rows).zip(channels) {
+                        panels.push(Panel::Oscilloscope { rect, channel, trigger: *trigger, periods: *periods, auto_gain: *auto_gain });
+                    }
+                },
+                PanelConfig::Overlay { rect, lines, align, show_from, show_for, text_scale } => {
+                    if *text_scale <= 0.0 {
+                        return Err(format!("Invalid overlay text scale {}", text_scale));
+                    }
+
+                    panels.push(Panel::Overlay(OverlayPanel {
+                        rect: rect.scaled(sx, sy),
+                        lines: lines.clone(),
+                        align: *align,
+                        show_from: *show_from,
+                        show_for: *show_for,
+                        text_scale: (text_scale * scale).floor().max(1.0) as usize
+                    }));
+                },
+                PanelConfig::Registers { rect, channels, text_scale } => {
+                    if *text_scale <= 0.0 {
+                        return Err(format!("Invalid register readout text scale {}", text_scale));
+                    }
+
+                    panels.push(Panel::Registers(RegistersPanel {
+                        rect: rect.scaled(sx, sy),
+                        channels: check_channels(channels.as_ref())?,
+                        text_scale: (text_scale * scale).floor().max(1.0) as usize
+                    }));
+                },
+                PanelConfig::Echo { rect } => {
+                    panels.push(Panel::Echo { rect: rect.scaled(sx, sy) });
+                },
+                PanelConfig::Spectrum { rect, style, bands, min_frequency, max_frequency, falloff, peak_hold, stack_channels } => {
+                    if *bands == 0 {
+                        return Err("Spectrum analyzers need at least one band".to_string());
+                    }
+                    if *min_frequency <= 0.0 || *min_frequency >= *max_frequency || *max_frequency > SPECTRUM_MAX_FREQUENCY {
+                        return Err(format!("Invalid spectrum frequency range {}-{} Hz, it has to be within 0-{} Hz",
+                                           min_frequency, max_frequency, SPECTRUM_MAX_FREQUENCY));
+                    }
+                    if *falloff < 0.0 || *peak_hold < 0.0 {
+                        return Err(format!("Invalid spectrum falloff {} or peak hold {}", falloff, peak_hold));
+                    }
+
+                    panels.push(Panel::Spectrum(SpectrumPanel {
+                        rect: rect.scaled(sx, sy),
+                        style: *style,
+                        bands: *bands,
+                        min_frequency: *min_frequency,
+                        max_frequency: *max_frequency,
+                        falloff: *falloff,
+                        peak_hold: *peak_hold,
+                        stack_channels: *stack_channels
+                    }));
+                },
+                PanelConfig::Vectorscope { rect, channel } => {
+                    let channel = match channel {
+                        Some(channel) => Some(check_channels(Some(&vec![*channel]))?[0]),
+                        None => None
+                    };
+                    panels.push(Panel::Vectorscope { rect: rect.scaled(sx, sy), channel });
+                },
+                PanelConfig::Envelopes { rect, duration, channels } => {
+                    if *duration <= 0.0 {
+                        return Err(format!("Invalid envelope duration {}", duration));
+                    }
+
+                    panels.push(Panel::Envelopes(EnvelopesPanel {
+                        rect: rect.scaled(sx, sy),
+                        duration: *duration,
+                        channels: check_channels(channels.as_ref())?
+                    }));
+                },
+                PanelConfig::Samples { rect, columns, rows, channels } => {
+                    let channels = check_channels(channels.as_ref())?;
+                    if columns * rows < channels.len() {
+                        return Err(format!("A {}x{} sample grid can't fit {} channels", columns, rows, channels.len()));
+                    }
+
+                    for (rect, channel) in grid_cells(rect.scaled(sx, sy), *columns, *rows).zip(channels) {
+                        panels.push(Panel::Sample { rect, channel });
+                    }
+                },
+                PanelConfig::Memory { rect, fade } => {
+                    if *fade <= 0.0 {
+                        return Err(format!("Invalid memory map fade {}", fade));
+                    }
+
+                    panels.push(Panel::Memory { rect: rect.scaled(sx, sy), fade: *fade });
+                }
+            }
+        }
+
+        Ok(Self {
+            resolution: (width, height),
+            scale,
+            panels
+        })
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        self.resolution
+    }
+
+    /// Size of the canvas relative to `REFERENCE_RESOLUTION`
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Width of a line that is one pixel wide at the reference resolution
+    pub fn line_width(&self) -> f32 {
+        self.scale.round().max(1.0)
+    }
+
+    /// Integer scale for pixel art like the bitmap font
+    pub fn pixel_scale(&self) -> usize {
+        self.scale.floor().max(1.0) as usize
+    }
+
+    pub fn panels(&self) -> &[Panel] {
+        &self.panels
+    }
+
+    pub fn piano_rolls(&self) -> impl Iterator<Item = &PianoRollPanel> {
+        self.panels.iter().filter_map(|panel| match panel {
+            Panel::PianoRoll(piano_roll) => Some(piano_roll),
+            _ => None
+        })
+    }
+
+    /// Longest history of any envelope panel in the layout, in seconds
+    pub fn max_envelope_duration(&self) -> f64 {
+        self.panels.iter()
+            .filter_map(|panel| match panel {
+                Panel::Envelopes(envelopes) => Some(envelopes.duration),
+                _ => None
+            })
+            .fold(0.0, f64::max)
+    }
+
+    /// Whether any panel needs to keep track of APU RAM accesses
+    pub fn has_memory_map(&self) -> bool {
+        self.panels.iter().any(|panel| matches!(panel, Panel::Memory { .. }))
+    }
+
+    /// Longest look-ahead of any piano roll in the layout, in seconds
+    pub fn max_look_ahead(&self) -> f64 {
+        self.piano_rolls()
+            .map(|piano_roll| piano_roll.look_ahead)
+            .fold(0.0, f64::max)
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new(REFERENCE_RESOLUTION.0, REFERENCE_RESOLUTION.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLUTIONS: [(u32, u32); 4] = [(960, 540), (1920, 1080), (1080, 1920), (640, 640)];
+
+    fn scope_channels(layout: &Layout) -> Vec<usize> {
+        layout.panels().iter()
+            .filter_map(|panel| match panel {
+                Panel::Oscilloscope { channel, .. } => Some(*channel),
+                _ => None
+            })
+            .collect()
+    }
+
+    #[test]
+    fn presets_test() {
+        for name in LAYOUT_PRESETS {
+            for (width, height) in RESOLUTIONS {
+                let layout = Layout::preset(name, width, height).unwrap();
+                assert_eq!(layout.resolution(), (width, height));
+                assert!(!layout.panels().is_empty(), "{} is empty", name);
+
+                // Every panel stays on the canvas
+                for panel in layout.panels() {
+                    let rects = match panel {
+                        Panel::PianoRoll(piano_roll) => vec![piano_roll.roll, piano_roll.keys],
+                        Panel::Oscilloscope { rect, .. } => vec![*rect],
+                        Panel::Overlay(overlay) => vec![overlay.rect],
+                        Panel::Registers(registers) => vec![registers.rect],
+                        Panel::Echo { rect } => vec![*rect],
+                        Panel::Spectrum(spectrum) => vec![spectrum.rect],
+                        Panel::Vectorscope { rect, .. } => vec![*rect],
+                        Panel::Envelopes(envelopes) => vec![envelopes.rect],
+                        Panel::Sample { rect, .. } => vec![*rect],
+                        Panel::Memory { rect, .. } => vec![*rect]
+                    };
+                    for rect in rects {
+                        assert!(rect.w > 0.0 && rect.h > 0.0, "{} at {}x{}: {:?}", name, width, height, rect);
+                        assert!(rect.x >= 0.0 && rect.x + rect.w <= width as f32 + 0.01, "{} at {}x{}: {:?}", name, width, height, rect);
+                        assert!(rect.y >= 0.0 && rect.y + rect.h <= height as f32 + 0.01, "{} at {}x{}: {:?}", name, width, height, rect);
+                    }
+                }
+
+                // Layouts with oscilloscopes show every channel once
+                let channels = scope_channels(&layout);
+                assert!(channels.is_empty() || channels == (0..NUM_CHANNELS).collect::<Vec<_>>(), "{}: {:?}", name, channels);
+            }
+        }
+
+        assert!(LayoutConfig::preset("nothing", 960, 540).is_err());
+    }
+
+    #[test]
+    fn check_channels_test() {
+        assert_eq!(check_channels(None), Ok((0..NUM_CHANNELS).collect()));
+        assert_eq!(check_channels(Some(&vec![7, 0, 3])), Ok(vec![7, 0, 3]));
+        assert_eq!(check_channels(Some(&vec![])), Ok(vec![]));
+        assert!(check_channels(Some(&vec![0, NUM_CHANNELS])).is_err());
+    }
+
+    #[test]
+    fn from_config_test() {
+        let config = LayoutConfig::from_json(r#"{
+            "width": 100,
+            "height": 100,
+            "panels": [
+                { "type": "oscilloscope", "x": 50, "y": 50, "w": 50, "h": 50, "channel": 3 },
+                { "type": "oscilloscopes", "x": 0, "y": 0, "w": 50, "h": 100, "columns": 1, "rows": 2, "channels": [5, 6],
+                  "trigger": "correlation", "periods": 2, "auto_gain": true }
+            ]
+        }"#).unwrap();
+        let layout = Layout::from_config(&config, 1000, 500).unwrap();
+
+        match layout.panels() {
+            [
+                Panel::Oscilloscope { rect: first_rect, channel: 3, trigger: Trigger::Edge, periods: None, auto_gain: false },
+                Panel::Oscilloscope { rect: second_rect, channel: 5, .. },
+                Panel::Oscilloscope { rect: third_rect, channel: 6, trigger: Trigger::Correlation, periods: Some(periods), auto_gain: true }
+            ] => {
+                assert_eq!(*first_rect, Rect::new(500.0, 250.0, 500.0, 250.0));
+                assert_eq!(*second_rect, Rect::new(0.0, 0.0, 500.0, 250.0));
+                assert_eq!(*third_rect, Rect::new(0.0, 250.0, 500.0, 250.0));
+                assert_eq!(*periods, 2.0);
+            },
+            panels => panic!("Unexpected panels {:?}", panels)
+        }
+    }
+
+    #[test]
+    fn from_config_errors_test() {
+        let invalid = [
+            r#"{ "panels": [{ "type": "oscilloscope", "x": 0, "y": 0, "w": 1, "h": 1, "channel": 8 }] }"#,
+            r#"{ "panels": [{ "type": "piano_roll", "x": 0, "y": 0, "w": 1, "h": 1, "channels": [0, 9] }] }"#,
+            r#"{ "panels": [{ "type": "oscilloscopes", "x": 0, "y": 0, "w": 1, "h": 1, "columns": 2, "rows": 2 }] }"#,
+            r#"{ "panels": [{ "type": "oscilloscope", "x": 0, "y": 0, "w": 1, "h": 1, "channel": 0, "periods": 0 }] }"#,
+            r#"{ "width": 0, "panels": [] }"#,
+            r#"{ "panels": [{ "type": "piano_roll", "x": 0, "y": 0, "w": 1, "h": 1, "scroll_speed": 0 }] }"#,
+            r#"{ "panels": [{ "type": "piano_roll", "x": 0, "y": 0, "w": 1, "h": 1, "look_ahead": -1 }] }"#,
+            r#"{ "panels": [{ "type": "overlay", "x": 0, "y": 0, "w": 1, "h": 1, "text_scale": 0 }] }"#,
+            r#"{ "panels": [{ "type": "registers", "x": 0, "y": 0, "w": 1, "h": 1, "channels": [8] }] }"#,
+            r#"{ "panels": [{ "type": "spectrum", "x": 0, "y": 0, "w": 1, "h": 1, "bands": 0 }] }"#,
+            r#"{ "panels": [{ "type": "spectrum", "x": 0, "y": 0, "w": 1, "h": 1, "min_frequency": 1000, "max_frequency": 500 }] }"#,
+            r#"{ "panels": [{ "type": "spectrum", "x": 0, "y": 0, "w": 1, "h": 1, "max_frequency": 22050 }] }"#,
+            r#"{ "panels": [{ "type": "spectrum", "x": 0, "y": 0, "w": 1, "h": 1, "falloff": -1 }] }"#,
+            r#"{ "panels": [{ "type": "vectorscope", "x": 0, "y": 0, "w": 1, "h": 1, "channel": 8 }] }"#,
+            r#"{ "panels": [{ "type": "envelopes", "x": 0, "y": 0, "w": 1, "h": 1, "duration": 0 }] }"#,
+            r#"{ "panels": [{ "type": "samples", "x": 0, "y": 0, "w": 1, "h": 1, "columns": 3, "rows": 2 }] }"#,
+            r#"{ "panels": [{ "type": "memory", "x": 0, "y": 0, "w": 1, "h": 1, "fade": 0 }] }"#
+        ];
+        for json in invalid {
+            let config = LayoutConfig::from_json(json).unwrap();
+            assert!(Layout::from_config(&config, 960, 540).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn from_toml_test() {
+        let config = LayoutConfig::from_toml(r#"
+            [[panels]]
+            type = "piano_roll"
+            x = 0
+            y = 0
+            w = 960
+            h = 540
+            channels = [1, 2]
+        "#).unwrap();
+        let layout = Layout::from_config(&config, 1920, 1080).unwrap();
+
+        match layout.panels() {
+            [Panel::PianoRoll(piano_roll)] => {
+                assert_eq!((piano_roll.roll.x, piano_roll.roll.y, piano_roll.roll.w), (0.0, 0.0, 1920.0));
+                assert_eq!(piano_roll.keys.y + piano_roll.keys.h, 1080.0);
+                assert_eq!(piano_roll.channels, vec![1, 2]);
+            },
+            panels => panic!("Unexpected panels {:?}", panels)
+        }
+    }
+
+    #[test]
+    fn horizontal_piano_roll_test() {
+        let config = LayoutConfig::from_json(r#"{
+            "panels": [
+                { "type": "piano_roll", "x": 0, "y": 0, "w": 960, "h": 540, "orientation": "horizontal",
+                  "key_height": 60, "scroll_speed": 480, "look_ahead": 2 }
+            ]
+        }"#).unwrap();
+        let layout = Layout::from_config(&config, 1920, 1080).unwrap();
+
+        match layout.panels() {
+            [Panel::PianoRoll(piano_roll)] => {
+                assert_eq!(piano_roll.orientation, Orientation::Horizontal);
+                assert_eq!(piano_roll.keys, Rect::new(0.0, 0.0, 120.0, 1080.0));
+                assert_eq!(piano_roll.roll, Rect::new(120.0, 0.0, 1800.0, 1080.0));
+                assert_eq!(piano_roll.scroll_length(), 1800.0);
+                assert_eq!(piano_roll.scroll_speed, 960.0);
+            },
+            panels => panic!("Unexpected panels {:?}", panels)
+        }
+        assert_eq!(layout.max_look_ahead(), 2.0);
+        assert_eq!(Layout::default().max_look_ahead(), 0.0);
+    }
+
+    #[test]
+    fn falling_notes_test() {
+        let config = LayoutConfig::from_json(r#"{
+            "panels": [
+                { "type": "piano_roll", "x": 0, "y": 0, "w": 960, "h": 540, "key_height": 60,
+                  "scroll_speed": 240, "look_ahead": 10, "falling_notes": true }
+            ]
+        }"#).unwrap();
+
+        // Notes take (540 - 60) / 240 seconds to reach the keyboard, whatever the canvas size
+        for (width, height) in [(960, 540), (1920, 1080)] {
+            let layout = Layout::from_config(&config, width, height).unwrap();
+            assert!((layout.max_look_ahead() - 2.0).abs() < 1e-6, "{}", layout.max_look_ahead());
+        }
+    }
+
+    #[test]
+    fn overlay_test() {
+        let config = LayoutConfig::from_toml(r#"
+            [[panels]]
+            type = "overlay"
+            x = 0
+            y = 0
+            w = 480
+            h = 100
+            lines = ["{title}", "{elapsed}"]
+            align = "right"
+            show_from = 1
+            show_for = 5
+            text_scale = 2
+        "#).unwrap();
+        let layout = Layout::from_config(&config, 1920, 1080).unwrap();
+
+        match layout.panels() {
+            [Panel::Overlay(overlay)] => {
+                assert_eq!(overlay.rect, Rect::new(0.0, 0.0, 960.0, 200.0));
+                assert_eq!(overlay.align, Alignment::Right);
+                assert_eq!(overlay.text_scale, 4);
+                assert!(!overlay.is_shown(0.5));
+                assert!(overlay.is_shown(1.0));
+                assert!(overlay.is_shown(5.9));
+                assert!(!overlay.is_shown(6.0));
+            },
+            panels => panic!("Unexpected panels {:?}", panels)
+        }
+
+        // Everything but the rectangle has a default, and overlays stay up by default
+        let config = LayoutConfig::from_json(r#"{ "panels": [{ "type": "overlay", "x": 0, "y": 0, "w": 1, "h": 1 }] }"#).unwrap();
+        match Layout::from_config(&config, 960, 540).unwrap().panels() {
+            [Panel::Overlay(overlay)] => {
+                assert_eq!(overlay.lines, default_overlay_lines());
+                assert!(overlay.is_shown(3600.0));
+            },
+            panels => panic!("Unexpected panels {:?}", panels)
+        }
+    }
+}
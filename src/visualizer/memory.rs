@@ -0,0 +1,172 @@
+use raqote::{Color, DrawOptions, ExtendMode, FilterMode, Image, Source, Transform};
+use crate::emulator::MemoryAccess;
+use crate::visualizer::layout::Rect;
+use super::Visualizer;
+
+/// Bytes per row of the memory map, so that every row is a page
+const ROW_BYTES: usize = 0x100;
+const RAM_SIZE: usize = 0x10000;
+
+/// What the sound driver uses each byte of APU RAM for, and how long ago it was accessed.
+pub struct MemoryMap {
+    /// Seconds since each byte was last read, and last written
+    read_ages: Vec<f32>,
+    write_ages: Vec<f32>,
+    /// Code, directory and sample flags of each byte, until it is written over
+    usage: Vec<u8>
+}
+
+impl MemoryMap {
+    const USAGE: u8 = MemoryAccess::EXECUTE | MemoryAccess::DIRECTORY | MemoryAccess::SAMPLE;
+
+    pub fn new() -> Self {
+        Self {
+            read_ages: vec![f32::INFINITY; RAM_SIZE],
+            write_ages: vec![f32::INFINITY; RAM_SIZE],
+            usage: vec![0; RAM_SIZE]
+        }
+    }
+
+    /// Move on to the next frame, `dt` seconds later, with the accesses made during it
+    pub fn update(&mut self, access: &MemoryAccess, dt: f32) {
+        for (address, &flags) in access.0.iter().enumerate() {
+            self.read_ages[address] = match flags & MemoryAccess::READ {
+                0 => self.read_ages[address] + dt,
+                _ => 0.0
+            };
+            self.write_ages[address] = match flags & MemoryAccess::WRITE {
+                0 => self.write_ages[address] + dt,
+                _ => 0.0
+            };
+
+            // Anything written over, like a new sample being uploaded, starts over
+            if flags & MemoryAccess::WRITE != 0 {
+                self.usage[address] = 0;
+            }
+            self.usage[address] |= flags & Self::USAGE;
+        }
+    }
+}
+
+/// How lit up an access `age` seconds ago is, from 1 right away to 0 after `fade` seconds
+fn brightness(age: f32, fade: f32) -> f32 {
+    (1.0 - age / fade).max(0.0)
+}
+
+/// Composite `color` over a premultiplied ARGB pixel, with its alpha scaled by `amount`
+fn over(pixel: [f32; 4], color: Color, amount: f32) -> [f32; 4] {
+    let alpha = color.a() as f32 / 255.0 * amount;
+    let source = [1.0, color.r() as f32 / 255.0, color.g() as f32 / 255.0, color.b() as f32 / 255.0];
+    [0, 1, 2, 3].map(|i| source[i] * alpha + pixel[i] * (1.0 - alpha))
+}
+
+/// A premultiplied ARGB pixel in the format of a raqote image
+fn pack(pixel: [f32; 4]) -> u32 {
+    pixel.iter().fold(0, |packed, channel| (packed << 8) | (channel.clamp(0.0, 1.0) * 255.0).round() as u32)
+}
+
+impl Visualizer {
+    pub fn draw_memory(&mut self, rect: Rect, fade: f64) {
+        let Rect { x, y, w, h } = rect;
+        let theme = self.theme.memory.clone();
+        let padding = self.font.line_height(self.layout.pixel_scale()) / 2.0;
+
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.oscilloscope.background),
+            &DrawOptions::new()
+        );
+
+        let memory_map = match &self.memory_map {
+            Some(memory_map) => memory_map,
+            None => return
+        };
+
+        // The largest square that fits, with a byte per cell
+        let size = f32::min(w, h) - 2.0 * padding;
+        if size <= 0.0 {
+            return;
+        }
+        let (grid_x, grid_y) = (x + (w - size) / 2.0, y + (h - size) / 2.0);
+
+        let echo_start = self.registers.echo_start() as u32 * 0x100;
+        let echo_length = match self.registers.flags() & 0x20 {
+            0 => self.registers.echo_length(),
+            _ => 0
+        };
+
+        let background = over([0.0; 4], self.theme.oscilloscope.background, 1.0);
+        let data: Vec<u32> = (0..RAM_SIZE)
+            .map(|address| {
+                let usage = memory_map.usage[address];
+                let regions = [
+                    ((address as u32).wrapping_sub(echo_start) & 0xffff < echo_length, theme.echo),
+                    (usage & MemoryAccess::SAMPLE != 0, theme.sample),
+                    (usage & MemoryAccess::DIRECTORY != 0, theme.directory),
+                    (usage & MemoryAccess::EXECUTE != 0, theme.code)
+                ];
+                let pixel = regions.iter()
+                    .filter(|(inside, _)| *inside)
+                    .fold(background, |pixel, (_, color)| over(pixel, *color, 1.0));
+
+                let pixel = over(pixel, theme.read, brightness(memory_map.read_ages[address], fade as f32));
+                pack(over(pixel, theme.write, brightness(memory_map.write_ages[address], fade as f32)))
+            })
+            .collect();
+
+        let image = Image {
+            width: ROW_BYTES as i32,
+            height: (RAM_SIZE / ROW_BYTES) as i32,
+            data: &data
+        };
+        let cell_size = size / ROW_BYTES as f32;
+        self.canvas.fill_rect(
+            grid_x, grid_y, size, size,
+            &Source::Image(image, ExtendMode::Pad, FilterMode::Nearest,
+                           Transform::translation(-grid_x, -grid_y).then_scale(1.0 / cell_size, 1.0 / cell_size)),
+            &DrawOptions::new()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_map_test() {
+        let mut memory_map = MemoryMap::new();
+        let mut access = MemoryAccess::default();
+        access.mark(0x0800, 2, MemoryAccess::READ | MemoryAccess::EXECUTE);
+        access.mark(0x2000, 9, MemoryAccess::READ | MemoryAccess::SAMPLE);
+        memory_map.update(&access, 0.1);
+
+        assert_eq!(memory_map.read_ages[0x0800], 0.0);
+        assert_eq!(memory_map.write_ages[0x0800], f32::INFINITY);
+        assert_eq!(memory_map.usage[0x0801], MemoryAccess::EXECUTE);
+        assert_eq!(memory_map.usage[0x2008], MemoryAccess::SAMPLE);
+
+        // Usage is kept until the byte is written over
+        access.clear();
+        access.mark(0x2000, 1, MemoryAccess::WRITE);
+        memory_map.update(&access, 0.1);
+        assert!((memory_map.read_ages[0x0800] - 0.1).abs() < 1e-6);
+        assert_eq!(memory_map.usage[0x0800], MemoryAccess::EXECUTE);
+        assert_eq!(memory_map.usage[0x2000], 0);
+        assert_eq!(memory_map.usage[0x2001], MemoryAccess::SAMPLE);
+        assert_eq!(memory_map.write_ages[0x2000], 0.0);
+    }
+
+    #[test]
+    fn pixel_test() {
+        assert_eq!(brightness(0.0, 0.5), 1.0);
+        assert_eq!(brightness(0.25, 0.5), 0.5);
+        assert_eq!(brightness(f32::INFINITY, 0.5), 0.0);
+
+        let red = Color::new(0xff, 0xff, 0, 0);
+        let half_blue = Color::new(0x80, 0, 0, 0xff);
+        assert_eq!(pack(over([0.0; 4], red, 1.0)), 0xffff0000);
+        assert_eq!(pack(over([0.0; 4], red, 0.0)), 0);
+        assert_eq!(pack(over(over([0.0; 4], red, 1.0), half_blue, 1.0)), 0xff7f0080);
+    }
+}
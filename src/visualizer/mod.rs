@@ -1,14 +1,37 @@
 mod oscilloscope;
 pub mod channel_settings;
 mod tile_map;
+pub mod font;
 mod filters;
 mod piano_roll;
+mod echo;
+mod spectrum;
+mod vectorscope;
+mod envelope;
+mod sample;
+mod memory;
+pub mod overlay;
+pub mod registers;
+pub mod layout;
+pub mod theme;
 
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
 use ringbuf::{HeapRb, Rb, StaticRb};
 use raqote::{DrawTarget, SolidSource};
-use crate::emulator::ApuStateReceiver;
+use rustfft::{Fft, FftPlanner};
+use crate::emulator::{ApuStateReceiver, DspRegisters, EnvelopeState, MemoryAccess, SpcMetadata, VoiceSample, SAMPLE_RATE};
 use crate::visualizer::channel_settings::ChannelSettingsManager;
 use crate::visualizer::filters::HighPassIIR;
+use crate::visualizer::layout::{Layout, Panel};
+use crate::visualizer::overlay::OverlayText;
+use crate::visualizer::spectrum::{SpectrumState, FFT_SIZE};
+use crate::visualizer::envelope::EnvelopeAccumulator;
+use crate::visualizer::oscilloscope::OscilloscopeState;
+use crate::visualizer::memory::MemoryMap;
+use crate::visualizer::theme::Theme;
+use crate::visualizer::font::Font;
 use crate::visualizer::tile_map::TileMap;
 
 #[derive(Copy, Clone, Default)]
@@ -23,13 +46,61 @@ pub struct ChannelState {
     pub kon_frames: usize
 }
 
+/// One S-DSP output sample as (left, right) pairs: the mix, and what each voice adds to it.
+#[derive(Copy, Clone, Default)]
+pub struct MixSample {
+    pub mix: (i16, i16),
+    pub voices: [(i16, i16); 8]
+}
+
+/// The APU states of one frame that is still ahead of the frame being shown.
+#[derive(Default)]
+struct UpcomingFrame {
+    states: Vec<ChannelState>,
+    registers: Option<DspRegisters>,
+    echo_samples: Vec<i16>,
+    mix_samples: Vec<MixSample>,
+    envelope_points: Vec<[EnvelopeState; 8]>,
+    voice_samples: Option<[Option<VoiceSample>; 8]>,
+    memory_access: Option<MemoryAccess>,
+    /// Piano roll rows added for this frame
+    slice_rows: usize
+}
+
 pub struct Visualizer {
     canvas: DrawTarget,
+    layout: Layout,
+    theme: Theme,
     settings: ChannelSettingsManager,
     channel_states: Vec<HeapRb<ChannelState>>,
     channel_filters: Vec<HighPassIIR>,
+    /// Latest state of each channel, which is ahead of `channel_states` by the look-ahead
+    latest_states: Vec<Option<ChannelState>>,
+    /// Frames received but not shown yet, oldest first. The last one is being received.
+    upcoming_frames: VecDeque<UpcomingFrame>,
     state_slices: HeapRb<ChannelState>,
-    font: TileMap
+    /// S-DSP registers as of the frame being shown
+    registers: DspRegisters,
+    /// Echo return samples up to the frame being shown
+    echo_samples: HeapRb<i16>,
+    /// Output samples up to the frame being shown, enough for one spectrum
+    mix_samples: HeapRb<MixSample>,
+    /// State of each spectrum analyzer, by panel index
+    spectra: HashMap<usize, SpectrumState>,
+    /// State of each oscilloscope, by panel index
+    oscilloscopes: HashMap<usize, OscilloscopeState>,
+    fft: Arc<dyn Fft<f32>>,
+    envelope_accumulator: EnvelopeAccumulator,
+    /// Points of envelope history up to the frame being shown
+    envelope_history: HeapRb<[EnvelopeState; 8]>,
+    /// BRR sample of each voice as of the frame being shown
+    voice_samples: [Option<VoiceSample>; 8],
+    /// APU RAM as of the frame being shown, only kept for layouts with a memory map
+    memory_map: Option<MemoryMap>,
+    font: Font,
+    overlay_text: OverlayText,
+    frame_rate: f64,
+    pending_slice_rows: f64
 }
 
 const APU_STATE_BUF_SIZE: usize = 8192;
@@ -37,22 +108,95 @@ const FONT_IMAGE: &'static [u8] = include_bytes!("8x8_font.png");
 const FONT_CHAR_MAP: &'static str = " !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~";
 
 impl Visualizer {
-    pub fn new() -> Self {
+    pub fn new(layout: Layout) -> Self {
         let mut channel_states: Vec<HeapRb<ChannelState>> = Vec::new();
-        let mut channel_filters: Vec<HighPassIIR> = Vec::new();
         for _ in 0..8 {
             channel_states.push(HeapRb::new(APU_STATE_BUF_SIZE));
-            channel_filters.push(HighPassIIR::new(44100.0, 300.0));
         }
+        let theme = Theme::default();
+        let channel_filters = Self::channel_filters(theme.oscilloscope.dc_cutoff);
+
+        // Long or slow piano rolls show more history than the default buffer holds
+        let slice_buf_size = usize::max(APU_STATE_BUF_SIZE, piano_roll::slice_buffer_rows(&layout) * 8);
+        let envelope_buf_size = envelope::history_points(&layout);
+        let memory_map = layout.has_memory_map().then(MemoryMap::new);
 
         Self {
-            canvas: DrawTarget::new(960, 540),
+            canvas: DrawTarget::new(layout.resolution().0 as i32, layout.resolution().1 as i32),
+            layout,
+            theme,
             settings: ChannelSettingsManager::default(),
             channel_states,
             channel_filters,
-            state_slices: HeapRb::new(APU_STATE_BUF_SIZE),
-            font: TileMap::new(FONT_IMAGE, 8, 8, FONT_CHAR_MAP).unwrap()
+            latest_states: vec![None; 8],
+            upcoming_frames: VecDeque::from([UpcomingFrame::default()]),
+            state_slices: HeapRb::new(slice_buf_size),
+            registers: DspRegisters::default(),
+            echo_samples: HeapRb::new(APU_STATE_BUF_SIZE),
+            mix_samples: HeapRb::new(FFT_SIZE),
+            spectra: HashMap::new(),
+            oscilloscopes: HashMap::new(),
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            envelope_accumulator: EnvelopeAccumulator::default(),
+            envelope_history: HeapRb::new(envelope_buf_size),
+            voice_samples: Default::default(),
+            memory_map,
+            font: Font::Tiles(TileMap::new(FONT_IMAGE, 8, 8, FONT_CHAR_MAP).unwrap()),
+            overlay_text: OverlayText::default(),
+            frame_rate: 60.0,
+            pending_slice_rows: 0.0
+        }
+    }
+
+    /// Set the rate `draw_piano_roll` is called at, so the piano roll scrolls at the
+    /// same speed regardless of the video frame rate.
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.frame_rate = frame_rate;
+    }
+
+    /// How many frames the emulator has to run ahead of the video for the piano rolls
+    /// to show their upcoming notes.
+    pub fn look_ahead_frames(&self) -> usize {
+        (self.layout.max_look_ahead() * self.frame_rate).ceil() as usize
+    }
+
+    pub fn set_song_metadata(&mut self, metadata: &SpcMetadata) {
+        self.overlay_text.set_metadata(metadata);
+    }
+
+    /// Set the position shown by overlays for the next frame. `total` is the expected
+    /// length of the video, if known yet.
+    pub fn set_playback_position(&mut self, elapsed: Duration, total: Option<Duration>, loop_count: u64) {
+        self.overlay_text.elapsed = elapsed;
+        self.overlay_text.total = total;
+        self.overlay_text.loop_count = loop_count;
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Switch to a different theme. Its channel colors, if any, replace the current ones.
+    pub fn set_theme(&mut self, theme: Theme) -> Result<(), String> {
+        let dc_cutoff = theme.oscilloscope.dc_cutoff;
+        if !(0.0..SAMPLE_RATE as f32 / 2.0).contains(&dc_cutoff) {
+            return Err(format!("Invalid oscilloscope DC cutoff {} Hz", dc_cutoff));
+        }
+        if let Some(font) = &theme.font {
+            self.font = Font::open(font, FONT_CHAR_MAP)?;
+        }
+        for (channel, color) in theme.channel_colors.iter().take(8).enumerate() {
+            self.settings.settings_mut(channel).set_colors(&[color.clone()]);
         }
+        self.channel_filters = Self::channel_filters(theme.oscilloscope.dc_cutoff);
+        self.theme = theme;
+
+        Ok(())
+    }
+
+    /// DC blockers for the oscilloscope waveforms, one per channel
+    fn channel_filters(dc_cutoff: f32) -> Vec<HighPassIIR> {
+        (0..8).map(|_| HighPassIIR::new(SAMPLE_RATE as f32, dc_cutoff)).collect()
     }
 
     /// Get canvas buffer as BGRA data (little endian) or ARGB data (big endian)
@@ -61,7 +205,68 @@ impl Visualizer {
     }
 
     pub fn clear(&mut self) {
-        self.canvas.clear(SolidSource::from_unpremultiplied_argb(0, 0, 0, 0));
+        self.canvas.clear(SolidSource::from(self.theme.background));
+    }
+
+    /// Finish receiving a frame. Its piano roll rows are added right away, but everything
+    /// else only shows it once it is `look_ahead_frames()` old. Called once per frame by
+    /// `draw()`, and on its own while the emulator runs ahead before the first frame.
+    pub fn end_frame(&mut self) {
+        let slice_rows = self.push_state_slices();
+        self.upcoming_frames.back_mut().unwrap().slice_rows = slice_rows;
+
+        while self.upcoming_frames.len() > self.look_ahead_frames() {
+            let frame = self.upcoming_frames.pop_front().unwrap();
+            for state in frame.states {
+                self.channel_states.get_mut(state.channel).unwrap().push_overwrite(state);
+            }
+            if let Some(registers) = frame.registers {
+                self.registers = registers;
+            }
+            for sample in frame.echo_samples {
+                self.echo_samples.push_overwrite(sample);
+            }
+            for sample in frame.mix_samples {
+                self.mix_samples.push_overwrite(sample);
+            }
+            for point in frame.envelope_points {
+                self.envelope_history.push_overwrite(point);
+            }
+            if let Some(voice_samples) = frame.voice_samples {
+                self.voice_samples = voice_samples;
+            }
+            if let (Some(memory_map), Some(access)) = (self.memory_map.as_mut(), frame.memory_access) {
+                memory_map.update(&access, (1.0 / self.frame_rate) as f32);
+            }
+        }
+        self.upcoming_frames.push_back(UpcomingFrame::default());
+    }
+
+    /// Piano roll rows added since the frame being shown
+    fn upcoming_slice_rows(&self) -> usize {
+        self.upcoming_frames.iter().map(|frame| frame.slice_rows).sum()
+    }
+
+    /// Draw every panel of the layout for the current frame
+    pub fn draw(&mut self) {
+        self.end_frame();
+
+        for (index, panel) in self.layout.panels().to_vec().into_iter().enumerate() {
+            match panel {
+                Panel::PianoRoll(piano_roll) => self.draw_piano_roll(&piano_roll),
+                Panel::Oscilloscope { rect, channel, trigger, periods, auto_gain } => {
+                    self.draw_oscilloscope_view(index, channel, trigger, periods, auto_gain, rect)
+                },
+                Panel::Overlay(overlay) => self.draw_overlay(&overlay),
+                Panel::Registers(registers) => self.draw_registers(&registers),
+                Panel::Echo { rect } => self.draw_echo(rect),
+                Panel::Spectrum(spectrum) => self.draw_spectrum(index, &spectrum),
+                Panel::Vectorscope { rect, channel } => self.draw_vectorscope(rect, channel),
+                Panel::Envelopes(envelopes) => self.draw_envelopes(&envelopes),
+                Panel::Sample { rect, channel } => self.draw_sample(rect, channel),
+                Panel::Memory { rect, fade } => self.draw_memory(rect, fade)
+            }
+        }
     }
 
     pub fn settings_manager(&self) -> ChannelSettingsManager {
@@ -77,7 +282,6 @@ impl ApuStateReceiver for Visualizer {
     fn receive(&mut self, channel: usize, volume: u8, amplitude: i16, frequency: f64, timbre: usize, balance: f64, edge: bool, kon_frames: usize) {
         const C_0: f64 = 16.351597831287;
 
-        let buf = self.channel_states.get_mut(channel).unwrap();
         let filter = self.channel_filters.get_mut(channel).unwrap();
 
         filter.consume(amplitude as f32);
@@ -95,6 +299,35 @@ impl ApuStateReceiver for Visualizer {
             kon_frames
         };
 
-        buf.push_overwrite(state);
+        self.latest_states[channel] = Some(state);
+        self.upcoming_frames.back_mut().unwrap().states.push(state);
+    }
+
+    fn receive_registers(&mut self, registers: &DspRegisters) {
+        self.upcoming_frames.back_mut().unwrap().registers = Some(*registers);
+    }
+
+    fn receive_echo(&mut self, sample: i16) {
+        self.upcoming_frames.back_mut().unwrap().echo_samples.push(sample);
+    }
+
+    fn receive_mix(&mut self, left: i16, right: i16, voices: &[(i16, i16); 8]) {
+        self.upcoming_frames.back_mut().unwrap().mix_samples.push(MixSample { mix: (left, right), voices: *voices });
+    }
+
+    fn receive_samples(&mut self, samples: &[Option<VoiceSample>; 8]) {
+        self.upcoming_frames.back_mut().unwrap().voice_samples = Some(samples.clone());
+    }
+
+    fn receive_memory(&mut self, access: &MemoryAccess) {
+        if self.memory_map.is_some() {
+            self.upcoming_frames.back_mut().unwrap().memory_access = Some(access.clone());
+        }
+    }
+
+    fn receive_envelopes(&mut self, envelopes: &[EnvelopeState; 8]) {
+        if let Some(point) = self.envelope_accumulator.push(envelopes) {
+            self.upcoming_frames.back_mut().unwrap().envelope_points.push(point);
+        }
     }
 }
\ No newline at end of file
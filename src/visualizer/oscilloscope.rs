@@ -1,12 +1,95 @@
+use std::ops::RangeInclusive;
 use ringbuf::Rb;
 use ringbuf::ring_buffer::RbBase;
 use raqote::{AntialiasMode, BlendMode, Color, DrawOptions, DrawTarget, Gradient, GradientStop, LineCap, LineJoin, PathBuilder, Point, SolidSource, Source, Spread, StrokeStyle, Transform};
-use super::{Visualizer, ChannelState};
+use crate::emulator::SAMPLE_RATE;
+use crate::visualizer::layout::{Rect, Trigger};
+use super::{Visualizer, ChannelState, APU_STATE_BUF_SIZE};
 
-const DIVIDER_WIDTH: u32 = 5;
+/// Fewest and most samples an oscilloscope sized in periods shows. The most leaves room in
+/// the channel's buffer to search for a trigger before the window.
+const MIN_WINDOW_SIZE: usize = 32;
+const MAX_WINDOW_SIZE: usize = APU_STATE_BUF_SIZE / 4;
+/// Auto-gain scales the peak of a channel to this, out of the 6000 at the edge of a scope
+const AUTO_GAIN_TARGET: f32 = 5400.0;
+/// Most a quiet channel gets scaled up by auto-gain
+const MAX_AUTO_GAIN: f32 = 16.0;
+/// Seconds for the auto-gain peak to fall by half once a channel gets quieter
+const AUTO_GAIN_HALF_LIFE: f32 = 0.5;
+/// Fraction of the recent peak that a waveform has to dip below before its next rising
+/// zero crossing counts, so that noise around zero doesn't retrigger
+const ZERO_CROSSING_HYSTERESIS: f32 = 0.1;
+
+/// What an oscilloscope carries over from one frame to the next.
+#[derive(Default)]
+pub struct OscilloscopeState {
+    /// Window shown last frame, kept for correlation triggering
+    window: Vec<f32>,
+    /// Peak amplitude followed by auto-gain
+    peak: f32
+}
+
+/// Follow the peak amplitude of a channel, `dt` seconds after `peak`. It jumps up to
+/// louder peaks right away, and falls back by half every `AUTO_GAIN_HALF_LIFE` seconds.
+pub fn track_peak(peak: f32, window_peak: f32, dt: f32) -> f32 {
+    f32::max(window_peak, peak * 0.5f32.powf(dt / AUTO_GAIN_HALF_LIFE))
+}
+
+/// Gain that brings a waveform peaking at `peak` to `AUTO_GAIN_TARGET`
+pub fn normalizing_gain(peak: f32) -> f32 {
+    match peak > 0.0 {
+        true => (AUTO_GAIN_TARGET / peak).min(MAX_AUTO_GAIN),
+        false => MAX_AUTO_GAIN
+    }
+}
+
+/// Index of the last rising zero crossing in `amplitudes`, after the signal has been below
+/// `-hysteresis`
+pub fn last_rising_zero_crossing(amplitudes: &[f32], hysteresis: f32) -> Option<usize> {
+    let mut armed = false;
+    let mut crossing = None;
+    for (i, &amplitude) in amplitudes.iter().enumerate() {
+        if amplitude < -hysteresis {
+            armed = true;
+        } else if armed && amplitude >= 0.0 {
+            crossing = Some(i);
+            armed = false;
+        }
+    }
+    crossing
+}
+
+/// Start of the window within `starts` where `amplitudes` lines up best with `previous`,
+/// by cross-correlation. Ties go to the latest start.
+pub fn best_alignment(amplitudes: &[f32], previous: &[f32], starts: RangeInclusive<usize>) -> usize {
+    let mut best = (*starts.end(), f32::NEG_INFINITY);
+    for start in starts.filter(|start| start + previous.len() <= amplitudes.len()) {
+        let correlation: f32 = amplitudes[start..].iter().zip(previous.iter()).map(|(a, b)| a * b).sum();
+        if correlation >= best.1 {
+            best = (start, correlation);
+        }
+    }
+    best.0
+}
+
+/// Start of a window centered on the last rising zero crossing it fits around, or of the
+/// latest window if there is none nearby
+fn zero_crossing_start(amplitudes: &[f32], window_size: usize) -> usize {
+    let latest_start = amplitudes.len() - window_size;
+    let search_start = latest_start.saturating_sub(window_size);
+    let searchable = &amplitudes[search_start..(amplitudes.len() - window_size / 2)];
+
+    let peak = searchable.iter().fold(0.0, |peak: f32, amplitude| peak.max(amplitude.abs()));
+    match last_rising_zero_crossing(searchable, peak * ZERO_CROSSING_HYSTERESIS) {
+        Some(crossing) if search_start + crossing >= window_size / 2 => search_start + crossing - window_size / 2,
+        _ => latest_start
+    }
+}
 
 impl Visualizer {
-    fn oscilloscope_window(&self, channel: usize, window_size: usize) -> (Vec<f32>, ChannelState) {
+    /// The last `window_size` amplitudes of a channel, lined up by `trigger`. `previous` is
+    /// the window shown last frame, and `period` the length of one period in samples.
+    fn oscilloscope_window(&self, channel: usize, window_size: usize, trigger: Trigger, previous: Option<&[f32]>, period: Option<usize>) -> (Vec<f32>, ChannelState) {
         let buf = self.channel_states.get(channel).unwrap();
 
         if buf.is_empty() {
@@ -17,33 +100,38 @@ impl Visualizer {
             return (result, buf.iter().last().cloned().unwrap());
         }
 
+        let amplitudes: Vec<f32> = buf.iter().map(|s| s.amplitude).collect();
+        let start_index = match trigger {
+            Trigger::Edge => self.edge_start(channel, window_size),
+            Trigger::ZeroCrossing => zero_crossing_start(&amplitudes, window_size),
+            // The waveform repeats every period, so there's no need to search any further back
+            Trigger::Correlation => match previous {
+                Some(previous) if previous.len() == window_size => {
+                    let latest_start = amplitudes.len() - window_size;
+                    let search = period.unwrap_or(window_size).clamp(1, window_size);
+                    best_alignment(&amplitudes, previous, latest_start.saturating_sub(search)..=latest_start)
+                },
+                _ => zero_crossing_start(&amplitudes, window_size)
+            }
+        };
+        let end_index = std::cmp::min(start_index + window_size, buf.len());
+
+        (amplitudes[start_index..end_index].to_vec(), buf.iter().last().cloned().unwrap())
+    }
+
+    /// Start of a window centered on the last time the voice restarted its sample
+    fn edge_start(&self, channel: usize, window_size: usize) -> usize {
+        let buf = self.channel_states.get(channel).unwrap();
+
         // Perform edge detection:
         let edge_detect_end = buf.len() - window_size;
         let mut edge_buffer: Vec<_> = buf.iter().collect();
         edge_buffer.truncate(edge_detect_end);
-        // let min_sample = edge_buffer.iter()
-        //     .map(|s| s.amplitude)
-        //     .reduce(f32::min)
-        //     .unwrap_or(0.0);
-        // let max_sample = edge_buffer.iter()
-        //     .map(|s| s.amplitude)
-        //     .reduce(f32::max)
-        //     .unwrap_or(0.0);
-        // let edge_threshold = (min_sample + max_sample) / 2.0;
-        // let edge_index = edge_buffer.windows(2)
-        //     // Convolve the function f: amplitude > threshold with the kernel g: [1, -1]
-        //     // to detect edges
-        //     .map(|w| {
-        //         let f0 = (w[0].amplitude > edge_threshold) as i16;
-        //         let f1 = (w[1].amplitude > edge_threshold) as i16;
-        //         f0 - f1
-        //     })
-        //     .rposition(|s| s == -1);
         let edge_index = edge_buffer.iter().rposition(|s| s.edge);
 
-        let start_index = match edge_index {
+        match edge_index {
             Some(mut edge_index) => {
-                while edge_index < edge_detect_end - window_size {
+                while edge_index < edge_detect_end.saturating_sub(window_size) {
                     // If the last edge is outside of the last window, then move to the right
                     // one window size to prevent long samples from "freezing".
                     edge_index += window_size;
@@ -53,31 +141,43 @@ impl Visualizer {
             },
             // If no edge was found, just use the latest window
             None => edge_detect_end
-        };
-        let end_index = std::cmp::min(start_index + window_size, buf.len());
-
-        let samples: Vec<_> = buf.iter()
-            .enumerate()
-            .filter_map(|(i, s)| {
-                if (start_index..end_index).contains(&i) {
-                    Some(s.amplitude)
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        (samples, buf.iter().last().cloned().unwrap())
+        }
     }
 
-    pub fn draw_oscilloscope_view(&mut self, channel: usize, x: f32, y: f32, w: f32, h: f32) {
+    pub fn draw_oscilloscope_view(&mut self, index: usize, channel: usize, trigger: Trigger, periods: Option<f64>, auto_gain: bool, rect: Rect) {
+        let Rect { x, y, w, h } = rect;
         let settings = self.settings.settings(channel);
-        let (window, last_state) = self.oscilloscope_window(channel, (w * 2.0) as _);
+        let scale = self.layout.scale();
+
+        // A number of periods at the pitch the channel is playing, or two samples per pixel
+        let frequency = self.channel_states[channel].iter().last().map_or(0.0, |state| state.frequency);
+        let period = match frequency > 0.0 && frequency.is_finite() {
+            true => Some(SAMPLE_RATE as f64 / frequency),
+            false => None
+        };
+        let window_size = match (periods, period) {
+            (Some(periods), Some(period)) => ((periods * period).round() as usize).clamp(MIN_WINDOW_SIZE, MAX_WINDOW_SIZE),
+            _ => (w * 2.0 / scale) as usize
+        };
+        let previous = self.oscilloscopes.get(&index).map(|state| state.window.as_slice());
+        let (window, last_state) = self.oscilloscope_window(channel, window_size, trigger, previous, period.map(|period| period.ceil() as usize));
+
+        let dt = (1.0 / self.frame_rate) as f32;
+        let state = self.oscilloscopes.entry(index).or_default();
+        let window_peak = window.iter().fold(0.0, |peak: f32, s| peak.max(s.abs()));
+        state.peak = track_peak(state.peak, window_peak, dt);
+        let gain = match auto_gain {
+            true => normalizing_gain(state.peak),
+            false => 1.0
+        };
+        if trigger == Trigger::Correlation {
+            state.window = window.clone();
+        }
 
         let mut pb = PathBuilder::new();
         for (i, s) in window.iter().enumerate() {
-            let px = (i as f32) / 2.0;
-            let py = ((6000.0 - *s) * h / 12000.0).max(-5.0).min(h + 5.0);
+            let px = (i as f32) * w / window_size as f32;
+            let py = ((6000.0 - *s * gain) * h / 12000.0).max(-5.0 * scale).min(h + 5.0 * scale);
 
             if i == 0 {
                 pb.move_to(x + px, y + py);
@@ -87,14 +187,17 @@ impl Visualizer {
         }
         let path = pb.finish();
 
-        let color = settings.color(&last_state).unwrap();
+        let theme = self.theme.oscilloscope.clone();
+        let color = settings.color(&last_state, &self.theme.dimming).unwrap();
+        let background = theme.background;
 
-        let bg_color = Color::new(0x20, color.r(), color.g(), color.b());
+        let bg_color = Color::new(theme.gradient_alpha, color.r(), color.g(), color.b());
+        let bg_center_color = Color::new(theme.gradient_alpha, background.r(), background.g(), background.b());
         let bg_source = Source::new_linear_gradient(
             Gradient {
                 stops: vec![
                     GradientStop { position: 0.0, color: bg_color },
-                    GradientStop { position: 0.5, color: Color::new(0x20, 0, 0, 0)},
+                    GradientStop { position: 0.5, color: bg_center_color },
                     GradientStop { position: 1.0, color: bg_color }
                 ],
             },
@@ -104,7 +207,7 @@ impl Visualizer {
         );
         self.canvas.fill_rect(
             x, y, w, h,
-            &Source::from(Color::new(0xFF, 0, 0, 0)),
+            &Source::from(background),
             &DrawOptions::new()
         );
 
@@ -123,18 +226,21 @@ impl Visualizer {
             );
         }
 
-        let padding = (self.font.tile_h() as f32) / 2.0;
-        let name_width = (self.font.tile_w() * settings.name().len()) as f32;
-        self.font.draw_text(&mut self.canvas, "S-DSP", x + padding, y + padding, 1.0);
-        self.font.draw_text(&mut self.canvas, &settings.name(), x + w - name_width - padding, y + h - 3.0 * padding, 1.0);
+        let font_scale = self.layout.pixel_scale();
+        let padding = self.font.line_height(font_scale) / 2.0;
+        let name_width = self.font.text_width(&settings.name(), font_scale);
+        self.font.draw_text_scaled(&mut self.canvas, &theme.header, x + padding, y + padding, font_scale, 1.0);
+        if theme.show_channel_name {
+            self.font.draw_text_scaled(&mut self.canvas, &settings.name(), x + w - name_width - padding, y + h - 3.0 * padding, font_scale, 1.0);
+        }
 
-        let glow_color = Color::new(0x40, color.r(), color.g(), color.b());
+        let glow_color = Color::new(theme.glow_alpha, color.r(), color.g(), color.b());
         let glow_source = Source::Solid(SolidSource::from(glow_color));
         self.canvas.stroke(
             &path,
             &glow_source,
             &StrokeStyle {
-                width: 3.0,
+                width: theme.glow_width * scale,
                 cap: LineCap::Round,
                 join: LineJoin::Round,
                 miter_limit: 2.0,
@@ -149,7 +255,7 @@ impl Visualizer {
             &path,
             &line_source,
             &StrokeStyle {
-                width: 1.0,
+                width: theme.line_width * scale,
                 cap: LineCap::Round,
                 join: LineJoin::Round,
                 miter_limit: 2.0,
@@ -159,27 +265,70 @@ impl Visualizer {
             &DrawOptions::default()
         );
 
-        for dx in 0..DIVIDER_WIDTH {
-            let gradient_index = (255 * (DIVIDER_WIDTH - dx)) / DIVIDER_WIDTH;
+        let line_width = self.layout.line_width();
+        let divider_width = theme.divider_width;
+        for dx in 0..divider_width {
+            let gradient_index = (255 * (divider_width - dx)) / divider_width;
             let gradient_color = Color::new(((gradient_index * gradient_index) / 255) as u8, 0, 0, 0);
             let gradient_source = Source::Solid(SolidSource::from(gradient_color));
 
             self.canvas.fill_rect(
-                x - 1.0 + dx as f32, y, 1.0, h,
+                x + line_width * (dx as f32 - 1.0), y, line_width, h,
                 &gradient_source,
                 &DrawOptions::new()
             );
             self.canvas.fill_rect(
-                x + w - 1.0 - dx as f32, y, 1.0, h,
+                x + w - line_width * (dx as f32 + 1.0), y, line_width, h,
                 &gradient_source,
                 &DrawOptions::new()
             );
         }
     }
+}
 
-    pub fn draw_oscilloscopes(&mut self) {
-        for channel in 0..8 {
-            self.draw_oscilloscope_view(channel, 120.0 * channel as f32, 540.0 - 48.0, 120.0, 48.0);
-        }
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use super::*;
+
+    #[test]
+    fn auto_gain_test() {
+        // Peaks are followed right up, and fall by half every half-life
+        assert_eq!(track_peak(100.0, 800.0, 0.1), 800.0);
+        assert_eq!(track_peak(1000.0, 0.0, AUTO_GAIN_HALF_LIFE), 500.0);
+        assert_eq!(track_peak(1000.0, 600.0, AUTO_GAIN_HALF_LIFE), 600.0);
+
+        assert_eq!(normalizing_gain(AUTO_GAIN_TARGET * 2.0), 0.5);
+        assert_eq!(normalizing_gain(1.0), MAX_AUTO_GAIN);
+        assert_eq!(normalizing_gain(0.0), MAX_AUTO_GAIN);
+    }
+
+    #[test]
+    fn last_rising_zero_crossing_test() {
+        let amplitudes = [0.5, -1.0, -0.5, 0.5, 1.0, -1.0, 0.0, 1.0];
+        assert_eq!(last_rising_zero_crossing(&amplitudes, 0.0), Some(6));
+        assert_eq!(last_rising_zero_crossing(&[1.0, 0.5, 0.0], 0.0), None);
+
+        // Wiggling around zero doesn't count until the signal dips far enough
+        let noisy = [-1.0, 0.2, -0.1, 0.1, 0.8];
+        assert_eq!(last_rising_zero_crossing(&noisy, 0.0), Some(3));
+        assert_eq!(last_rising_zero_crossing(&noisy, 0.5), Some(1));
+    }
+
+    #[test]
+    fn best_alignment_test() {
+        let wave: Vec<f32> = (0..400).map(|i| (2.0 * PI * i as f32 / 50.0).sin() * (1.0 + i as f32 / 400.0)).collect();
+        let previous = wave[120..220].to_vec();
+
+        // The start in phase with the previous window wins
+        assert_eq!(best_alignment(&wave[..300], &previous, 100..=130), 120);
+        assert_eq!(best_alignment(&wave, &previous, 150..=200), 170);
+        // Starts that would run past the end are skipped
+        assert_eq!(best_alignment(&wave, &previous, 160..=330), 270);
+
+        // Every period of a steady wave lines up just as well, and the latest start wins
+        let square: Vec<f32> = (0..400).map(|i| if i % 50 < 25 { 1.0 } else { -1.0 }).collect();
+        assert_eq!(best_alignment(&square, &square[120..220], 100..=200), 170);
+        assert_eq!(best_alignment(&square, &square[120..220], 100..=169), 120);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+use raqote::{DrawOptions, Source};
+use crate::emulator::SpcMetadata;
+use crate::visualizer::layout::{Alignment, OverlayPanel, Rect};
+use super::Visualizer;
+
+/// What the placeholders of overlay lines are filled in with.
+#[derive(Clone, Default)]
+pub struct OverlayText {
+    pub title: String,
+    pub game: String,
+    pub artist: String,
+    pub dumper: String,
+    pub comments: String,
+    pub elapsed: Duration,
+    /// Unknown until the song loop has been found when stopping after a number of loops
+    pub total: Option<Duration>,
+    pub loop_count: u64
+}
+
+impl OverlayText {
+    pub fn set_metadata(&mut self, metadata: &SpcMetadata) {
+        self.title = metadata.title.clone();
+        self.game = metadata.ost_title.clone().unwrap_or(metadata.game.clone());
+        self.artist = metadata.artist.clone();
+        self.dumper = metadata.dumper.clone();
+        self.comments = metadata.comments.clone();
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Fill in the `{title}`, `{game}`, `{artist}`, `{dumper}`, `{comments}`, `{elapsed}`,
+/// `{total}` and `{loop}` placeholders of an overlay line. Lines with placeholders that
+/// all turn out empty, like `Dumped by {dumper}` for an untagged dump, are left out.
+pub fn fill_line(line: &str, text: &OverlayText) -> Option<String> {
+    let placeholders = [
+        ("{title}", text.title.trim().to_string()),
+        ("{game}", text.game.trim().to_string()),
+        ("{artist}", text.artist.trim().to_string()),
+        ("{dumper}", text.dumper.trim().to_string()),
+        ("{comments}", text.comments.trim().to_string()),
+        ("{elapsed}", format_duration(text.elapsed)),
+        ("{total}", text.total.map(format_duration).unwrap_or("?".to_string())),
+        ("{loop}", text.loop_count.to_string())
+    ];
+
+    // A single pass over the line, so that placeholders in the tags themselves stay as they are
+    let mut filled = String::new();
+    let mut has_placeholders = false;
+    let mut has_values = false;
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        match placeholders.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                has_placeholders = true;
+                has_values |= !value.is_empty();
+                filled.push_str(value);
+                rest = &rest[placeholder.len()..];
+            },
+            None => {
+                filled.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    match has_placeholders && !has_values {
+        true => None,
+        false => Some(filled)
+    }
+}
+
+impl Visualizer {
+    pub fn draw_overlay(&mut self, panel: &OverlayPanel) {
+        if !panel.is_shown(self.overlay_text.elapsed.as_secs_f64()) {
+            return;
+        }
+
+        // Multi-line values like comments get a line each
+        let lines: Vec<String> = panel.lines.iter()
+            .filter_map(|line| fill_line(line, &self.overlay_text))
+            .flat_map(|line| line.lines().map(|l| l.to_string()).collect::<Vec<_>>())
+            .collect();
+        if lines.is_empty() {
+            return;
+        }
+
+        let Rect { x, y, w, h } = panel.rect;
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.overlay.background),
+            &DrawOptions::new()
+        );
+
+        let line_h = self.font.line_height(panel.text_scale);
+        let padding = line_h / 2.0;
+        for (i, line) in lines.iter().enumerate() {
+            let line_y = y + padding + i as f32 * (line_h + padding);
+            if line_y + line_h > y + h {
+                break;
+            }
+
+            // Cut off whatever doesn't fit
+            let mut line_w = 0.0;
+            let line: String = line.chars()
+                .take_while(|&c| {
+                    line_w += self.font.char_width(c, panel.text_scale);
+                    line_w <= w - 2.0 * padding
+                })
+                .collect();
+            let line_w = self.font.text_width(&line, panel.text_scale);
+            let line_x = match panel.align {
+                Alignment::Left => x + padding,
+                Alignment::Center => x + (w - line_w) / 2.0,
+                Alignment::Right => x + w - padding - line_w
+            };
+            self.font.draw_text_scaled(&mut self.canvas, &line, line_x, line_y, panel.text_scale, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay_text() -> OverlayText {
+        OverlayText {
+            title: "Opening".to_string(),
+            game: "Game".to_string(),
+            elapsed: Duration::from_secs_f64(65.5),
+            loop_count: 2,
+            ..OverlayText::default()
+        }
+    }
+
+    #[test]
+    fn fill_line_test() {
+        let text = overlay_text();
+
+        assert_eq!(fill_line("{title} ({game})", &text), Some("Opening (Game)".to_string()));
+        assert_eq!(fill_line("{elapsed} / {total}, loop {loop}", &text), Some("1:05 / ?, loop 2".to_string()));
+        assert_eq!(fill_line("Now playing", &text), Some("Now playing".to_string()));
+        assert_eq!(fill_line("Dumped by {dumper}", &text), None);
+        assert_eq!(fill_line("{artist}{dumper} - {title}", &text), Some(" - Opening".to_string()));
+    }
+
+    #[test]
+    fn fill_line_tag_placeholders_test() {
+        // Tags that look like placeholders are shown as they are
+        let text = OverlayText {
+            title: "{artist} {elapsed}".to_string(),
+            artist: "Composer".to_string(),
+            comments: "Ripped from {title}".to_string(),
+            ..overlay_text()
+        };
+
+        assert_eq!(fill_line("{title} by {artist}", &text), Some("{artist} {elapsed} by Composer".to_string()));
+        assert_eq!(fill_line("{comments} {elapsed}", &text), Some("Ripped from {title} 1:05".to_string()));
+        assert_eq!(fill_line("{{title}}", &text), Some("{{artist} {elapsed}}".to_string()));
+    }
+}
@@ -1,11 +1,11 @@
-use raqote::{AntialiasMode, BlendMode, Color, DrawOptions, PathBuilder, SolidSource, Source};
+use raqote::{AntialiasMode, BlendMode, Color, DrawOptions, PathBuilder, SolidSource, Source, Transform};
 use ringbuf::Rb;
 use crate::visualizer::ChannelState;
+use crate::visualizer::layout::{Layout, Orientation, PianoRollPanel, Rect};
 use super::Visualizer;
 
 const KEY_COUNT: usize = 108;
-const KEY_THICKNESS: f32 = 8.5;
-const KEY_HEIGHT: f32 = 72.0;
+const SLICE_ROWS_PER_SECOND: f64 = 240.0;
 
 #[derive(Copy, Clone, PartialEq)]
 enum PianoKey {
@@ -31,12 +31,48 @@ const PIANO_KEYS: [PianoKey; 12] = [
 ];
 const C_0: f64 = 16.351597831287;
 
+fn look_ahead_rows(look_ahead: f64) -> usize {
+    (look_ahead * SLICE_ROWS_PER_SECOND).round() as usize
+}
+
+/// Rows of history needed to fill the longest piano roll in the layout, with a second
+/// to spare on top of the look-ahead since that is rounded up to whole frames.
+pub(super) fn slice_buffer_rows(layout: &Layout) -> usize {
+    let upcoming_rows = look_ahead_rows(layout.max_look_ahead() + 1.0);
+    layout.piano_rolls()
+        .map(|piano_roll| {
+            let row_size = piano_roll.scroll_speed / SLICE_ROWS_PER_SECOND as f32;
+            (piano_roll.scroll_length() / row_size).ceil() as usize + upcoming_rows + 2
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Horizontal piano rolls are drawn as vertical ones, and turned on their side by the
+/// returned transform so the lowest key ends up at the bottom.
+fn upright(panel: &PianoRollPanel) -> (PianoRollPanel, Transform) {
+    match panel.orientation {
+        Orientation::Vertical => (panel.clone(), Transform::identity()),
+        Orientation::Horizontal => {
+            let Rect { x, y, w, h } = panel.keys;
+            let upright_panel = PianoRollPanel {
+                roll: Rect::new(0.0, -panel.roll.w, h, panel.roll.w),
+                keys: Rect::new(0.0, 0.0, h, w),
+                orientation: Orientation::Vertical,
+                ..panel.clone()
+            };
+            // (x', y') = (x + w - y, y + h - x)
+            (upright_panel, Transform::new(0.0, -1.0, -1.0, 0.0, x + w, y + h))
+        }
+    }
+}
+
 impl Visualizer {
     fn draw_piano_key(&mut self, key: PianoKey, x: f32, y: f32, w: f32, h: f32, color: Option<Color>) {
         let key_source = match (color, key) {
             (Some(color), _) => Source::Solid(SolidSource::from(color)),
-            (None, PianoKey::Black) => Source::Solid(SolidSource::from_unpremultiplied_argb(0xff, 0x00, 0x00, 0x00)),
-            (None, _) => Source::Solid(SolidSource::from_unpremultiplied_argb(0xff, 0xff, 0xff, 0xff))
+            (None, PianoKey::Black) => Source::Solid(SolidSource::from(self.theme.piano.black_key)),
+            (None, _) => Source::Solid(SolidSource::from(self.theme.piano.white_key))
         };
 
         let draw_options = DrawOptions {
@@ -46,57 +82,58 @@ impl Visualizer {
         };
 
         // TODO convert to path-based rendering instead
+        let b = self.layout.line_width();
         match key {
             PianoKey::WhiteLeft => {
                 self.canvas.fill_rect(
-                    x - (w / 2.0) + 1.0,
-                    y + 1.0,
-                    w - 1.0,
-                    h - 1.0,
+                    x - (w / 2.0) + b,
+                    y + b,
+                    w - b,
+                    h - b,
                     &key_source,
                     &draw_options
                 );
                 self.canvas.fill_rect(
                     x + (w / 2.0),
-                    y + (h / 2.0) + 1.0,
+                    y + (h / 2.0) + b,
                     w / 2.0,
-                    (h / 2.0) - 1.0,
+                    (h / 2.0) - b,
                     &key_source,
                     &draw_options
                 );
             },
             PianoKey::WhiteCenter => {
                 self.canvas.fill_rect(
-                    x - (w / 2.0) + 1.0,
-                    y + 1.0,
-                    w - 1.0,
+                    x - (w / 2.0) + b,
+                    y + b,
+                    w - b,
                     h / 2.0,
                     &key_source,
                     &draw_options
                 );
                 self.canvas.fill_rect(
-                    x - w + 1.0,
-                    y + (h / 2.0) + 1.0,
-                    (w * 2.0) - 1.0,
-                    (h / 2.0) - 1.0,
+                    x - w + b,
+                    y + (h / 2.0) + b,
+                    (w * 2.0) - b,
+                    (h / 2.0) - b,
                     &key_source,
                     &draw_options
                 );
             },
             PianoKey::WhiteRight => {
                 self.canvas.fill_rect(
-                    x - (w / 2.0) + 1.0,
-                    y + 1.0,
-                    w - 1.0,
-                    h - 1.0,
+                    x - (w / 2.0) + b,
+                    y + b,
+                    w - b,
+                    h - b,
                     &key_source,
                     &draw_options
                 );
                 self.canvas.fill_rect(
-                    x - w + 1.0,
-                    y + (h / 2.0) + 1.0,
+                    x - w + b,
+                    y + (h / 2.0) + b,
                     w / 2.0,
-                    (h / 2.0) - 1.0,
+                    (h / 2.0) - b,
                     &key_source,
                     &draw_options
                 );
@@ -104,8 +141,8 @@ impl Visualizer {
             PianoKey::Black => {
                 self.canvas.fill_rect(
                     x - (w / 2.0),
-                    y + 1.0,
-                    w + 1.0,
+                    y + b,
+                    w + b,
                     h / 2.0,
                     &key_source,
                     &draw_options
@@ -115,13 +152,14 @@ impl Visualizer {
     }
 
     fn draw_piano_keys(&mut self, x: f32, y: f32, w: f32, h: f32, key_w: f32) {
-        let white_border_source = Source::Solid(SolidSource::from_unpremultiplied_argb(0xff, 0x18, 0x18, 0x18));
-        let top_edge_source = Source::Solid(SolidSource::from_unpremultiplied_argb(0xff, 0x04, 0x04, 0x04));
+        let white_border_source = Source::Solid(SolidSource::from(self.theme.piano.key_border));
+        let top_edge_source = Source::Solid(SolidSource::from(self.theme.piano.top_edge));
 
         let keys_w = key_w * KEY_COUNT as f32;
         let keys_x = x + ((w - keys_w) / 2.0);
 
-        self.canvas.fill_rect(x, y, w, h + 1.0, &top_edge_source, &DrawOptions::default());
+        let b = self.layout.line_width();
+        self.canvas.fill_rect(x, y, w, h + b, &top_edge_source, &DrawOptions::default());
         self.canvas.fill_rect(keys_x, y, keys_w, h, &white_border_source, &DrawOptions::default());
         for key_i in 0..KEY_COUNT {
             let key_t = PIANO_KEYS[key_i % 12].clone();
@@ -129,7 +167,7 @@ impl Visualizer {
 
             self.draw_piano_key(key_t, key_x, y, key_w, h, None);
         }
-        self.canvas.fill_rect(x, y, w, 1.0, &top_edge_source, &DrawOptions::default());
+        self.canvas.fill_rect(x, y, w, b, &top_edge_source, &DrawOptions::default());
     }
 
     fn draw_channel_key_spot(&mut self, channel: usize, x: f32, y: f32, w: f32, h: f32, key_w: f32) {
@@ -140,7 +178,7 @@ impl Visualizer {
         let last_state = last_state.unwrap();
 
         let settings = self.settings.settings(channel);
-        let color = settings.color(&last_state).unwrap();
+        let color = settings.color(&last_state, &self.theme.dimming).unwrap();
         let volume_alpha = match last_state.volume {
             0 => return,
             v => 0.5 + (v as f32) / 30.0
@@ -172,37 +210,43 @@ impl Visualizer {
         self.draw_piano_key(upper_key, upper_x, y, key_w, h, Some(upper_color));
     }
 
-    fn draw_channel_slices(&mut self, x: f32, y: f32, w: f32, h: f32, key_w: f32, outline: bool) {
+    fn draw_channel_slices(&mut self, panel: &PianoRollPanel, outline: bool) {
+        let Rect { x, y, w, h } = panel.roll;
+        let key_w = panel.key_thickness;
         let keys_w = key_w * KEY_COUNT as f32;
         let keys_x = x + (w / 2.0) - (keys_w / 2.0);
+        let row_h = panel.scroll_speed / SLICE_ROWS_PER_SECOND as f32;
+        let scale = self.layout.scale();
+        // Rows further ahead than this piano roll's look-ahead
+        let skipped_rows = self.upcoming_slice_rows().saturating_sub(look_ahead_rows(panel.look_ahead));
 
-        for (i, state) in self.state_slices.iter().rev().enumerate() {
-            if (i / 8) > h.floor() as usize {
+        for (i, state) in self.state_slices.iter().rev().skip(skipped_rows * 8).enumerate() {
+            if (i / 8) as f32 * row_h > h.floor() {
                 break;
             }
-            if state.volume == 0 {
+            if state.volume == 0 || !panel.channels.contains(&state.channel) {
                 continue;
             }
 
             let settings = self.settings.settings(state.channel);
-            let color = settings.color(&state).unwrap();
+            let color = settings.color(&state, &self.theme.dimming).unwrap();
 
             let frequency = state.frequency;
             let n = 12.0 * (frequency / C_0).log2() as f32;
             let octave = (n / 12.0).floor();
             let note = n.rem_euclid(12.0);
 
-            let slice_w = state.volume as f32;
+            let slice_w = state.volume as f32 * scale;
             let slice_x = keys_x + (key_w * (note + 12.0 * octave)) - (slice_w / 2.0);
-            let slice_y = y + (i / 8) as f32;
+            let slice_y = y + (i / 8) as f32 * row_h;
 
             if outline {
                 self.canvas.fill_rect(
-                    slice_x - 1.0,
-                    slice_y - 1.0,
-                    slice_w + 2.0,
-                    3.0,
-                    &Source::from(Color::new(0x80, 0, 0, 0)),
+                    slice_x - scale,
+                    slice_y - scale,
+                    slice_w + 2.0 * scale,
+                    row_h + 2.0 * scale,
+                    &Source::from(self.theme.piano.slice_outline),
                     &DrawOptions::default()
                 );
             } else {
@@ -210,7 +254,7 @@ impl Visualizer {
                     slice_x,
                     slice_y,
                     slice_w,
-                    1.0,
+                    row_h,
                     &Source::Solid(SolidSource::from(color)),
                     &DrawOptions::default()
                 );
@@ -218,29 +262,64 @@ impl Visualizer {
         }
     }
 
-    pub fn draw_piano_roll(&mut self) {
-        let slices_y = 0.0;
-        let slices_h = 540.0 - KEY_HEIGHT - 48.0;
+    /// Mark the frame being shown, with the upcoming notes between it and the far end of
+    /// the piano roll.
+    fn draw_playhead(&mut self, panel: &PianoRollPanel) {
+        if panel.look_ahead <= 0.0 {
+            return;
+        }
 
-        let mut state_slices: Vec<ChannelState> = Vec::new();
-        for channel in 0..8 {
-            if let Some(last_state) = self.channel_states.get(channel).unwrap().iter().last() {
-                state_slices.push(last_state.clone());
-            }
+        let Rect { x, y, w, h } = panel.roll;
+        let row_h = panel.scroll_speed / SLICE_ROWS_PER_SECOND as f32;
+        let shown_rows = usize::min(self.upcoming_slice_rows(), look_ahead_rows(panel.look_ahead));
+        let playhead_y = y + shown_rows as f32 * row_h;
+        if playhead_y < y + h {
+            self.canvas.fill_rect(
+                x,
+                playhead_y,
+                w,
+                self.layout.line_width(),
+                &Source::from(self.theme.piano.playhead),
+                &DrawOptions::default()
+            );
         }
+    }
+
+    /// Add the latest frame's rows to the piano roll history and return how many there
+    /// were. Called once per frame, no matter how many piano rolls the layout has.
+    pub(super) fn push_state_slices(&mut self) -> usize {
+        let mut state_slices: Vec<ChannelState> = self.latest_states.iter().flatten().cloned().collect();
         state_slices.sort_unstable_by_key(|state| state.kon_frames);
 
-        for _ in 0..4 {
+        self.pending_slice_rows += SLICE_ROWS_PER_SECOND / self.frame_rate;
+        let slice_rows = self.pending_slice_rows.floor();
+        self.pending_slice_rows -= slice_rows;
+
+        for _ in 0..slice_rows as usize {
             for last_state in &state_slices {
                 self.state_slices.push_overwrite(last_state.clone());
             }
         }
-        self.draw_channel_slices(0.0, slices_y, 960.0, slices_h, KEY_THICKNESS, true);
-        self.draw_channel_slices(0.0, slices_y, 960.0, slices_h, KEY_THICKNESS, false);
 
-        self.draw_piano_keys(0.0, 540.0 - KEY_HEIGHT - 48.0, 960.0, KEY_HEIGHT, KEY_THICKNESS);
-        for channel in 0..8 {
-            self.draw_channel_key_spot(channel, 0.0, 540.0 - KEY_HEIGHT - 48.0, 960.0, KEY_HEIGHT, KEY_THICKNESS);
+        slice_rows as usize
+    }
+
+    pub fn draw_piano_roll(&mut self, panel: &PianoRollPanel) {
+        let (panel, transform) = upright(panel);
+        let keys = panel.keys;
+        let key_w = panel.key_thickness;
+
+        self.canvas.set_transform(&transform);
+
+        self.draw_channel_slices(&panel, true);
+        self.draw_channel_slices(&panel, false);
+        self.draw_playhead(&panel);
+
+        self.draw_piano_keys(keys.x, keys.y, keys.w, keys.h, key_w);
+        for &channel in panel.channels.iter() {
+            self.draw_channel_key_spot(channel, keys.x, keys.y, keys.w, keys.h, key_w);
         }
+
+        self.canvas.set_transform(&Transform::identity());
     }
 }
@@ -0,0 +1,110 @@
+use raqote::{DrawOptions, Source};
+use crate::emulator::DspRegisters;
+use crate::visualizer::layout::{Rect, RegistersPanel};
+use super::Visualizer;
+
+/// Column names of the voice lines. The last column holds the voice's KON, KOF, PMON,
+/// NON and EON bits as a letter each, or `.` when not set.
+pub const VOICE_HEADER: &'static str = "V VL VR PTCH SR A1 A2 GN EX OX KFPNE";
+
+fn voice_line(registers: &DspRegisters, channel: usize) -> String {
+    let voice = registers.voice(channel);
+    let bits = [
+        ('K', registers.key_on()),
+        ('F', registers.key_off()),
+        ('P', registers.pitch_modulation()),
+        ('N', registers.noise_on()),
+        ('E', registers.echo_on())
+    ];
+    let flags: String = bits.iter()
+        .map(|&(letter, bits)| if bits & (1 << channel) != 0 { letter } else { '.' })
+        .collect();
+
+    format!("{} {:02X} {:02X} {:04X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {}",
+            channel, voice.vol_left, voice.vol_right, voice.pitch, voice.source,
+            voice.adsr1, voice.adsr2, voice.gain, voice.envx, voice.outx, flags)
+}
+
+fn global_lines(registers: &DspRegisters) -> Vec<String> {
+    let (main_left, main_right) = registers.main_volume();
+    let (echo_left, echo_right) = registers.echo_volume();
+    let fir: Vec<String> = registers.fir_coefficients().iter()
+        .map(|coefficient| format!("{:02X}", coefficient))
+        .collect();
+
+    vec![
+        format!("MVOL {:02X} {:02X} EVOL {:02X} {:02X} EFB {:02X}",
+                main_left, main_right, echo_left, echo_right, registers.echo_feedback()),
+        format!("EDL {:02X} ESA {:02X} FLG {:02X} DIR {:02X}",
+                registers.echo_delay(), registers.echo_start(), registers.flags(), registers.source_directory()),
+        format!("FIR {}", fir.join(" "))
+    ]
+}
+
+/// Every line of the readout: a header, a line per voice in `channels`, then the globals.
+pub fn readout_lines(registers: &DspRegisters, channels: &[usize]) -> Vec<String> {
+    let mut lines = vec![VOICE_HEADER.to_string()];
+    lines.extend(channels.iter().map(|&channel| voice_line(registers, channel)));
+    lines.push(String::new());
+    lines.extend(global_lines(registers));
+    lines
+}
+
+impl Visualizer {
+    pub fn draw_registers(&mut self, panel: &RegistersPanel) {
+        let Rect { x, y, w, h } = panel.rect;
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.overlay.background),
+            &DrawOptions::new()
+        );
+
+        let line_h = self.font.line_height(panel.text_scale);
+        let padding = line_h / 2.0;
+        for (i, line) in readout_lines(&self.registers, &panel.channels).iter().enumerate() {
+            let line_y = y + padding + i as f32 * line_h;
+            if line_y + line_h > y + h {
+                break;
+            }
+
+            // Cut off whatever doesn't fit
+            let mut line_w = 0.0;
+            let line: String = line.chars()
+                .take_while(|&c| {
+                    line_w += self.font.char_width(c, panel.text_scale);
+                    line_w <= w - 2.0 * padding
+                })
+                .collect();
+            self.font.draw_text_scaled(&mut self.canvas, &line, x + padding, line_y, panel.text_scale, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readout_lines_test() {
+        let mut registers = DspRegisters::default();
+        let voice_regs = [0x7f, 0x60, 0x00, 0x10, 0x03, 0xff, 0xe0, 0x7f, 0x58, 0xf4];
+        registers.0[0x20..0x2a].copy_from_slice(&voice_regs);
+        registers.0[0x4c] = 0x04; // KON
+        registers.0[0x4d] = 0x05; // EON
+        registers.0[0x0c] = 0x7f; // MVOLL
+        registers.0[0x6d] = 0xd0; // ESA
+        registers.0[0x0f] = 0x7f; // C0
+
+        let lines = readout_lines(&registers, &[2, 0]);
+        assert_eq!(lines, vec![
+            VOICE_HEADER.to_string(),
+            "2 7F 60 1000 03 FF E0 7F 58 F4 K...E".to_string(),
+            "0 00 00 0000 00 00 00 00 00 00 ....E".to_string(),
+            "".to_string(),
+            "MVOL 7F 00 EVOL 00 00 EFB 00".to_string(),
+            "EDL 00 ESA D0 FLG 00 DIR 00".to_string(),
+            "FIR 7F 00 00 00 00 00 00 00".to_string()
+        ]);
+        assert_eq!(lines[1].len(), VOICE_HEADER.len());
+    }
+}
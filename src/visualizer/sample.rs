@@ -0,0 +1,97 @@
+use ringbuf::Rb;
+use raqote::{DrawOptions, Source};
+use crate::visualizer::layout::Rect;
+use super::{ChannelState, Visualizer};
+
+/// Lowest and highest sample in each of `columns` equal parts of `samples`, scaled so
+/// that the loudest sample is at -1 or 1. Samples shorter than `columns` get a column
+/// per sample.
+pub fn thumbnail(samples: &[i16], columns: usize) -> Vec<(f32, f32)> {
+    let columns = usize::min(columns, samples.len());
+    let peak = samples.iter()
+        .map(|sample| sample.saturating_abs())
+        .max()
+        .unwrap_or(0)
+        .max(1) as f32;
+
+    (0..columns)
+        .map(|column| {
+            let part = &samples[(column * samples.len() / columns)..((column + 1) * samples.len() / columns)];
+            let min = part.iter().cloned().min().unwrap_or(0);
+            let max = part.iter().cloned().max().unwrap_or(0);
+            (min as f32 / peak, max as f32 / peak)
+        })
+        .collect()
+}
+
+impl Visualizer {
+    pub fn draw_sample(&mut self, rect: Rect, channel: usize) {
+        let Rect { x, y, w, h } = rect;
+        let font_scale = self.layout.pixel_scale();
+        let line_width = self.layout.line_width();
+        let padding = self.font.line_height(font_scale) / 2.0;
+
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.oscilloscope.background),
+            &DrawOptions::new()
+        );
+
+        let voice_sample = match &self.voice_samples[channel] {
+            Some(voice_sample) if !voice_sample.sample.samples.is_empty() => voice_sample.clone(),
+            _ => return
+        };
+        let samples = &voice_sample.sample.samples;
+        let sample_x = |position: f64| x + (position / samples.len() as f64) as f32 * w;
+
+        // The channel's color for this sample, dimmed while silent or muted
+        let last_state = self.channel_states[channel].iter().last().cloned().unwrap_or(ChannelState::default());
+        let color = self.settings.settings(channel)
+            .color(&last_state, &self.theme.dimming)
+            .unwrap_or(self.theme.sample.playhead);
+
+        let columns = thumbnail(samples, (w / line_width).ceil() as usize);
+        let column_w = w / columns.len() as f32;
+        let (center_y, amplitude) = (y + h / 2.0, (h - 2.0 * padding) / 2.0);
+        for (i, (min, max)) in columns.iter().enumerate() {
+            self.canvas.fill_rect(
+                x + i as f32 * column_w, center_y - max * amplitude, column_w, ((max - min) * amplitude).max(line_width),
+                &Source::from(color),
+                &DrawOptions::new()
+            );
+        }
+
+        if let Some(loop_start) = voice_sample.sample.loop_start {
+            self.canvas.fill_rect(
+                sample_x(loop_start as f64), y, line_width, h,
+                &Source::from(self.theme.sample.loop_marker),
+                &DrawOptions::new()
+            );
+        }
+        if voice_sample.position < samples.len() as f64 {
+            self.canvas.fill_rect(
+                sample_x(voice_sample.position), y, line_width, h,
+                &Source::from(self.theme.sample.playhead),
+                &DrawOptions::new()
+            );
+        }
+
+        let label = format!("{:02X}", voice_sample.source);
+        self.font.draw_text_scaled(&mut self.canvas, &label, x + padding, y + padding, font_scale, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_test() {
+        let samples = [0, 100, -200, 50, 400, -400, 0, 0];
+
+        assert_eq!(thumbnail(&samples, 4), vec![(0.0, 0.25), (-0.5, 0.125), (-1.0, 1.0), (0.0, 0.0)]);
+        assert_eq!(thumbnail(&samples, 100).len(), samples.len());
+        assert_eq!(thumbnail(&[0; 4], 2), vec![(0.0, 0.0), (0.0, 0.0)]);
+        assert!(thumbnail(&[], 2).is_empty());
+    }
+}
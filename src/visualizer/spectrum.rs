@@ -0,0 +1,270 @@
+use std::f32::consts::PI;
+use ringbuf::Rb;
+use rustfft::Fft;
+use rustfft::num_complex::Complex;
+use raqote::{Color, DrawOptions, SolidSource, Source};
+use crate::emulator::SAMPLE_RATE;
+use crate::visualizer::layout::{Rect, SpectrumPanel, SpectrumStyle};
+use super::echo::{line_style, polyline};
+use super::{MixSample, Visualizer};
+
+/// Number of S-DSP output samples each spectrum is taken over
+pub const FFT_SIZE: usize = 2048;
+/// Level shown at the bottom of a spectrum analyzer, in dB relative to full scale
+const MIN_DB: f32 = -72.0;
+
+/// Edges of `bands` frequency bands spaced evenly on a log scale, `bands + 1` in all
+pub fn band_edges(bands: usize, min_frequency: f32, max_frequency: f32) -> Vec<f32> {
+    (0..=bands)
+        .map(|i| min_frequency * (max_frequency / min_frequency).powf(i as f32 / bands as f32))
+        .collect()
+}
+
+/// Magnitude of each FFT bin up to half the sample rate, with a Hann window. A full
+/// scale sine wave peaks at 1.
+pub fn magnitudes(fft: &dyn Fft<f32>, samples: &[f32]) -> Vec<f32> {
+    let n = samples.len();
+    let mut buffer: Vec<Complex<f32>> = samples.iter()
+        .enumerate()
+        .map(|(i, &sample)| Complex::new(sample * (0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos()), 0.0))
+        .collect();
+    fft.process(&mut buffer);
+
+    // The window halves the amplitude, and each side of the spectrum gets half of what's left
+    let gain = 4.0 / n as f32;
+    buffer[..(n / 2)].iter().map(|bin| bin.norm() * gain).collect()
+}
+
+/// Strongest bin within each band. Bands narrower than a bin use the bin they are in.
+pub fn band_magnitudes(magnitudes: &[f32], edges: &[f32], sample_rate: f32) -> Vec<f32> {
+    let bin_width = sample_rate / (2 * magnitudes.len()) as f32;
+    edges.windows(2)
+        .map(|edge| {
+            let low = ((edge[0] / bin_width).round() as usize).min(magnitudes.len() - 1);
+            let high = ((edge[1] / bin_width).round() as usize).clamp(low + 1, magnitudes.len());
+            magnitudes[low..high].iter().cloned().fold(0.0, f32::max)
+        })
+        .collect()
+}
+
+/// Height of a magnitude on a dB scale, from 0 at `MIN_DB` to 1 at full scale
+pub fn level(magnitude: f32) -> f32 {
+    ((20.0 * magnitude.max(1e-9).log10() - MIN_DB) / -MIN_DB).clamp(0.0, 1.0)
+}
+
+/// Band heights carried over between frames, so they fall gradually and peaks are held.
+pub struct SpectrumState {
+    pub levels: Vec<f32>,
+    pub peaks: Vec<f32>,
+    /// Seconds since each peak was set
+    peak_ages: Vec<f64>
+}
+
+impl SpectrumState {
+    pub fn new(bands: usize) -> Self {
+        Self {
+            levels: vec![0.0; bands],
+            peaks: vec![0.0; bands],
+            peak_ages: vec![0.0; bands]
+        }
+    }
+
+    /// Move to the next frame, `dt` seconds later. Bands jump up to louder levels right
+    /// away, but fall by at most `falloff` per second.
+    pub fn update(&mut self, levels: &[f32], dt: f64, falloff: f32, peak_hold: f64) {
+        let fall = falloff * dt as f32;
+        for (band, &level) in levels.iter().enumerate() {
+            self.levels[band] = f32::max(level, self.levels[band] - fall);
+
+            if self.levels[band] >= self.peaks[band] {
+                self.peaks[band] = self.levels[band];
+                self.peak_ages[band] = 0.0;
+            } else {
+                self.peak_ages[band] += dt;
+                if self.peak_ages[band] > peak_hold {
+                    self.peaks[band] = f32::max(self.levels[band], self.peaks[band] - fall);
+                }
+            }
+        }
+    }
+}
+
+impl Visualizer {
+    /// Band magnitudes of a stereo signal, averaged over both sides so that out of phase
+    /// content like SNES surround doesn't cancel out the way it would in a mono mix
+    fn stereo_band_magnitudes(&self, samples: &[(i16, i16)], edges: &[f32]) -> Vec<f32> {
+        let left: Vec<f32> = samples.iter().map(|&(left, _)| left as f32 / 32768.0).collect();
+        let right: Vec<f32> = samples.iter().map(|&(_, right)| right as f32 / 32768.0).collect();
+        let left = band_magnitudes(&magnitudes(&*self.fft, &left), edges, SAMPLE_RATE as f32);
+        let right = band_magnitudes(&magnitudes(&*self.fft, &right), edges, SAMPLE_RATE as f32);
+        left.iter().zip(right.iter()).map(|(left, right)| (left + right) / 2.0).collect()
+    }
+
+    pub fn draw_spectrum(&mut self, index: usize, panel: &SpectrumPanel) {
+        let Rect { x, y, w, h } = panel.rect;
+        let line_width = self.layout.line_width();
+
+        let mut samples = vec![MixSample::default(); FFT_SIZE.saturating_sub(self.mix_samples.len())];
+        samples.extend(self.mix_samples.iter().cloned());
+        let mix: Vec<(i16, i16)> = samples.iter().map(|sample| sample.mix).collect();
+
+        let edges = band_edges(panel.bands, panel.min_frequency, panel.max_frequency);
+        let levels: Vec<f32> = self.stereo_band_magnitudes(&mix, &edges).into_iter().map(level).collect();
+        let state = self.spectra.entry(index).or_insert_with(|| SpectrumState::new(panel.bands));
+        state.update(&levels, 1.0 / self.frame_rate, panel.falloff, panel.peak_hold);
+        let heights = state.levels.clone();
+        let peaks = state.peaks.clone();
+
+        // How much of each band every channel makes up
+        let shares: Vec<Vec<f32>> = match panel.stack_channels {
+            true => {
+                let channel_bands: Vec<Vec<f32>> = (0..8)
+                    .map(|channel| {
+                        let voice: Vec<(i16, i16)> = samples.iter().map(|sample| sample.voices[channel]).collect();
+                        self.stereo_band_magnitudes(&voice, &edges)
+                    })
+                    .collect();
+                (0..panel.bands)
+                    .map(|band| {
+                        let total: f32 = channel_bands.iter().map(|bands| bands[band]).sum();
+                        channel_bands.iter()
+                            .map(|bands| if total > 0.0 { bands[band] / total } else { 0.0 })
+                            .collect()
+                    })
+                    .collect()
+            },
+            false => Vec::new()
+        };
+        let channel_colors: Vec<Color> = (0..8)
+            .map(|channel| self.settings.settings(channel).colors().first().cloned().unwrap_or(self.theme.spectrum.bar))
+            .collect();
+
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.oscilloscope.background),
+            &DrawOptions::new()
+        );
+
+        let band_w = w / panel.bands as f32;
+        let gap = match band_w >= 4.0 * line_width {
+            true => line_width,
+            false => 0.0
+        };
+        match panel.style {
+            SpectrumStyle::Bars => {
+                for (band, &height) in heights.iter().enumerate() {
+                    let band_x = x + band as f32 * band_w + gap / 2.0;
+                    let mut top = y + h;
+                    // Bands made up of nothing but echo have no channels to split them into
+                    let segments: Vec<(f32, Color)> = match panel.stack_channels && shares[band].iter().any(|&share| share > 0.0) {
+                        true => shares[band].iter().zip(channel_colors.iter()).map(|(&share, &color)| (share, color)).collect(),
+                        false => vec![(1.0, self.theme.spectrum.bar)]
+                    };
+                    for (share, color) in segments {
+                        let segment_h = height * share * h;
+                        top -= segment_h;
+                        self.canvas.fill_rect(
+                            band_x, top, band_w - gap, segment_h,
+                            &Source::from(color),
+                            &DrawOptions::new()
+                        );
+                    }
+                }
+            },
+            SpectrumStyle::Line => {
+                // Stacked channels get a line each along the top of their share
+                let lines: Vec<(Vec<f32>, Color)> = match panel.stack_channels {
+                    true => (0..8)
+                        .map(|channel| {
+                            let tops: Vec<f32> = heights.iter().enumerate()
+                                .map(|(band, &height)| height * shares[band][..=channel].iter().sum::<f32>())
+                                .collect();
+                            (tops, channel_colors[channel])
+                        })
+                        .collect(),
+                    false => vec![(heights.clone(), self.theme.spectrum.bar)]
+                };
+                for (tops, color) in lines {
+                    let path = polyline(tops.iter().enumerate().map(|(band, &top)| {
+                        (x + (band as f32 + 0.5) * band_w, y + h * (1.0 - top))
+                    }));
+                    self.canvas.stroke(
+                        &path,
+                        &Source::Solid(SolidSource::from(color)),
+                        &line_style(self.theme.oscilloscope.line_width * self.layout.scale()),
+                        &DrawOptions::default()
+                    );
+                }
+            }
+        }
+
+        if panel.peak_hold > 0.0 {
+            for (band, &peak) in peaks.iter().enumerate() {
+                self.canvas.fill_rect(
+                    x + band as f32 * band_w + gap / 2.0, y + h * (1.0 - peak) - line_width, band_w - gap, line_width,
+                    &Source::from(self.theme.spectrum.peak),
+                    &DrawOptions::new()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustfft::FftPlanner;
+    use super::*;
+
+    #[test]
+    fn band_edges_test() {
+        let edges = band_edges(3, 100.0, 100000.0);
+        let expected = [100.0, 1000.0, 10000.0, 100000.0];
+        assert_eq!(edges.len(), expected.len());
+        for (edge, expected) in edges.iter().zip(expected) {
+            assert!((edge - expected).abs() / expected < 1e-4, "{:?}", edges);
+        }
+    }
+
+    #[test]
+    fn magnitudes_test() {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+        // A full scale sine right on bin 64, which is 1 kHz
+        let samples: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| (2.0 * PI * 64.0 * i as f32 / FFT_SIZE as f32).sin())
+            .collect();
+
+        let magnitudes = magnitudes(&*fft, &samples);
+        assert_eq!(magnitudes.len(), FFT_SIZE / 2);
+        assert!((magnitudes[64] - 1.0).abs() < 1e-3, "{}", magnitudes[64]);
+        assert!(magnitudes[200] < 1e-3);
+
+        let bands = band_magnitudes(&magnitudes, &[40.0, 100.0, 900.0, 1100.0, 16000.0], SAMPLE_RATE as f32);
+        assert!(bands[0] < 1e-3 && bands[3] < 1e-3, "{:?}", bands);
+        assert!((bands[2] - 1.0).abs() < 1e-3, "{:?}", bands);
+        assert!((level(bands[2]) - 1.0).abs() < 1e-3);
+        assert_eq!(level(0.0), 0.0);
+    }
+
+    #[test]
+    fn spectrum_state_test() {
+        let mut state = SpectrumState::new(1);
+
+        state.update(&[0.8], 0.1, 1.0, 0.25);
+        assert_eq!(state.levels, vec![0.8]);
+        assert_eq!(state.peaks, vec![0.8]);
+
+        // Levels fall, while the peak is held for a while
+        state.update(&[0.0], 0.1, 1.0, 0.25);
+        assert!((state.levels[0] - 0.7).abs() < 1e-6);
+        assert_eq!(state.peaks, vec![0.8]);
+        state.update(&[0.0], 0.1, 1.0, 0.25);
+        state.update(&[0.0], 0.1, 1.0, 0.25);
+        assert!((state.levels[0] - 0.5).abs() < 1e-6);
+        assert!((state.peaks[0] - 0.7).abs() < 1e-6);
+
+        // Louder levels jump right up
+        state.update(&[0.9], 0.1, 1.0, 0.25);
+        assert_eq!(state.levels, vec![0.9]);
+        assert_eq!(state.peaks, vec![0.9]);
+    }
+}
@@ -0,0 +1,562 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use csscolorparser::Color as CssColor;
+use raqote::Color;
+use serde::Deserialize;
+
+/// Names of the built-in themes, in the order they are offered to the user.
+pub const THEME_PRESETS: [&'static str; 3] = ["default", "neon", "flat"];
+
+pub fn parse_css_color(s: &str) -> Result<Color, String> {
+    let [r, g, b, a] = s.parse::<CssColor>()
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+
+    Ok(Color::new(a, r, g, b))
+}
+
+/// Colors are written as CSS color strings in theme files
+mod css_color {
+    use raqote::Color;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        super::parse_css_color(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+mod css_colors {
+    use raqote::Color;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Color>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| super::parse_css_color(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PianoTheme {
+    #[serde(with = "css_color")]
+    pub white_key: Color,
+    #[serde(with = "css_color")]
+    pub black_key: Color,
+    /// Gaps between the white keys
+    #[serde(with = "css_color")]
+    pub key_border: Color,
+    /// Line between the piano roll and the keyboard
+    #[serde(with = "css_color")]
+    pub top_edge: Color,
+    /// Shadow drawn around each piano roll slice
+    #[serde(with = "css_color")]
+    pub slice_outline: Color,
+    /// Line marking the current position on piano rolls with a look-ahead
+    #[serde(with = "css_color")]
+    pub playhead: Color
+}
+
+impl Default for PianoTheme {
+    fn default() -> Self {
+        Self {
+            white_key: Color::new(0xFF, 0xFF, 0xFF, 0xFF),
+            black_key: Color::new(0xFF, 0x00, 0x00, 0x00),
+            key_border: Color::new(0xFF, 0x18, 0x18, 0x18),
+            top_edge: Color::new(0xFF, 0x04, 0x04, 0x04),
+            slice_outline: Color::new(0x80, 0x00, 0x00, 0x00),
+            playhead: Color::new(0x40, 0xFF, 0xFF, 0xFF)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OscilloscopeTheme {
+    #[serde(with = "css_color")]
+    pub background: Color,
+    /// Opacity of the channel colored gradient on the panned side of the scope
+    pub gradient_alpha: u8,
+    /// Opacity of the glow around the waveform
+    pub glow_alpha: u8,
+    /// Line widths at the reference resolution, scaled with the canvas
+    pub glow_width: f32,
+    pub line_width: f32,
+    /// Width of the shadow between neighboring scopes, in reference pixels
+    pub divider_width: u32,
+    /// Label in the top left corner of each scope
+    pub header: String,
+    pub show_channel_name: bool,
+    /// Cutoff of the high-pass filter that takes the DC offset out of the waveforms, in
+    /// Hz. 0 leaves the waveforms unfiltered.
+    pub dc_cutoff: f32
+}
+
+impl Default for OscilloscopeTheme {
+    fn default() -> Self {
+        Self {
+            background: Color::new(0xFF, 0x00, 0x00, 0x00),
+            gradient_alpha: 0x20,
+            glow_alpha: 0x40,
+            glow_width: 3.0,
+            line_width: 1.0,
+            divider_width: 5,
+            header: "S-DSP".to_string(),
+            show_channel_name: true,
+            dc_cutoff: 50.0
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OverlayTheme {
+    /// Box drawn behind the overlay text
+    #[serde(with = "css_color")]
+    pub background: Color
+}
+
+impl Default for OverlayTheme {
+    fn default() -> Self {
+        Self {
+            background: Color::new(0x80, 0x00, 0x00, 0x00)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct EchoTheme {
+    /// Echo return waveform
+    #[serde(with = "css_color")]
+    pub waveform: Color,
+    /// FIR filter frequency response
+    #[serde(with = "css_color")]
+    pub response: Color,
+    /// Zero line of the waveform and unity gain line of the frequency response
+    #[serde(with = "css_color")]
+    pub guide: Color
+}
+
+impl Default for EchoTheme {
+    fn default() -> Self {
+        Self {
+            waveform: Color::new(0xFF, 0xFF, 0xFF, 0xFF),
+            response: Color::new(0xFF, 0x40, 0xC0, 0xFF),
+            guide: Color::new(0x40, 0xFF, 0xFF, 0xFF)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SpectrumTheme {
+    /// Bars or line, unless the channels are stacked in their own colors
+    #[serde(with = "css_color")]
+    pub bar: Color,
+    /// Markers of the held peaks
+    #[serde(with = "css_color")]
+    pub peak: Color
+}
+
+impl Default for SpectrumTheme {
+    fn default() -> Self {
+        Self {
+            bar: Color::new(0xFF, 0xFF, 0xFF, 0xFF),
+            peak: Color::new(0xC0, 0xFF, 0xFF, 0xFF)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct VectorscopeTheme {
+    /// Trace of the mix, while single channels use their channel color
+    #[serde(with = "css_color")]
+    pub trace: Color,
+    /// Axes and the correlation meter's track
+    #[serde(with = "css_color")]
+    pub guide: Color,
+    /// Correlation meter while both sides are mostly in phase
+    #[serde(with = "css_color")]
+    pub in_phase: Color,
+    /// Correlation meter while the sides are out of phase, which cancels out in mono
+    #[serde(with = "css_color")]
+    pub out_of_phase: Color
+}
+
+impl Default for VectorscopeTheme {
+    fn default() -> Self {
+        Self {
+            trace: Color::new(0xFF, 0x40, 0xFF, 0x80),
+            guide: Color::new(0x40, 0xFF, 0xFF, 0xFF),
+            in_phase: Color::new(0xFF, 0x40, 0xFF, 0x80),
+            out_of_phase: Color::new(0xFF, 0xFF, 0x40, 0x40)
+        }
+    }
+}
+
+/// Colors of the envelope lanes, by envelope phase or GAIN mode
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct EnvelopeTheme {
+    #[serde(with = "css_color")]
+    pub attack: Color,
+    #[serde(with = "css_color")]
+    pub decay: Color,
+    #[serde(with = "css_color")]
+    pub sustain: Color,
+    #[serde(with = "css_color")]
+    pub release: Color,
+    /// GAIN setting the level directly
+    #[serde(with = "css_color")]
+    pub direct: Color,
+    /// GAIN linear and bent increase
+    #[serde(with = "css_color")]
+    pub increase: Color,
+    /// GAIN linear and exponential decrease
+    #[serde(with = "css_color")]
+    pub decrease: Color,
+    /// Markers of KON and KOF writes
+    #[serde(with = "css_color")]
+    pub key_on: Color,
+    #[serde(with = "css_color")]
+    pub key_off: Color
+}
+
+impl Default for EnvelopeTheme {
+    fn default() -> Self {
+        Self {
+            attack: Color::new(0xFF, 0x40, 0xFF, 0x80),
+            decay: Color::new(0xFF, 0xFF, 0xD0, 0x40),
+            sustain: Color::new(0xFF, 0x40, 0xA0, 0xFF),
+            release: Color::new(0xFF, 0x80, 0x80, 0x80),
+            direct: Color::new(0xFF, 0xC0, 0xC0, 0xC0),
+            increase: Color::new(0xFF, 0xFF, 0x80, 0xFF),
+            decrease: Color::new(0xFF, 0xFF, 0x80, 0x40),
+            key_on: Color::new(0xFF, 0xFF, 0xFF, 0xFF),
+            key_off: Color::new(0xFF, 0xFF, 0x40, 0x40)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SampleTheme {
+    /// Position of the voice within its sample
+    #[serde(with = "css_color")]
+    pub playhead: Color,
+    /// Where looping samples loop back to
+    #[serde(with = "css_color")]
+    pub loop_marker: Color
+}
+
+impl Default for SampleTheme {
+    fn default() -> Self {
+        Self {
+            playhead: Color::new(0xFF, 0xFF, 0xFF, 0xFF),
+            loop_marker: Color::new(0xC0, 0xFF, 0xD0, 0x40)
+        }
+    }
+}
+
+/// Colors of the memory map. Accesses are drawn over the regions, fading out as they age.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MemoryTheme {
+    #[serde(with = "css_color")]
+    pub read: Color,
+    #[serde(with = "css_color")]
+    pub write: Color,
+    /// Sound driver code, as executed by the SMP
+    #[serde(with = "css_color")]
+    pub code: Color,
+    /// Sample directory entries read by the S-DSP
+    #[serde(with = "css_color")]
+    pub directory: Color,
+    /// BRR blocks read by the S-DSP
+    #[serde(with = "css_color")]
+    pub sample: Color,
+    /// The echo buffer, while echo writes are enabled
+    #[serde(with = "css_color")]
+    pub echo: Color
+}
+
+impl Default for MemoryTheme {
+    fn default() -> Self {
+        Self {
+            read: Color::new(0xFF, 0x40, 0xC0, 0xFF),
+            write: Color::new(0xFF, 0xFF, 0x60, 0x40),
+            code: Color::new(0x66, 0xFF, 0xFF, 0xFF),
+            directory: Color::new(0x99, 0xFF, 0xD0, 0x40),
+            sample: Color::new(0x66, 0x40, 0xFF, 0x80),
+            echo: Color::new(0x66, 0xC0, 0x60, 0xFF)
+        }
+    }
+}
+
+/// How a channel color is dimmed: each component becomes `component * brightness + offset`.
+#[derive(Deserialize, Copy, Clone)]
+#[serde(default)]
+pub struct Dimming {
+    pub brightness: f32,
+    pub offset: u8,
+    /// Convert to grey before dimming
+    pub greyscale: bool
+}
+
+impl Dimming {
+    pub fn apply(&self, color: Color) -> Color {
+        let dim = |c: u8| ((c as f32 * self.brightness) as u32 + self.offset as u32).min(0xFF) as u8;
+
+        if self.greyscale {
+            let grey = ((color.r() as u32 * 3 + color.g() as u32 * 6 + color.b() as u32) / 10) as u8;
+            Color::new(color.a(), dim(grey), dim(grey), dim(grey))
+        } else {
+            Color::new(color.a(), dim(color.r()), dim(color.g()), dim(color.b()))
+        }
+    }
+}
+
+impl Default for Dimming {
+    fn default() -> Self {
+        Self {
+            brightness: 0.5,
+            offset: 0x10,
+            greyscale: false
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(default)]
+pub struct DimmingTheme {
+    /// Channels that are playing at zero volume
+    pub silent: Dimming,
+    /// Channels muted by the user
+    pub muted: Dimming
+}
+
+impl Default for DimmingTheme {
+    fn default() -> Self {
+        Self {
+            silent: Dimming::default(),
+            muted: Dimming { greyscale: true, ..Dimming::default() }
+        }
+    }
+}
+
+fn default_tile_size() -> usize {
+    8
+}
+
+fn default_font_size() -> f32 {
+    8.0
+}
+
+fn default_font_color() -> Color {
+    Color::new(0xFF, 0xFF, 0xFF, 0xFF)
+}
+
+/// A font file: a PNG of equally sized tiles laid out in `char_map` order, a BDF bitmap
+/// font, or a TrueType/OpenType font. The last two can draw any character they have,
+/// like the Japanese in many song and game titles.
+#[derive(Deserialize, Clone)]
+pub struct FontTheme {
+    #[serde(alias = "image")]
+    pub file: PathBuf,
+    #[serde(default = "default_tile_size")]
+    pub tile_w: usize,
+    #[serde(default = "default_tile_size")]
+    pub tile_h: usize,
+    pub char_map: Option<String>,
+    /// Pixel size of TrueType/OpenType fonts at the reference resolution
+    #[serde(default = "default_font_size")]
+    pub size: f32,
+    /// Color of BDF and TrueType/OpenType text, since unlike tiles they have none of their own
+    #[serde(with = "css_color", default = "default_font_color")]
+    pub color: Color
+}
+
+/// Colors, line styles and text used by the visualizer. Theme files are JSON or TOML,
+/// and any value they leave out keeps its default.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Theme {
+    /// Canvas color behind all panels, transparent by default so backgrounds show through
+    #[serde(with = "css_color")]
+    pub background: Color,
+    /// Base color of each channel, unless set from the CLI or GUI
+    #[serde(with = "css_colors")]
+    pub channel_colors: Vec<Color>,
+    pub piano: PianoTheme,
+    pub oscilloscope: OscilloscopeTheme,
+    pub overlay: OverlayTheme,
+    pub echo: EchoTheme,
+    pub spectrum: SpectrumTheme,
+    pub vectorscope: VectorscopeTheme,
+    pub envelope: EnvelopeTheme,
+    pub sample: SampleTheme,
+    pub memory: MemoryTheme,
+    pub dimming: DimmingTheme,
+    pub font: Option<FontTheme>
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            background: Color::new(0, 0, 0, 0),
+            channel_colors: Vec::new(),
+            piano: PianoTheme::default(),
+            oscilloscope: OscilloscopeTheme::default(),
+            overlay: OverlayTheme::default(),
+            echo: EchoTheme::default(),
+            spectrum: SpectrumTheme::default(),
+            vectorscope: VectorscopeTheme::default(),
+            envelope: EnvelopeTheme::default(),
+            sample: SampleTheme::default(),
+            memory: MemoryTheme::default(),
+            dimming: DimmingTheme::default(),
+            font: None
+        }
+    }
+}
+
+impl Theme {
+    pub fn preset(name: &str) -> Result<Self, String> {
+        match name {
+            "default" => Ok(Self::default()),
+            "neon" => Ok(Self {
+                channel_colors: ["#ff2a6d", "#f9f871", "#05ffa1", "#d1f7ff", "#b967ff", "#01cdfe", "#ff9f1c", "#00f5d4"]
+                    .iter()
+                    .map(|c| parse_css_color(c).unwrap())
+                    .collect(),
+                piano: PianoTheme {
+                    key_border: Color::new(0xFF, 0x2A, 0x10, 0x40),
+                    top_edge: Color::new(0xFF, 0x01, 0xCD, 0xFE),
+                    ..PianoTheme::default()
+                },
+                oscilloscope: OscilloscopeTheme {
+                    background: Color::new(0xFF, 0x0B, 0x02, 0x1A),
+                    gradient_alpha: 0x40,
+                    glow_alpha: 0x80,
+                    glow_width: 6.0,
+                    line_width: 1.5,
+                    ..OscilloscopeTheme::default()
+                },
+                dimming: DimmingTheme {
+                    silent: Dimming { brightness: 0.7, offset: 0x00, greyscale: false },
+                    ..DimmingTheme::default()
+                },
+                ..Self::default()
+            }),
+            "flat" => Ok(Self {
+                piano: PianoTheme {
+                    slice_outline: Color::new(0, 0, 0, 0),
+                    ..PianoTheme::default()
+                },
+                oscilloscope: OscilloscopeTheme {
+                    gradient_alpha: 0,
+                    glow_alpha: 0,
+                    divider_width: 0,
+                    header: "".to_string(),
+                    ..OscilloscopeTheme::default()
+                },
+                ..Self::default()
+            }),
+            _ => Err(format!("Unknown theme {}, try one of {}", name, THEME_PRESETS.join(", ")))
+        }
+    }
+
+    pub fn from_json(j: &str) -> Result<Self, String> {
+        serde_json::from_str(j).map_err(|e| e.to_string())
+    }
+
+    pub fn from_toml(t: &str) -> Result<Self, String> {
+        toml::from_str(t).map_err(|e| e.to_string())
+    }
+
+    /// Load a theme file, as TOML if it has a `.toml` extension and as JSON otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let contents = fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("Could not read theme {}: {}", path.as_ref().display(), e))?;
+        let is_toml = path.as_ref().extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("toml"));
+
+        let mut theme = match is_toml {
+            true => Self::from_toml(&contents)?,
+            false => Self::from_json(&contents)?
+        };
+
+        // Font files are relative to the theme file
+        if let (Some(font), Some(theme_dir)) = (theme.font.as_mut(), path.as_ref().parent()) {
+            font.file = theme_dir.join(&font.file);
+        }
+
+        Ok(theme)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argb(color: Color) -> (u8, u8, u8, u8) {
+        (color.a(), color.r(), color.g(), color.b())
+    }
+
+    fn parse(s: &str) -> (u8, u8, u8, u8) {
+        argb(parse_css_color(s).unwrap())
+    }
+
+    #[test]
+    fn parse_css_color_test() {
+        assert_eq!(parse("#ff8000"), (0xFF, 0xFF, 0x80, 0x00));
+        assert_eq!(parse("#F80"), (0xFF, 0xFF, 0x88, 0x00));
+        assert_eq!(parse("#ff000080"), (0x80, 0xFF, 0x00, 0x00));
+        assert_eq!(parse("rebeccapurple"), (0xFF, 0x66, 0x33, 0x99));
+        assert_eq!(parse("transparent"), (0x00, 0x00, 0x00, 0x00));
+        assert_eq!(parse("rgb(1, 2, 3)"), (0xFF, 1, 2, 3));
+        assert_eq!(parse("rgba(255, 255, 255, 0.5)"), (0x80, 0xFF, 0xFF, 0xFF));
+        assert_eq!(parse("hsl(120, 100%, 50%)"), (0xFF, 0x00, 0xFF, 0x00));
+    }
+
+    #[test]
+    fn parse_css_color_invalid_test() {
+        for s in ["", "not a color", "#12345", "rgb(1, 2)"] {
+            assert!(parse_css_color(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn theme_file_test() {
+        let theme = Theme::from_json(r##"{
+            "background": "#102030",
+            "channel_colors": ["red", "#00ff00"],
+            "piano": { "playhead": "rgba(0, 0, 255, 0.25)" }
+        }"##).unwrap();
+        assert_eq!(argb(theme.background), (0xFF, 0x10, 0x20, 0x30));
+        assert_eq!(theme.channel_colors.iter().map(|&c| argb(c)).collect::<Vec<_>>(), vec![(0xFF, 0xFF, 0, 0), (0xFF, 0, 0xFF, 0)]);
+        assert_eq!(argb(theme.piano.playhead), (0x40, 0, 0, 0xFF));
+        // Values left out keep their defaults
+        assert_eq!(argb(theme.piano.white_key), argb(PianoTheme::default().white_key));
+
+        let theme = Theme::from_toml("background = \"white\"\n[piano]\nkey_border = \"#000\"").unwrap();
+        assert_eq!(argb(theme.background), (0xFF, 0xFF, 0xFF, 0xFF));
+        assert_eq!(argb(theme.piano.key_border), (0xFF, 0, 0, 0));
+
+        assert!(Theme::from_json(r#"{ "background": "nope" }"#).is_err());
+        assert!(Theme::from_json(r#"{ "channel_colors": ["red", "nope"] }"#).is_err());
+    }
+
+    #[test]
+    fn presets_test() {
+        for name in THEME_PRESETS {
+            assert!(Theme::preset(name).is_ok(), "{}", name);
+        }
+        assert_eq!(Theme::preset("neon").unwrap().channel_colors.len(), 8);
+        assert!(Theme::preset("nothing").is_err());
+    }
+}
@@ -86,16 +86,29 @@ impl TileMap {
         })
     }
 
-    pub fn draw_tile(&mut self, dt: &mut DrawTarget, c: char, x: f32, y: f32, alpha: f32) {
-        let tile_buf = self.tile_buf(c);
-        if tile_buf.is_none() {
-            return;
-        }
+    /// Draw a tile enlarged by an integer factor with nearest neighbor scaling
+    pub fn draw_tile_scaled(&mut self, dt: &mut DrawTarget, c: char, x: f32, y: f32, scale: usize, alpha: f32) {
+        let tile_buf = match self.tile_buf(c) {
+            Some(tile_buf) => tile_buf,
+            None => return
+        };
+
+        let tile_buf = match scale {
+            0 | 1 => tile_buf,
+            _ => (0..self.tile_h * scale * self.tile_w * scale)
+                .map(|i| {
+                    let dx = (i % (self.tile_w * scale)) / scale;
+                    let dy = (i / (self.tile_w * scale)) / scale;
+                    tile_buf[dy * self.tile_w + dx]
+                })
+                .collect()
+        };
+        let scale = scale.max(1);
 
         let image = Image {
-            width: self.tile_w as _,
-            height: self.tile_h as _,
-            data: &tile_buf.unwrap(),
+            width: (self.tile_w * scale) as _,
+            height: (self.tile_h * scale) as _,
+            data: &tile_buf,
         };
 
         dt.draw_image_at(x, y, &image, &DrawOptions {
@@ -105,10 +118,10 @@ impl TileMap {
         });
     }
 
-    pub fn draw_text(&mut self, dt: &mut DrawTarget, text: &str, x: f32, y: f32, alpha: f32) {
+    pub fn draw_text_scaled(&mut self, dt: &mut DrawTarget, text: &str, x: f32, y: f32, scale: usize, alpha: f32) {
         for (i, c) in text.chars().enumerate() {
-            let dx = (i as f32) * (self.tile_w as f32);
-            self.draw_tile(dt, c, x + dx, y, alpha);
+            let dx = (i * self.tile_w * scale.max(1)) as f32;
+            self.draw_tile_scaled(dt, c, x + dx, y, scale, alpha);
         }
     }
 }
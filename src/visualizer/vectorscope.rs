@@ -0,0 +1,163 @@
+use ringbuf::Rb;
+use raqote::{DrawOptions, SolidSource, Source};
+use crate::visualizer::layout::Rect;
+use super::echo::{line_style, polyline};
+use super::Visualizer;
+
+/// Number of output samples traced by a vectorscope
+const VECTORSCOPE_WINDOW: usize = 1024;
+/// Quietest peak that is scaled up to fill the whole scope
+const MIN_PEAK: f32 = 1.0 / 16.0;
+
+/// Correlation between the left and right side: 1 for mono, 0 for unrelated sides and
+/// -1 for one side inverting the other, as with SNES surround. Silence counts as 0.
+pub fn correlation(samples: &[(i16, i16)]) -> f32 {
+    let (mut lr, mut ll, mut rr) = (0.0f64, 0.0f64, 0.0f64);
+    for &(left, right) in samples {
+        let (left, right) = (left as f64, right as f64);
+        lr += left * right;
+        ll += left * left;
+        rr += right * right;
+    }
+
+    match ll > 0.0 && rr > 0.0 {
+        true => (lr / (ll * rr).sqrt()) as f32,
+        false => 0.0
+    }
+}
+
+/// Goniometer position of a sample as (x, y) with y pointing up, and the sides on the
+/// diagonals. Mono runs up and down the middle, while inverted sides spread sideways.
+pub fn goniometer_point(left: f32, right: f32) -> (f32, f32) {
+    ((right - left) / 2.0, (left + right) / 2.0)
+}
+
+impl Visualizer {
+    pub fn draw_vectorscope(&mut self, rect: Rect, channel: Option<usize>) {
+        let Rect { x, y, w, h } = rect;
+        let theme = self.theme.vectorscope.clone();
+        let font_scale = self.layout.pixel_scale();
+        let line_width = self.layout.line_width();
+        let line_h = self.font.line_height(font_scale);
+        let padding = line_h / 2.0;
+
+        self.canvas.fill_rect(
+            x, y, w, h,
+            &Source::from(self.theme.oscilloscope.background),
+            &DrawOptions::new()
+        );
+
+        let samples: Vec<(i16, i16)> = self.mix_samples.iter()
+            .skip(self.mix_samples.len().saturating_sub(VECTORSCOPE_WINDOW))
+            .map(|sample| match channel {
+                Some(channel) => sample.voices[channel],
+                None => sample.mix
+            })
+            .collect();
+        let color = match channel {
+            Some(channel) => self.settings.settings(channel).colors().first().cloned().unwrap_or(theme.trace),
+            None => theme.trace
+        };
+
+        // The scope in the largest square that fits above the correlation meter
+        let size = f32::min(w - 2.0 * padding, h - line_h - 3.0 * padding);
+        if size <= 0.0 {
+            return;
+        }
+        let radius = size / 2.0;
+        let (center_x, center_y) = (x + w / 2.0, y + padding + radius);
+
+        let guides = [
+            ((0.0, -1.0), (0.0, 1.0)),
+            ((-1.0, 0.0), (1.0, 0.0)),
+            ((-0.5, -0.5), (0.5, 0.5)),
+            ((-0.5, 0.5), (0.5, -0.5))
+        ];
+        for (from, to) in guides {
+            let path = polyline([from, to].into_iter().map(|(gx, gy)| (center_x + gx * radius, center_y - gy * radius)));
+            self.canvas.stroke(
+                &path,
+                &Source::Solid(SolidSource::from(theme.guide)),
+                &line_style(line_width),
+                &DrawOptions::default()
+            );
+        }
+        self.font.draw_text_scaled(&mut self.canvas, "L", center_x - radius, y + padding, font_scale, 1.0);
+        let r_w = self.font.text_width("R", font_scale);
+        self.font.draw_text_scaled(&mut self.canvas, "R", center_x + radius - r_w, y + padding, font_scale, 1.0);
+
+        // Quiet channels are scaled up, since it's the shape that matters
+        let peak = samples.iter()
+            .map(|&(left, right)| i16::max(left.saturating_abs(), right.saturating_abs()) as f32 / 32768.0)
+            .fold(MIN_PEAK, f32::max);
+        let path = polyline(samples.iter().map(|&(left, right)| {
+            let (px, py) = goniometer_point(left as f32 / 32768.0 / peak, right as f32 / 32768.0 / peak);
+            (center_x + px * radius, center_y - py * radius)
+        }));
+        self.canvas.stroke(
+            &path,
+            &Source::Solid(SolidSource::from(color)),
+            &line_style(self.theme.oscilloscope.line_width * self.layout.scale()),
+            &DrawOptions::default()
+        );
+
+        // Correlation meter from -1 on the left to 1 on the right, with its value next to it
+        let correlation = correlation(&samples);
+        let label = format!("{:+.2}", correlation);
+        let label_w = self.font.text_width("+0.00", font_scale);
+        let meter_x = x + padding;
+        let meter_y = y + h - padding - line_h;
+        let meter_w = w - 3.0 * padding - label_w;
+        if meter_w <= 0.0 {
+            return;
+        }
+        let meter_center = meter_x + meter_w / 2.0;
+        self.canvas.fill_rect(
+            meter_x, meter_y + (line_h - line_width) / 2.0, meter_w, line_width,
+            &Source::from(theme.guide),
+            &DrawOptions::new()
+        );
+        self.canvas.fill_rect(
+            meter_center - line_width / 2.0, meter_y, line_width, line_h,
+            &Source::from(theme.guide),
+            &DrawOptions::new()
+        );
+
+        let meter_end = meter_center + correlation * meter_w / 2.0;
+        let meter_color = match correlation < 0.0 {
+            true => theme.out_of_phase,
+            false => theme.in_phase
+        };
+        self.canvas.fill_rect(
+            f32::min(meter_center, meter_end), meter_y + line_h / 4.0, (meter_end - meter_center).abs(), line_h / 2.0,
+            &Source::from(meter_color),
+            &DrawOptions::new()
+        );
+        self.font.draw_text_scaled(&mut self.canvas, &label, meter_x + meter_w + padding, meter_y, font_scale, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlation_test() {
+        let mono: Vec<(i16, i16)> = (0..100).map(|i| (i * 100 - 5000, i * 100 - 5000)).collect();
+        let surround: Vec<(i16, i16)> = mono.iter().map(|&(left, right)| (left, -right)).collect();
+        let left_only: Vec<(i16, i16)> = mono.iter().map(|&(left, _)| (left, 0)).collect();
+
+        assert!((correlation(&mono) - 1.0).abs() < 1e-6);
+        assert!((correlation(&surround) + 1.0).abs() < 1e-6);
+        assert_eq!(correlation(&left_only), 0.0);
+        assert_eq!(correlation(&[(0, 0); 16]), 0.0);
+    }
+
+    #[test]
+    fn goniometer_point_test() {
+        assert_eq!(goniometer_point(1.0, 1.0), (0.0, 1.0));
+        assert_eq!(goniometer_point(1.0, -1.0), (-1.0, 0.0));
+        assert_eq!(goniometer_point(1.0, 0.0), (-0.5, 0.5));
+        assert_eq!(goniometer_point(0.0, 1.0), (0.5, 0.5));
+    }
+}